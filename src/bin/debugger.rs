@@ -1,13 +1,28 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::io::{stdin, stdout, BufRead, BufReader, Write};
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
 
 use anyhow::{anyhow, Result};
+use bytes::Bytes;
 use clap::{crate_version, App, Arg};
-use tifs::fs::inode::Inode;
-use tifs::fs::key::{ScopedKey, ROOT_INODE};
+use fuser::FileType;
+use serde::{Deserialize, Serialize};
+use tifs::fs::atime::AtimePolicy;
+use tifs::fs::commit::CommitPolicy;
+use tifs::fs::dir::Directory;
+use tifs::fs::error::FsError;
+use tifs::fs::health::ping;
+use tifs::fs::index::Index;
+use tifs::fs::inode::{Inode, XATTR_SIZE_MAX};
+use tifs::fs::key::{Pages, ScopedKey, ROOT_INODE};
+use tifs::fs::meta::{Meta, Usage};
+use tifs::fs::reply::DirItem;
+use tifs::fs::scrub::{check_inode, Inconsistency};
+use tifs::fs::serialize::{deserialize, serialize};
 use tifs::fs::tikv_fs::TiFs;
 use tifs::fs::transaction::Txn;
-use tikv_client::TransactionClient;
+use tikv_client::{Key, TimestampExt, TransactionClient};
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -24,6 +39,18 @@ async fn main() -> Result<()> {
                 .help("set all pd endpoints of the tikv cluster")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("never commit, commands modifying the filesystem are disabled"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("TIMESTAMP")
+                .help("read the filesystem at the snapshot of a timestamp, implies --read-only")
+                .takes_value(true),
+        )
         .get_matches();
 
     tracing_subscriber::fmt()
@@ -37,7 +64,14 @@ async fn main() -> Result<()> {
         .to_owned()
         .collect();
 
-    let console = Console::construct(endpoints).await?;
+    let snapshot = matches
+        .value_of("snapshot")
+        .map(|ts| {
+            ts.parse::<u64>()
+                .map_err(|err| anyhow!("invalid timestamp `{}`: {}", ts, err))
+        })
+        .transpose()?;
+    let console = Console::construct(endpoints, matches.is_present("read-only"), snapshot).await?;
 
     loop {
         match console.interact().await {
@@ -51,32 +85,111 @@ async fn main() -> Result<()> {
 struct Console {
     pd_endpoints: Vec<String>,
     client: TransactionClient,
+    read_only: bool,
+    // every transaction reads the snapshot at this timestamp
+    snapshot: Option<u64>,
+    // the debugger takes no mount options, every transaction commits by the default policy
+    commit_policy: CommitPolicy,
 }
 
 impl Console {
-    async fn construct<S>(pd_endpoints: Vec<S>) -> Result<Self>
+    /// Commands modifying the filesystem are disabled on a `read_only` console.
+    const MUTATING_COMMANDS: &'static [&'static str] = &["reset", "rm", "import"];
+
+    async fn construct<S>(
+        pd_endpoints: Vec<S>,
+        read_only: bool,
+        snapshot: Option<u64>,
+    ) -> Result<Self>
     where
         S: Clone + Debug + Into<String>,
     {
-        let client =
-            TransactionClient::new_with_config(pd_endpoints.clone(), Default::default())
-                .await
-                .map_err(|err| anyhow!("{}", err))?;
+        let client = TransactionClient::new_with_config(pd_endpoints.clone(), Default::default())
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
         Ok(Self {
             client,
             pd_endpoints: pd_endpoints.into_iter().map(Into::into).collect(),
+            read_only: read_only || snapshot.is_some(),
+            snapshot,
+            commit_policy: CommitPolicy::default(),
         })
     }
 
-    async fn interact(&self) -> Result<bool> {
-        let mut txn = Txn::begin_optimistic(
+    async fn begin(&self, block_size: u64, dedup: bool) -> Result<Txn> {
+        if let Some(timestamp) = self.snapshot {
+            return Ok(Txn::snapshot(
+                &self.client,
+                timestamp,
+                block_size,
+                TiFs::MAX_NAME_LEN,
+                block_size / Txn::INLINE_DATA_THRESHOLD_BASE,
+                dedup,
+                None,
+            ));
+        }
+        Ok(Txn::begin_optimistic(
             &self.client,
-            TiFs::DEFAULT_BLOCK_SIZE,
+            block_size,
             None,
             TiFs::MAX_NAME_LEN,
+            block_size / Txn::INLINE_DATA_THRESHOLD_BASE,
+            false,
+            AtimePolicy::Never,
+            dedup,
+            None,
+            self.commit_policy,
+        )
+        .await?)
+    }
+
+    /// A transaction writing blocks and inodes as the filesystem of `meta` does.
+    async fn begin_import(&self, meta: &Meta) -> Result<Txn> {
+        let divisor = meta
+            .inline_divisor
+            .unwrap_or(Txn::INLINE_DATA_THRESHOLD_BASE);
+        let threshold = meta.inline_threshold.unwrap_or(meta.block_size / divisor);
+        let txn = Txn::begin_optimistic(
+            &self.client,
+            meta.block_size,
+            None,
+            TiFs::MAX_NAME_LEN,
+            threshold,
+            meta.disable_atime,
+            AtimePolicy::Never,
+            meta.dedup,
+            None,
+            self.commit_policy,
         )
         .await?;
+        Ok(txn.with_checksum(meta.checksum))
+    }
+
+    /// A transaction reading blocks as the filesystem stored in meta does, by the default
+    /// settings on an unformatted cluster or a corrupt meta, which `verify` tells.
+    async fn begin_stored(&self) -> Result<Txn> {
+        let mut txn = self.begin(TiFs::DEFAULT_BLOCK_SIZE, false).await?;
+        let meta = txn.read_meta().await;
+        txn.rollback().await?;
+        match meta {
+            Ok(Some(meta)) => Ok(self
+                .begin(meta.block_size, meta.dedup)
+                .await?
+                .with_checksum(meta.checksum)),
+            Ok(None) | Err(FsError::Serialize { .. }) => {
+                self.begin(TiFs::DEFAULT_BLOCK_SIZE, false).await
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn interact(&self) -> Result<bool> {
+        let mut txn = self.begin_stored().await?;
         match self.interact_with_txn(&mut txn).await {
+            Ok(exit) if self.read_only => {
+                txn.rollback().await?;
+                Ok(exit)
+            }
             Ok(exit) => {
                 txn.commit().await?;
                 Ok(exit)
@@ -98,41 +211,529 @@ impl Console {
         if commands.is_empty() {
             return Ok(false);
         }
+        if self.read_only
+            && (Self::MUTATING_COMMANDS.contains(&commands[0])
+                || commands[0] == "fsck" && commands.contains(&"--repair"))
+        {
+            return Err(anyhow!("`{}` is disabled in read-only mode", buffer.trim()));
+        }
 
         match commands[0] {
             "exit" => return Ok(true),
-            "reset" => self.reset(txn).await?,
+            "ping" => self.ping().await?,
+            "ts" => self.timestamp().await?,
+            "verify" => self.verify(txn).await?,
+            "reset" => self.reset(txn, &commands[1..]).await?,
             "get" => self.get_block(txn, &commands[1..]).await?,
             "get_str" => self.get_block_str(txn, &commands[1..]).await?,
             "get_attr" => self.get_attr(txn, &commands[1..]).await?,
+            "stat" => self.stat(txn, &commands[1..]).await?,
             "get_raw" => self.get_attr_raw(txn, &commands[1..]).await?,
             "get_inline" => self.get_inline(txn, &commands[1..]).await?,
+            "blocks" => self.get_blocks(txn, &commands[1..]).await?,
             "rm" => self.delete_block(txn, &commands[1..]).await?,
+            "fsck" => self.fsck(txn, &commands[1..]).await?,
+            "ls" => self.ls(txn, &commands[1..]).await?,
+            "tree" => self.tree(txn, &commands[1..]).await?,
+            "du" => self.du(txn, &commands[1..]).await?,
+            "export" => self.export(&commands[1..]).await?,
+            "import" => self.import(&commands[1..]).await?,
             cmd => return Err(anyhow!("unknow command `{}`", cmd)),
         }
 
         Ok(false)
     }
 
-    async fn reset(&self, txn: &mut Txn) -> Result<()> {
-        let next_inode = txn
-            .read_meta()
+    /// Verify the cluster by a round trip on the probe key, which is only read in read-only mode.
+    async fn ping(&self) -> Result<()> {
+        let latency = ping(&self.client, !self.read_only).await?;
+        println!("pong from {:?} in {:?}", self.pd_endpoints, latency);
+        Ok(())
+    }
+
+    /// Print the current timestamp, to read the filesystem at by `--snapshot` later.
+    async fn timestamp(&self) -> Result<()> {
+        println!("{}", self.client.current_timestamp().await?.version());
+        Ok(())
+    }
+
+    /// Check the cluster holds a filesystem, telling an unformatted cluster from a wrong or
+    /// corrupt one, and print the block size and the next inode number of it.
+    async fn verify(&self, txn: &mut Txn) -> Result<()> {
+        let meta = match txn.read_meta().await {
+            Ok(meta) => meta,
+            Err(err @ FsError::Serialize { .. }) => return Err(anyhow!("corrupt meta: {}", err)),
+            Err(err) => return Err(err.into()),
+        };
+        let root = match txn.read_inode(ROOT_INODE).await {
+            Ok(inode) => Some(inode),
+            Err(FsError::InodeNotFound { .. }) => None,
+            Err(err @ FsError::Serialize { .. }) => {
+                return Err(anyhow!("corrupt root inode: {}", err))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match (meta, root) {
+            (None, None) => {
+                // keys of anything else than probes tell a wrong cluster
+                let probe: Key = ScopedKey::probe().into();
+                let other = txn
+                    .scan(Vec::<u8>::new().., 2)
+                    .await?
+                    .any(|pair| *pair.key() != probe);
+                if other {
+                    Err(anyhow!(
+                        "wrong cluster: it holds data other than a filesystem"
+                    ))
+                } else {
+                    Err(anyhow!("not formatted: the cluster is empty"))
+                }
+            }
+            (None, Some(_)) => Err(anyhow!("corrupt: the root inode exists without meta")),
+            (Some(_), None) => Err(anyhow!("corrupt: meta exists without the root inode")),
+            (Some(_), Some(root)) if root.kind != FileType::Directory => Err(anyhow!(
+                "corrupt: the root inode is a {:?} but not a directory",
+                root.kind
+            )),
+            (Some(meta), Some(_)) if meta.inode_next <= ROOT_INODE => Err(anyhow!(
+                "corrupt: inode_next({}) is not beyond the root inode",
+                meta.inode_next
+            )),
+            (Some(meta), Some(_)) => {
+                println!(
+                    "block size: {}, inode_next: {}",
+                    meta.block_size, meta.inode_next
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Destroy the filesystem. Inodes to delete are listed unless `--yes` is given,
+    /// and `--dry-run` only counts them.
+    async fn reset(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let mut confirmed = false;
+        let mut dry_run = false;
+        for arg in args.iter().filter(|arg| !arg.is_empty()) {
+            match *arg {
+                "--yes" => confirmed = true,
+                "--dry-run" => dry_run = true,
+                arg => return Err(anyhow!("unknown argument `{}` of reset", arg)),
+            }
+        }
+
+        let meta = txn.read_meta().await?;
+        // inodes may be left without meta, then all numbers are scanned
+        let inode_next = match &meta {
+            Some(meta) if !meta.importing => meta.inode_next,
+            Some(_) => {
+                println!("an import is unfinished, scan all inodes");
+                u64::MAX
+            }
+            None => {
+                println!("meta is missing, scan all inodes");
+                u64::MAX
+            }
+        };
+        let mut inodes = Vec::new();
+        let mut pages = Pages::new(
+            ScopedKey::inode_range(ROOT_INODE..inode_next),
+            txn.scan_limit(),
+        );
+        while let Some(range) = pages.range() {
+            let pairs: Vec<_> = txn.scan(range, pages.limit()).await?.collect();
+            for pair in &pairs {
+                let ino = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::Inode(ino) => ino,
+                    key => return Err(anyhow!("unexpected key `{:?}`", key)),
+                };
+                inodes.push((ino, Inode::deserialize(pair.value()).ok()));
+            }
+            pages.advance(pairs.len(), pairs.last().map(|pair| pair.key()));
+        }
+        let free_limit = (inode_next - ROOT_INODE).min(u32::MAX as u64) as u32;
+        let free = txn
+            .scan_keys(ScopedKey::free_inode_scope(), free_limit)
             .await?
-            .map(|meta| meta.inode_next)
-            .unwrap_or(ROOT_INODE);
-        for inode in txn
-            .scan(
-                ScopedKey::inode_range(ROOT_INODE..next_inode),
-                (next_inode - ROOT_INODE) as u32,
-            )
+            .count();
+
+        if meta.is_none() && inodes.is_empty() && free == 0 {
+            println!("nothing to reset");
+            return Ok(());
+        }
+        if !dry_run && !confirmed {
+            for (ino, inode) in &inodes {
+                match inode {
+                    Some(inode) => println!("{}\t{:?}\t{}", ino, inode.kind, inode.size),
+                    None => println!("{}\tcorrupt", ino),
+                }
+            }
+        }
+        let size: u64 = inodes
+            .iter()
+            .filter_map(|(_, inode)| inode.as_ref())
+            .map(|inode| inode.size)
+            .sum();
+        println!(
+            "{} inodes of {} bytes, {} free inode numbers{}",
+            inodes.len(),
+            size,
+            free,
+            if meta.is_some() { " and the meta" } else { "" },
+        );
+        if dry_run {
+            return Ok(());
+        }
+        if !confirmed {
+            println!("nothing is deleted, run `reset --yes` to delete them");
+            return Ok(());
+        }
+
+        // blocks are found by scanning, corrupt inodes can't tell their size;
+        // values of a corrupt one which are no hashes of contents release nothing
+        let dedup = meta.as_ref().map_or(false, |meta| meta.dedup);
+        for (ino, inode) in inodes {
+            let deduped = dedup
+                && inode
+                    .as_ref()
+                    .map_or(true, |inode| inode.kind == FileType::RegularFile);
+            txn.delete_blocks(ino, 0, deduped).await?;
+            match inode {
+                Some(_) => txn.remove_inode(ino).await?,
+                None => txn.delete(ScopedKey::inode(ino)).await?,
+            }
+        }
+        // numbers of a new filesystem start over
+        let free: Vec<_> = txn
+            .scan_keys(ScopedKey::free_inode_scope(), free_limit)
             .await?
-            .map(|pair| Inode::deserialize(pair.value()))
-        {
-            let inode = inode?;
-            txn.clear_data(inode.ino).await?;
-            txn.remove_inode(inode.ino).await?;
+            .collect();
+        for key in free {
+            txn.delete(key).await?;
         }
         txn.delete(ScopedKey::meta()).await?;
+        println!("the filesystem is reset");
+        Ok(())
+    }
+
+    /// The directory to list, the root by default.
+    fn dir_arg(args: &[&str]) -> Result<u64> {
+        match args.first() {
+            Some(ino) if !ino.is_empty() => Ok(ino.parse()?),
+            _ => Ok(ROOT_INODE),
+        }
+    }
+
+    async fn ls(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let ino = Self::dir_arg(args)?;
+        for item in txn.read_dir(ino).await? {
+            println!("{}\t{:?}\t{}", item.ino, item.typ, item.name);
+        }
+        Ok(())
+    }
+
+    async fn tree(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let root = Self::dir_arg(args)?;
+        let mut visited = HashSet::new();
+        // (ino, kind, name, depth) of entries to print, in depth-first order
+        let mut stack = vec![(root, FileType::Directory, root.to_string(), 0)];
+        while let Some((ino, typ, name, depth)) = stack.pop() {
+            let indent = "    ".repeat(depth);
+            println!("{}{}\t{:?}\t{}", indent, ino, typ, name);
+            if typ != FileType::Directory {
+                continue;
+            }
+            if !visited.insert(ino) {
+                println!("{}    <visited>", indent);
+                continue;
+            }
+            match txn.read_dir(ino).await {
+                Ok(dir) => stack.extend(
+                    dir.into_iter()
+                        .rev()
+                        .filter(|item| item.name != "." && item.name != "..")
+                        .map(|item| (item.ino, item.typ, item.name, depth + 1)),
+                ),
+                Err(err) => println!("{}    <{}>", indent, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sum the blocks of inodes reachable from a directory, hard links are counted once.
+    async fn du(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let root = Self::dir_arg(args)?;
+        let block_size = txn
+            .read_meta()
+            .await?
+            .map(|meta| meta.block_size)
+            .unwrap_or_else(|| txn.block_size());
+        let mut visited = HashSet::new();
+        let mut stack = vec![root];
+        let mut blocks = 0;
+        while let Some(ino) = stack.pop() {
+            if !visited.insert(ino) {
+                continue;
+            }
+            let inode = txn.read_inode(ino).await?;
+            blocks += inode.blocks;
+            if inode.kind == FileType::Directory {
+                stack.extend(
+                    txn.read_dir(ino)
+                        .await?
+                        .into_iter()
+                        .filter(|item| item.name != "." && item.name != "..")
+                        .map(|item| item.ino),
+                );
+            }
+        }
+        println!(
+            "inodes: {}, blocks: {}, bytes: {}",
+            visited.len(),
+            blocks,
+            blocks * block_size
+        );
+        Ok(())
+    }
+
+    /// Dump the filesystem into a file, see `Record` for the format.
+    async fn export(&self, args: &[&str]) -> Result<()> {
+        let path = args
+            .first()
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| anyhow!("invalid arguments `{:?}`", args))?;
+        let mut txn = self.begin(TiFs::DEFAULT_BLOCK_SIZE, false).await?;
+        let meta = txn.read_meta().await?;
+        txn.rollback().await?;
+        let meta = meta.ok_or_else(|| anyhow!("the filesystem is not initialized"))?;
+        if meta.encrypted {
+            return Err(anyhow!("cannot export an encrypted filesystem"));
+        }
+
+        // the dump is read in one snapshot, nothing is written
+        let mut txn = self
+            .begin(meta.block_size, meta.dedup)
+            .await?
+            .with_checksum(meta.checksum);
+        let result = Self::export_with_txn(&mut txn, &meta, path).await;
+        txn.rollback().await?;
+        let (inodes, bytes) = result?;
+        println!("exported inodes: {}, bytes: {}", inodes, bytes);
+        Ok(())
+    }
+
+    async fn export_with_txn(txn: &mut Txn, meta: &Meta, path: &str) -> Result<(u64, u64)> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_record(&mut writer, &Record::Meta(meta.clone()))?;
+
+        let mut inodes = 0;
+        let mut bytes = 0;
+        let mut pages = Pages::new(
+            ScopedKey::inode_range(ROOT_INODE..meta.inode_next),
+            txn.scan_limit(),
+        );
+        while let Some(range) = pages.range() {
+            let keys: Vec<_> = txn.scan_keys(range, pages.limit()).await?.collect();
+            for key in &keys {
+                let ino = match ScopedKey::parse(key.into())? {
+                    ScopedKey::Inode(ino) => ino,
+                    key => return Err(anyhow!("unexpected key `{:?}`", key)),
+                };
+
+                // every inode is dumped once, however many entries link to it
+                let mut inode = txn.read_inode(ino).await?;
+                let xattrs = std::mem::take(&mut inode.xattrs);
+                write_record(&mut writer, &Record::Inode(inode.clone()))?;
+                for (name, value) in xattrs {
+                    write_record(&mut writer, &Record::Xattr { ino, name, value })?;
+                }
+                inodes += 1;
+                match inode.kind {
+                    FileType::RegularFile => {
+                        let mut offset = 0;
+                        while offset < inode.size {
+                            let data = txn.read_data(ino, offset, Some(meta.block_size)).await?;
+                            // holes stay holes
+                            if data.iter().any(|byte| *byte != 0) {
+                                bytes += data.len() as u64;
+                                write_record(&mut writer, &Record::Data { ino, offset, data })?;
+                            }
+                            offset += meta.block_size;
+                        }
+                    }
+                    FileType::Directory => {
+                        for item in txn.read_dir(ino).await? {
+                            write_record(&mut writer, &Record::Entry { parent: ino, item })?;
+                        }
+                    }
+                    // the target of a symlink is inlined in the inode
+                    _ => (),
+                }
+            }
+            pages.advance(keys.len(), keys.last());
+        }
+        writer.flush()?;
+        Ok((inodes, bytes))
+    }
+
+    /// Restore a dump into an uninitialized filesystem, inode numbers are preserved.
+    ///
+    /// Records are written by transactions of `IMPORT_BATCH` records, and the meta marks the
+    /// filesystem as importing until the last one commits, so that an import failed halfway
+    /// is never mounted but only reset.
+    async fn import(&self, args: &[&str]) -> Result<()> {
+        let path = args
+            .first()
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| anyhow!("invalid arguments `{:?}`", args))?;
+        let mut reader = BufReader::new(File::open(path)?);
+        let dumped = match read_record(&mut reader, RECORD_OVERHEAD)? {
+            Some(Record::Meta(meta)) => meta,
+            _ => return Err(anyhow!("`{}` is not a dump", path)),
+        };
+        let limit = record_limit(dumped.block_size);
+
+        let mut txn = self.begin_import(&dumped).await?;
+        match txn.read_meta().await {
+            Ok(None) => (),
+            Ok(Some(_)) => {
+                txn.rollback().await?;
+                return Err(anyhow!(
+                    "the filesystem is not empty, try `reset --yes` first"
+                ));
+            }
+            Err(err) => {
+                txn.rollback().await?;
+                return Err(err.into());
+            }
+        }
+        let mut meta = Meta::new(dumped.block_size, dumped.disable_atime, dumped.dedup, false);
+        meta.checksum = dumped.checksum;
+        meta.inline_divisor = dumped.inline_divisor;
+        meta.inline_threshold = dumped.inline_threshold;
+        meta.importing = true;
+        // inodes made later must not be mistaken for the imported ones
+        meta.renew_generation();
+        txn.save_meta(&meta).await?;
+
+        // the attributes to restore after data and entries are written
+        let mut inodes = Vec::new();
+        let mut dirs: HashMap<u64, Directory> = HashMap::new();
+        let mut pending = 0;
+        loop {
+            let record = match read_record(&mut reader, limit) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err) => {
+                    txn.rollback().await?;
+                    return Err(err);
+                }
+            };
+            if let Err(err) = Self::import_record(&mut txn, record, &mut inodes, &mut dirs).await {
+                txn.rollback().await?;
+                return Err(err);
+            }
+            pending += 1;
+            if pending >= IMPORT_BATCH {
+                txn.commit().await?;
+                txn = self.begin_import(&dumped).await?;
+                pending = 0;
+            }
+        }
+
+        let inode_next = inodes.iter().map(|inode: &Inode| inode.ino + 1).max();
+        for original in &inodes {
+            let dir = dirs.remove(&original.ino);
+            if let Err(err) = Self::restore_inode(&mut txn, original, dir).await {
+                txn.rollback().await?;
+                return Err(err);
+            }
+            pending += 1;
+            if pending >= IMPORT_BATCH {
+                txn.commit().await?;
+                txn = self.begin_import(&dumped).await?;
+                pending = 0;
+            }
+        }
+
+        let result: Result<()> = async {
+            let mut meta = txn
+                .read_meta()
+                .await?
+                .ok_or_else(|| anyhow!("the meta is missing"))?;
+            meta.inode_next = inode_next.unwrap_or(ROOT_INODE);
+            meta.importing = false;
+            txn.save_meta(&meta).await?;
+            Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) => txn.commit().await?,
+            Err(err) => {
+                txn.rollback().await?;
+                return Err(err);
+            }
+        }
+        println!("imported inodes: {}", inodes.len());
+        Ok(())
+    }
+
+    async fn import_record(
+        txn: &mut Txn,
+        record: Record,
+        inodes: &mut Vec<Inode>,
+        dirs: &mut HashMap<u64, Directory>,
+    ) -> Result<()> {
+        match record {
+            Record::Meta(_) => return Err(anyhow!("unexpected meta in the dump")),
+            Record::Inode(original) => {
+                // handlers and locks belong to the old mount
+                let mut inode: Inode = original.file_attr.into();
+                inode.generation = original.generation;
+                inode.xattrs = original.xattrs.clone();
+                // flags are restored with the attributes, an immutable file is written till then
+                inode.flags = 0;
+                if inode.kind == FileType::Symlink {
+                    inode.inline_data = original.inline_data.clone();
+                } else {
+                    inode.set_size(0, txn.block_size());
+                }
+                txn.save_inode(&inode).await?;
+                inodes.push(original);
+            }
+            Record::Xattr { ino, name, value } => {
+                let mut inode = txn.read_inode(ino).await?;
+                inode.xattrs.insert(name, value);
+                txn.save_inode(&inode).await?;
+            }
+            Record::Data { ino, offset, data } => {
+                txn.write_data(ino, offset, Bytes::from(data)).await?;
+            }
+            Record::Entry { parent, item } => {
+                txn.set_index(parent, item.name.clone().into(), item.ino)
+                    .await?;
+                dirs.entry(parent).or_default().push(item);
+            }
+        }
+        Ok(())
+    }
+
+    async fn restore_inode(txn: &mut Txn, original: &Inode, dir: Option<Directory>) -> Result<()> {
+        let mut inode = match original.kind {
+            FileType::Directory => txn.save_dir(original.ino, &dir.unwrap_or_default()).await?,
+            FileType::RegularFile => {
+                let mut inode = txn.read_inode(original.ino).await?;
+                txn.truncate(&mut inode, original.size).await?;
+                inode
+            }
+            _ => txn.read_inode(original.ino).await?,
+        };
+        let (size, blocks, blksize) = (inode.size, inode.blocks, inode.blksize);
+        inode.file_attr = original.file_attr;
+        inode.size = size;
+        inode.blocks = blocks;
+        inode.blksize = blksize;
+        txn.save_inode(&inode).await?;
         Ok(())
     }
 
@@ -168,13 +769,87 @@ impl Console {
         if args.is_empty() {
             return Err(anyhow!("invalid arguments `{:?}`", args));
         }
-        match txn.get(ScopedKey::inode(args[0].parse()?)).await? {
+        Self::print_inode(txn, args[0].parse()?).await
+    }
+
+    async fn print_inode(txn: &mut Txn, ino: u64) -> Result<()> {
+        match txn.get(ScopedKey::inode(ino)).await? {
             Some(value) => println!("{:?}", Inode::deserialize(&value)?),
             None => println!("Not Found"),
         }
         Ok(())
     }
 
+    /// `stat [-L] <path>`, symlinks in the last component are followed with `-L`.
+    async fn stat(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let (follow, path) = match args {
+            ["-L", path] => (true, path),
+            [path] if !path.is_empty() => (false, path),
+            _ => return Err(anyhow!("invalid arguments `{:?}`", args)),
+        };
+        match Self::resolve(txn, path, follow).await? {
+            Some(ino) => Self::print_inode(txn, ino).await,
+            None => {
+                println!("Not Found");
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolve a path component by component from the root, `None` if any is missing.
+    ///
+    /// Symlinks before the last component are always followed, absolute targets are
+    /// resolved from the root of the filesystem.
+    async fn resolve(txn: &mut Txn, path: &str, follow: bool) -> Result<Option<u64>> {
+        fn components(path: &str) -> impl '_ + DoubleEndedIterator<Item = String> {
+            path.split('/')
+                .filter(|name| !name.is_empty())
+                .map(ToOwned::to_owned)
+        }
+
+        // the directories from the root to the current one
+        let mut dirs = vec![ROOT_INODE];
+        let mut pending: Vec<String> = components(path).rev().collect();
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            let parent = *dirs.last().unwrap();
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if dirs.len() > 1 {
+                        dirs.pop();
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+            let ino = match txn.lookup(parent, name.clone().into()).await {
+                Ok(ino) => ino,
+                Err(FsError::FileNotFound { .. }) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            let inode = txn.read_inode(ino).await?;
+            let last = pending.is_empty();
+            match inode.kind {
+                FileType::Symlink if follow || !last => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(anyhow!("too many levels of symbolic links in `{}`", path));
+                    }
+                    let target = String::from_utf8(txn.read_link(ino).await?)?;
+                    if target.starts_with('/') {
+                        dirs.truncate(1);
+                    }
+                    pending.extend(components(&target).rev());
+                }
+                FileType::Directory => dirs.push(ino),
+                _ if last => return Ok(Some(ino)),
+                _ => return Err(anyhow!("`{}` in `{}` is not a directory", name, path)),
+            }
+        }
+        Ok(dirs.last().copied())
+    }
+
     async fn get_attr_raw(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
         if args.is_empty() {
             return Err(anyhow!("invalid arguments `{:?}`", args));
@@ -202,6 +877,68 @@ impl Console {
         Ok(())
     }
 
+    async fn get_blocks(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        if args.is_empty() {
+            return Err(anyhow!("invalid arguments `{:?}`", args));
+        }
+        let ino = args[0].parse()?;
+        let inode = match txn.get(ScopedKey::inode(ino)).await? {
+            Some(value) => Inode::deserialize(&value)?,
+            None => {
+                println!("Not Found");
+                return Ok(());
+            }
+        };
+        let block_size = txn
+            .read_meta()
+            .await?
+            .map(|meta| meta.block_size)
+            .unwrap_or_else(|| txn.block_size());
+        let expected_blocks = inode.data_blocks(block_size);
+        println!(
+            "size: {}, block size: {}, inline: {}, expected blocks: {}",
+            inode.size,
+            block_size,
+            inode.inline_data.is_some(),
+            expected_blocks
+        );
+
+        let mut present_blocks = 0;
+        let mut beyond_blocks = 0;
+        let mut bad_blocks = 0;
+        let mut pages = Pages::new(ScopedKey::block_range(ino, 0..u64::MAX), txn.scan_limit());
+        while let Some(range) = pages.range() {
+            let pairs: Vec<_> = txn.scan(range, pages.limit()).await?.collect();
+            for pair in &pairs {
+                let block = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::Block { ino: _, block } => block,
+                    key => return Err(anyhow!("unexpected key `{:?}`", key)),
+                };
+                let len = pair.value().len() as u64;
+                let flag = if block >= expected_blocks {
+                    beyond_blocks += 1;
+                    bad_blocks += 1;
+                    " (beyond size)"
+                } else if len != block_size && block + 1 != expected_blocks {
+                    bad_blocks += 1;
+                    " (wrong size)"
+                } else {
+                    ""
+                };
+                println!("block {}: {} bytes{}", block, len, flag);
+                present_blocks += 1;
+            }
+            pages.advance(pairs.len(), pairs.last().map(|pair| pair.key()));
+        }
+        println!(
+            "present blocks: {}, absent blocks: {}, bad blocks: {}",
+            present_blocks,
+            expected_blocks - (present_blocks - beyond_blocks),
+            bad_blocks
+        );
+        Ok(())
+    }
+
     async fn delete_block(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
         if args.len() < 2 {
             return Err(anyhow!("invalid arguments `{:?}`", args));
@@ -210,4 +947,331 @@ impl Console {
             .await?;
         Ok(())
     }
+
+    async fn fsck(&self, txn: &mut Txn, args: &[&str]) -> Result<()> {
+        let repair = args.contains(&"--repair");
+        let (inodes, issues) = Self::check(txn).await?;
+        let mut dangling_indexes = 0;
+        let mut orphan_blocks = 0;
+        let mut link_mismatches = 0;
+        for issue in &issues {
+            match issue {
+                Inconsistency::DanglingIndex { .. } => dangling_indexes += 1,
+                Inconsistency::OrphanBlock { .. } => orphan_blocks += 1,
+                Inconsistency::LinkCountMismatch { .. } => link_mismatches += 1,
+                _ => (),
+            }
+            println!("{:?}", issue);
+            if repair {
+                self.repair(txn, issue).await?;
+            }
+        }
+        println!(
+            "inodes: {}, issues: {}, dangling indexes: {}, orphan blocks: {}, link count mismatches: {}",
+            inodes,
+            issues.len(),
+            dangling_indexes,
+            orphan_blocks,
+            link_mismatches
+        );
+        if repair {
+            println!("repaired");
+        }
+        Ok(())
+    }
+
+    /// Check the whole filesystem, returns the number of inodes and the issues found.
+    async fn check(txn: &mut Txn) -> Result<(usize, Vec<Inconsistency>)> {
+        let meta = txn.read_meta().await?;
+        let inode_next = meta
+            .as_ref()
+            .map(|meta| meta.inode_next)
+            .unwrap_or(ROOT_INODE);
+
+        let mut issues = Vec::new();
+        let mut present = HashSet::new();
+        let mut inodes = Vec::new();
+        let mut pages = Pages::new(
+            ScopedKey::inode_range(ROOT_INODE..inode_next),
+            txn.scan_limit(),
+        );
+        while let Some(range) = pages.range() {
+            let pairs: Vec<_> = txn.scan(range, pages.limit()).await?.collect();
+            for pair in &pairs {
+                let ino = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::Inode(ino) => ino,
+                    key => return Err(anyhow!("unexpected key `{:?}`", key)),
+                };
+                present.insert(ino);
+                match Inode::deserialize(pair.value()) {
+                    Ok(inode) => inodes.push(inode),
+                    Err(err) => issues.push(Inconsistency::CorruptInode {
+                        ino,
+                        msg: err.to_string(),
+                    }),
+                }
+            }
+            pages.advance(pairs.len(), pairs.last().map(|pair| pair.key()));
+        }
+
+        let mut scanned = Usage::default();
+        for inode in &inodes {
+            scanned.update(None, Some(inode.blocks));
+        }
+        let stored = meta.and_then(|meta| meta.usage);
+        if stored != Some(scanned) {
+            issues.push(Inconsistency::UsageMismatch { stored, scanned });
+        }
+
+        // count the directory entries referring to every inode
+        let mut links: HashMap<u64, u32> = HashMap::new();
+        let mut entries = HashSet::new();
+        // the root has no entry in its parent
+        links.insert(ROOT_INODE, 1);
+        for inode in &inodes {
+            issues.extend(check_inode(txn, inode).await?);
+            if inode.kind == fuser::FileType::Directory {
+                if let Ok(dir) = txn.read_dir(inode.ino).await {
+                    for item in dir {
+                        *links.entry(item.ino).or_default() += 1;
+                        entries.insert((inode.ino, item.name));
+                    }
+                }
+            }
+        }
+        for inode in &inodes {
+            let count = links.get(&inode.ino).copied().unwrap_or_default();
+            if count != inode.nlink {
+                issues.push(Inconsistency::LinkCountMismatch {
+                    ino: inode.ino,
+                    nlink: inode.nlink,
+                    links: count,
+                });
+            }
+        }
+
+        let mut pages = Pages::new(ScopedKey::index_scope(), txn.scan_limit());
+        while let Some(range) = pages.range() {
+            let pairs: Vec<_> = txn.scan(range, pages.limit()).await?.collect();
+            for pair in &pairs {
+                let (parent, name) = match ScopedKey::parse(pair.key().into())? {
+                    ScopedKey::FileIndex { parent, name } => (parent, name.to_owned()),
+                    key => return Err(anyhow!("unexpected key `{:?}`", key)),
+                };
+                let ino = Index::deserialize(pair.value())?.ino;
+                if !entries.contains(&(parent, name.clone())) {
+                    issues.push(Inconsistency::DanglingIndex { parent, name, ino });
+                }
+            }
+            pages.advance(pairs.len(), pairs.last().map(|pair| pair.key()));
+        }
+
+        let mut pages = Pages::new(ScopedKey::block_scope(), txn.scan_limit());
+        while let Some(range) = pages.range() {
+            let keys: Vec<_> = txn.scan_keys(range, pages.limit()).await?.collect();
+            let mut skip = None;
+            for key in &keys {
+                let (ino, block) = match ScopedKey::parse(key.into())? {
+                    ScopedKey::Block { ino, block } => (ino, block),
+                    key => return Err(anyhow!("unexpected key `{:?}`", key)),
+                };
+                if present.contains(&ino) {
+                    // skip the remaining blocks of an existing inode
+                    skip = Some(ScopedKey::block(ino + 1, 0).into());
+                    break;
+                }
+                issues.push(Inconsistency::OrphanBlock { ino, block });
+            }
+            match skip {
+                Some(next) => pages.seek(next),
+                None => pages.advance(keys.len(), keys.last()),
+            }
+        }
+        Ok((present.len(), issues))
+    }
+
+    async fn repair(&self, txn: &mut Txn, issue: &Inconsistency) -> Result<()> {
+        match issue {
+            Inconsistency::DanglingEntry { parent, name, ino } => {
+                let dir: Directory = txn
+                    .read_dir(*parent)
+                    .await?
+                    .into_iter()
+                    .filter(|item| item.name != *name)
+                    .collect();
+                txn.save_dir(*parent, &dir).await?;
+                if txn.get_index(*parent, name.clone().into()).await? == Some(*ino) {
+                    txn.remove_index(*parent, name.clone().into()).await?;
+                }
+            }
+            Inconsistency::MismatchedIndex {
+                parent, name, ino, ..
+            } => {
+                if txn.get(ScopedKey::inode(*ino)).await?.is_some() {
+                    txn.set_index(*parent, name.clone().into(), *ino).await?;
+                }
+            }
+            Inconsistency::DanglingIndex { parent, name, .. } => {
+                txn.remove_index(*parent, name.clone().into()).await?
+            }
+            // only blocks of files are found beyond the size, the contents they refer to
+            // are released
+            Inconsistency::BlockBeyondSize { ino, block } => {
+                let deduped = txn.dedup();
+                txn.delete_blocks(*ino, *block, deduped).await?
+            }
+            // the inode is gone, so are all its blocks from this one on; values which are no
+            // hashes of contents release nothing
+            Inconsistency::OrphanBlock { ino, block } => {
+                let deduped = txn.dedup();
+                txn.delete_blocks(*ino, *block, deduped).await?
+            }
+            Inconsistency::LinkCountMismatch { ino, links, .. } => {
+                let mut inode = txn.read_inode(*ino).await?;
+                inode.nlink = *links;
+                txn.save_inode(&inode).await?;
+            }
+            Inconsistency::UsageMismatch { .. } => {
+                txn.recompute_usage().await?;
+            }
+            Inconsistency::CorruptInode { .. } | Inconsistency::CorruptDirectory { .. } => {
+                println!("cannot repair {:?}", issue)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records committed by a transaction on import.
+const IMPORT_BATCH: usize = 1 << 10;
+
+/// Bytes taken by a record besides its data, names and values.
+const RECORD_OVERHEAD: u64 = 1 << 12;
+
+/// Bytes a byte of data takes at most in a record, as `255,` in json.
+const ENCODED_BYTE_LEN: u64 = 4;
+
+/// Symlinks followed when resolving a path, as `MAXSYMLINKS` of linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// A record of a dump, written as its length in big endian followed by the serialized record.
+///
+/// A dump starts with the meta, then every inode is followed by its extended attributes,
+/// and its data or entries.
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    Meta(Meta),
+    // extended attributes are dumped by their own records
+    Inode(Inode),
+    Xattr {
+        ino: u64,
+        name: String,
+        value: Vec<u8>,
+    },
+    Data {
+        ino: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Entry {
+        parent: u64,
+        item: DirItem,
+    },
+}
+
+fn write_record(writer: &mut impl Write, record: &Record) -> Result<()> {
+    let data = serialize(record).map_err(|err| anyhow!("fail to serialize record: {}", err))?;
+    writer.write_all(&(data.len() as u64).to_be_bytes())?;
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+/// The largest record of a dump of `block_size` blocks, which holds at most a block of data,
+/// or an extended attribute, or an inode inlining its data.
+fn record_limit(block_size: u64) -> u64 {
+    let data = block_size.max(XATTR_SIZE_MAX as u64) + TiFs::MAX_NAME_LEN as u64;
+    data * ENCODED_BYTE_LEN + RECORD_OVERHEAD
+}
+
+/// Read the next record of at most `limit` bytes, `None` at the end of the dump.
+fn read_record(reader: &mut impl Read, limit: u64) -> Result<Option<Record>> {
+    let mut len = [0; 8];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u64::from_be_bytes(len);
+    if len > limit {
+        return Err(anyhow!(
+            "record of {} bytes is larger than {} bytes",
+            len,
+            limit
+        ));
+    }
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some(deserialize(&data).map_err(|err| {
+        anyhow!("fail to deserialize record: {}", err)
+    })?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use tifs::fs::async_fs::AsyncFileSystem;
+    use tifs::fs::mode::make_mode;
+    use tifs::MountOption;
+
+    use super::*;
+
+    const PD_ENDPOINT: &str = "127.0.0.1:2379";
+
+    #[tokio::test]
+    #[ignore]
+    async fn fsck_by_the_stored_block_size() {
+        // the block size the library tests make the filesystem on the cluster with
+        let options = vec![MountOption::BlkSize("4096".to_owned())];
+        let fs = TiFs::construct(
+            vec![PD_ENDPOINT],
+            Default::default(),
+            Default::default(),
+            options,
+            None,
+        )
+        .await
+        .unwrap();
+        fs.prepare(0, 0).await.unwrap();
+        assert_ne!(fs.block_size, TiFs::DEFAULT_BLOCK_SIZE);
+
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let name = format!("fsck-{}", nanos);
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone().into(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        // blocks past the size divided by the default block size
+        let data = Bytes::from(vec![1u8; 3 * fs.block_size as usize]);
+        fs.write(ino, fh, 0, data, 0, flags, None).await.unwrap();
+        fs.release(ino, fh, flags, None, true).await.unwrap();
+
+        let console = Console::construct(vec![PD_ENDPOINT], true, None)
+            .await
+            .unwrap();
+        let mut txn = console.begin_stored().await.unwrap();
+        assert_eq!(txn.block_size(), fs.block_size);
+        let checked = Console::check(&mut txn).await;
+        txn.rollback().await.unwrap();
+        let (_, issues) = checked.unwrap();
+        assert_eq!(issues, Vec::new());
+
+        fs.unlink(0, ROOT_INODE, name.into()).await.unwrap();
+    }
 }
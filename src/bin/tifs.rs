@@ -1,6 +1,7 @@
 use clap::{crate_version, App, Arg};
 use tifs::{mount_tifs_daemonize, MountOption};
-use tracing::{debug, info, trace};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, trace};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
@@ -30,6 +31,14 @@ async fn main() -> anyhow::Result<()> {
                 .short("t")
                 .help("the tracer <logger | jaeger>, logger by default")
         )
+        .arg(
+            Arg::with_name("log-format")
+                .value_name("FORMAT")
+                .long("log-format")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("the format of logs <text | json>, json logs are also written besides jaeger traces")
+        )
         .arg(
             Arg::with_name("jaeger-collector")
                 .value_name("JAEGER_COLLECTOR")
@@ -72,6 +81,7 @@ async fn main() -> anyhow::Result<()> {
         )
         .get_matches();
 
+    let json_log = matches.value_of("log-format") == Some("json");
     match matches.value_of("tracer").unwrap_or_else(|| {
         if matches.value_of("jaeger-agent").is_some()
             || matches.value_of("jaeger-collector").is_some()
@@ -81,6 +91,13 @@ async fn main() -> anyhow::Result<()> {
             "logger"
         }
     }) {
+        "logger" if json_log => {
+            tracing_subscriber::fmt()
+                .json()
+                .with_env_filter(EnvFilter::from_default_env())
+                .try_init()
+                .map_err(|err| anyhow::anyhow!("fail to init tracing subscriber: {}", err))?;
+        }
         "logger" => {
             tracing_subscriber::fmt()
                 .with_env_filter(EnvFilter::from_default_env())
@@ -100,6 +117,7 @@ async fn main() -> anyhow::Result<()> {
             tracing_subscriber::registry()
                 .with(EnvFilter::from_default_env())
                 .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .with(json_log.then(|| tracing_subscriber::fmt::layer().json()))
                 .try_init()?;
         }
         t => return Err(anyhow::anyhow!("unsupported tracer: {}", t)),
@@ -163,6 +181,9 @@ async fn main() -> anyhow::Result<()> {
             args.push("--log-file".to_owned());
             args.push(f);
         }
+        if json_log {
+            args.push("--log-format=json".to_owned());
+        }
         let child = Command::new(&exe)
             .args(args)
             .current_dir("/")
@@ -194,6 +215,17 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    let mut terminate = signal(SignalKind::terminate())?;
+    let unmount_point = mountpoint.clone();
+    tokio::spawn(async move {
+        if terminate.recv().await.is_some() {
+            info!("terminated, unmounting {}", unmount_point);
+            if let Err(err) = unmount(&unmount_point) {
+                error!("fail to unmount {}: {}", unmount_point, err);
+            }
+        }
+    });
+
     mount_tifs_daemonize(mountpoint.to_string(), endpoints, options, move || {
         if serve {
             use std::ffi::CString;
@@ -248,3 +280,21 @@ async fn main() -> anyhow::Result<()> {
     })
     .await
 }
+
+/// Unmount the filesystem, then the fuse session ends and destroys the filesystem in order.
+fn unmount(mountpoint: &str) -> anyhow::Result<()> {
+    use std::process::Command;
+
+    let fusermount = Command::new("fusermount")
+        .arg("-u")
+        .arg(mountpoint)
+        .status();
+    let status = match fusermount {
+        Ok(status) if status.success() => status,
+        _ => Command::new("umount").arg(mountpoint).status()?,
+    };
+    if !status.success() {
+        anyhow::bail!("umount exited with {}", status);
+    }
+    Ok(())
+}
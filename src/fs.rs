@@ -1,15 +1,29 @@
+pub mod acl;
 pub mod async_fs;
+pub mod atime;
 pub mod block;
+pub mod cache;
 pub mod client;
+pub mod commit;
+pub mod crypto;
 pub mod dir;
 pub mod error;
 pub mod file_handler;
+pub mod health;
 pub mod index;
 pub mod inode;
 pub mod key;
 pub mod meta;
+pub mod metrics;
 pub mod mode;
+pub mod prefetch;
+pub mod readahead;
 pub mod reply;
+pub mod scrub;
 pub mod serialize;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tifs_client;
 pub mod tikv_fs;
 pub mod transaction;
+pub mod write_buffer;
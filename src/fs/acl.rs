@@ -0,0 +1,318 @@
+use fuser::FileAttr;
+
+use super::error::{FsError, Result};
+use super::mode::check_access;
+
+/// The extended attribute of the access ACL of a file.
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+/// The extended attribute of the default ACL of a directory, inherited by files created in it.
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// Tags of entries, in the order the kernel sorts them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: Tag,
+    pub perm: u16,
+}
+
+/// A POSIX ACL, stored in extended attributes in the binary format of the kernel:
+/// a header of the version, followed by entries of a tag, permissions and an id,
+/// all in little endian.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Parse an ACL, `None` if it has no entry, which removes the ACL.
+    pub fn parse(value: &[u8]) -> Result<Option<Self>> {
+        let invalid = |msg: &str| FsError::InvalidAcl {
+            msg: msg.to_owned(),
+        };
+        if value.len() < HEADER_LEN || (value.len() - HEADER_LEN) % ENTRY_LEN != 0 {
+            return Err(invalid("size is not of whole entries"));
+        }
+        let version = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+        if version != VERSION {
+            return Err(invalid("unknown version"));
+        }
+        if value.len() == HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut entries = Vec::with_capacity((value.len() - HEADER_LEN) / ENTRY_LEN);
+        for entry in value[HEADER_LEN..].chunks(ENTRY_LEN) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let tag = match tag {
+                USER_OBJ => Tag::UserObj,
+                USER => Tag::User(id),
+                GROUP_OBJ => Tag::GroupObj,
+                GROUP => Tag::Group(id),
+                MASK => Tag::Mask,
+                OTHER => Tag::Other,
+                _ => return Err(invalid("unknown tag")),
+            };
+            if perm & !0o7 != 0 {
+                return Err(invalid("unknown permissions"));
+            }
+            entries.push(AclEntry { tag, perm });
+        }
+        entries.sort_by_key(|entry| entry.tag);
+        if entries.windows(2).any(|pair| pair[0].tag == pair[1].tag) {
+            return Err(invalid("duplicate entries"));
+        }
+
+        let acl = Self { entries };
+        let named = acl
+            .entries
+            .iter()
+            .any(|entry| matches!(entry.tag, Tag::User(_) | Tag::Group(_)));
+        if acl.get(Tag::UserObj).is_none()
+            || acl.get(Tag::GroupObj).is_none()
+            || acl.get(Tag::Other).is_none()
+            || (named && acl.get(Tag::Mask).is_none())
+        {
+            return Err(invalid("missing entries"));
+        }
+        Ok(Some(acl))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(HEADER_LEN + self.entries.len() * ENTRY_LEN);
+        value.extend_from_slice(&VERSION.to_le_bytes());
+        for entry in &self.entries {
+            let (tag, id) = match entry.tag {
+                Tag::UserObj => (USER_OBJ, UNDEFINED_ID),
+                Tag::User(id) => (USER, id),
+                Tag::GroupObj => (GROUP_OBJ, UNDEFINED_ID),
+                Tag::Group(id) => (GROUP, id),
+                Tag::Mask => (MASK, UNDEFINED_ID),
+                Tag::Other => (OTHER, UNDEFINED_ID),
+            };
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    fn get(&self, tag: Tag) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perm)
+    }
+
+    fn set(&mut self, tag: Tag, perm: u16) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.tag == tag) {
+            entry.perm = perm;
+        }
+    }
+
+    /// The entry of the group class, which is the mask if there is one.
+    fn group_class(&self) -> Tag {
+        match self.get(Tag::Mask) {
+            Some(_) => Tag::Mask,
+            None => Tag::GroupObj,
+        }
+    }
+
+    /// Whether the ACL grants no more than the permission bits do, it needn't be stored then.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// The permission bits reflecting the ACL.
+    pub fn perm(&self) -> u16 {
+        let perm = |tag| self.get(tag).unwrap_or(0);
+        perm(Tag::UserObj) << 6 | perm(self.group_class()) << 3 | perm(Tag::Other)
+    }
+
+    /// Make the ACL follow the permission bits changed by `chmod`.
+    pub fn set_perm(&mut self, perm: u16) {
+        self.set(Tag::UserObj, (perm >> 6) & 0o7);
+        self.set(self.group_class(), (perm >> 3) & 0o7);
+        self.set(Tag::Other, perm & 0o7);
+    }
+
+    /// The access ACL of a file created as `perm` in a directory of this default ACL,
+    /// which grants no more than `perm`.
+    pub fn inherit(&self, perm: u16) -> Self {
+        let mut acl = self.clone();
+        let inherited = acl.perm() & perm;
+        acl.set_perm(inherited);
+        acl
+    }
+
+    /// Check whether the caller(`uid`, in `groups`) is allowed to access the file as `mask`,
+    /// by the access check algorithm of POSIX ACLs.
+    pub fn check(&self, attr: &FileAttr, uid: u32, groups: &[u32], mask: i32) -> bool {
+        let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        // root is decided by the permission bits, which reflect the ACL
+        if wanted == 0 || uid == 0 {
+            return check_access(attr, uid, groups, mask);
+        }
+        let allows = |perm: u16| perm & wanted == wanted;
+        let class_mask = self.get(Tag::Mask).unwrap_or(0o7);
+
+        if uid == attr.uid {
+            return allows(self.get(Tag::UserObj).unwrap_or(0));
+        }
+        if let Some(perm) = self.get(Tag::User(uid)) {
+            return allows(perm & class_mask);
+        }
+        let mut group_matched = false;
+        for entry in &self.entries {
+            let matched = match entry.tag {
+                Tag::GroupObj => groups.contains(&attr.gid),
+                Tag::Group(id) => groups.contains(&id),
+                _ => false,
+            };
+            if matched {
+                if allows(entry.perm & class_mask) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        !group_matched && allows(self.get(Tag::Other).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::file_attr;
+
+    fn encode(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    // user::rw-, user:1001:rwx, group::r--, group:2001:rw-, mask::rw-, other::---
+    fn named() -> Vec<u8> {
+        encode(&[
+            (USER_OBJ, 0o6, UNDEFINED_ID),
+            (GROUP, 0o6, 2001),
+            (USER, 0o7, 1001),
+            (GROUP_OBJ, 0o4, UNDEFINED_ID),
+            (MASK, 0o6, UNDEFINED_ID),
+            (OTHER, 0, UNDEFINED_ID),
+        ])
+    }
+
+    #[test]
+    fn parse_acl() {
+        let acl = Acl::parse(&named()).unwrap().unwrap();
+        assert!(!acl.is_minimal());
+        assert_eq!(acl.perm(), 0o660);
+        // entries are sorted as the kernel does
+        assert_eq!(Acl::parse(&acl.encode()).unwrap().unwrap(), acl);
+
+        assert_eq!(Acl::parse(&encode(&[])).unwrap(), None);
+        let minimal = encode(&[
+            (USER_OBJ, 0o7, UNDEFINED_ID),
+            (GROUP_OBJ, 0o5, UNDEFINED_ID),
+            (OTHER, 0o4, UNDEFINED_ID),
+        ]);
+        let acl = Acl::parse(&minimal).unwrap().unwrap();
+        assert!(acl.is_minimal());
+        assert_eq!(acl.perm(), 0o754);
+    }
+
+    #[test]
+    fn reject_invalid_acl() {
+        let invalid = |value: &[u8]| matches!(Acl::parse(value), Err(FsError::InvalidAcl { .. }));
+        assert!(invalid(&[]));
+        assert!(invalid(&named()[..10]));
+        let mut version = named();
+        version[0] = 1;
+        assert!(invalid(&version));
+        // without a mask
+        assert!(invalid(&encode(&[
+            (USER_OBJ, 0o6, UNDEFINED_ID),
+            (USER, 0o7, 1001),
+            (GROUP_OBJ, 0o4, UNDEFINED_ID),
+            (OTHER, 0, UNDEFINED_ID),
+        ])));
+        // twice the same user
+        assert!(invalid(&encode(&[
+            (USER_OBJ, 0o6, UNDEFINED_ID),
+            (USER, 0o7, 1001),
+            (USER, 0o4, 1001),
+            (GROUP_OBJ, 0o4, UNDEFINED_ID),
+            (MASK, 0o6, UNDEFINED_ID),
+            (OTHER, 0, UNDEFINED_ID),
+        ])));
+        assert!(invalid(&encode(&[(0x40, 0o7, UNDEFINED_ID)])));
+    }
+
+    #[test]
+    fn check_acl() {
+        let acl = Acl::parse(&named()).unwrap().unwrap();
+        let attr = file_attr(1000, 2000, acl.perm());
+        let (r, w, x) = (libc::R_OK, libc::W_OK, libc::X_OK);
+
+        assert!(acl.check(&attr, 1000, &[0], r | w));
+        assert!(!acl.check(&attr, 1000, &[0], x));
+        // the named user is limited by the mask
+        assert!(acl.check(&attr, 1001, &[0], r | w));
+        assert!(!acl.check(&attr, 1001, &[0], x));
+        // the owning group and the named group
+        assert!(acl.check(&attr, 1002, &[2000], r));
+        assert!(!acl.check(&attr, 1002, &[2000], w));
+        assert!(acl.check(&attr, 1002, &[2001], r | w));
+        // any of the supplementary groups
+        assert!(acl.check(&attr, 1002, &[0, 2001], r | w));
+        assert!(acl.check(&attr, 1002, &[2000, 2001], r | w));
+        // others
+        assert!(!acl.check(&attr, 1002, &[0], r));
+        assert!(acl.check(&attr, 1002, &[0], libc::F_OK));
+        assert!(acl.check(&attr, 0, &[0], r | w));
+    }
+
+    #[test]
+    fn acl_follows_perm() {
+        let mut acl = Acl::parse(&named()).unwrap().unwrap();
+        acl.set_perm(0o750);
+        assert_eq!(acl.perm(), 0o750);
+        // the group class is the mask, the owning group is kept
+        assert_eq!(acl.get(Tag::Mask), Some(0o5));
+        assert_eq!(acl.get(Tag::GroupObj), Some(0o4));
+        assert_eq!(acl.get(Tag::User(1001)), Some(0o7));
+
+        let default = Acl::parse(&named()).unwrap().unwrap();
+        let inherited = default.inherit(0o644);
+        assert_eq!(inherited.perm(), 0o640);
+        assert_eq!(inherited.get(Tag::Group(2001)), Some(0o6));
+    }
+}
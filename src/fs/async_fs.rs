@@ -1,37 +1,51 @@
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::future::Future;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::SystemTime;
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::{
-    Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs,
-    ReplyWrite, ReplyXattr, Request, TimeOrNow,
+    Filesystem, KernelConfig, MountOption, Notifier, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, Session, SessionUnmounter, TimeOrNow,
 };
 use tokio::runtime::Handle;
-use tokio::task::{block_in_place, spawn};
-use tracing::trace;
+use tokio::task::{block_in_place, spawn, spawn_blocking, JoinHandle};
+use tracing::{trace, Instrument, Span};
 
 use super::error::{FsError, Result};
 use super::reply::{
-    Attr, Bmap, Create, Data, Dir, DirPlus, Entry, FsReply, Lock, Lseek, Open, StatFs, Write, Xattr,
+    Attr, Bmap, Create, Data, Dir, DirPlus, Entry, FsReply, Ioctl, Lock, Lseek, Open, StatFs,
+    Write, Xattr,
 };
 
-pub fn spawn_reply<F, R, V>(id: u64, reply: R, f: F)
+/// A span of the whole lifecycle of a request, named after the operation,
+/// so transactions and their retries nest under the request.
+macro_rules! request_span {
+    ($req: expr, $op: literal, $ino: expr) => {
+        tracing::info_span!($op, id = $req.unique(), ino = $ino)
+    };
+}
+
+pub fn spawn_reply<F, R, V>(id: u64, span: Span, reply: R, f: F)
 where
     F: Future<Output = Result<V>> + Send + 'static,
     R: FsReply<V> + Send + 'static,
     V: Debug,
 {
-    spawn(async move {
-        trace!("reply to request({})", id);
-        let result = f.await;
-        reply.reply(id, result);
-    });
+    spawn(
+        async move {
+            trace!("reply to request({})", id);
+            let result = f.await;
+            reply.reply(id, result);
+        }
+        .instrument(span),
+    );
 }
 
 fn block_on<F, T>(future: F) -> T
@@ -41,6 +55,13 @@ where
     block_in_place(move || Handle::current().block_on(future))
 }
 
+// the absolute path of a mount point, resolved before it's covered by the mount
+fn canonical(mountpoint: &Path) -> PathBuf {
+    mountpoint
+        .canonicalize()
+        .unwrap_or_else(|_| mountpoint.to_owned())
+}
+
 #[allow(clippy::too_many_arguments)]
 #[async_trait]
 pub trait AsyncFileSystem: Send + Sync {
@@ -55,6 +76,14 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Called on filesystem exit.
     async fn destroy(&self) {}
 
+    /// Take the notifier of the session, which invalidates caches of the kernel.
+    /// Called once the filesystem is mounted, before serving any request.
+    fn set_notifier(&self, _notifier: Notifier) {}
+
+    /// Take the path the filesystem is mounted on.
+    /// Called before the filesystem is mounted.
+    fn set_mountpoint(&self, _mountpoint: &Path) {}
+
     /// Look up a directory entry by name and get its attributes.
     async fn lookup(&self, _parent: u64, _name: ByteString) -> Result<Entry> {
         Err(FsError::unimplemented())
@@ -77,6 +106,9 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Set file attributes.
     async fn setattr(
         &self,
+        _req_uid: u32,
+        _req_gid: u32,
+        _pid: u32,
         _ino: u64,
         _mode: Option<u32>,
         _uid: Option<u32>,
@@ -128,12 +160,12 @@ pub trait AsyncFileSystem: Send + Sync {
     }
 
     /// Remove a file.
-    async fn unlink(&self, _parent: u64, _name: ByteString) -> Result<()> {
+    async fn unlink(&self, _uid: u32, _parent: u64, _name: ByteString) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
     /// Remove a directory.
-    async fn rmdir(&self, _parent: u64, _name: ByteString) -> Result<()> {
+    async fn rmdir(&self, _uid: u32, _parent: u64, _name: ByteString) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -152,6 +184,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// Rename a file.
     async fn rename(
         &self,
+        _uid: u32,
         _parent: u64,
         _name: ByteString,
         _newparent: u64,
@@ -217,7 +250,7 @@ pub trait AsyncFileSystem: Send + Sync {
         _ino: u64,
         _fh: u64,
         _offset: i64,
-        _data: Vec<u8>,
+        _data: Bytes,
         _write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
@@ -352,7 +385,7 @@ pub trait AsyncFileSystem: Send + Sync {
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    async fn access(&self, _ino: u64, _mask: i32) -> Result<()> {
+    async fn access(&self, _uid: u32, _gid: u32, _pid: u32, _ino: u64, _mask: i32) -> Result<()> {
         Err(FsError::unimplemented())
     }
 
@@ -421,6 +454,21 @@ pub trait AsyncFileSystem: Send + Sync {
         Err(FsError::unimplemented())
     }
 
+    /// Control a file, commands the filesystem doesn't know fail with ENOTTY.
+    async fn ioctl(
+        &self,
+        _req_uid: u32,
+        _pid: u32,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: Bytes,
+        _out_size: u32,
+    ) -> Result<Ioctl> {
+        Err(FsError::UnknownIoctl { cmd })
+    }
+
     /// Preallocate or deallocate space to a file
     async fn fallocate(
         &self,
@@ -452,6 +500,20 @@ pub trait AsyncFileSystem: Send + Sync {
     ) -> Result<Write> {
         Err(FsError::unimplemented())
     }
+
+    /// Advise on the expected use of a range of a file, as `posix_fadvise`.
+    /// The FUSE protocol doesn't forward fadvise, so it's not bridged; it's a hook
+    /// for releasing client-side state, e.g. cached blocks on `POSIX_FADV_DONTNEED`.
+    async fn fadvise(
+        &self,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _len: i64,
+        _advice: i32,
+    ) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub struct AsyncFs<T>(Arc<T>);
@@ -462,6 +524,56 @@ impl<T: AsyncFileSystem> From<T> for AsyncFs<T> {
     }
 }
 
+impl<T: AsyncFileSystem + 'static> AsyncFs<T> {
+    /// Mount the filesystem and serve requests until it's unmounted.
+    pub fn mount<P: AsRef<Path>>(self, mountpoint: P, options: &[MountOption]) -> io::Result<()> {
+        let inner = self.0.clone();
+        inner.set_mountpoint(&canonical(mountpoint.as_ref()));
+        let mut session = Session::new(self, mountpoint.as_ref(), options)?;
+        inner.set_notifier(session.notifier());
+        session.run()
+    }
+
+    /// Mount the filesystem and serve requests in a blocking task of the runtime,
+    /// so that several filesystems can be served by one runtime.
+    pub fn spawn_mount<P: AsRef<Path>>(
+        self,
+        mountpoint: P,
+        options: &[MountOption],
+    ) -> io::Result<MountHandle> {
+        let inner = self.0.clone();
+        inner.set_mountpoint(&canonical(mountpoint.as_ref()));
+        let mut session = Session::new(self, mountpoint.as_ref(), options)?;
+        inner.set_notifier(session.notifier());
+        let unmounter = session.unmount_callable();
+        Ok(MountHandle {
+            unmounter,
+            session: spawn_blocking(move || session.run()),
+        })
+    }
+}
+
+/// A filesystem served in the background, see `AsyncFs::spawn_mount`.
+pub struct MountHandle {
+    unmounter: SessionUnmounter,
+    session: JoinHandle<io::Result<()>>,
+}
+
+impl MountHandle {
+    /// Wait until the filesystem is unmounted, by `umount` or `unmount`.
+    pub async fn join(self) -> io::Result<()> {
+        self.session
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+    }
+
+    /// Unmount the filesystem, then wait for its session to end.
+    pub async fn unmount(mut self) -> io::Result<()> {
+        self.unmounter.unmount()?;
+        self.join().await
+    }
+}
+
 impl<T: Debug> Debug for AsyncFs<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -487,9 +599,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.lookup(parent, name).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "lookup", parent),
+            reply,
+            async move { async_impl.lookup(parent, name).await },
+        );
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
@@ -505,6 +620,7 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let async_impl = self.0.clone();
         spawn_reply(
             req.unique(),
+            request_span!(req, "getattr", ino),
             reply,
             async move { async_impl.getattr(ino).await },
         );
@@ -529,21 +645,32 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .setattr(
-                    ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
-                    flags,
-                )
-                .await
-        });
+        let req_uid = req.uid();
+        let req_gid = req.gid();
+        let pid = req.pid();
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "setattr", ino),
+            reply,
+            async move {
+                async_impl
+                    .setattr(
+                        req_uid, req_gid, pid, ino, mode, uid, gid, size, atime, mtime, ctime, fh,
+                        crtime, chgtime, bkuptime, flags,
+                    )
+                    .await
+            },
+        );
     }
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.readlink(ino).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "readlink", ino),
+            reply,
+            async move { async_impl.readlink(ino).await },
+        );
     }
 
     fn mknod(
@@ -561,11 +688,16 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .mknod(parent, name, mode, gid, uid, umask, rdev)
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "mknod", parent),
+            reply,
+            async move {
+                async_impl
+                    .mknod(parent, name, mode, gid, uid, umask, rdev)
+                    .await
+            },
+        );
     }
 
     fn mkdir(
@@ -582,25 +714,36 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.mkdir(parent, name, mode, gid, uid, umask).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "mkdir", parent),
+            reply,
+            async move { async_impl.mkdir(parent, name, mode, gid, uid, umask).await },
+        );
     }
 
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let uid = req.uid();
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.unlink(parent, name).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "unlink", parent),
+            reply,
+            async move { async_impl.unlink(uid, parent, name).await },
+        );
     }
 
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let uid = req.uid();
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.rmdir(parent, name).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "rmdir", parent),
+            reply,
+            async move { async_impl.rmdir(uid, parent, name).await },
+        );
     }
 
     fn symlink(
@@ -617,9 +760,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let uid = req.uid();
         let gid = req.gid();
 
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.symlink(gid, uid, parent, name, link).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "symlink", parent),
+            reply,
+            async move { async_impl.symlink(gid, uid, parent, name, link).await },
+        );
     }
 
     fn rename(
@@ -632,14 +778,20 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         flags: u32,
         reply: ReplyEmpty,
     ) {
+        let uid = req.uid();
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
         let newname = newname.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .rename(parent, name, newparent, newname, flags)
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "rename", parent),
+            reply,
+            async move {
+                async_impl
+                    .rename(uid, parent, name, newparent, newname, flags)
+                    .await
+            },
+        );
     }
 
     fn link(
@@ -652,16 +804,22 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
     ) {
         let async_impl = self.0.clone();
         let newname = newname.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.link(ino, newparent, newname).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "link", ino),
+            reply,
+            async move { async_impl.link(ino, newparent, newname).await },
+        );
     }
 
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.open(ino, flags).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "open", ino),
+            reply,
+            async move { async_impl.open(ino, flags).await },
+        );
     }
 
     fn read(
@@ -676,11 +834,16 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .read(ino, fh, offset, size, flags, lock_owner)
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "read", ino),
+            reply,
+            async move {
+                async_impl
+                    .read(ino, fh, offset, size, flags, lock_owner)
+                    .await
+            },
+        );
     }
 
     fn write(
@@ -696,19 +859,28 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
-        let data = data.to_owned();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .write(ino, fh, offset, data, write_flags, flags, lock_owner)
-                .await
-        });
+        // the only copy of the data, it's sliced into blocks from now on
+        let data = Bytes::copy_from_slice(data);
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "write", ino),
+            reply,
+            async move {
+                async_impl
+                    .write(ino, fh, offset, data, write_flags, flags, lock_owner)
+                    .await
+            },
+        );
     }
 
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.flush(ino, fh, lock_owner).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "flush", ino),
+            reply,
+            async move { async_impl.flush(ino, fh, lock_owner).await },
+        );
     }
 
     fn release(
@@ -722,30 +894,42 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.release(ino, fh, flags, lock_owner, flush).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "release", ino),
+            reply,
+            async move { async_impl.release(ino, fh, flags, lock_owner, flush).await },
+        );
     }
 
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.fsync(ino, fh, datasync).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "fsync", ino),
+            reply,
+            async move { async_impl.fsync(ino, fh, datasync).await },
+        );
     }
 
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.opendir(ino, flags).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "opendir", ino),
+            reply,
+            async move { async_impl.opendir(ino, flags).await },
+        );
     }
 
     fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.readdir(ino, fh, offset).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "readdir", ino),
+            reply,
+            async move { async_impl.readdir(ino, fh, offset).await },
+        );
     }
 
     fn readdirplus(
@@ -757,22 +941,29 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyDirectoryPlus,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.readdirplus(ino, fh, offset).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "readdirplus", ino),
+            reply,
+            async move { async_impl.readdirplus(ino, fh, offset).await },
+        );
     }
 
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.fsyncdir(ino, fh, datasync).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "fsyncdir", ino),
+            reply,
+            async move { async_impl.fsyncdir(ino, fh, datasync).await },
+        );
     }
 
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
         spawn_reply(
             req.unique(),
+            request_span!(req, "statfs", ino),
             reply,
             async move { async_impl.statfs(ino).await },
         );
@@ -791,38 +982,57 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
         let value = value.to_owned();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.setxattr(ino, name, value, flags, position).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "setxattr", ino),
+            reply,
+            async move { async_impl.setxattr(ino, name, value, flags, position).await },
+        );
     }
 
     fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.getxattr(ino, name, size).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "getxattr", ino),
+            reply,
+            async move { async_impl.getxattr(ino, name, size).await },
+        );
     }
 
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.listxattr(ino, size).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "listxattr", ino),
+            reply,
+            async move { async_impl.listxattr(ino, size).await },
+        );
     }
 
     fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.removexattr(ino, name).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "removexattr", ino),
+            reply,
+            async move { async_impl.removexattr(ino, name).await },
+        );
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let uid = req.uid();
+        let gid = req.gid();
+        let pid = req.pid();
+
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.access(ino, mask).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "access", ino),
+            reply,
+            async move { async_impl.access(uid, gid, pid, ino, mask).await },
+        );
     }
 
     fn create(
@@ -840,11 +1050,16 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_string_lossy().to_string().into();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .create(uid, gid, parent, name, mode, umask, flags)
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "create", parent),
+            reply,
+            async move {
+                async_impl
+                    .create(uid, gid, parent, name, mode, umask, flags)
+                    .await
+            },
+        );
     }
 
     fn getlk(
@@ -860,11 +1075,16 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .getlk(ino, fh, lock_owner, start, end, typ, pid)
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "getlk", ino),
+            reply,
+            async move {
+                async_impl
+                    .getlk(ino, fh, lock_owner, start, end, typ, pid)
+                    .await
+            },
+        );
     }
 
     fn setlk(
@@ -881,18 +1101,53 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "setlk", ino),
+            reply,
+            async move {
+                async_impl
+                    .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
+                    .await
+            },
+        );
     }
 
     fn bmap(&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.bmap(ino, blocksize, idx).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "bmap", ino),
+            reply,
+            async move { async_impl.bmap(ino, blocksize, idx).await },
+        );
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let async_impl = self.0.clone();
+        let uid = req.uid();
+        let pid = req.pid();
+        let in_data = Bytes::copy_from_slice(in_data);
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "ioctl", ino),
+            reply,
+            async move {
+                async_impl
+                    .ioctl(uid, pid, ino, fh, flags, cmd, in_data, out_size)
+                    .await
+            },
+        );
     }
 
     fn fallocate(
@@ -906,9 +1161,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.fallocate(ino, fh, offset, length, mode).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "fallocate", ino),
+            reply,
+            async move { async_impl.fallocate(ino, fh, offset, length, mode).await },
+        );
     }
 
     fn lseek(
@@ -921,9 +1179,12 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyLseek,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl.lseek(ino, fh, offset, whence).await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "lseek", ino),
+            reply,
+            async move { async_impl.lseek(ino, fh, offset, whence).await },
+        );
     }
 
     fn copy_file_range(
@@ -940,12 +1201,17 @@ impl<T: AsyncFileSystem + 'static> Filesystem for AsyncFs<T> {
         reply: ReplyWrite,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
-            async_impl
-                .copy_file_range(
-                    ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
-                )
-                .await
-        });
+        spawn_reply(
+            req.unique(),
+            request_span!(req, "copy_file_range", ino_in),
+            reply,
+            async move {
+                async_impl
+                    .copy_file_range(
+                        ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
+                    )
+                    .await
+            },
+        );
     }
 }
@@ -0,0 +1,76 @@
+use std::time::{Duration, SystemTime};
+
+use fuser::FileAttr;
+
+/// When to update atime of an inode being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AtimePolicy {
+    /// Update atime on every read, turning every read into a write.
+    Strict,
+    /// Update atime only if it isn't newer than mtime or ctime, or it's older than a day.
+    #[default]
+    Relative,
+    /// Never update atime on reads.
+    Never,
+}
+
+impl AtimePolicy {
+    pub const RELATIME_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn should_update(self, attr: &FileAttr, now: SystemTime) -> bool {
+        use AtimePolicy::*;
+
+        match self {
+            Strict => true,
+            Never => false,
+            Relative => {
+                attr.atime <= attr.mtime
+                    || attr.atime <= attr.ctime
+                    || matches!(
+                        now.duration_since(attr.atime),
+                        Ok(elapsed) if elapsed >= Self::RELATIME_WINDOW
+                    )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing;
+
+    fn file_attr(atime: SystemTime, mtime: SystemTime) -> FileAttr {
+        FileAttr {
+            atime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            ..testing::file_attr(0, 0, 0o644)
+        }
+    }
+
+    #[test]
+    fn atime_policy() {
+        let now = SystemTime::now();
+        let hour_ago = now - Duration::from_secs(60 * 60);
+        let days_ago = now - Duration::from_secs(2 * 24 * 60 * 60);
+
+        // read after a write
+        let written = file_attr(hour_ago, hour_ago);
+        assert!(AtimePolicy::Strict.should_update(&written, now));
+        assert!(AtimePolicy::Relative.should_update(&written, now));
+        assert!(!AtimePolicy::Never.should_update(&written, now));
+
+        // read again
+        let read = file_attr(now - Duration::from_secs(1), hour_ago);
+        assert!(AtimePolicy::Strict.should_update(&read, now));
+        assert!(!AtimePolicy::Relative.should_update(&read, now));
+        assert!(!AtimePolicy::Never.should_update(&read, now));
+
+        // read a long time ago
+        let stale = file_attr(days_ago + Duration::from_secs(1), days_ago);
+        assert!(AtimePolicy::Relative.should_update(&stale, now));
+        assert!(!AtimePolicy::Never.should_update(&stale, now));
+    }
+}
@@ -1,5 +1,396 @@
+use std::convert::TryInto;
+use std::iter::once;
+use std::mem::size_of;
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+
 type Block = Vec<u8>;
 
+/// The content hash naming a deduplicated block.
+pub type Hash = [u8; 32];
+
 pub fn empty_block(block_size: u64) -> Block {
     vec![0; block_size as usize]
 }
+
+/// Read `size` bytes of inline data from `start`, bytes beyond the data are zeros.
+pub fn read_inlined(inlined: &[u8], start: u64, size: u64) -> Vec<u8> {
+    let start = start as usize;
+    let size = size as usize;
+    let mut data = vec![0; size];
+    if inlined.len() > start {
+        let to_copy = size.min(inlined.len() - start);
+        data[..to_copy].copy_from_slice(&inlined[start..start + to_copy]);
+    }
+    data
+}
+
+/// Copy the part of a block, starting at `block_start` of the file, which lies in
+/// `data` read from `start`. The last block of a file may be shorter than others.
+pub fn copy_block(data: &mut [u8], start: u64, block_start: u64, block: &[u8]) {
+    let target = start + data.len() as u64;
+    let from = start.max(block_start);
+    let to = target.min(block_start + block.len() as u64);
+    if from < to {
+        data[(from - start) as usize..(to - start) as usize]
+            .copy_from_slice(&block[(from - block_start) as usize..(to - block_start) as usize]);
+    }
+}
+
+/// Blocks holding the bytes in `start..start + size`, a read fetches nothing else.
+/// An empty range lies in no block.
+pub fn blocks_of_range(start: u64, size: u64, block_size: u64) -> Range<u64> {
+    if size == 0 {
+        return start / block_size..start / block_size;
+    }
+    start / block_size..(start + size + block_size - 1) / block_size
+}
+
+/// Where a write of a file goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Into the inline data.
+    Inline,
+    /// Into blocks, the inline data is moved to the first block beforehand if `transfer`.
+    Blocks { transfer: bool },
+}
+
+impl Placement {
+    /// Place a write ending at `target` of a file of `size`, whose data is inlined if `inlined`.
+    ///
+    /// A file is inlined while it fits in `threshold`. Once a write goes beyond it, the file moves
+    /// to blocks for good, even if it still fits in the first block.
+    pub fn of_write(inlined: bool, size: u64, target: u64, threshold: u64, direct: bool) -> Self {
+        if inlined && (direct || target > threshold) {
+            Placement::Blocks { transfer: true }
+        } else if !direct && threshold > 0 && (inlined || size == 0) && target <= threshold {
+            Placement::Inline
+        } else {
+            Placement::Blocks { transfer: false }
+        }
+    }
+}
+
+/// The first block of a file moving out of inline data, `None` if it's all zeros,
+/// e.g. extended by truncation, which is a hole rather than a block.
+pub fn inline_block(inlined: &[u8], block_size: u64) -> Option<Block> {
+    if inlined.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    let mut block = inlined.to_vec();
+    block.resize(block_size as usize, 0);
+    Some(block)
+}
+
+/// Split data written at `start` into pieces of blocks, as (block index, offset in the block,
+/// piece). Only blocks touched by the data are yielded, so a write far beyond the end of a file
+/// leaves a hole.
+pub fn split_blocks(
+    start: u64,
+    data: &[u8],
+    block_size: u64,
+) -> impl Iterator<Item = (u64, usize, &[u8])> {
+    let first_block = start / block_size;
+    let offset = (start % block_size) as usize;
+    let (head, rest) = data.split_at(data.len().min(block_size as usize - offset));
+    once((first_block, offset, head)).chain(
+        rest.chunks(block_size as usize)
+            .enumerate()
+            .map(move |(i, piece)| (first_block + 1 + i as u64, 0, piece)),
+    )
+}
+
+pub fn hash_block(data: &[u8]) -> Hash {
+    let mut hash = Hash::default();
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+/// Length of the checksum trailing block values in checksum mode.
+pub const CHECKSUM_LEN: usize = size_of::<u32>();
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    // the reversed Castagnoli polynomial
+    const POLY: u32 = 0x82f6_3b78;
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The value of a block holding `data`, with room for the checksum,
+/// so that sealing it never reallocates.
+pub fn block_value(data: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(data.len() + CHECKSUM_LEN);
+    value.extend_from_slice(data);
+    value
+}
+
+/// Append the CRC32C of the data to it.
+pub fn append_checksum(mut data: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32c(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/// Strip the checksum trailing a value, `None` if it doesn't match the data.
+pub fn strip_checksum(mut value: Vec<u8>) -> Option<Vec<u8>> {
+    let len = value.len().checked_sub(CHECKSUM_LEN)?;
+    let checksum = u32::from_le_bytes(value[len..].try_into().ok()?);
+    value.truncate(len);
+    (crc32c(&value) == checksum).then(|| value)
+}
+
+/// A deduplicated block is stored once, prefixed with the number of block entries referring to it.
+pub fn encode_content(refs: u64, data: &[u8]) -> Vec<u8> {
+    let mut content = Vec::with_capacity(size_of::<u64>() + data.len());
+    content.extend(refs.to_be_bytes().iter());
+    content.extend_from_slice(data);
+    content
+}
+
+pub fn decode_content(content: &[u8]) -> Option<(u64, &[u8])> {
+    if content.len() < size_of::<u64>() {
+        return None;
+    }
+    let (refs, data) = content.split_at(size_of::<u64>());
+    let mut bytes = [0; size_of::<u64>()];
+    bytes.copy_from_slice(refs);
+    Some((u64::from_be_bytes(bytes), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn content_round_trip() {
+        let content = encode_content(3, b"block");
+        assert_eq!(decode_content(&content), Some((3, &b"block"[..])));
+        assert_eq!(decode_content(&encode_content(1, b"")), Some((1, &b""[..])));
+        assert_eq!(decode_content(&content[..4]), None);
+    }
+
+    #[test]
+    fn read_inline_range() {
+        assert_eq!(read_inlined(b"hello", 1, 3), b"ell");
+        assert_eq!(read_inlined(b"hello", 3, 4), b"lo\0\0");
+        assert_eq!(read_inlined(b"hello", 8, 2), b"\0\0");
+    }
+
+    #[test]
+    fn copy_block_range() {
+        let mut data = vec![0; 4];
+        copy_block(&mut data, 6, 4, b"abcdef");
+        assert_eq!(data, b"cdef");
+
+        // a read across blocks takes the head of the latter
+        let mut data = vec![0; 4];
+        copy_block(&mut data, 6, 8, b"ghij");
+        assert_eq!(data, b"\0\0gh");
+
+        // the last byte of a short block at the end of file
+        let mut data = vec![0; 1];
+        copy_block(&mut data, 10, 8, b"ghi");
+        assert_eq!(data, b"i");
+
+        // blocks out of the range are left alone
+        let mut data = vec![0; 2];
+        copy_block(&mut data, 10, 12, b"mnop");
+        copy_block(&mut data, 10, 4, b"abcdef");
+        assert_eq!(data, b"\0\0");
+    }
+
+    #[test]
+    fn split_sparse_write() {
+        // a byte written at 1GiB of a small file touches its own block only
+        let pieces: Vec<_> = split_blocks(1 << 30, b"x", 1 << 16).collect();
+        assert_eq!(pieces, [(1 << 14, 0, &b"x"[..])]);
+
+        let pieces: Vec<_> = split_blocks(6, b"abcdefghij", 4).collect();
+        assert_eq!(
+            pieces,
+            [
+                (1, 2, &b"ab"[..]),
+                (2, 0, &b"cdef"[..]),
+                (3, 0, &b"ghij"[..])
+            ]
+        );
+        let pieces: Vec<_> = split_blocks(8, b"abcd", 4).collect();
+        assert_eq!(pieces, [(2, 0, &b"abcd"[..])]);
+    }
+
+    #[test]
+    fn place_writes() {
+        use Placement::*;
+        const THRESHOLD: u64 = 16;
+        let place =
+            |inlined, size, target| Placement::of_write(inlined, size, target, THRESHOLD, false);
+
+        assert_eq!(place(false, 0, THRESHOLD), Inline);
+        assert_eq!(place(true, 10, THRESHOLD), Inline);
+        // beyond the threshold, but within the first block
+        assert_eq!(
+            place(true, THRESHOLD, THRESHOLD + 1),
+            Blocks { transfer: true }
+        );
+        assert_eq!(place(true, 10, 64), Blocks { transfer: true });
+        assert_eq!(place(false, 0, THRESHOLD + 1), Blocks { transfer: false });
+        // a file in blocks never goes back to inline data
+        assert_eq!(place(false, 64, 1), Blocks { transfer: false });
+
+        assert_eq!(
+            Placement::of_write(false, 0, 1, 0, false),
+            Blocks { transfer: false }
+        );
+        assert_eq!(
+            Placement::of_write(true, 10, 12, THRESHOLD, true),
+            Blocks { transfer: true }
+        );
+    }
+
+    /// Write `data` at `start` of a file with 5 bytes inlined, returns the content of the file.
+    fn write_inlined_file(start: u64, data: &[u8], threshold: u64, block_size: u64) -> Vec<u8> {
+        let inlined = b"hello".to_vec();
+        let target = start + data.len() as u64;
+        let size = (inlined.len() as u64).max(target);
+        match Placement::of_write(true, inlined.len() as u64, target, threshold, false) {
+            Placement::Inline => {
+                let mut inlined = read_inlined(&inlined, 0, size);
+                inlined[start as usize..target as usize].copy_from_slice(data);
+                inlined
+            }
+            Placement::Blocks { transfer } => {
+                assert!(transfer);
+                let mut blocks = HashMap::new();
+                blocks.insert(0, inline_block(&inlined, block_size).unwrap());
+                for (block, offset, piece) in split_blocks(start, data, block_size) {
+                    let value = blocks
+                        .entry(block)
+                        .or_insert_with(|| empty_block(block_size));
+                    value[offset..offset + piece.len()].copy_from_slice(piece);
+                }
+                let mut content = vec![0; size as usize];
+                for (block, value) in blocks {
+                    copy_block(&mut content, 0, block * block_size, &value);
+                }
+                content
+            }
+        }
+    }
+
+    #[test]
+    fn write_across_inline_threshold() {
+        let cases: [(u64, &[u8], &[u8]); 5] = [
+            // up to the threshold
+            (5, b"!!!", b"hello!!!"),
+            // just beyond the threshold
+            (5, b"!!!!", b"hello!!!!"),
+            // up to the end of the first block
+            (5, b"!!!!!!!!!!!", b"hello!!!!!!!!!!!"),
+            // just beyond the first block
+            (5, b"!!!!!!!!!!!!", b"hello!!!!!!!!!!!!"),
+            (14, b"far", b"hello\0\0\0\0\0\0\0\0\0far"),
+        ];
+        for (start, data, content) in cases.iter() {
+            assert_eq!(&write_inlined_file(*start, data, 8, 16), content);
+        }
+    }
+
+    #[test]
+    fn zeros_leave_a_hole() {
+        assert_eq!(inline_block(&[0; 8], 16), None);
+        assert_eq!(inline_block(b"", 16), None);
+        assert_eq!(inline_block(b"hi", 4), Some(b"hi\0\0".to_vec()));
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn small_reads_fetch_containing_blocks() {
+        let block_size = 1 << 16;
+        // a byte in the middle of a file of 1GiB
+        let start = (1 << 29) + 123;
+        assert_eq!(blocks_of_range(start, 1, block_size), 8192..8193);
+        // the first and the last byte of a block
+        assert_eq!(
+            blocks_of_range(8192 * block_size, 1, block_size),
+            8192..8193
+        );
+        assert_eq!(
+            blocks_of_range(8193 * block_size - 1, 1, block_size),
+            8192..8193
+        );
+        // bytes across a boundary
+        assert_eq!(
+            blocks_of_range(8193 * block_size - 1, 2, block_size),
+            8192..8194
+        );
+        assert_eq!(blocks_of_range(0, block_size, block_size), 0..1);
+        assert_eq!(blocks_of_range(1, block_size, block_size), 0..2);
+        // nothing is fetched for an empty read
+        assert!(blocks_of_range(start, 0, block_size).is_empty());
+    }
+
+    #[test]
+    fn sealing_block_value_never_reallocates() {
+        let data = vec![7; 4096];
+        let value = block_value(&data);
+        let buffer = value.as_ptr();
+        let sealed = append_checksum(value);
+        assert_eq!(sealed.as_ptr(), buffer);
+        assert_eq!(strip_checksum(sealed), Some(data));
+    }
+
+    #[test]
+    fn corrupted_block_fails_checksum() {
+        let mut value = append_checksum(b"hello".to_vec());
+        assert_eq!(value.len(), 5 + CHECKSUM_LEN);
+        assert_eq!(strip_checksum(value.clone()), Some(b"hello".to_vec()));
+
+        // a bit flipped in the data
+        value[1] ^= 1;
+        assert_eq!(strip_checksum(value.clone()), None);
+        value[1] ^= 1;
+        // a bit flipped in the checksum
+        *value.last_mut().unwrap() ^= 0x80;
+        assert_eq!(strip_checksum(value.clone()), None);
+        // a partial write
+        assert_eq!(strip_checksum(value[..4].to_vec()), None);
+        assert_eq!(strip_checksum(Vec::new()), None);
+    }
+
+    #[test]
+    fn hash_identical_blocks() {
+        assert_eq!(hash_block(&empty_block(16)), hash_block(&[0; 16]));
+        assert_ne!(hash_block(&empty_block(16)), hash_block(&empty_block(32)));
+    }
+}
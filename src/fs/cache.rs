@@ -0,0 +1,540 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytestring::ByteString;
+use fuser::FileAttr;
+use lru::LruCache;
+
+use super::inode::Inode;
+
+/// An in-memory cache of inode attributes and generations, shared by all requests of a mount.
+///
+/// Entries are invalidated after every committed transaction that saved the inode,
+/// so writes from the same mount are never hidden. Changes made by other mounts are
+/// only observed after the `ttl` expires.
+///
+/// Attributes read by a transaction are only inserted if no invalidation happened since the
+/// transaction began, or a commit of another request could be hidden by attributes read before it.
+pub struct AttrCache {
+    ttl: Duration,
+    state: Mutex<AttrState>,
+}
+
+struct AttrState {
+    entries: LruCache<u64, (Instant, FileAttr, u64)>,
+    // bumped by every invalidation
+    epoch: u64,
+}
+
+impl AttrCache {
+    pub const DEFAULT_CAPACITY: usize = 1 << 16;
+
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_capacity(ttl, Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(AttrState {
+                entries: LruCache::new(capacity),
+                epoch: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, ino: u64) -> Option<(FileAttr, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let entries = &mut state.entries;
+        let cached = entries.get(&ino).map(|(cached_at, attr, generation)| {
+            (cached_at.elapsed() < self.ttl, (*attr, *generation))
+        });
+        match cached {
+            Some((true, cached)) => Some(cached),
+            Some((false, _)) => {
+                entries.pop(&ino);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The epoch to insert attributes read from now on with.
+    pub fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Insert attributes read since `epoch`, they're dropped if any invalidation happened since.
+    pub fn insert(&self, attr: FileAttr, generation: u64, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.epoch == epoch {
+            state
+                .entries
+                .put(attr.ino, (Instant::now(), attr, generation));
+        }
+    }
+
+    pub fn invalidate(&self, ino: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.entries.pop(&ino);
+    }
+}
+
+struct DentryState {
+    // inodes by (parent, name)
+    entries: LruCache<(u64, ByteString), (Instant, u64)>,
+    names: HashMap<u64, HashSet<ByteString>>,
+    // bumped by every invalidation
+    epoch: u64,
+}
+
+/// An in-memory cache of directory entries, resolving names to inodes without a transaction.
+///
+/// Entries of a directory are invalidated after every committed transaction that changed its
+/// entries, so that names unlinked, renamed or linked by the same mount are never resolved to
+/// stale inodes. Changes made by other mounts are only observed after the `ttl` expires.
+///
+/// An entry read by a transaction is only inserted if no invalidation happened since the
+/// transaction began, or a commit of another request could be hidden by the entry read before it.
+pub struct DentryCache {
+    ttl: Duration,
+    capacity: usize,
+    state: Mutex<DentryState>,
+}
+
+impl DentryCache {
+    pub const DEFAULT_CAPACITY: usize = 1 << 16;
+
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_capacity(ttl, Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            state: Mutex::new(DentryState {
+                entries: LruCache::unbounded(),
+                names: HashMap::new(),
+                epoch: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, parent: u64, name: &ByteString) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let key = (parent, name.clone());
+        let cached = state
+            .entries
+            .get(&key)
+            .map(|(cached_at, ino)| (cached_at.elapsed() < self.ttl, *ino));
+        match cached {
+            Some((true, ino)) => Some(ino),
+            Some((false, _)) => {
+                state.entries.pop(&key);
+                state.remove_name(parent, name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The epoch to insert entries read from now on with.
+    pub fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Insert an entry read since `epoch`, it's dropped if any invalidation happened since.
+    pub fn insert(&self, parent: u64, name: ByteString, ino: u64, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+        state
+            .names
+            .entry(parent)
+            .or_insert_with(HashSet::new)
+            .insert(name.clone());
+        state.entries.put((parent, name), (Instant::now(), ino));
+        while state.entries.len() > self.capacity {
+            match state.entries.pop_lru() {
+                Some(((parent, name), _)) => state.remove_name(parent, &name),
+                None => break,
+            }
+        }
+    }
+
+    /// Drop cached entries of a directory, called once its entries are changed.
+    pub fn invalidate(&self, parent: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        if let Some(names) = state.names.remove(&parent) {
+            for name in names {
+                state.entries.pop(&(parent, name));
+            }
+        }
+    }
+}
+
+impl DentryState {
+    fn remove_name(&mut self, parent: u64, name: &ByteString) {
+        if let Some(names) = self.names.get_mut(&parent) {
+            names.remove(name);
+            if names.is_empty() {
+                self.names.remove(&parent);
+            }
+        }
+    }
+}
+
+/// The attributes telling whether cached blocks of an inode are still its data,
+/// every change of the blocks updates the ctime and mtime of the inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockStamp {
+    pub generation: u64,
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+}
+
+impl BlockStamp {
+    pub fn of(inode: &Inode) -> Self {
+        Self {
+            generation: inode.generation,
+            size: inode.size,
+            mtime: inode.mtime,
+            ctime: inode.ctime,
+        }
+    }
+}
+
+/// A cached block along with its index.
+pub type CachedBlock = (u64, Arc<Vec<u8>>);
+
+struct CachedInode {
+    stamp: BlockStamp,
+    blocks: HashSet<u64>,
+}
+
+struct BlockState {
+    // data of blocks by (ino, block), holes are cached as empty data
+    entries: LruCache<(u64, u64), Arc<Vec<u8>>>,
+    inodes: HashMap<u64, CachedInode>,
+    size: u64,
+}
+
+/// An in-memory cache of file blocks, bounded by the size of cached data.
+///
+/// Blocks are cached along with the stamp of their inode, and dropped once the inode is read
+/// with another stamp, so changes made by other mounts are observed by the next read.
+pub struct BlockCache {
+    capacity: u64,
+    state: Mutex<BlockState>,
+}
+
+impl BlockCache {
+    // memory taken by an entry besides its data
+    const ENTRY_COST: u64 = 1 << 6;
+
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(BlockState {
+                entries: LruCache::unbounded(),
+                inodes: HashMap::new(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Size of cached data.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    pub fn get(&self, ino: u64, stamp: BlockStamp, block: u64) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        match state.inodes.get(&ino) {
+            Some(cached) if cached.stamp == stamp => (),
+            Some(_) => {
+                state.remove_inode(ino);
+                return None;
+            }
+            None => return None,
+        }
+        state.entries.get(&(ino, block)).cloned()
+    }
+
+    /// Take the cached blocks in `blocks`, along with the smallest range covering the blocks
+    /// to fetch, cached blocks in that range are not taken.
+    pub fn get_range(
+        &self,
+        ino: u64,
+        stamp: BlockStamp,
+        blocks: Range<u64>,
+    ) -> (Vec<CachedBlock>, Option<Range<u64>>) {
+        let cached: Vec<_> = blocks
+            .clone()
+            .map(|block| (block, self.get(ino, stamp, block)))
+            .collect();
+        let start = cached.iter().position(|(_, data)| data.is_none());
+        let end = cached.iter().rposition(|(_, data)| data.is_none());
+        let fetch = start
+            .zip(end)
+            .map(|(start, end)| blocks.start + start as u64..blocks.start + end as u64 + 1);
+        let taken = cached
+            .into_iter()
+            .filter(|(block, _)| !fetch.as_ref().map_or(false, |fetch| fetch.contains(block)))
+            .filter_map(|(block, data)| Some((block, data?)))
+            .collect();
+        (taken, fetch)
+    }
+
+    /// Cache a block read by an inode of `stamp`, an empty block is a hole.
+    pub fn insert(&self, ino: u64, stamp: BlockStamp, block: u64, data: Vec<u8>) {
+        let cost = data.len() as u64 + Self::ENTRY_COST;
+        if cost > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        // blocks of another stamp are stale, or newer than the reader, they are never mixed
+        let mixed = matches!(state.inodes.get(&ino), Some(cached) if cached.stamp != stamp);
+        if mixed {
+            state.remove_inode(ino);
+        }
+        state
+            .inodes
+            .entry(ino)
+            .or_insert_with(|| CachedInode {
+                stamp,
+                blocks: HashSet::new(),
+            })
+            .blocks
+            .insert(block);
+        if let Some(old) = state.entries.put((ino, block), Arc::new(data)) {
+            state.size -= old.len() as u64 + Self::ENTRY_COST;
+        }
+        state.size += cost;
+        while state.size > self.capacity {
+            match state.entries.pop_lru() {
+                Some(((ino, block), data)) => {
+                    state.size -= data.len() as u64 + Self::ENTRY_COST;
+                    state.remove_block(ino, block);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drop cached blocks of an inode, called once its blocks are written.
+    pub fn invalidate(&self, ino: u64) {
+        self.state.lock().unwrap().remove_inode(ino);
+    }
+}
+
+impl BlockState {
+    fn remove_inode(&mut self, ino: u64) {
+        if let Some(cached) = self.inodes.remove(&ino) {
+            for block in cached.blocks {
+                if let Some(data) = self.entries.pop(&(ino, block)) {
+                    self.size -= data.len() as u64 + BlockCache::ENTRY_COST;
+                }
+            }
+        }
+    }
+
+    // forget an evicted block
+    fn remove_block(&mut self, ino: u64, block: u64) {
+        if let Some(cached) = self.inodes.get_mut(&ino) {
+            cached.blocks.remove(&block);
+            if cached.blocks.is_empty() {
+                self.inodes.remove(&ino);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing;
+
+    fn stamp(ctime: u64) -> BlockStamp {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(ctime);
+        BlockStamp {
+            generation: 0,
+            size: 1 << 20,
+            mtime: time,
+            ctime: time,
+        }
+    }
+
+    // blocks to fetch from the store
+    fn fetch(cache: &BlockCache, ino: u64, ctime: u64, blocks: Range<u64>) -> Option<Range<u64>> {
+        cache.get_range(ino, stamp(ctime), blocks).1
+    }
+
+    #[test]
+    fn hits_avoid_fetches() {
+        let cache = BlockCache::new(1 << 20);
+        assert_eq!(fetch(&cache, 1, 1, 0..4), Some(0..4));
+        for block in 0..4 {
+            cache.insert(1, stamp(1), block, vec![block as u8; 16]);
+        }
+        // nothing is fetched once all blocks are cached
+        assert_eq!(fetch(&cache, 1, 1, 0..4), None);
+        let (cached, _) = cache.get_range(1, stamp(1), 2..8);
+        assert_eq!(
+            cached,
+            vec![(2, Arc::new(vec![2; 16])), (3, Arc::new(vec![3; 16]))]
+        );
+        assert_eq!(fetch(&cache, 1, 1, 2..8), Some(4..8));
+
+        // holes are cached as well
+        cache.insert(1, stamp(1), 5, Vec::new());
+        assert_eq!(fetch(&cache, 1, 1, 4..6), Some(4..5));
+        assert_eq!(cache.get(1, stamp(1), 5).as_deref(), Some(&Vec::new()));
+        // cached blocks between missing ones are fetched again
+        assert_eq!(fetch(&cache, 1, 1, 3..8), Some(4..8));
+        assert_eq!(cache.get_range(1, stamp(1), 3..8).0.len(), 1);
+    }
+
+    #[test]
+    fn writes_invalidate() {
+        let cache = BlockCache::new(1 << 20);
+        cache.insert(1, stamp(1), 0, vec![1; 16]);
+        cache.insert(2, stamp(1), 0, vec![2; 16]);
+        cache.invalidate(1);
+        assert_eq!(cache.get(1, stamp(1), 0), None);
+        assert_eq!(fetch(&cache, 1, 1, 0..1), Some(0..1));
+        assert!(cache.get(2, stamp(1), 0).is_some());
+
+        // writes of other mounts change the stamp
+        assert_eq!(cache.get(2, stamp(2), 0), None);
+        assert_eq!(cache.get(2, stamp(1), 0), None);
+        assert_eq!(cache.size(), 0);
+
+        // blocks of different stamps are never mixed
+        cache.insert(2, stamp(2), 0, vec![3; 16]);
+        cache.insert(2, stamp(1), 1, vec![2; 16]);
+        assert_eq!(fetch(&cache, 2, 1, 0..2), Some(0..1));
+        assert_eq!(fetch(&cache, 2, 2, 0..2), Some(0..2));
+    }
+
+    fn attr(ino: u64, size: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            ..testing::file_attr(0, 0, 0o644)
+        }
+    }
+
+    #[test]
+    fn cached_attrs() {
+        let cache = AttrCache::new(Duration::from_secs(60));
+        cache.insert(attr(1, 16), 1, cache.epoch());
+        cache.insert(attr(2, 32), 1, cache.epoch());
+        assert_eq!(cache.get(1), Some((attr(1, 16), 1)));
+        assert_eq!(cache.get(3), None);
+
+        // a committed change drops the attributes of the saved inode only
+        cache.invalidate(1);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some((attr(2, 32), 1)));
+
+        let cache = AttrCache::new(Duration::from_secs(0));
+        cache.insert(attr(1, 16), 1, cache.epoch());
+        assert_eq!(cache.get(1), None);
+    }
+
+    #[test]
+    fn stale_attrs_are_not_inserted() {
+        let cache = AttrCache::new(Duration::from_secs(60));
+        // a getattr begins, then another request truncates the file and invalidates
+        let epoch = cache.epoch();
+        cache.invalidate(1);
+        cache.insert(attr(1, 16), 1, epoch);
+        assert_eq!(cache.get(1), None);
+
+        cache.insert(attr(1, 0), 1, cache.epoch());
+        assert_eq!(cache.get(1), Some((attr(1, 0), 1)));
+    }
+
+    #[test]
+    fn rename_invalidates_dentries() {
+        let cache = DentryCache::new(Duration::from_secs(60));
+        cache.insert(1, "a".into(), 10, cache.epoch());
+        cache.insert(1, "b".into(), 11, cache.epoch());
+        cache.insert(2, "c".into(), 12, cache.epoch());
+        assert_eq!(cache.get(1, &"a".into()), Some(10));
+
+        // renaming `1/a` to `2/d` changes entries of both directories
+        cache.invalidate(1);
+        cache.invalidate(2);
+        assert_eq!(cache.get(1, &"a".into()), None);
+        assert_eq!(cache.get(1, &"b".into()), None);
+        assert_eq!(cache.get(2, &"c".into()), None);
+        assert_eq!(cache.get(2, &"d".into()), None);
+
+        // entries of other directories are kept
+        cache.insert(1, "b".into(), 11, cache.epoch());
+        cache.insert(3, "e".into(), 13, cache.epoch());
+        cache.invalidate(1);
+        assert_eq!(cache.get(3, &"e".into()), Some(13));
+    }
+
+    #[test]
+    fn stale_dentries_are_not_inserted() {
+        let cache = DentryCache::new(Duration::from_secs(60));
+        // a lookup begins, then another request renames the entry away and invalidates
+        let epoch = cache.epoch();
+        cache.invalidate(1);
+        cache.insert(1, "a".into(), 10, epoch);
+        assert_eq!(cache.get(1, &"a".into()), None);
+
+        // entries read after the invalidation are inserted
+        cache.insert(1, "a".into(), 11, cache.epoch());
+        assert_eq!(cache.get(1, &"a".into()), Some(11));
+    }
+
+    #[test]
+    fn dentries_expire_and_evict() {
+        let cache = DentryCache::new(Duration::from_secs(0));
+        cache.insert(1, "a".into(), 10, cache.epoch());
+        assert_eq!(cache.get(1, &"a".into()), None);
+
+        let cache = DentryCache::with_capacity(Duration::from_secs(60), 2);
+        cache.insert(1, "a".into(), 10, cache.epoch());
+        cache.insert(1, "b".into(), 11, cache.epoch());
+        cache.get(1, &"a".into());
+        cache.insert(1, "c".into(), 12, cache.epoch());
+        assert_eq!(cache.get(1, &"a".into()), Some(10));
+        assert_eq!(cache.get(1, &"b".into()), None);
+        assert_eq!(cache.get(1, &"c".into()), Some(12));
+    }
+
+    #[test]
+    fn bounded_by_size() {
+        let cost = 16 + BlockCache::ENTRY_COST;
+        let cache = BlockCache::new(cost * 4);
+        for block in 0..8 {
+            cache.insert(1, stamp(1), block, vec![0; 16]);
+        }
+        assert_eq!(cache.size(), cost * 4);
+        assert_eq!(fetch(&cache, 1, 1, 0..8), Some(0..4));
+
+        // recently used blocks are kept
+        cache.get(1, stamp(1), 4);
+        cache.insert(2, stamp(1), 0, vec![0; 16]);
+        assert!(cache.get(1, stamp(1), 4).is_some());
+        assert!(cache.get(1, stamp(1), 5).is_none());
+
+        // a block larger than the cache is never cached
+        cache.insert(3, stamp(1), 0, vec![0; cost as usize * 4]);
+        assert!(cache.get(3, stamp(1), 0).is_none());
+        assert_eq!(cache.size(), cost * 4);
+    }
+}
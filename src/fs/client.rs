@@ -3,22 +3,52 @@ use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 use tikv_client::Config;
 
+/// The config file of clients, the tls section is at the top level for compatibility.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
-pub struct TlsConfig {
+pub struct ClientConfig {
+    #[serde(default)]
     pub ca_path: PathBuf,
+    #[serde(default)]
     pub cert_path: PathBuf,
+    #[serde(default)]
     pub key_path: PathBuf,
+    // file of the key in hex to encrypt data with, it's required by `-o encrypt`.
+    #[serde(default)]
+    pub encryption_key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub robustness: RobustnessConfig,
 }
 
-impl TlsConfig {
+/// Config files written before `ClientConfig` only contain the tls section.
+pub type TlsConfig = ClientConfig;
+
+/// How transactions are retried, mount options take precedence over it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RobustnessConfig {
+    // times to retry a transaction failed by region errors
+    #[serde(default)]
+    pub retry_limit: Option<u32>,
+    // delay between retries of transactions conflicted by key errors, like "10ms"
+    #[serde(default)]
+    pub spin_delay: Option<String>,
+    // timeout of requests to pd and tikv, like "2s"
+    #[serde(default)]
+    pub grpc_timeout: Option<String>,
+    // timeout of an operation including all its retries, like "30s"
+    #[serde(default)]
+    pub op_timeout: Option<String>,
+}
+
+impl ClientConfig {
     fn exist_all(&self) -> bool {
         self.ca_path.exists() && self.cert_path.exists() && self.key_path.exists()
     }
 }
 
-impl From<TlsConfig> for Config {
-    fn from(tls_cfg: TlsConfig) -> Self {
+impl From<ClientConfig> for Config {
+    fn from(tls_cfg: ClientConfig) -> Self {
         let cfg = Config::default();
         if !tls_cfg.exist_all() {
             cfg
@@ -27,3 +57,42 @@ impl From<TlsConfig> for Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_client_config() {
+        let tls_only: ClientConfig = toml::from_str(
+            r#"
+            ca-path = "/root/.tifs/tls/ca.crt"
+            cert-path = "/root/.tifs/tls/client.crt"
+            key-path = "/root/.tifs/tls/client.key"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(tls_only.ca_path, PathBuf::from("/root/.tifs/tls/ca.crt"));
+        assert_eq!(tls_only.robustness, RobustnessConfig::default());
+
+        let cfg: ClientConfig = toml::from_str(
+            r#"
+            [robustness]
+            retry-limit = 3
+            spin-delay = "10ms"
+            grpc-timeout = "2s"
+            op-timeout = "30s"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.robustness,
+            RobustnessConfig {
+                retry_limit: Some(3),
+                spin_delay: Some("10ms".to_owned()),
+                grpc_timeout: Some("2s".to_owned()),
+                op_timeout: Some("30s".to_owned()),
+            }
+        );
+    }
+}
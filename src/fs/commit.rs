@@ -0,0 +1,78 @@
+use tikv_client::{CheckLevel, TransactionOptions};
+
+use crate::MountOption;
+
+/// How a transaction writing is committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommitPolicy {
+    /// The classic two-phase commit, a transaction is done after the primary key is committed.
+    TwoPhase,
+    /// A transaction is done once all its keys are prewritten, committing happens in the background.
+    #[default]
+    Async,
+    /// Commit a transaction whose keys all lie in one region in a single phase,
+    /// other transactions fall back to async commit.
+    OnePc,
+}
+
+impl CommitPolicy {
+    /// The policy of the last commit option, async commit if none is given.
+    pub fn from_options(options: &[MountOption]) -> Self {
+        options
+            .iter()
+            .rev()
+            .find_map(|option| match option {
+                MountOption::NoAsyncCommit => Some(CommitPolicy::TwoPhase),
+                MountOption::AsyncCommit => Some(CommitPolicy::Async),
+                MountOption::OnePc => Some(CommitPolicy::OnePc),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Options to begin an optimistic transaction with.
+    pub fn options(self) -> TransactionOptions {
+        let options = TransactionOptions::new_optimistic();
+        let options = match self {
+            CommitPolicy::TwoPhase => options,
+            CommitPolicy::Async => options.use_async_commit(),
+            CommitPolicy::OnePc => options.use_async_commit().try_one_pc(),
+        };
+        // a transaction timed out is dropped before committing
+        options.drop_check(CheckLevel::Warn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_option_wins() {
+        assert_eq!(CommitPolicy::from_options(&[]), CommitPolicy::Async);
+        assert_eq!(
+            CommitPolicy::from_options(&[MountOption::Dedup]),
+            CommitPolicy::Async
+        );
+        assert_eq!(
+            CommitPolicy::from_options(&[MountOption::NoAsyncCommit]),
+            CommitPolicy::TwoPhase
+        );
+        assert_eq!(
+            CommitPolicy::from_options(&[
+                MountOption::OnePc,
+                MountOption::Dedup,
+                MountOption::NoAsyncCommit
+            ]),
+            CommitPolicy::TwoPhase
+        );
+        assert_eq!(
+            CommitPolicy::from_options(&[MountOption::NoAsyncCommit, MountOption::AsyncCommit]),
+            CommitPolicy::Async
+        );
+        assert_eq!(
+            CommitPolicy::from_options(&[MountOption::AsyncCommit, MountOption::OnePc]),
+            CommitPolicy::OnePc
+        );
+    }
+}
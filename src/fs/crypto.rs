@@ -0,0 +1,157 @@
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use sha2::Sha256;
+
+use super::block::Hash;
+use super::error::{FsError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Encrypts values with AES-256-GCM, each value is prefixed with its own random nonce.
+pub struct BlockCipher {
+    cipher: Aes256Gcm,
+    // keyed by a secret derived from the key, for the content keys of deduplicated blocks
+    content_mac: HmacSha256,
+}
+
+impl BlockCipher {
+    pub const KEY_LEN: usize = 32;
+    pub const NONCE_LEN: usize = 12;
+    const CONTENT_KEY_CONTEXT: &'static [u8] = b"tifs content key";
+
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != Self::KEY_LEN {
+            return Err(FsError::Crypto {
+                msg: format!("key of {} bytes, expect {}", key.len(), Self::KEY_LEN),
+            });
+        }
+        let mut derive = HmacSha256::new_from_slice(key).expect("hmac takes keys of any length");
+        derive.update(Self::CONTENT_KEY_CONTEXT);
+        let content_key = derive.finalize().into_bytes();
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::from_slice(key)),
+            content_mac: HmacSha256::new_from_slice(&content_key)
+                .expect("hmac takes keys of any length"),
+        })
+    }
+
+    /// Hash of a block in dedup mode, identical blocks share it, but it can't be
+    /// computed for a guessed content without the key.
+    pub fn content_hash(&self, data: &[u8]) -> Hash {
+        let mut mac = self.content_mac.clone();
+        mac.update(data);
+        let mut hash = Hash::default();
+        hash.copy_from_slice(&mac.finalize().into_bytes());
+        hash
+    }
+
+    /// Build a cipher from a key in hex, surrounding whitespaces are ignored.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        let invalid = || FsError::Crypto {
+            msg: "key should be in hex".to_owned(),
+        };
+        if hex.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(invalid)
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        Self::new(&key)
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; Self::NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|err| FsError::Crypto {
+                msg: err.to_string(),
+            })?;
+        let mut value = Vec::with_capacity(Self::NONCE_LEN + sealed.len());
+        value.extend_from_slice(&nonce);
+        value.extend(sealed);
+        Ok(value)
+    }
+
+    pub fn decrypt(&self, value: &[u8]) -> Result<Vec<u8>> {
+        if value.len() < Self::NONCE_LEN {
+            return Err(FsError::Crypto {
+                msg: "value is shorter than a nonce".to_owned(),
+            });
+        }
+        let (nonce, sealed) = value.split_at(Self::NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|err| FsError::Crypto {
+                msg: format!("fail to decrypt, is the key right? {}", err),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::block::hash_block;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn round_trip() {
+        let cipher = BlockCipher::from_hex(KEY).unwrap();
+        let cases: [&[u8]; 3] = [b"", b"hello tifs", &[7; 1 << 16]];
+        for data in cases {
+            let value = cipher.encrypt(data).unwrap();
+            assert_eq!(cipher.decrypt(&value).unwrap(), data);
+        }
+        // every value has its own nonce
+        assert_ne!(
+            cipher.encrypt(b"same").unwrap(),
+            cipher.encrypt(b"same").unwrap()
+        );
+    }
+
+    #[test]
+    fn not_plaintext() {
+        let cipher = BlockCipher::from_hex(KEY).unwrap();
+        let data = b"a secret worth hiding from cluster operators";
+        let value = cipher.encrypt(data).unwrap();
+        assert!(!value.windows(data.len()).any(|window| window == data));
+        assert!(!value.windows(6).any(|window| window == b"secret"));
+
+        let other = BlockCipher::new(&[0; BlockCipher::KEY_LEN]).unwrap();
+        assert!(other.decrypt(&value).is_err());
+    }
+
+    #[test]
+    fn keyed_content_hash() {
+        let cipher = BlockCipher::from_hex(KEY).unwrap();
+        let data = b"a block shared by two files";
+        assert_eq!(cipher.content_hash(data), cipher.content_hash(data));
+        assert_ne!(
+            cipher.content_hash(data),
+            cipher.content_hash(b"another block")
+        );
+        // a plain hash of a guessed content never matches
+        assert_ne!(cipher.content_hash(data), hash_block(data));
+
+        let other = BlockCipher::new(&[0; BlockCipher::KEY_LEN]).unwrap();
+        assert_ne!(cipher.content_hash(data), other.content_hash(data));
+    }
+
+    #[test]
+    fn parse_key() {
+        assert!(BlockCipher::from_hex(&format!(" {}\n", KEY)).is_ok());
+        assert!(BlockCipher::from_hex(&KEY[2..]).is_err());
+        assert!(BlockCipher::from_hex(&KEY[1..]).is_err());
+        assert!(BlockCipher::from_hex(&KEY.replace('0', "g")).is_err());
+    }
+}
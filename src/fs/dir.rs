@@ -1,9 +1,67 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+
 use super::error::{FsError, Result};
 use super::reply::DirItem;
 use super::serialize::{deserialize, serialize, ENCODING};
+use super::tikv_fs::{DIR_PARENT, DIR_SELF};
 
 pub type Directory = Vec<DirItem>;
 
+type ParentFuture<'a> = Pin<Box<dyn 'a + Send + Future<Output = Result<Option<u64>>>>>;
+
+/// List a directory with `.` and `..` in front, wherever they are stored.
+/// The parent of a directory without `..`, like the root, is itself.
+pub fn listing(ino: u64, dir: Directory) -> Directory {
+    let is_dot = |item: &DirItem| item.name == *DIR_SELF || item.name == *DIR_PARENT;
+    let parent = dir
+        .iter()
+        .find(|item| item.name == *DIR_PARENT)
+        .map_or(ino, |item| item.ino);
+
+    let mut list = Vec::with_capacity(dir.len() + 2);
+    list.push(DirItem {
+        ino,
+        name: DIR_SELF.to_string(),
+        typ: fuser::FileType::Directory,
+    });
+    list.push(DirItem {
+        ino: parent,
+        name: DIR_PARENT.to_string(),
+        typ: fuser::FileType::Directory,
+    });
+    list.extend(dir.into_iter().filter(|item| !is_dot(item)));
+    list
+}
+
+/// Refuse to move directory `ino` into `dir` if `dir` is `ino` or one of its descendants,
+/// which would detach them from the tree in a cycle.
+///
+/// It walks `..` from `dir` up to the root, the parent of a directory is looked up
+/// by `parent_of` in `state`.
+pub async fn check_ancestor<S, F>(state: &mut S, ino: u64, dir: u64, mut parent_of: F) -> Result<()>
+where
+    F: for<'a> FnMut(&'a mut S, u64) -> ParentFuture<'a>,
+{
+    let mut visited = HashSet::new();
+    let mut current = Some(dir);
+    while let Some(ancestor) = current {
+        if ancestor == ino {
+            return Err(FsError::MoveIntoDescendant {
+                dir: ino,
+                parent: dir,
+            });
+        }
+        // stop at an existing cycle
+        if !visited.insert(ancestor) {
+            break;
+        }
+        current = parent_of(state, ancestor).await?;
+    }
+    Ok(())
+}
+
 pub fn encode(dir: &[DirItem]) -> Result<Vec<u8>> {
     serialize(dir).map_err(|err| FsError::Serialize {
         target: "directory",
@@ -35,3 +93,69 @@ pub fn decode_item(bytes: &[u8]) -> Result<DirItem> {
         msg: err.to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use fuser::FileType;
+
+    use super::*;
+
+    fn item(ino: u64, name: &str, typ: FileType) -> DirItem {
+        DirItem {
+            ino,
+            name: name.to_owned(),
+            typ,
+        }
+    }
+
+    #[test]
+    fn dots_in_front() {
+        // `..` is moved to the end on rename
+        let dir = vec![
+            item(3, ".", FileType::Directory),
+            item(4, "a", FileType::RegularFile),
+            item(2, "..", FileType::Directory),
+        ];
+        let names: Vec<_> = listing(3, dir)
+            .into_iter()
+            .map(|item| (item.ino, item.name))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (3, ".".to_owned()),
+                (2, "..".to_owned()),
+                (4, "a".to_owned())
+            ]
+        );
+
+        // the root
+        let dir = vec![item(1, ".", FileType::Directory)];
+        let list = listing(1, dir);
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].ino, list[0].name.as_str()), (1, "."));
+        assert_eq!((list[1].ino, list[1].name.as_str()), (1, ".."));
+    }
+
+    #[test]
+    fn move_into_descendant() {
+        use std::collections::HashMap;
+
+        // `/a/b/c` as 2, 3, 4 under the root
+        let mut parents: HashMap<u64, u64> = vec![(2, 1), (3, 2), (4, 3)].into_iter().collect();
+        let mut check = |ino, dir| {
+            futures::executor::block_on(check_ancestor(&mut parents, ino, dir, |parents, dir| {
+                Box::pin(async move { Ok(parents.get(&dir).copied()) })
+            }))
+        };
+        // move `/a/b` into `/a/b/c` or itself
+        assert!(matches!(
+            check(3, 4),
+            Err(FsError::MoveIntoDescendant { dir: 3, parent: 4 })
+        ));
+        assert!(check(3, 3).is_err());
+        // move `/a/b` into the root, or `/a/b/c` into `/a`
+        assert!(check(3, 1).is_ok());
+        assert!(check(4, 2).is_ok());
+    }
+}
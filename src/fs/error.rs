@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use thiserror::Error;
 use tracing::error;
 
@@ -6,6 +8,9 @@ pub enum FsError {
     #[error("unimplemented")]
     Unimplemented,
 
+    #[error("{op} is not supported")]
+    Unsupported { op: &'static str },
+
     #[error("invalid scoped key: {0:?}")]
     InvalidScopedKey(Vec<u8>),
 
@@ -31,6 +36,9 @@ pub enum FsError {
     #[error("cannot find {ino}({fh})")]
     FhNotFound { ino: u64, fh: u64 },
 
+    #[error("too many open files, the limit is {max}")]
+    TooManyOpenFiles { max: usize },
+
     #[error("invalid offset({offset}) of ino({ino})")]
     InvalidOffset { ino: u64, offset: i64 },
 
@@ -49,9 +57,21 @@ pub enum FsError {
     #[error("unknown file type")]
     UnknownFileType,
 
+    #[error("inode({ino}) is not a regular file")]
+    NotRegularFile { ino: u64 },
+
+    #[error("inode({ino}) is not a directory")]
+    NotDirectory { ino: u64 },
+
+    #[error("inode({ino}) is a directory")]
+    IsDirectory { ino: u64 },
+
     #[error("key error: {0}")]
     KeyError(String),
 
+    #[error("region error: {0}")]
+    RegionError(String),
+
     #[error("excess max retry times: {0}")]
     RetryTimesExcess(u64),
 
@@ -64,11 +84,112 @@ pub enum FsError {
     #[error("invalid lock")]
     InvalidLock,
 
+    #[error("invalid block size({size}), it should be a power of two in [{min}, {max}]")]
+    InvalidBlockSize { size: u64, min: u64, max: u64 },
+
     #[error("block size conflicts: origin({origin}) != new({new})")]
     BlockSizeConflict { origin: u64, new: u64 },
 
+    #[error("atime disabled conflicts: origin({origin}) != new({new})")]
+    DisableAtimeConflict { origin: bool, new: bool },
+
+    #[error("dedup conflicts: origin({origin}) != new({new})")]
+    DedupConflict { origin: bool, new: bool },
+
+    #[error("encryption conflicts: origin({origin}) != new({new})")]
+    EncryptionConflict { origin: bool, new: bool },
+
+    #[error("inline divisor conflicts: origin({origin}) != new({new})")]
+    InlineDivisorConflict { origin: u64, new: u64 },
+
+    #[error("checksum conflicts: origin({origin}) != new({new})")]
+    ChecksumConflict { origin: bool, new: bool },
+
+    #[error("the filesystem is being imported, or an import failed halfway and must be reset")]
+    UnfinishedImport,
+
+    #[error("checksum mismatch of block(<{ino}>[{block}])")]
+    ChecksumMismatch { ino: u64, block: u64 },
+
+    #[error("crypto error: {msg}")]
+    Crypto { msg: String },
+
+    #[error(
+        "encoding conflicts: origin({origin}) != new({new}), rebuild with the origin encoding"
+    )]
+    EncodingConflict {
+        origin: &'static str,
+        new: &'static str,
+    },
+
+    #[error("unsupported format version({version}), the newest supported is {supported}")]
+    UnsupportedFormat { version: u8, supported: u8 },
+
     #[error("no space left: MaxSize({0})")]
     NoSpaceLeft(u64),
+
+    #[error("read-only snapshot")]
+    ReadOnly,
+
+    #[error("operation timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("permission denied: access inode({ino}) as mask({mask})")]
+    PermissionDenied { ino: u64, mask: i32 },
+
+    #[error("operation not permitted: inode({ino}) is immutable")]
+    Immutable { ino: u64 },
+
+    #[error("operation not permitted: inode({ino}) is append-only")]
+    AppendOnly { ino: u64 },
+
+    #[error("operation not permitted: change flags of inode({ino})")]
+    ChangeFlags { ino: u64 },
+
+    #[error("inappropriate ioctl({cmd:#x})")]
+    UnknownIoctl { cmd: u32 },
+
+    #[error("unknown flags({flags:#x}) of copy_file_range")]
+    UnknownCopyFlags { flags: u32 },
+
+    #[error("cannot clone from fd({fd}) of another filesystem")]
+    CloneAcrossFs { fd: i64 },
+
+    #[error("operation not permitted: entry({name}) of sticky dir({dir})")]
+    StickyEntry { dir: u64, name: String },
+
+    #[error("cannot move dir({dir}) into its descendant({parent})")]
+    MoveIntoDescendant { dir: u64, parent: u64 },
+
+    #[error("operation not permitted: hard link to dir({ino})")]
+    LinkDirectory { ino: u64 },
+
+    #[error("operation not permitted: change owner of inode({ino})")]
+    ChangeOwner { ino: u64 },
+
+    #[error("operation not permitted: change mode of inode({ino})")]
+    ChangeMode { ino: u64 },
+
+    #[error("cannot find xattr({name}) of inode({ino})")]
+    XattrNotFound { ino: u64, name: String },
+
+    #[error("xattr({name}) of inode({ino}) already exist")]
+    XattrExist { ino: u64, name: String },
+
+    #[error("value of {len} bytes is too large for an xattr of inode({ino})")]
+    XattrTooLarge { ino: u64, len: usize },
+
+    #[error("buffer of size({size}) is too small for {len} bytes")]
+    BufferTooSmall { size: u32, len: usize },
+
+    #[error("invalid write size({len}), at most {max} bytes")]
+    InvalidWriteSize { len: u64, max: u64 },
+
+    #[error("invalid acl: {msg}")]
+    InvalidAcl { msg: String },
+
+    #[error("default acl of non-directory inode({ino})")]
+    DefaultAclOfFile { ino: u64 },
 }
 
 pub type Result<T> = std::result::Result<T, FsError>;
@@ -102,32 +223,125 @@ impl From<tikv_client::Error> for FsError {
         match err {
             KeyError(err) => Self::KeyError(format!("{:?}", err)),
             MultipleKeyErrors(errs) => Self::KeyError(format!("{:?}", errs)),
+            RegionError(err) => Self::RegionError(format!("{:?}", err)),
+            LeaderNotFound { region } => {
+                Self::RegionError(format!("leader of region({:?}) not found", region))
+            }
+            ExtractedErrors(errs) if !errs.is_empty() && errs.iter().all(is_region_error) => {
+                Self::RegionError(format!("{:?}", errs))
+            }
             _ => Self::UnknownError(err.to_string()),
         }
     }
 }
 
+/// Errors of stale region caches, like not leader or epoch not match, which go away
+/// once the client reloads the region.
+fn is_region_error(err: &tikv_client::Error) -> bool {
+    matches!(
+        err,
+        tikv_client::Error::RegionError(_) | tikv_client::Error::LeaderNotFound { .. }
+    )
+}
+
 impl From<FsError> for libc::c_int {
     fn from(e: FsError) -> Self {
         use FsError::*;
         match e {
             Unimplemented => libc::ENOSYS,
+            Unsupported { op: _ } => libc::EOPNOTSUPP,
             NameTooLong { file: _ } => libc::ENAMETOOLONG,
             FileNotFound { file: _ } => libc::ENOENT,
             FileExist { file: _ } => libc::EEXIST,
             InodeNotFound { inode: _ } => libc::EFAULT,
             FhNotFound { ino: _, fh: _ } => libc::EBADF,
+            TooManyOpenFiles { max: _ } => libc::EMFILE,
             InvalidOffset { ino: _, offset: _ } => libc::EINVAL,
             UnknownWhence { whence: _ } => libc::EINVAL,
             BlockNotFound { inode: _, block: _ } => libc::EINVAL,
             DirNotEmpty { dir: _ } => libc::ENOTEMPTY,
             UnknownFileType => libc::EINVAL,
+            NotRegularFile { ino: _ } => libc::EINVAL,
+            NotDirectory { ino: _ } => libc::ENOTDIR,
+            IsDirectory { ino: _ } => libc::EISDIR,
             KeyError(_) => libc::EAGAIN,
+            RegionError(_) => libc::EAGAIN,
             RetryTimesExcess(_) => libc::EAGAIN,
             InvalidStr => libc::EINVAL,
+            InvalidBlockSize {
+                size: _,
+                min: _,
+                max: _,
+            } => libc::EINVAL,
             BlockSizeConflict { origin: _, new: _ } => libc::EINVAL,
+            DisableAtimeConflict { origin: _, new: _ } => libc::EINVAL,
+            DedupConflict { origin: _, new: _ } => libc::EINVAL,
+            EncryptionConflict { origin: _, new: _ } => libc::EINVAL,
+            InlineDivisorConflict { origin: _, new: _ } => libc::EINVAL,
+            ChecksumConflict { origin: _, new: _ } => libc::EINVAL,
+            UnfinishedImport => libc::EBUSY,
+            ChecksumMismatch { ino: _, block: _ } => libc::EIO,
+            Crypto { msg: _ } => libc::EIO,
+            EncodingConflict { origin: _, new: _ } => libc::EINVAL,
+            UnsupportedFormat {
+                version: _,
+                supported: _,
+            } => libc::EINVAL,
             NoSpaceLeft(_) => libc::ENOSPC,
+            ReadOnly => libc::EROFS,
+            Timeout(_) => libc::ETIMEDOUT,
+            PermissionDenied { ino: _, mask: _ } => libc::EACCES,
+            Immutable { ino: _ } => libc::EPERM,
+            AppendOnly { ino: _ } => libc::EPERM,
+            ChangeFlags { ino: _ } => libc::EPERM,
+            UnknownIoctl { cmd: _ } => libc::ENOTTY,
+            UnknownCopyFlags { flags: _ } => libc::EINVAL,
+            CloneAcrossFs { fd: _ } => libc::EXDEV,
+            StickyEntry { dir: _, name: _ } => libc::EPERM,
+            MoveIntoDescendant { dir: _, parent: _ } => libc::EINVAL,
+            LinkDirectory { ino: _ } => libc::EPERM,
+            ChangeOwner { ino: _ } => libc::EPERM,
+            ChangeMode { ino: _ } => libc::EPERM,
+            #[cfg(target_os = "linux")]
+            XattrNotFound { ino: _, name: _ } => libc::ENODATA,
+            #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+            XattrNotFound { ino: _, name: _ } => libc::ENOATTR,
+            XattrExist { ino: _, name: _ } => libc::EEXIST,
+            XattrTooLarge { ino: _, len: _ } => libc::E2BIG,
+            BufferTooSmall { size: _, len: _ } => libc::ERANGE,
+            InvalidWriteSize { len: _, max: _ } => libc::EINVAL,
+            InvalidAcl { msg: _ } => libc::EINVAL,
+            DefaultAclOfFile { ino: _ } => libc::EACCES,
             _ => libc::EFAULT,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_region_errors() {
+        let not_leader = || tikv_client::Error::RegionError(Box::default());
+        assert!(matches!(
+            FsError::from(not_leader()),
+            FsError::RegionError(_)
+        ));
+        assert!(matches!(
+            FsError::from(tikv_client::Error::ExtractedErrors(vec![
+                not_leader(),
+                not_leader()
+            ])),
+            FsError::RegionError(_)
+        ));
+        assert!(matches!(
+            FsError::from(tikv_client::Error::ExtractedErrors(vec![
+                not_leader(),
+                tikv_client::Error::StringError("oops".to_owned())
+            ])),
+            FsError::UnknownError(_)
+        ));
+        assert_eq!(libc::c_int::from(FsError::from(not_leader())), libc::EAGAIN);
+    }
+}
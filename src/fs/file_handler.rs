@@ -1,17 +1,55 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
 use serde::{Deserialize, Serialize};
 
 use super::error::{FsError, Result};
 use super::serialize::{deserialize, serialize, ENCODING};
 
+/// Files are addressed by `loff_t`, offsets beyond are invalid.
+pub const MAX_OFFSET: u64 = i64::MAX as u64;
+
+/// The offset `offset` bytes from `base`, `None` unless it lies in `0..=MAX_OFFSET`.
+pub fn offset_from(base: u64, offset: i64) -> Option<u64> {
+    let position = i64::try_from(base).ok()?.checked_add(offset)?;
+    u64::try_from(position).ok()
+}
+
+/// The start of `len` bytes at `offset` from `base`, `None` unless all of them lie in
+/// `0..=MAX_OFFSET`.
+pub fn checked_range(base: u64, offset: i64, len: u64) -> Option<u64> {
+    let start = offset_from(base, offset)?;
+    start.checked_add(len).filter(|end| *end <= MAX_OFFSET)?;
+    Some(start)
+}
+
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy, Deserialize, Serialize)]
 pub struct FileHandler {
-    // TODO: add open flags
     pub cursor: u64,
+    pub flags: i32,
+    // opened with `FOPEN_DIRECT_IO`, data goes to blocks without caching or inlining.
+    #[serde(default)]
+    pub direct: bool,
 }
 
 impl FileHandler {
-    pub const fn new(cursor: u64) -> Self {
-        Self { cursor }
+    pub const fn new(cursor: u64, flags: i32) -> Self {
+        Self {
+            cursor,
+            flags,
+            direct: false,
+        }
+    }
+
+    pub const fn is_append(&self) -> bool {
+        self.flags & libc::O_APPEND != 0
+    }
+
+    /// The start of accessing `len` bytes at `offset` from the cursor.
+    pub fn position(&self, ino: u64, offset: i64, len: u64) -> Result<u64> {
+        checked_range(self.cursor, offset, len).ok_or(FsError::InvalidOffset { ino, offset })
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>> {
@@ -33,6 +71,117 @@ impl FileHandler {
 
 impl Default for FileHandler {
     fn default() -> Self {
-        Self::new(0)
+        Self::new(0, 0)
+    }
+}
+
+/// The file handlers opened on this mount.
+///
+/// Handlers are process-local, so they are kept in memory rather than in TiKV.
+/// Handlers persisted by former versions (`ScopedKey::handler`) are ignored.
+#[derive(Debug, Default)]
+pub struct FileHandlers {
+    next_fh: AtomicU64,
+    handlers: Mutex<HashMap<(u64, u64), FileHandler>>,
+    // the limit of handlers opened at once
+    max_open: Option<usize>,
+}
+
+impl FileHandlers {
+    pub fn new(max_open: Option<usize>) -> Self {
+        Self {
+            max_open,
+            ..Default::default()
+        }
+    }
+
+    pub fn open(&self, ino: u64, handler: FileHandler) -> Result<u64> {
+        let mut handlers = self.handlers.lock().unwrap();
+        if let Some(max) = self.max_open {
+            if handlers.len() >= max {
+                return Err(FsError::TooManyOpenFiles { max });
+            }
+        }
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        handlers.insert((ino, fh), handler);
+        Ok(fh)
+    }
+
+    pub fn get(&self, ino: u64, fh: u64) -> Result<FileHandler> {
+        self.handlers
+            .lock()
+            .unwrap()
+            .get(&(ino, fh))
+            .copied()
+            .ok_or(FsError::FhNotFound { ino, fh })
+    }
+
+    pub fn save(&self, ino: u64, fh: u64, handler: FileHandler) -> Result<()> {
+        match self.handlers.lock().unwrap().get_mut(&(ino, fh)) {
+            Some(saved) => {
+                *saved = handler;
+                Ok(())
+            }
+            None => Err(FsError::FhNotFound { ino, fh }),
+        }
+    }
+
+    pub fn close(&self, ino: u64, fh: u64) -> Result<FileHandler> {
+        self.handlers
+            .lock()
+            .unwrap()
+            .remove(&(ino, fh))
+            .ok_or(FsError::FhNotFound { ino, fh })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_never_wrap() {
+        assert_eq!(offset_from(0, i64::MAX), Some(MAX_OFFSET));
+        assert_eq!(offset_from(1, i64::MAX), None);
+        assert_eq!(offset_from(MAX_OFFSET, -1), Some(MAX_OFFSET - 1));
+        assert_eq!(offset_from(u64::MAX, -1), None);
+        assert_eq!(offset_from(0, -1), None);
+        assert_eq!(offset_from(1, i64::MIN), None);
+
+        assert_eq!(checked_range(0, i64::MAX, 0), Some(MAX_OFFSET));
+        assert_eq!(checked_range(0, i64::MAX - 4, 4), Some(MAX_OFFSET - 4));
+        assert_eq!(checked_range(0, i64::MAX - 4, 5), None);
+        assert_eq!(checked_range(0, 0, u64::MAX), None);
+
+        let handler = FileHandler::new(1 << 40, 0);
+        assert_eq!(handler.position(1, -(1 << 40), 16).unwrap(), 0);
+        assert!(matches!(
+            handler.position(1, i64::MAX, 16),
+            Err(FsError::InvalidOffset {
+                ino: 1,
+                offset: i64::MAX
+            })
+        ));
+        assert!(handler.position(1, -(1 << 41), 16).is_err());
+    }
+
+    #[test]
+    fn limit_open_handlers() {
+        let handlers = FileHandlers::new(Some(2));
+        let fh = handlers.open(1, FileHandler::default()).unwrap();
+        handlers.open(2, FileHandler::default()).unwrap();
+        assert!(matches!(
+            handlers.open(1, FileHandler::default()),
+            Err(FsError::TooManyOpenFiles { max: 2 })
+        ));
+
+        // released handlers make room for others
+        handlers.close(1, fh).unwrap();
+        assert!(handlers.open(3, FileHandler::default()).is_ok());
+
+        let unlimited = FileHandlers::default();
+        for ino in 0..16 {
+            unlimited.open(ino, FileHandler::default()).unwrap();
+        }
     }
 }
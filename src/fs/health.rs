@@ -0,0 +1,197 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use tikv_client::{Config, Key, TransactionClient};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info, warn};
+
+use super::error::{FsError, Result};
+use super::key::ScopedKey;
+
+/// A transaction client shared by the filesystem and the health checker,
+/// the checker replaces it once the cluster becomes unreachable through it.
+pub type SharedClient = Arc<RwLock<Arc<TransactionClient>>>;
+
+/// Verify the cluster serves transactions by a round trip on the probe key, the value written
+/// is read back, or the key is only read unless `write`. Returns the time it takes.
+pub async fn ping(client: &TransactionClient, write: bool) -> Result<Duration> {
+    let start = Instant::now();
+    let key: Key = ScopedKey::probe().into();
+    let value = write.then(|| rand::random::<u64>().to_be_bytes().to_vec());
+    if let Some(value) = &value {
+        let mut txn = client.begin_optimistic().await?;
+        txn.put(key.clone(), value.clone()).await?;
+        txn.commit().await?;
+    }
+    let mut txn = client.begin_optimistic().await?;
+    let read = txn.get(key).await;
+    txn.rollback().await?;
+    let read = read?;
+    if value.is_some() && read != value {
+        return Err(FsError::UnknownError(format!(
+            "probe value is {:?} after writing {:?}",
+            read, value
+        )));
+    }
+    Ok(start.elapsed())
+}
+
+/// Run `attempt` till it succeeds, `retries` more times at most, waiting `delay` in between.
+/// Every failed attempt is logged, the last error is returned.
+pub async fn retry<T, E, F, Fut>(
+    retries: u32,
+    delay: Duration,
+    mut attempt: F,
+) -> std::result::Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<T, E>>,
+    E: Display,
+{
+    let mut failures = 0;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(err) if failures < retries => {
+                failures += 1;
+                warn!(
+                    "attempt {} of {} fails, retry in {:?}: {}",
+                    failures,
+                    retries + 1,
+                    delay,
+                    err
+                );
+                sleep(delay).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A background task probing pd endpoints and rebuilding the shared client.
+pub struct HealthChecker {
+    pd_endpoints: Vec<String>,
+    cfg: Config,
+    client: SharedClient,
+    interval: Duration,
+    threshold: u32,
+}
+
+impl HealthChecker {
+    pub const DEFAULT_THRESHOLD: u32 = 3;
+    pub const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new(
+        pd_endpoints: Vec<String>,
+        cfg: Config,
+        client: SharedClient,
+        interval: Duration,
+        threshold: u32,
+    ) -> Self {
+        Self {
+            pd_endpoints,
+            cfg,
+            client,
+            interval,
+            threshold,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut failures = 0;
+        loop {
+            sleep(self.interval).await;
+            let healthy = self.probe_endpoints().await;
+            if self.probe_client().await {
+                failures = 0;
+                continue;
+            }
+
+            failures += 1;
+            warn!(
+                "fail to reach the cluster by the current client ({}/{})",
+                failures, self.threshold
+            );
+            if failures < self.threshold {
+                continue;
+            }
+            if healthy.is_empty() {
+                error!("no healthy pd endpoint in {:?}", self.pd_endpoints);
+                continue;
+            }
+
+            match TransactionClient::new_with_config(healthy.clone(), self.cfg.clone()).await {
+                Ok(client) => {
+                    *self.client.write().unwrap() = Arc::new(client);
+                    failures = 0;
+                    info!("reconnected to pd endpoints: {:?}", healthy);
+                }
+                Err(err) => error!("fail to reconnect to pd endpoints({:?}): {}", healthy, err),
+            }
+        }
+    }
+
+    /// Probe the current client by fetching a timestamp.
+    async fn probe_client(&self) -> bool {
+        let client = self.client.read().unwrap().clone();
+        matches!(
+            timeout(Self::PROBE_TIMEOUT, client.current_timestamp()).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Probe every pd endpoint, returns the reachable ones.
+    async fn probe_endpoints(&self) -> Vec<String> {
+        let mut healthy = Vec::new();
+        for endpoint in &self.pd_endpoints {
+            let addr = endpoint
+                .trim_start_matches("http://")
+                .trim_start_matches("https://");
+            match timeout(Self::PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(_)) => healthy.push(endpoint.clone()),
+                Ok(Err(err)) => debug!("pd endpoint({}) is unhealthy: {}", endpoint, err),
+                Err(_) => debug!("pd endpoint({}) is unhealthy: timeout", endpoint),
+            }
+        }
+        healthy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// Fails the first `failures` attempts, like a cluster which is not ready yet.
+    async fn connect(attempts: &Cell<u32>, failures: u32) -> std::result::Result<u32, String> {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() > failures {
+            Ok(attempts.get())
+        } else {
+            Err("connection refused".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_transient_failures() {
+        let attempts = Cell::new(0);
+        let connected = retry(3, Duration::ZERO, || connect(&attempts, 2)).await;
+        assert_eq!(connected, Ok(3));
+
+        let attempts = Cell::new(0);
+        let connected = retry(3, Duration::ZERO, || connect(&attempts, 4)).await;
+        assert_eq!(connected, Err("connection refused".to_owned()));
+        assert_eq!(attempts.get(), 4);
+
+        // no retry by default
+        let attempts = Cell::new(0);
+        assert!(retry(0, Duration::ZERO, || connect(&attempts, 1))
+            .await
+            .is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -1,13 +1,52 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::ops::{Deref, DerefMut};
+use std::time::SystemTime;
 
-use fuser::FileAttr;
-use libc::F_UNLCK;
+use fuser::{FileAttr, FileType};
+use libc::{F_UNLCK, F_WRLCK};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use libc::{XATTR_CREATE, XATTR_REPLACE};
 use serde::{Deserialize, Serialize};
 
+use super::acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
 use super::error::{FsError, Result};
 use super::serialize::{deserialize, serialize, ENCODING};
 
+// the flags of linux, which are passed through by FUSE on FreeBSD
+#[cfg(target_os = "freebsd")]
+const XATTR_CREATE: i32 = 1;
+#[cfg(target_os = "freebsd")]
+const XATTR_REPLACE: i32 = 2;
+
+/// The largest value of an extended attribute, as `XATTR_SIZE_MAX` of linux.
+pub const XATTR_SIZE_MAX: usize = 1 << 16;
+
+// inode flags as `chattr` sets them on linux
+#[cfg(target_os = "linux")]
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+#[cfg(target_os = "linux")]
+pub const FS_APPEND_FL: u32 = 0x20;
+// `UF_IMMUTABLE | SF_IMMUTABLE` and `UF_APPEND | SF_APPEND`, as `chflags` sets them on BSDs
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+pub const FS_IMMUTABLE_FL: u32 = 0x0002_0002;
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+pub const FS_APPEND_FL: u32 = 0x0004_0004;
+// the ioctls of `lsattr` and `chattr`, taking a long, and their 32-bit versions taking an int
+#[cfg(target_os = "linux")]
+pub const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+#[cfg(target_os = "linux")]
+pub const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+#[cfg(target_os = "linux")]
+pub const FS_IOC32_GETFLAGS: u32 = 0x8004_6601;
+#[cfg(target_os = "linux")]
+pub const FS_IOC32_SETFLAGS: u32 = 0x4004_6602;
+
+/// The locks of a file shared by all mounts, flock locks and POSIX record locks apart.
+///
+/// Flock locks cover the whole file, their owners are open files, as the kernel passes a lock
+/// owner per open file for flock. Record locks cover ranges, their owners are the file tables of
+/// processes. The kernel sends only one kind of them, by the `posix_locks` option, and keeps the
+/// other kind on each mount. Either kind never interferes with the other.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct LockState {
     pub owner_set: HashSet<u64>,
@@ -15,6 +54,25 @@ pub struct LockState {
     pub lk_type: i32,
     #[cfg(any(target_os = "freebsd", target_os = "macos"))]
     pub lk_type: i16,
+    // POSIX record locks, reported to `getlk`.
+    #[serde(default)]
+    pub records: Vec<RecordLock>,
+}
+
+/// A POSIX record lock from `start` to `end`, both inclusive, as the kernel passes them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RecordLock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+}
+
+impl RecordLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -22,13 +80,24 @@ pub struct Inode {
     pub file_attr: FileAttr,
     pub lock_state: LockState,
     pub inline_data: Option<Vec<u8>>,
+    // file handlers are allocated in memory now, it's kept for compatibility.
     pub next_fh: u64,
     pub opened_fh: u64,
+    // tells apart inodes that have had the same number, see `Meta::generation`.
+    #[serde(default)]
+    pub generation: u64,
+    // extended attributes by their names.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
 }
 
 impl Inode {
+    /// The size a directory reports for each of its entries.
+    pub const DIR_ENTRY_SIZE: u64 = 32;
+
     fn update_blocks(&mut self, block_size: u64) {
         self.blocks = (self.size + block_size - 1) / block_size;
+        self.blksize = block_size as u32;
     }
 
     pub fn set_size(&mut self, size: u64, block_size: u64) {
@@ -36,6 +105,133 @@ impl Inode {
         self.update_blocks(block_size);
     }
 
+    /// A directory reports its size by the number of entries, however they are encoded,
+    /// while its blocks hold the `encoded` bytes.
+    pub fn set_dir_size(&mut self, entries: usize, encoded: u64, block_size: u64) {
+        self.size = entries as u64 * Self::DIR_ENTRY_SIZE;
+        self.blocks = (encoded + block_size - 1) / block_size;
+        self.blksize = block_size as u32;
+    }
+
+    /// The number of blocks holding data, blocks of a directory are not told by its size.
+    pub fn data_blocks(&self, block_size: u64) -> u64 {
+        match self.kind {
+            FileType::Directory => self.blocks,
+            _ => (self.size + block_size - 1) / block_size,
+        }
+    }
+
+    /// Fail if the inode is immutable, nothing but its flags may change then.
+    pub fn check_mutable(&self) -> Result<()> {
+        if self.flags & FS_IMMUTABLE_FL != 0 {
+            return Err(FsError::Immutable { ino: self.ino });
+        }
+        Ok(())
+    }
+
+    /// Fail unless the inode may be modified other than growing, an append-only inode is
+    /// never truncated, overwritten, linked or removed, nor are its attributes changed.
+    pub fn check_overwrite(&self) -> Result<()> {
+        self.check_mutable()?;
+        if self.flags & FS_APPEND_FL != 0 {
+            return Err(FsError::AppendOnly { ino: self.ino });
+        }
+        Ok(())
+    }
+
+    /// Fail unless `req_uid` may set the flags of the inode to `flags`. Only the owner may set
+    /// flags, and only root may change the immutable or append-only flag, as linux requires
+    /// `CAP_LINUX_IMMUTABLE` for them.
+    pub fn check_set_flags(&self, req_uid: u32, flags: u32) -> Result<()> {
+        let protected = (self.flags ^ flags) & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0;
+        if req_uid != 0 && (req_uid != self.uid || protected) {
+            return Err(FsError::ChangeFlags { ino: self.ino });
+        }
+        Ok(())
+    }
+
+    /// Fail unless data may be written from `start`, only appended to an append-only file.
+    pub fn check_write(&self, start: u64) -> Result<()> {
+        if start >= self.size {
+            self.check_mutable()
+        } else {
+            self.check_overwrite()
+        }
+    }
+
+    /// Count a new link to the inode, only its ctime changes along with the link count.
+    pub fn link(&mut self) {
+        self.nlink += 1;
+        self.ctime = SystemTime::now();
+    }
+
+    /// Drop a link to the inode, only its ctime changes along with the link count.
+    pub fn unlink(&mut self) {
+        self.nlink -= 1;
+        self.ctime = SystemTime::now();
+    }
+
+    /// The target of a symlink, which is always inlined.
+    pub fn link_target(&self) -> Vec<u8> {
+        self.inline_data.clone().unwrap_or_default()
+    }
+
+    /// The access ACL, `None` if the permission bits say everything.
+    pub fn access_acl(&self) -> Result<Option<Acl>> {
+        match self.xattrs.get(ACL_ACCESS) {
+            Some(value) => Acl::parse(value),
+            None => Ok(None),
+        }
+    }
+
+    /// Change the permission bits, the access ACL follows them if there is one.
+    pub fn chmod(&mut self, perm: u16) -> Result<()> {
+        self.perm = perm;
+        if let Some(mut acl) = self.access_acl()? {
+            acl.set_perm(perm);
+            self.xattrs.insert(ACL_ACCESS.to_owned(), acl.encode());
+        }
+        Ok(())
+    }
+
+    /// Inherit the default ACL of the directory a new inode is made in,
+    /// directories take it as their own default ACL as well.
+    pub fn inherit_acl(&mut self, default: &Acl) {
+        let acl = default.inherit(self.perm & 0o777);
+        self.perm = (self.perm & !0o777) | acl.perm();
+        if !acl.is_minimal() {
+            self.xattrs.insert(ACL_ACCESS.to_owned(), acl.encode());
+        }
+        if self.kind == FileType::Directory {
+            self.xattrs.insert(ACL_DEFAULT.to_owned(), default.encode());
+        }
+    }
+
+    /// Check the flags of setting the extended attribute `name`, `XATTR_CREATE` fails if it
+    /// exists and `XATTR_REPLACE` fails unless it exists. Values are at most `XATTR_SIZE_MAX`.
+    pub fn check_set_xattr(&self, name: &str, value: &[u8], flags: i32) -> Result<()> {
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(FsError::XattrTooLarge {
+                ino: self.ino,
+                len: value.len(),
+            });
+        }
+        let exist = self.xattrs.contains_key(name);
+        if flags & XATTR_CREATE != 0 && exist {
+            return Err(FsError::XattrExist {
+                ino: self.ino,
+                name: name.to_owned(),
+            });
+        }
+        if flags & XATTR_REPLACE != 0 && !exist {
+            return Err(FsError::XattrNotFound {
+                ino: self.ino,
+                name: name.to_owned(),
+            });
+        }
+        Ok(())
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>> {
         serialize(self).map_err(|err| FsError::Serialize {
             target: "inode",
@@ -61,6 +257,8 @@ impl From<FileAttr> for Inode {
             inline_data: None,
             next_fh: 0,
             opened_fh: 0,
+            generation: 0,
+            xattrs: BTreeMap::new(),
         }
     }
 }
@@ -94,10 +292,323 @@ impl DerefMut for Inode {
 impl LockState {
     #[cfg(target_os = "linux")]
     pub fn new(owner_set: HashSet<u64>, lk_type: i32) -> LockState {
-        LockState { owner_set, lk_type }
+        LockState {
+            owner_set,
+            lk_type,
+            records: Vec::new(),
+        }
     }
     #[cfg(any(target_os = "freebsd", target_os = "macos"))]
     pub fn new(owner_set: HashSet<u64>, lk_type: i16) -> LockState {
-        LockState { owner_set, lk_type }
+        LockState {
+            owner_set,
+            lk_type,
+            records: Vec::new(),
+        }
+    }
+
+    /// Release the flock lock of `owner`, the file is unlocked once nobody holds it.
+    /// Record locks are left as they are.
+    pub fn unlock(&mut self, owner: u64) {
+        self.owner_set.remove(&owner);
+        if self.owner_set.is_empty() {
+            self.lk_type = F_UNLCK;
+        }
+    }
+
+    /// A record lock of another owner, which conflicts with a lock of `typ` from `start` to
+    /// `end` requested by `owner`. Overlapping locks conflict unless both of them are read locks.
+    pub fn conflict(&self, owner: u64, start: u64, end: u64, typ: i32) -> Option<&RecordLock> {
+        self.records.iter().find(|held| {
+            held.owner != owner
+                && held.overlaps(start, end)
+                && (held.typ == F_WRLCK as i32 || typ == F_WRLCK as i32)
+        })
+    }
+
+    /// Place a record lock, which replaces the locks of its owner in its range, or releases
+    /// them if it is of `F_UNLCK`. Locks partly in the range are split.
+    pub fn set_record(&mut self, lock: RecordLock) {
+        let mut records = Vec::with_capacity(self.records.len() + 2);
+        for held in self.records.drain(..) {
+            if held.owner != lock.owner || !held.overlaps(lock.start, lock.end) {
+                records.push(held);
+                continue;
+            }
+            if held.start < lock.start {
+                records.push(RecordLock {
+                    end: lock.start - 1,
+                    ..held
+                });
+            }
+            if lock.end < held.end {
+                records.push(RecordLock {
+                    start: lock.end + 1,
+                    ..held
+                });
+            }
+        }
+        if lock.typ != F_UNLCK as i32 {
+            records.push(lock);
+        }
+        self.records = records;
+    }
+
+    /// Release every record lock of `owner`, as closing any file does for a process.
+    /// Flock locks are left as they are. Returns whether anything was released.
+    pub fn unlock_records(&mut self, owner: u64) -> bool {
+        let held = self.records.len();
+        self.records.retain(|lock| lock.owner != owner);
+        self.records.len() != held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libc::F_RDLCK;
+
+    use super::*;
+    use crate::fs::mode::{as_file_kind, make_mode};
+    use crate::fs::testing::file_attr;
+
+    fn special_inode(kind: FileType, rdev: u32) -> Inode {
+        FileAttr {
+            kind: as_file_kind(make_mode(kind, 0o644)),
+            rdev,
+            ..file_attr(0, 0, 0o644)
+        }
+        .into()
+    }
+
+    #[test]
+    fn serialize_special_files() {
+        let fifo = special_inode(FileType::NamedPipe, 0);
+        let decoded = Inode::deserialize(&fifo.serialize().unwrap()).unwrap();
+        assert_eq!(decoded, fifo);
+        assert_eq!(decoded.kind, FileType::NamedPipe);
+        assert_eq!(decoded.blocks, 0);
+        assert!(decoded.inline_data.is_none());
+
+        // major 1, minor 3, as /dev/null
+        let null = special_inode(FileType::CharDevice, libc::makedev(1, 3) as u32);
+        let decoded = Inode::deserialize(&null.serialize().unwrap()).unwrap();
+        assert_eq!(decoded, null);
+        assert_eq!(decoded.kind, FileType::CharDevice);
+        assert_eq!(decoded.rdev, libc::makedev(1, 3) as u32);
+    }
+
+    #[test]
+    fn read_link_target() {
+        let mut link = special_inode(FileType::Symlink, 0);
+        link.inline_data = Some(b"../target".to_vec());
+        link.size = 9;
+        let stored = link.serialize().unwrap();
+        assert_eq!(link.link_target(), b"../target");
+        // the inode is left as stored, atime included
+        assert_eq!(link.serialize().unwrap(), stored);
+        assert_eq!(link.atime, SystemTime::UNIX_EPOCH);
+    }
+
+    fn record(owner: u64, start: u64, end: u64, typ: i32) -> RecordLock {
+        RecordLock {
+            owner,
+            pid: owner as u32 * 100,
+            start,
+            end,
+            typ,
+        }
+    }
+
+    #[test]
+    fn record_locks_conflict_and_split() {
+        let (read, write, unlocked) = (F_RDLCK as i32, F_WRLCK as i32, F_UNLCK as i32);
+        let mut state = LockState::new(HashSet::new(), F_UNLCK);
+        state.set_record(record(1, 0, 99, read));
+        state.set_record(record(2, 50, 149, read));
+        assert_eq!(state.conflict(3, 0, 9, read), None);
+        assert_eq!(
+            state.conflict(3, 120, 200, write),
+            Some(&record(2, 50, 149, read))
+        );
+        assert_eq!(state.conflict(1, 0, 9, write), None);
+        assert_eq!(
+            state.conflict(1, 60, 60, write),
+            Some(&record(2, 50, 149, read))
+        );
+
+        // a write lock in the middle of a read lock of the same owner splits it
+        state.set_record(record(1, 20, 29, write));
+        assert_eq!(
+            state.conflict(3, 25, 25, read),
+            Some(&record(1, 20, 29, write))
+        );
+        assert_eq!(
+            state.conflict(3, 10, 19, write),
+            Some(&record(1, 0, 19, read))
+        );
+        state.set_record(record(1, 0, 49, unlocked));
+        assert_eq!(
+            state.records,
+            vec![record(1, 50, 99, read), record(2, 50, 149, read)]
+        );
+
+        // flock locks are apart from record locks
+        state.owner_set.insert(4);
+        state.lk_type = F_WRLCK;
+        assert_eq!(state.conflict(3, 0, 9, write), None);
+        state.unlock(1);
+        assert_eq!(state.records.len(), 2);
+        assert!(state.unlock_records(1));
+        assert!(!state.unlock_records(4));
+        assert_eq!(state.records, vec![record(2, 50, 149, read)]);
+        assert_eq!(state.owner_set.len(), 1);
+    }
+
+    #[test]
+    fn unlock_owners() {
+        let mut state = LockState::new(vec![1, 2].into_iter().collect(), F_RDLCK);
+
+        state.unlock(1);
+        assert_eq!(state.lk_type, F_RDLCK);
+        assert!(!state.owner_set.contains(&1));
+
+        // unlocking twice, or by an owner holding nothing, changes nothing
+        state.unlock(1);
+        state.unlock(3);
+        assert_eq!(state.owner_set.len(), 1);
+
+        state.unlock(2);
+        assert_eq!(state, LockState::new(HashSet::new(), F_UNLCK));
+    }
+
+    #[test]
+    fn links_keep_attributes() {
+        let mut file = special_inode(FileType::RegularFile, 0);
+        file.uid = 1000;
+        file.gid = 100;
+        file.perm = 0o4750;
+        file.ino = 7;
+        let origin = file.clone();
+
+        file.link();
+        assert_eq!(file.nlink, 2);
+        assert!(file.ctime > origin.ctime);
+        let mut expected = origin.clone();
+        expected.nlink = 2;
+        expected.ctime = file.ctime;
+        assert_eq!(file, expected);
+
+        // a rename links the new name and unlinks the old one
+        let linked = file.clone();
+        std::thread::sleep(Duration::from_millis(1));
+        file.unlink();
+        assert!(file.ctime > linked.ctime);
+        let mut expected = origin;
+        expected.ctime = file.ctime;
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn xattr_flags() {
+        let mut file = special_inode(FileType::RegularFile, 0);
+        file.xattrs
+            .insert("user.present".to_owned(), b"value".to_vec());
+
+        assert!(file.check_set_xattr("user.present", b"value", 0).is_ok());
+        assert!(file.check_set_xattr("user.absent", b"value", 0).is_ok());
+        assert!(matches!(
+            file.check_set_xattr("user.present", b"value", XATTR_CREATE),
+            Err(FsError::XattrExist { ino: 2, .. })
+        ));
+        assert!(file
+            .check_set_xattr("user.absent", b"value", XATTR_CREATE)
+            .is_ok());
+        assert!(file
+            .check_set_xattr("user.present", b"value", XATTR_REPLACE)
+            .is_ok());
+        assert!(matches!(
+            file.check_set_xattr("user.absent", b"value", XATTR_REPLACE),
+            Err(FsError::XattrNotFound { ino: 2, .. })
+        ));
+
+        let largest = vec![0; XATTR_SIZE_MAX];
+        assert!(file.check_set_xattr("user.present", &largest, 0).is_ok());
+        let err = file
+            .check_set_xattr("user.present", &[0; XATTR_SIZE_MAX + 1], 0)
+            .unwrap_err();
+        assert!(matches!(err, FsError::XattrTooLarge { ino: 2, .. }));
+        assert_eq!(libc::c_int::from(err), libc::E2BIG);
+    }
+
+    #[test]
+    fn dir_size_by_entries() {
+        use crate::fs::reply::DirItem;
+
+        let dir: Vec<DirItem> = (0..16)
+            .map(|i| DirItem {
+                ino: i + 2,
+                name: format!("file-{}", i),
+                typ: FileType::RegularFile,
+            })
+            .collect();
+        // encoded lengths of the directory in every format, and a format twice as verbose
+        let mut lens = vec![crate::fs::dir::encode(&dir).unwrap().len() as u64];
+        #[cfg(feature = "json")]
+        lens.push(serde_json::to_vec(&dir).unwrap().len() as u64);
+        #[cfg(feature = "binc")]
+        lens.push(bincode::serialize(&dir).unwrap().len() as u64);
+        lens.push(lens[0] * 2);
+
+        for len in lens {
+            let mut inode = special_inode(FileType::Directory, 0);
+            inode.set_dir_size(dir.len(), len, 64);
+            assert_eq!(inode.size, 16 * Inode::DIR_ENTRY_SIZE);
+            assert_eq!(inode.data_blocks(64), (len + 63) / 64);
+        }
+
+        // blocks of other files are told by their sizes
+        let mut file = special_inode(FileType::RegularFile, 0);
+        file.set_size(65, 64);
+        assert_eq!(file.data_blocks(64), 2);
+    }
+
+    #[test]
+    fn immutable_and_append_only() {
+        let mut file = special_inode(FileType::RegularFile, 0);
+        file.size = 16;
+        assert!(file.check_overwrite().is_ok());
+        assert!(file.check_write(0).is_ok());
+
+        file.flags = FS_IMMUTABLE_FL;
+        for checked in [
+            file.check_mutable(),
+            file.check_overwrite(),
+            file.check_write(16),
+        ] {
+            assert!(matches!(checked, Err(FsError::Immutable { ino: 2 })));
+        }
+
+        file.flags = FS_APPEND_FL;
+        assert!(file.check_mutable().is_ok());
+        assert!(file.check_write(16).is_ok());
+        assert!(file.check_write(32).is_ok());
+        assert!(matches!(
+            file.check_write(15),
+            Err(FsError::AppendOnly { ino: 2 })
+        ));
+        assert!(matches!(
+            file.check_overwrite(),
+            Err(FsError::AppendOnly { ino: 2 })
+        ));
+        assert_eq!(
+            libc::c_int::from(FsError::AppendOnly { ino: 2 }),
+            libc::EPERM
+        );
+        assert_eq!(
+            libc::c_int::from(FsError::Immutable { ino: 2 }),
+            libc::EPERM
+        );
     }
 }
@@ -1,8 +1,10 @@
+use std::convert::TryFrom;
 use std::mem::size_of;
 use std::ops::Range;
 
 use tikv_client::Key;
 
+use super::block::Hash;
 use super::error::{FsError, Result};
 
 pub const ROOT_INODE: u64 = fuser::FUSE_ROOT_ID;
@@ -12,8 +14,15 @@ pub enum ScopedKey<'a> {
     Meta,
     Inode(u64),
     Block { ino: u64, block: u64 },
+    // file handlers are kept in memory now, the scope is only reserved for compatibility.
     FileHandler { ino: u64, handler: u64 },
     FileIndex { parent: u64, name: &'a str },
+    // blocks stored once by their content hash, in dedup mode.
+    Content { hash: Hash },
+    // numbers of removed inodes to be reused, with the generation of the last inode.
+    FreeInode(u64),
+    // written and read back to verify the cluster, see `health::ping`.
+    Probe,
 }
 
 impl<'a> ScopedKey<'a> {
@@ -22,6 +31,9 @@ impl<'a> ScopedKey<'a> {
     const BLOCK: u8 = 2;
     const HANDLER: u8 = 3;
     const INDEX: u8 = 4;
+    const CONTENT: u8 = 5;
+    const FREE_INODE: u8 = 6;
+    const PROBE: u8 = 7;
 
     pub const fn meta() -> Self {
         Self::Meta
@@ -47,6 +59,18 @@ impl<'a> ScopedKey<'a> {
         Self::FileIndex { parent, name }
     }
 
+    pub const fn content(hash: Hash) -> Self {
+        Self::Content { hash }
+    }
+
+    pub const fn free_inode(ino: u64) -> Self {
+        Self::FreeInode(ino)
+    }
+
+    pub const fn probe() -> Self {
+        Self::Probe
+    }
+
     pub fn block_range(ino: u64, block_range: Range<u64>) -> Range<Key> {
         debug_assert_ne!(0, ino);
         Self::block(ino, block_range.start).into()..Self::block(ino, block_range.end).into()
@@ -56,6 +80,25 @@ impl<'a> ScopedKey<'a> {
         Self::inode(ino_range.start).into()..Self::inode(ino_range.end).into()
     }
 
+    /// The range of blocks of all inodes.
+    pub fn block_scope() -> Range<Key> {
+        Self::scope_range(Self::BLOCK)
+    }
+
+    /// The range of all file indexes.
+    pub fn index_scope() -> Range<Key> {
+        Self::scope_range(Self::INDEX)
+    }
+
+    /// The range of all free inode numbers.
+    pub fn free_inode_scope() -> Range<Key> {
+        Self::scope_range(Self::FREE_INODE)
+    }
+
+    fn scope_range(scope: u8) -> Range<Key> {
+        Key::from(vec![scope])..Key::from(vec![scope + 1])
+    }
+
     pub fn scope(&self) -> u8 {
         use ScopedKey::*;
 
@@ -65,6 +108,9 @@ impl<'a> ScopedKey<'a> {
             Block { ino: _, block: _ } => Self::BLOCK,
             FileHandler { ino: _, handler: _ } => Self::HANDLER,
             FileIndex { parent: _, name: _ } => Self::INDEX,
+            Content { hash: _ } => Self::CONTENT,
+            FreeInode(_) => Self::FREE_INODE,
+            Probe => Self::PROBE,
         }
     }
 
@@ -77,6 +123,9 @@ impl<'a> ScopedKey<'a> {
             Block { ino: _, block: _ } => size_of::<u64>() * 2,
             FileHandler { ino: _, handler: _ } => size_of::<u64>() * 2,
             FileIndex { parent: _, name } => size_of::<u64>() + name.len(),
+            Content { hash } => hash.len(),
+            FreeInode(_) => size_of::<u64>(),
+            Probe => 0,
         }
     }
 
@@ -113,6 +162,14 @@ impl<'a> ScopedKey<'a> {
                     std::str::from_utf8(&data[size_of::<u64>()..]).map_err(|_| invalid_key())?,
                 ))
             }
+            Self::CONTENT => Ok(Self::content(
+                Hash::try_from(data).map_err(|_| invalid_key())?,
+            )),
+            Self::FREE_INODE => {
+                let ino = u64::from_be_bytes(*data.array_chunks().next().ok_or_else(invalid_key)?);
+                Ok(Self::free_inode(ino))
+            }
+            Self::PROBE => Ok(Self::probe()),
             _ => Err(invalid_key()),
         }
     }
@@ -139,7 +196,146 @@ impl<'a> From<ScopedKey<'a>> for Key {
                 data.extend(parent.to_be_bytes().iter());
                 data.extend(name.as_bytes().iter());
             }
+            Content { hash } => data.extend(hash.iter()),
+            FreeInode(ino) => data.extend(ino.to_be_bytes().iter()),
+            Probe => (),
         }
         data.into()
     }
 }
+
+/// The smallest key greater than `key`.
+pub fn successor(key: &Key) -> Key {
+    let mut data: Vec<u8> = key.clone().into();
+    data.push(0);
+    data.into()
+}
+
+/// Pages scanning a range of keys, each page holds at most `limit` keys.
+///
+/// A scan capped at one page silently misses the keys beyond, so scans go page by page
+/// until a page is shorter than the limit.
+#[derive(Debug, Clone)]
+pub struct Pages {
+    next: Option<Key>,
+    end: Key,
+    limit: u32,
+}
+
+impl Pages {
+    pub fn new(range: Range<Key>, limit: u32) -> Self {
+        let Range { start, end } = range;
+        Self {
+            next: Some(start).filter(|start| *start < end),
+            end,
+            limit: limit.max(1),
+        }
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// The range of the next page, `None` once the range is exhausted.
+    pub fn range(&self) -> Option<Range<Key>> {
+        self.next.clone().map(|next| next..self.end.clone())
+    }
+
+    /// Go on from `next` rather than past the last page.
+    pub fn seek(&mut self, next: Key) {
+        self.next = Some(next).filter(|next| *next < self.end);
+    }
+
+    /// Move past a page of `scanned` keys ending with `last`.
+    pub fn advance(&mut self, scanned: usize, last: Option<&Key>) {
+        self.next = match last {
+            Some(last) if scanned >= self.limit as usize => Some(successor(last)),
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// Scan all keys of `range` in the store page by page, returns the keys and the number of
+    /// pages scanned.
+    fn scan_all(store: &BTreeSet<Key>, range: Range<Key>, limit: u32) -> (Vec<Key>, usize) {
+        let mut keys = Vec::new();
+        let mut scans = 0;
+        let mut pages = Pages::new(range, limit);
+        while let Some(range) = pages.range() {
+            let page: Vec<Key> = store
+                .range(range)
+                .take(pages.limit() as usize)
+                .cloned()
+                .collect();
+            scans += 1;
+            pages.advance(page.len(), page.last());
+            keys.extend(page);
+        }
+        (keys, scans)
+    }
+
+    #[test]
+    fn scan_pages_of_inodes() {
+        let mut store: BTreeSet<Key> = (ROOT_INODE..ROOT_INODE + 2500)
+            .map(|ino| ScopedKey::inode(ino).into())
+            .collect();
+        // keys of other scopes are never scanned
+        store.insert(ScopedKey::meta().into());
+        store.insert(ScopedKey::block(ROOT_INODE, 0).into());
+
+        let range = ScopedKey::inode_range(ROOT_INODE..u64::MAX);
+        let (keys, scans) = scan_all(&store, range.clone(), 1 << 10);
+        assert_eq!(scans, 3);
+        let expected: Vec<Key> = (ROOT_INODE..ROOT_INODE + 2500)
+            .map(|ino| ScopedKey::inode(ino).into())
+            .collect();
+        assert_eq!(keys, expected);
+
+        // a full last page is followed by an empty one
+        let (keys, scans) = scan_all(&store, range.clone(), 1250);
+        assert_eq!((keys.len(), scans), (2500, 3));
+        assert_eq!(scan_all(&store, range, 1).1, 2501);
+
+        let empty = ScopedKey::inode_range(ROOT_INODE..ROOT_INODE);
+        assert_eq!(scan_all(&store, empty, 1 << 10), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn seek_past_blocks() {
+        let store: BTreeSet<Key> = (1..4)
+            .flat_map(|ino| (0..3).map(move |block| ScopedKey::block(ino, block).into()))
+            .collect();
+        // the first block of every inode, skipping the others as fsck does
+        let mut firsts = Vec::new();
+        let mut pages = Pages::new(ScopedKey::block_scope(), 2);
+        while let Some(range) = pages.range() {
+            let page: Vec<Key> = store
+                .range(range)
+                .take(pages.limit() as usize)
+                .cloned()
+                .collect();
+            match page
+                .first()
+                .map(|key| ScopedKey::parse(key.into()).unwrap())
+            {
+                Some(ScopedKey::Block { ino, block }) => {
+                    firsts.push((ino, block));
+                    pages.seek(ScopedKey::block(ino + 1, 0).into());
+                }
+                _ => pages.advance(page.len(), page.last()),
+            }
+        }
+        assert_eq!(firsts, [(1, 0), (2, 0), (3, 0)]);
+
+        // seeking beyond the range ends it
+        pages = Pages::new(ScopedKey::block_range(1, 0..2), 2);
+        pages.seek(ScopedKey::block(2, 0).into());
+        assert!(pages.range().is_none());
+    }
+}
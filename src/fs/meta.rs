@@ -1,39 +1,260 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
 use super::error::{FsError, Result};
 use super::key::ROOT_INODE;
 use super::reply::StatFs;
-use super::serialize::{deserialize, serialize, ENCODING};
+use super::serialize::{deserialize, serialize, Header, ENCODING};
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Meta {
     pub inode_next: u64,
     pub block_size: u64,
     pub last_stat: Option<StatFs>,
+    #[serde(default)]
+    pub disable_atime: bool,
+    // `None` for filesystems created before usage was maintained, until it's recomputed.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub dedup: bool,
+    #[serde(default)]
+    pub encrypted: bool,
+    // the generation of inodes made from now on, renewed whenever inode numbers may start over.
+    #[serde(default)]
+    pub generation: u64,
+    // the divisor of the block size giving the inline threshold, `None` for the default.
+    #[serde(default)]
+    pub inline_divisor: Option<u64>,
+    // the inline threshold the filesystem is made with, `noinline` and `inline_threshold` included.
+    #[serde(default)]
+    pub inline_threshold: Option<u64>,
+    // whether every block is stored with a trailing checksum of its data.
+    #[serde(default)]
+    pub checksum: bool,
+    // set by the debugger while a dump is imported, such a filesystem is never mounted.
+    #[serde(default)]
+    pub importing: bool,
+}
+
+/// Space used by the filesystem, maintained incrementally as inodes are saved.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Usage {
+    pub blocks: u64,
+    pub files: u64,
+}
+
+impl Usage {
+    /// Account an inode changing from `old` blocks to `new` blocks,
+    /// `None` means the inode doesn't exist.
+    pub fn update(&mut self, old: Option<u64>, new: Option<u64>) {
+        if let Some(blocks) = old {
+            self.blocks = self.blocks.saturating_sub(blocks);
+            self.files = self.files.saturating_sub(1);
+        }
+        if let Some(blocks) = new {
+            self.blocks += blocks;
+            self.files += 1;
+        }
+    }
+
+    /// Whether an inode growing from `old` blocks to `new` blocks fits in `max_blocks`.
+    pub fn fits(&self, old: u64, new: u64, max_blocks: u64) -> bool {
+        new <= old || self.blocks + (new - old) <= max_blocks
+    }
 }
 
 impl Meta {
-    pub const fn new(block_size: u64) -> Self {
+    pub const fn new(block_size: u64, disable_atime: bool, dedup: bool, encrypted: bool) -> Self {
         Self {
             inode_next: ROOT_INODE,
             block_size,
             last_stat: None,
+            disable_atime,
+            usage: Some(Usage {
+                blocks: 0,
+                files: 0,
+            }),
+            dedup,
+            encrypted,
+            generation: 0,
+            inline_divisor: None,
+            inline_threshold: None,
+            checksum: false,
+            importing: false,
         }
     }
 
+    /// Move to a generation no inode has been made in, inode numbers are free to be reused then.
+    /// Generations grow with the time, so they can't repeat even if the meta is lost.
+    pub fn renew_generation(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.generation = (self.generation + 1).max(now);
+    }
+
+    /// Allocate an inode number and its generation, reusing the number of a removed inode
+    /// given as `(ino, generation)` if any.
+    /// A reused number always comes with a newer generation than the removed inode.
+    pub fn allocate(&mut self, free: Option<(u64, u64)>) -> (u64, u64) {
+        match free {
+            Some((ino, generation)) => (ino, self.generation.max(generation.saturating_add(1))),
+            None => {
+                let ino = self.inode_next;
+                self.inode_next += 1;
+                (ino, self.generation)
+            }
+        }
+    }
+
+    /// Serialize the meta prefixed with a header of the encoding and format version.
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        serialize(self).map_err(|err| FsError::Serialize {
+        let body = serialize(self).map_err(|err| FsError::Serialize {
             target: "meta",
             typ: ENCODING,
             msg: err.to_string(),
-        })
+        })?;
+        let mut data = Header::current().encode();
+        data.extend(body);
+        Ok(data)
     }
 
+    /// Deserialize the meta, fails if it's encoded in another way than this build.
+    /// Meta without a header is assumed to be encoded as this build.
     pub fn deserialize(bytes: &[u8]) -> Result<Self> {
-        deserialize(bytes).map_err(|err| FsError::Serialize {
+        let (header, body) = Header::split(bytes);
+        if let Some(header) = header {
+            header.check()?;
+        }
+        deserialize(body).map_err(|err| FsError::Serialize {
             target: "meta",
             typ: ENCODING,
             msg: err.to_string(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::serialize::{ENCODING_TAG, FORMAT_VERSION};
+
+    #[test]
+    fn serialize_meta() {
+        let meta = Meta::new(1 << 16, false, false, false);
+        let data = meta.serialize().unwrap();
+        assert!(data.starts_with(b"TIFS"));
+        assert_eq!(Meta::deserialize(&data).unwrap(), meta);
+
+        // meta written before headers were introduced
+        let legacy = serialize(&meta).unwrap();
+        assert_eq!(Meta::deserialize(&legacy).unwrap(), meta);
+    }
+
+    #[test]
+    fn reject_unknown_meta() {
+        let meta = Meta::new(1 << 16, false, false, false);
+        let body = serialize(&meta).unwrap();
+
+        let mut other_encoding = Header {
+            encoding: ENCODING_TAG + 1,
+            version: FORMAT_VERSION,
+        }
+        .encode();
+        other_encoding.extend(&body);
+        assert!(matches!(
+            Meta::deserialize(&other_encoding),
+            Err(FsError::EncodingConflict { .. })
+        ));
+
+        let mut newer_version = Header {
+            encoding: ENCODING_TAG,
+            version: FORMAT_VERSION + 1,
+        }
+        .encode();
+        newer_version.extend(&body);
+        assert!(matches!(
+            Meta::deserialize(&newer_version),
+            Err(FsError::UnsupportedFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn update_usage() {
+        // (ino, blocks) of existing inodes, as a full scan would see
+        let mut inodes = std::collections::HashMap::new();
+        let mut usage = Usage::default();
+        let mut save = |ino: u64, blocks: Option<u64>| {
+            let old = match blocks {
+                Some(blocks) => inodes.insert(ino, blocks),
+                None => inodes.remove(&ino),
+            };
+            usage.update(old, blocks);
+            let scanned = Usage {
+                blocks: inodes.values().sum(),
+                files: inodes.len() as u64,
+            };
+            assert_eq!(usage, scanned);
+        };
+
+        // create, write, truncate and unlink
+        save(1, Some(0));
+        save(2, Some(0));
+        save(2, Some(3));
+        save(3, Some(1));
+        save(2, Some(1));
+        save(3, None);
+        save(2, Some(5));
+        save(2, None);
+    }
+
+    #[test]
+    fn usage_fits() {
+        let usage = Usage {
+            blocks: 8,
+            files: 2,
+        };
+        assert!(usage.fits(2, 4, 10));
+        assert!(!usage.fits(2, 5, 10));
+        // shrinking or rewriting in place always fits, even beyond the limit
+        assert!(usage.fits(4, 4, 8));
+        assert!(usage.fits(4, 1, 4));
+    }
+
+    #[test]
+    fn renew_generation() {
+        let mut meta = Meta::new(1 << 16, false, false, false);
+        meta.renew_generation();
+        let first = meta.generation;
+        assert_ne!(first, 0);
+        meta.renew_generation();
+        assert!(meta.generation > first);
+
+        // even a generation from the future moves forward
+        meta.generation = u64::MAX - 1;
+        meta.renew_generation();
+        assert_eq!(meta.generation, u64::MAX);
+    }
+
+    #[test]
+    fn reuse_inode() {
+        let mut meta = Meta::new(1 << 16, false, false, false);
+        meta.renew_generation();
+        let generation = meta.generation;
+        assert_eq!(meta.allocate(None), (ROOT_INODE, generation));
+        assert_eq!(meta.allocate(None), (ROOT_INODE + 1, generation));
+
+        // the number of a removed inode is reused before new ones
+        let (ino, reused) = meta.allocate(Some((ROOT_INODE + 1, generation)));
+        assert_eq!(ino, ROOT_INODE + 1);
+        assert!(reused > generation);
+        assert_eq!(meta.inode_next, ROOT_INODE + 2);
+
+        // a number freed in an older generation is reused in the current one
+        let (ino, reused) = meta.allocate(Some((ROOT_INODE + 1, 1)));
+        assert_eq!((ino, reused), (ROOT_INODE + 1, generation));
+        assert_eq!(meta.allocate(None), (ROOT_INODE + 2, generation));
+    }
+}
@@ -0,0 +1,119 @@
+use std::net::SocketAddr;
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramTimer, HistogramVec,
+    IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error};
+
+/// Metrics of a mount, served in the prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    pub ops: IntCounterVec,
+    pub op_duration: HistogramVec,
+    pub txn_retries: IntCounter,
+    pub op_retries: Histogram,
+    pub commit_duration: Histogram,
+    pub read_bytes: IntCounter,
+    pub written_bytes: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("tifs".to_owned()), None)?;
+        let ops = IntCounterVec::new(Opts::new("ops_total", "number of operations"), &["op"])?;
+        let op_duration = HistogramVec::new(
+            HistogramOpts::new("op_duration_seconds", "latency of operations"),
+            &["op"],
+        )?;
+        let txn_retries = IntCounter::new(
+            "txn_retries_total",
+            "number of transactions retried because of key errors",
+        )?;
+        // operations without retries aren't observed
+        let op_retries = Histogram::with_opts(
+            HistogramOpts::new(
+                "op_retries",
+                "number of transaction retries of operations retried",
+            )
+            .buckets(exponential_buckets(1.0, 2.0, 8)?),
+        )?;
+        let commit_duration = Histogram::with_opts(HistogramOpts::new(
+            "commit_duration_seconds",
+            "latency of transaction commits",
+        ))?;
+        let read_bytes = IntCounter::new("read_bytes_total", "number of bytes read")?;
+        let written_bytes = IntCounter::new("written_bytes_total", "number of bytes written")?;
+
+        registry.register(Box::new(ops.clone()))?;
+        registry.register(Box::new(op_duration.clone()))?;
+        registry.register(Box::new(txn_retries.clone()))?;
+        registry.register(Box::new(op_retries.clone()))?;
+        registry.register(Box::new(commit_duration.clone()))?;
+        registry.register(Box::new(read_bytes.clone()))?;
+        registry.register(Box::new(written_bytes.clone()))?;
+
+        Ok(Self {
+            registry,
+            ops,
+            op_duration,
+            txn_retries,
+            op_retries,
+            commit_duration,
+            read_bytes,
+            written_bytes,
+        })
+    }
+
+    /// Count an operation, its latency is observed when the returned timer drops.
+    pub fn op(&self, op: &str) -> HistogramTimer {
+        self.ops.with_label_values(&[op]).inc();
+        self.op_duration.with_label_values(&[op]).start_timer()
+    }
+
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+}
+
+/// Serve metrics of the registry by http on `addr`, every path serves the same.
+pub async fn serve(registry: Registry, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(&registry, stream).await {
+                debug!("fail to serve metrics to {}: {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn respond(registry: &Registry, mut stream: TcpStream) -> std::io::Result<()> {
+    // the request is ignored
+    let mut request = [0; 1 << 10];
+    let len = stream.read(&mut request).await?;
+    debug!(
+        "metrics requested: {}",
+        String::from_utf8_lossy(&request[..len])
+    );
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(err) = encoder.encode(&registry.gather(), &mut body) {
+        error!("fail to encode metrics: {}", err);
+        return stream
+            .write_all(b"HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\n\r\n")
+            .await;
+    }
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await
+}
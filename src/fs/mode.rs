@@ -1,4 +1,165 @@
-use fuser::FileType;
+use fuser::{FileAttr, FileType};
+
+use super::error::{FsError, Result};
+
+/// Clear the permission bits in `umask` from `mode`, the file type bits are kept.
+pub const fn apply_umask(mode: u32, umask: u32) -> u32 {
+    mode & !(umask & 0o777)
+}
+
+/// Check whether the caller(`uid`, in `groups`) is allowed to access the file as `mask`,
+/// which is a combination of `R_OK`, `W_OK` and `X_OK`.
+///
+/// Root may read and write any file, but may only execute a file
+/// with at least one execute bit set.
+pub fn check_access(attr: &FileAttr, uid: u32, groups: &[u32], mask: i32) -> bool {
+    let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+    if mask == 0 {
+        return true;
+    }
+
+    if uid == 0 {
+        return mask & libc::X_OK as u16 == 0
+            || attr.kind == FileType::Directory
+            || attr.perm & 0o111 != 0;
+    }
+
+    let shift = if uid == attr.uid {
+        6
+    } else if groups.contains(&attr.gid) {
+        3
+    } else {
+        0
+    };
+    let granted = (attr.perm >> shift) & 0o7;
+    mask & granted == mask
+}
+
+/// Ownership and permissions presented in place of the stored ones, like the mount options
+/// of vfat. Nothing is overridden by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AttrOverride {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub fmask: Option<u16>,
+    pub dmask: Option<u16>,
+}
+
+impl AttrOverride {
+    /// The permission bits become `0o777` without the mask of the kind, directories are
+    /// masked by `dmask` and other files by `fmask`.
+    pub fn apply(&self, mut attr: FileAttr) -> FileAttr {
+        attr.uid = self.uid.unwrap_or(attr.uid);
+        attr.gid = self.gid.unwrap_or(attr.gid);
+        let mask = if attr.kind == FileType::Directory {
+            self.dmask
+        } else {
+            self.fmask
+        };
+        if let Some(mask) = mask {
+            attr.perm = (attr.perm & !0o777) | (0o777 & !mask);
+        }
+        attr
+    }
+}
+
+/// Check whether the sticky bit of directory `dir` forbids the caller(`uid`)
+/// to remove or rename its entry `file`.
+pub fn sticky_forbids(dir: &FileAttr, file: &FileAttr, uid: u32) -> bool {
+    dir.perm & libc::S_ISVTX as u16 != 0 && uid != 0 && uid != dir.uid && uid != file.uid
+}
+
+/// The gid and the permission bits of a file made by a caller of `gid` in directory `dir`.
+///
+/// Files made in a setgid directory belong to the group of the directory instead,
+/// and directories made in it are setgid as well.
+pub fn inherit_setgid(dir: &FileAttr, kind: FileType, gid: u32, perm: u16) -> (u32, u16) {
+    if dir.perm & libc::S_ISGID as u16 == 0 {
+        return (gid, perm);
+    }
+    match kind {
+        FileType::Directory => (dir.gid, perm | libc::S_ISGID as u16),
+        _ => (dir.gid, perm),
+    }
+}
+
+/// Check whether the caller(`uid`, in `groups`) may change the owner of the file to `new_uid`
+/// and its group to `new_gid`, unchanged ones are `None`.
+///
+/// Only root may give a file away, and the owner may only change the group
+/// to one of its groups.
+pub fn check_chown(
+    attr: &FileAttr,
+    uid: u32,
+    groups: &[u32],
+    new_uid: Option<u32>,
+    new_gid: Option<u32>,
+) -> Result<()> {
+    if uid == 0 {
+        return Ok(());
+    }
+    let owner = uid == attr.uid;
+    let uid_permitted = new_uid.map_or(true, |new_uid| owner && new_uid == attr.uid);
+    let gid_permitted = new_gid.map_or(true, |new_gid| {
+        owner && (new_gid == attr.gid || groups.contains(&new_gid))
+    });
+    if uid_permitted && gid_permitted {
+        Ok(())
+    } else {
+        Err(FsError::ChangeOwner { ino: attr.ino })
+    }
+}
+
+/// Check whether the caller(`uid`) may change the permission bits of the file,
+/// only root and the owner may.
+pub fn check_chmod(attr: &FileAttr, uid: u32) -> Result<()> {
+    if uid == 0 || uid == attr.uid {
+        Ok(())
+    } else {
+        Err(FsError::ChangeMode { ino: attr.ino })
+    }
+}
+
+/// The permission bits of a file after its owner or group is changed by the caller(`uid`),
+/// files other than directories lose the setuid and setgid bits unless the caller is root.
+pub fn chown_perm(attr: &FileAttr, uid: u32) -> u16 {
+    if uid == 0 || attr.kind == FileType::Directory {
+        attr.perm
+    } else {
+        attr.perm & !(libc::S_ISUID | libc::S_ISGID) as u16
+    }
+}
+
+/// The supplementary groups in the `/proc/<pid>/status` of a process.
+pub fn parse_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Only directories have entries to look up, list or remove.
+pub fn check_directory(attr: &FileAttr) -> Result<()> {
+    match attr.kind {
+        FileType::Directory => Ok(()),
+        _ => Err(FsError::NotDirectory { ino: attr.ino }),
+    }
+}
+
+/// Only regular files own data blocks, device nodes, fifos and sockets never do.
+pub fn check_regular_file(attr: &FileAttr) -> Result<()> {
+    match attr.kind {
+        FileType::RegularFile => Ok(()),
+        FileType::Directory => Err(FsError::IsDirectory { ino: attr.ino }),
+        _ => Err(FsError::NotRegularFile { ino: attr.ino }),
+    }
+}
 
 pub const fn as_file_perm(mode: u32) -> u16 {
     (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as _
@@ -69,3 +230,180 @@ pub fn make_mode(tpy: FileType, perm: u16) -> u32 {
 
     kind | perm as u32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::file_attr;
+
+    #[test]
+    fn umask() {
+        let mode = make_mode(FileType::RegularFile, 0o777);
+        assert_eq!(
+            apply_umask(mode, 0o022),
+            make_mode(FileType::RegularFile, 0o755)
+        );
+        assert_eq!(apply_umask(mode, 0), mode);
+        assert_eq!(
+            apply_umask(apply_umask(mode, 0o022), 0o022),
+            apply_umask(mode, 0o022)
+        );
+        assert_eq!(
+            apply_umask(make_mode(FileType::Directory, 0o777), 0o077),
+            make_mode(FileType::Directory, 0o700)
+        );
+    }
+
+    #[test]
+    fn access() {
+        use libc::{F_OK, R_OK, W_OK, X_OK};
+
+        let attr = file_attr(1000, 100, 0o640);
+        // owner
+        assert!(check_access(&attr, 1000, &[1000], R_OK | W_OK));
+        assert!(!check_access(&attr, 1000, &[1000], X_OK));
+        // group
+        assert!(check_access(&attr, 1001, &[100], R_OK));
+        assert!(!check_access(&attr, 1001, &[100], W_OK));
+        // other
+        assert!(!check_access(&attr, 1001, &[1001], R_OK));
+        assert!(check_access(&attr, 1001, &[1001], F_OK));
+        // a supplementary group
+        assert!(check_access(&attr, 1001, &[1001, 100], R_OK));
+        // owner bits take precedence over group and other bits
+        let attr = file_attr(1000, 100, 0o077);
+        assert!(!check_access(&attr, 1000, &[100], R_OK));
+        assert!(check_access(&attr, 1001, &[100], R_OK | W_OK | X_OK));
+        // root
+        assert!(check_access(&attr, 0, &[0], R_OK | W_OK));
+        assert!(check_access(&attr, 0, &[0], X_OK));
+        assert!(!check_access(&file_attr(1000, 100, 0o666), 0, &[0], X_OK));
+    }
+
+    #[test]
+    fn sticky() {
+        let sticky_dir = file_attr(1000, 100, 0o1777);
+        let dir = file_attr(1000, 100, 0o777);
+        let file = file_attr(1001, 100, 0o644);
+        // the file owner and the directory owner
+        assert!(!sticky_forbids(&sticky_dir, &file, 1001));
+        assert!(!sticky_forbids(&sticky_dir, &file, 1000));
+        // root
+        assert!(!sticky_forbids(&sticky_dir, &file, 0));
+        // others
+        assert!(sticky_forbids(&sticky_dir, &file, 1002));
+        assert!(!sticky_forbids(&dir, &file, 1002));
+    }
+
+    #[test]
+    fn setgid_directory() {
+        let setgid_dir = file_attr(1000, 100, 0o2775);
+        let dir = file_attr(1000, 100, 0o775);
+        // files belong to the group of a setgid directory
+        assert_eq!(
+            inherit_setgid(&setgid_dir, FileType::RegularFile, 1001, 0o644),
+            (100, 0o644)
+        );
+        assert_eq!(
+            inherit_setgid(&setgid_dir, FileType::Symlink, 1001, 0o777),
+            (100, 0o777)
+        );
+        // subdirectories inherit the setgid bit as well
+        assert_eq!(
+            inherit_setgid(&setgid_dir, FileType::Directory, 1001, 0o755),
+            (100, 0o2755)
+        );
+        // the caller's group otherwise
+        assert_eq!(
+            inherit_setgid(&dir, FileType::RegularFile, 1001, 0o644),
+            (1001, 0o644)
+        );
+        assert_eq!(
+            inherit_setgid(&dir, FileType::Directory, 1001, 0o755),
+            (1001, 0o755)
+        );
+    }
+
+    #[test]
+    fn chown() {
+        let attr = file_attr(1000, 100, 0o644);
+        // root may give the file away
+        assert!(check_chown(&attr, 0, &[0], Some(1001), Some(200)).is_ok());
+        // others may not
+        assert!(check_chown(&attr, 1000, &[100], Some(1001), None).is_err());
+        assert!(check_chown(&attr, 1001, &[100], None, Some(100)).is_err());
+        // the owner may only change the group to one of its groups
+        assert!(check_chown(&attr, 1000, &[100, 200], None, Some(200)).is_ok());
+        assert!(check_chown(&attr, 1000, &[100, 200], None, Some(300)).is_err());
+        assert!(check_chown(&attr, 1000, &[200], Some(1000), Some(100)).is_ok());
+        assert!(check_chown(&attr, 1001, &[300], None, None).is_ok());
+    }
+
+    #[test]
+    fn chown_clears_setid() {
+        let file = file_attr(1000, 100, 0o6755);
+        assert_eq!(chown_perm(&file, 1000), 0o755);
+        assert_eq!(chown_perm(&file, 0), 0o6755);
+        let mut dir = file_attr(1000, 100, 0o2775);
+        dir.kind = FileType::Directory;
+        assert_eq!(chown_perm(&dir, 1000), 0o2775);
+    }
+
+    #[test]
+    fn supplementary_groups() {
+        let status = "Name:\tcat\nUid:\t1000\t1000\t1000\t1000\nGroups:\t4 24 100 \nNgid:\t0\n";
+        assert_eq!(parse_groups(status), vec![4, 24, 100]);
+        assert_eq!(parse_groups("Groups:\n"), Vec::<u32>::new());
+        assert_eq!(parse_groups(""), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn file_types() {
+        let file = file_attr(0, 0, 0o644);
+        let mut dir = file;
+        dir.kind = FileType::Directory;
+        let mut fifo = file;
+        fifo.kind = FileType::NamedPipe;
+
+        // reading a directory
+        assert!(check_regular_file(&file).is_ok());
+        assert!(matches!(
+            check_regular_file(&dir),
+            Err(FsError::IsDirectory { ino: 2 })
+        ));
+        assert!(matches!(
+            check_regular_file(&fifo),
+            Err(FsError::NotRegularFile { ino: 2 })
+        ));
+
+        // looking up through a non-directory
+        assert!(check_directory(&dir).is_ok());
+        for attr in [file, fifo] {
+            let err = check_directory(&attr).unwrap_err();
+            assert_eq!(libc::c_int::from(err), libc::ENOTDIR);
+        }
+        let err = check_regular_file(&dir).unwrap_err();
+        assert_eq!(libc::c_int::from(err), libc::EISDIR);
+    }
+
+    #[test]
+    fn override_attr() {
+        let attr = file_attr(1, 2, 0o640);
+        assert_eq!(AttrOverride::default().apply(attr), attr);
+
+        let mapping = AttrOverride {
+            uid: Some(1000),
+            gid: Some(100),
+            fmask: Some(0o022),
+            dmask: Some(0o002),
+        };
+        let file = mapping.apply(attr);
+        assert_eq!((file.uid, file.gid, file.perm), (1000, 100, 0o755));
+
+        let mut dir = file_attr(1, 2, 0o1700);
+        dir.kind = FileType::Directory;
+        let dir = mapping.apply(dir);
+        // the sticky bit is kept
+        assert_eq!((dir.uid, dir.gid, dir.perm), (1000, 100, 0o1775));
+    }
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+use tikv_client::{Key, KvPair, Value};
+
+/// Values of keys fetched ahead in one request, so that reading them one by one later
+/// doesn't take a request each.
+///
+/// A value is served till its key is written, later reads go to the transaction.
+#[derive(Debug, Default)]
+pub struct Prefetched {
+    values: HashMap<Key, Option<Value>>,
+}
+
+impl Prefetched {
+    /// Record the pairs fetched for `keys`, keys without a pair don't exist.
+    pub fn fill(&mut self, keys: Vec<Key>, pairs: impl IntoIterator<Item = KvPair>) {
+        for key in keys {
+            self.values.insert(key, None);
+        }
+        for pair in pairs {
+            let (key, value) = pair.into();
+            self.values.insert(key, Some(value));
+        }
+    }
+
+    /// The value fetched for `key`, `None` unless it's fetched.
+    pub fn get(&self, key: &Key) -> Option<&Option<Value>> {
+        self.values.get(key)
+    }
+
+    /// Forget the value of a key being written.
+    pub fn forget(&mut self, key: &Key) {
+        self.values.remove(key);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Data prefetched for a file handler, from `offset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Window {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl Window {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// The reads of a file handler.
+#[derive(Debug, Default)]
+struct Stream {
+    // where the next sequential read starts
+    next: u64,
+    window: Option<Window>,
+    prefetching: bool,
+    // where the file ended when it was prefetched
+    eof: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    streams: HashMap<(u64, u64), Stream>,
+    // bumped on every invalidation, prefetches started before are dropped
+    versions: HashMap<u64, u64>,
+}
+
+/// A range to prefetch for a file handler, the result goes back by `Readahead::fill`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefetch {
+    pub ino: u64,
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u64,
+    version: u64,
+}
+
+/// Prefetched data of sequential readers on this mount.
+///
+/// A handler reading where its last read ended is sequential, then up to `size` bytes
+/// following its reads are prefetched, the prefetch is topped up once half of it is read.
+#[derive(Debug)]
+pub struct Readahead {
+    size: u64,
+    state: Mutex<State>,
+}
+
+impl Readahead {
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            state: Default::default(),
+        }
+    }
+
+    /// Serve a read from the prefetched data, `None` unless it's covered entirely.
+    pub fn read(&self, ino: u64, fh: u64, start: u64, size: u64) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let window = state.streams.get(&(ino, fh))?.window.as_ref()?;
+        if start < window.offset || start + size > window.end() {
+            return None;
+        }
+        let from = (start - window.offset) as usize;
+        Some(window.data[from..from + size as usize].to_vec())
+    }
+
+    /// Record a read of `len` bytes from `start`, returns the range to prefetch if the handler
+    /// reads sequentially and the prefetched data runs low.
+    pub fn advance(&self, ino: u64, fh: u64, start: u64, len: u64) -> Option<Prefetch> {
+        let mut state = self.state.lock().unwrap();
+        let version = state.versions.get(&ino).copied().unwrap_or_default();
+        let stream = state.streams.entry((ino, fh)).or_default();
+        let sequential = start == stream.next;
+        let end = start + len;
+        stream.next = end;
+        if !sequential {
+            stream.window = None;
+            return None;
+        }
+        if stream.prefetching {
+            return None;
+        }
+        let ahead = match &stream.window {
+            Some(window) if window.offset <= end => window.end().saturating_sub(end),
+            _ => 0,
+        };
+        match stream.eof {
+            // nothing more to prefetch unless the file grows
+            Some(eof) if end + ahead >= eof && end <= eof => return None,
+            _ => stream.eof = None,
+        }
+        if ahead * 2 > self.size {
+            return None;
+        }
+        stream.prefetching = true;
+        Some(Prefetch {
+            ino,
+            fh,
+            offset: end + ahead,
+            size: self.size - ahead,
+            version,
+        })
+    }
+
+    /// Take the prefetched data, `None` if the prefetch failed.
+    /// Data prefetched before an invalidation of the inode is dropped.
+    pub fn fill(&self, prefetch: Prefetch, data: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        let version = state
+            .versions
+            .get(&prefetch.ino)
+            .copied()
+            .unwrap_or_default();
+        let stream = match state.streams.get_mut(&(prefetch.ino, prefetch.fh)) {
+            Some(stream) => stream,
+            None => return,
+        };
+        stream.prefetching = false;
+        let data = match data {
+            Some(data) if version == prefetch.version => data,
+            _ => return,
+        };
+        if (data.len() as u64) < prefetch.size {
+            stream.eof = Some(prefetch.offset + data.len() as u64);
+        }
+        let mut window = match stream.window.take() {
+            Some(mut window) if window.end() == prefetch.offset => {
+                window.data.extend(data);
+                window
+            }
+            _ => Window {
+                offset: prefetch.offset,
+                data,
+            },
+        };
+        // drop the data already read
+        if stream.next > window.offset {
+            let read = (stream.next - window.offset).min(window.data.len() as u64);
+            window.data.drain(..read as usize);
+            window.offset += read;
+        }
+        if !window.data.is_empty() {
+            stream.window = Some(window);
+        }
+    }
+
+    /// Drop the prefetched data of an inode, called once it's modified.
+    pub fn invalidate(&self, ino: u64) {
+        let mut state = self.state.lock().unwrap();
+        *state.versions.entry(ino).or_default() += 1;
+        state
+            .streams
+            .iter_mut()
+            .filter(|((stream_ino, _), _)| *stream_ino == ino)
+            .for_each(|(_, stream)| {
+                stream.window = None;
+                stream.eof = None;
+            });
+    }
+
+    /// Forget a released file handler.
+    pub fn release(&self, ino: u64, fh: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.streams.remove(&(ino, fh));
+        let opened = state.streams.keys().any(|(opened, _)| *opened == ino);
+        if !opened {
+            state.versions.remove(&ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ_SIZE: u64 = 4 << 10;
+
+    /// Read the file sequentially, returns the number of ranges fetched from the store.
+    fn read_sequentially(readahead: Option<&Readahead>, file: &[u8]) -> usize {
+        let mut fetches = 0;
+        let len = file.len() as u64;
+        for start in (0..len).step_by(READ_SIZE as usize) {
+            let range = start as usize..(start + READ_SIZE).min(len) as usize;
+            let data = match readahead.and_then(|ra| ra.read(1, 0, start, READ_SIZE)) {
+                Some(data) => data,
+                None => {
+                    fetches += 1;
+                    file[range.clone()].to_vec()
+                }
+            };
+            assert_eq!(data, &file[range]);
+            if let Some(prefetch) = readahead.and_then(|ra| ra.advance(1, 0, start, READ_SIZE)) {
+                fetches += 1;
+                let from = prefetch.offset.min(len) as usize;
+                let to = (prefetch.offset + prefetch.size).min(len) as usize;
+                readahead
+                    .unwrap()
+                    .fill(prefetch, Some(file[from..to].to_vec()));
+            }
+        }
+        fetches
+    }
+
+    #[test]
+    fn fewer_fetches_for_sequential_reads() {
+        let file: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        assert_eq!(read_sequentially(None, &file), 256);
+        let readahead = Readahead::new(256 << 10);
+        // the first read, then a prefetch every 128KiB till the end of file
+        assert_eq!(read_sequentially(Some(&readahead), &file), 8);
+    }
+
+    #[test]
+    fn random_reads_prefetch_nothing() {
+        let readahead = Readahead::new(64);
+        let prefetch = readahead.advance(1, 0, 0, 8).unwrap();
+        readahead.fill(prefetch, Some(vec![1; 64]));
+        assert_eq!(readahead.read(1, 0, 8, 8), Some(vec![1; 8]));
+
+        // a seek drops the window
+        assert_eq!(readahead.advance(1, 0, 128, 8), None);
+        assert_eq!(readahead.read(1, 0, 16, 8), None);
+        // other handlers have their own streams
+        assert_eq!(readahead.read(1, 1, 8, 8), None);
+    }
+
+    #[test]
+    fn invalidate_on_writes() {
+        let readahead = Readahead::new(64);
+        let prefetch = readahead.advance(1, 0, 0, 8).unwrap();
+        readahead.fill(prefetch, Some(vec![1; 64]));
+        readahead.invalidate(1);
+        assert_eq!(readahead.read(1, 0, 8, 8), None);
+
+        // data prefetched before a write is stale
+        let prefetch = readahead.advance(1, 0, 8, 8).unwrap();
+        readahead.invalidate(1);
+        readahead.fill(prefetch, Some(vec![1; 64]));
+        assert_eq!(readahead.read(1, 0, 16, 8), None);
+
+        // prefetching goes on after a failure
+        let prefetch = readahead.advance(1, 0, 16, 8).unwrap();
+        readahead.fill(prefetch, None);
+        assert!(readahead.advance(1, 0, 24, 8).is_some());
+    }
+}
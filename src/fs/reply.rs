@@ -1,14 +1,22 @@
 use std::fmt::Debug;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 use fuser::*;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, trace};
 
-use super::error::Result;
+use super::error::{FsError, Result};
 
-pub fn get_time() -> Duration {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+/// The unit `st_blocks` is counted in.
+pub const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Attributes as reported to the kernel.
+/// Inodes count `blocks` in blocks of the filesystem, `st_blocks` counts them in 512-byte units.
+pub fn reported(attr: &FileAttr) -> FileAttr {
+    FileAttr {
+        blocks: (attr.blocks * attr.blksize as u64 + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE,
+        ..*attr
+    }
 }
 
 #[derive(Debug)]
@@ -19,9 +27,9 @@ pub struct Entry {
 }
 
 impl Entry {
-    pub fn new(stat: FileAttr, generation: u64) -> Self {
+    pub fn new(time: Duration, stat: FileAttr, generation: u64) -> Self {
         Self {
-            time: get_time(),
+            time,
             stat,
             generation,
         }
@@ -45,11 +53,8 @@ pub struct Attr {
     pub attr: FileAttr,
 }
 impl Attr {
-    pub fn new(attr: FileAttr) -> Self {
-        Self {
-            time: get_time(),
-            attr,
-        }
+    pub fn new(time: Duration, attr: FileAttr) -> Self {
+        Self { time, attr }
     }
 }
 
@@ -62,7 +67,7 @@ impl Data {
         Self { data }
     }
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct DirItem {
     pub ino: u64,
     pub name: String,
@@ -89,6 +94,10 @@ impl Dir {
     pub fn push(&mut self, item: DirItem) {
         self.items.push(item)
     }
+
+    pub fn items(&self) -> &[DirItem] {
+        &self.items
+    }
 }
 
 #[derive(Debug, Default)]
@@ -112,6 +121,10 @@ impl DirPlus {
     pub fn push(&mut self, item: DirItem, entry: Entry) {
         self.items.push((item, entry))
     }
+
+    pub fn items(&self) -> &[(DirItem, Entry)] {
+        &self.items
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
@@ -159,6 +172,15 @@ impl Write {
     pub fn new(size: u32) -> Self {
         Self { size }
     }
+
+    /// The reply of `len` bytes written out of `requested`, the count is replied in 32 bits.
+    pub fn written(len: u64, requested: u64) -> Result<Self> {
+        let max = requested.min(u32::MAX as u64);
+        if len > max {
+            return Err(FsError::InvalidWriteSize { len, max });
+        }
+        Ok(Self::new(len as u32))
+    }
 }
 
 #[derive(Debug)]
@@ -170,9 +192,9 @@ pub struct Create {
     pub flags: u32,
 }
 impl Create {
-    pub fn new(attr: FileAttr, generation: u64, fh: u64, flags: u32) -> Self {
+    pub fn new(ttl: Duration, attr: FileAttr, generation: u64, fh: u64, flags: u32) -> Self {
         Self {
-            ttl: get_time(),
+            ttl,
             attr,
             generation,
             fh,
@@ -236,6 +258,18 @@ impl Lseek {
     }
 }
 
+#[derive(Debug)]
+pub struct Ioctl {
+    pub result: i32,
+    pub data: Vec<u8>,
+}
+
+impl Ioctl {
+    pub fn new(result: i32, data: Vec<u8>) -> Self {
+        Self { result, data }
+    }
+}
+
 pub trait FsReply<T: Debug>: Sized {
     fn reply_ok(self, item: T);
     fn reply_err(self, err: libc::c_int);
@@ -261,7 +295,7 @@ pub trait FsReply<T: Debug>: Sized {
 
 impl FsReply<Entry> for ReplyEntry {
     fn reply_ok(self, item: Entry) {
-        self.entry(&item.time, &item.stat, item.generation);
+        self.entry(&item.time, &reported(&item.stat), item.generation);
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -279,7 +313,7 @@ impl FsReply<Open> for ReplyOpen {
 
 impl FsReply<Attr> for ReplyAttr {
     fn reply_ok(self, item: Attr) {
-        self.attr(&item.time, &item.attr);
+        self.attr(&item.time, &reported(&item.attr));
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -319,10 +353,10 @@ impl FsReply<DirPlus> for ReplyDirectoryPlus {
         for (index, (item, entry)) in dir.items.into_iter().enumerate() {
             if self.add(
                 item.ino,
-                (dir.offset + index) as i64,
+                (index + 1 + dir.offset) as i64,
                 item.name,
                 &entry.time,
-                &entry.stat,
+                &reported(&entry.stat),
                 entry.generation,
             ) {
                 break;
@@ -364,7 +398,13 @@ impl FsReply<Write> for ReplyWrite {
 
 impl FsReply<Create> for ReplyCreate {
     fn reply_ok(self, item: Create) {
-        self.created(&item.ttl, &item.attr, item.generation, item.fh, item.flags);
+        self.created(
+            &item.ttl,
+            &reported(&item.attr),
+            item.generation,
+            item.fh,
+            item.flags,
+        );
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -411,6 +451,15 @@ impl FsReply<Lseek> for ReplyLseek {
     }
 }
 
+impl FsReply<Ioctl> for ReplyIoctl {
+    fn reply_ok(self, item: Ioctl) {
+        self.ioctl(item.result, &item.data)
+    }
+    fn reply_err(self, err: libc::c_int) {
+        self.error(err);
+    }
+}
+
 impl FsReply<()> for ReplyEmpty {
     fn reply_ok(self, _: ()) {
         self.ok();
@@ -419,3 +468,51 @@ impl FsReply<()> for ReplyEmpty {
         self.error(err);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::inode::Inode;
+    use crate::fs::testing::file_attr;
+
+    fn regular_file(size: u64, block_size: u64) -> Inode {
+        let mut inode: Inode = FileAttr {
+            blksize: 0,
+            ..file_attr(0, 0, 0o644)
+        }
+        .into();
+        inode.set_size(size, block_size);
+        inode
+    }
+
+    #[test]
+    fn report_blocks_in_sectors() {
+        for block_size in [1 << 8, 1 << 12, 1 << 16] {
+            for size in [0, 1, 511, 512, 4096, 65535, 65536, 65537, 1 << 20] {
+                let inode = regular_file(size, block_size);
+                let attr = reported(&inode);
+                // what `du` computes from `st_blocks`
+                let du = attr.blocks * STAT_BLOCK_SIZE;
+                let stored = inode.blocks * block_size;
+                assert!(du >= stored, "size({}), block size({})", size, block_size);
+                assert!(du < stored + STAT_BLOCK_SIZE);
+                assert!(du >= size);
+                assert_eq!(attr.size, size);
+            }
+        }
+    }
+
+    #[test]
+    fn written_fits_in_reply() {
+        let max = u32::MAX as u64;
+        assert_eq!(Write::written(max, max).unwrap().size, u32::MAX);
+        assert_eq!(Write::written(max - 1, max + 1).unwrap().size, u32::MAX - 1);
+        assert!(matches!(
+            Write::written(max + 1, max + 1),
+            Err(FsError::InvalidWriteSize { len, max: u32_max }) if len == max + 1 && u32_max == max
+        ));
+        // never more than requested
+        assert!(Write::written(5, 4).is_err());
+        assert_eq!(Write::written(0, 0).unwrap().size, 0);
+    }
+}
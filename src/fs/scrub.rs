@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use fuser::FileType;
+use tikv_client::TransactionClient;
+use tokio::time::sleep;
+use tracing::{debug, error, warn};
+
+use super::atime::AtimePolicy;
+use super::commit::CommitPolicy;
+use super::error::Result;
+use super::inode::Inode;
+use super::key::{ScopedKey, ROOT_INODE};
+use super::meta::Usage;
+use super::transaction::Txn;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    CorruptInode {
+        ino: u64,
+        msg: String,
+    },
+    CorruptDirectory {
+        ino: u64,
+        msg: String,
+    },
+    DanglingEntry {
+        parent: u64,
+        name: String,
+        ino: u64,
+    },
+    MismatchedIndex {
+        parent: u64,
+        name: String,
+        ino: u64,
+        index: Option<u64>,
+    },
+    BlockBeyondSize {
+        ino: u64,
+        block: u64,
+    },
+    DanglingIndex {
+        parent: u64,
+        name: String,
+        ino: u64,
+    },
+    OrphanBlock {
+        ino: u64,
+        block: u64,
+    },
+    LinkCountMismatch {
+        ino: u64,
+        nlink: u32,
+        links: u32,
+    },
+    UsageMismatch {
+        stored: Option<Usage>,
+        scanned: Usage,
+    },
+}
+
+/// Check the consistency of a single inode.
+pub async fn check_inode(txn: &mut Txn, inode: &Inode) -> Result<Vec<Inconsistency>> {
+    let mut issues = Vec::new();
+    match inode.kind {
+        FileType::Directory => match txn.read_dir(inode.ino).await {
+            Ok(dir) => {
+                for item in dir {
+                    if txn.get(ScopedKey::inode(item.ino)).await?.is_none() {
+                        issues.push(Inconsistency::DanglingEntry {
+                            parent: inode.ino,
+                            name: item.name.clone(),
+                            ino: item.ino,
+                        });
+                    }
+                    let index = txn.get_index(inode.ino, item.name.clone().into()).await?;
+                    if index != Some(item.ino) {
+                        issues.push(Inconsistency::MismatchedIndex {
+                            parent: inode.ino,
+                            name: item.name,
+                            ino: item.ino,
+                            index,
+                        });
+                    }
+                }
+            }
+            Err(err) => issues.push(Inconsistency::CorruptDirectory {
+                ino: inode.ino,
+                msg: err.to_string(),
+            }),
+        },
+        FileType::RegularFile if inode.inline_data.is_none() => {
+            let end_block = (inode.size + txn.block_size() - 1) / txn.block_size();
+            let beyond = txn
+                .scan(ScopedKey::block_range(inode.ino, end_block..u64::MAX), 1)
+                .await?
+                .next();
+            if let Some(pair) = beyond {
+                if let Ok(ScopedKey::Block { ino: _, block }) = ScopedKey::parse(pair.key().into())
+                {
+                    issues.push(Inconsistency::BlockBeyondSize {
+                        ino: inode.ino,
+                        block,
+                    });
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(issues)
+}
+
+/// A background task verifying the filesystem slice by slice.
+pub struct Scrubber {
+    client: TransactionClient,
+    block_size: u64,
+    commit_policy: CommitPolicy,
+    scan_limit: u32,
+    checksum: bool,
+    interval: Duration,
+    batch: u64,
+}
+
+impl Scrubber {
+    pub const DEFAULT_BATCH: u64 = 1 << 6;
+
+    pub fn new(
+        client: TransactionClient,
+        block_size: u64,
+        commit_policy: CommitPolicy,
+        scan_limit: u32,
+        checksum: bool,
+        interval: Duration,
+        batch: u64,
+    ) -> Self {
+        Self {
+            client,
+            block_size,
+            commit_policy,
+            scan_limit,
+            checksum,
+            interval,
+            batch,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut next = ROOT_INODE;
+        loop {
+            sleep(self.interval).await;
+            match self.scrub(next).await {
+                Ok(ino) => next = ino,
+                Err(err) => error!("fail to scrub from inode({}): {}", next, err),
+            }
+        }
+    }
+
+    /// Verify a slice of inodes starting from `start`, returns where the next slice begins.
+    async fn scrub(&self, start: u64) -> Result<u64> {
+        let mut txn = Txn::begin_optimistic(
+            &self.client,
+            self.block_size,
+            None,
+            super::tikv_fs::TiFs::MAX_NAME_LEN,
+            self.block_size / Txn::INLINE_DATA_THRESHOLD_BASE,
+            false,
+            AtimePolicy::Never,
+            false,
+            None,
+            self.commit_policy,
+        )
+        .await?
+        .with_scan_limit(self.scan_limit)
+        .with_checksum(self.checksum);
+        let result = self.scrub_with_txn(&mut txn, start).await;
+        // the scrubber never writes
+        txn.rollback().await?;
+        result
+    }
+
+    async fn scrub_with_txn(&self, txn: &mut Txn, start: u64) -> Result<u64> {
+        let inode_next = match txn.read_meta().await? {
+            Some(meta) => meta.inode_next,
+            None => return Ok(ROOT_INODE),
+        };
+        if start >= inode_next {
+            return Ok(ROOT_INODE);
+        }
+        let end = inode_next.min(start + self.batch);
+        let pairs: Vec<_> = txn
+            .scan(ScopedKey::inode_range(start..end), (end - start) as u32)
+            .await?
+            .collect();
+        for pair in pairs {
+            let ino = match ScopedKey::parse(pair.key().into()) {
+                Ok(ScopedKey::Inode(ino)) => ino,
+                _ => continue,
+            };
+            let issues = match Inode::deserialize(pair.value()) {
+                Ok(inode) => check_inode(txn, &inode).await?,
+                Err(err) => vec![Inconsistency::CorruptInode {
+                    ino,
+                    msg: err.to_string(),
+                }],
+            };
+            for issue in issues {
+                warn!("scrubber found inconsistency: {:?}", issue);
+            }
+        }
+        debug!("scrubbed inodes [{}, {})", start, end);
+        Ok(if end >= inode_next { ROOT_INODE } else { end })
+    }
+}
@@ -1,11 +1,90 @@
 #[cfg(feature = "binc")]
 pub use bincode::{deserialize, serialize};
 
+use super::error::{FsError, Result};
+
 #[cfg(feature = "binc")]
 pub const ENCODING: &str = "bincode";
 
+#[cfg(feature = "binc")]
+pub const ENCODING_TAG: u8 = BINCODE_TAG;
+
 #[cfg(all(feature = "json", not(feature = "binc")))]
 pub use serde_json::{from_slice as deserialize, to_vec as serialize};
 
 #[cfg(all(feature = "json", not(feature = "binc")))]
 pub const ENCODING: &str = "json";
+
+#[cfg(all(feature = "json", not(feature = "binc")))]
+pub const ENCODING_TAG: u8 = JSON_TAG;
+
+pub const JSON_TAG: u8 = 0;
+pub const BINCODE_TAG: u8 = 1;
+
+/// Version of the on-disk format, bump it on incompatible changes.
+pub const FORMAT_VERSION: u8 = 1;
+
+pub const fn encoding_name(tag: u8) -> &'static str {
+    match tag {
+        JSON_TAG => "json",
+        BINCODE_TAG => "bincode",
+        _ => "unknown",
+    }
+}
+
+/// The header prefixed to the encoded meta, recording how the filesystem is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub encoding: u8,
+    pub version: u8,
+}
+
+impl Header {
+    const MAGIC: &'static [u8] = b"TIFS";
+
+    pub const fn current() -> Self {
+        Self {
+            encoding: ENCODING_TAG,
+            version: FORMAT_VERSION,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Self::MAGIC.to_vec();
+        data.push(self.encoding);
+        data.push(self.version);
+        data
+    }
+
+    /// Split the header from encoded bytes,
+    /// the header is `None` if the bytes were written before headers were introduced.
+    pub fn split(bytes: &[u8]) -> (Option<Self>, &[u8]) {
+        match bytes.strip_prefix(Self::MAGIC) {
+            Some([encoding, version, rest @ ..]) => (
+                Some(Self {
+                    encoding: *encoding,
+                    version: *version,
+                }),
+                rest,
+            ),
+            _ => (None, bytes),
+        }
+    }
+
+    /// Check whether data with this header can be read by this build.
+    pub fn check(&self) -> Result<()> {
+        if self.encoding != ENCODING_TAG {
+            Err(FsError::EncodingConflict {
+                origin: encoding_name(self.encoding),
+                new: ENCODING,
+            })
+        } else if self.version > FORMAT_VERSION {
+            Err(FsError::UnsupportedFormat {
+                version: self.version,
+                supported: FORMAT_VERSION,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::SystemTime;
+
+use bytestring::ByteString;
+use fuser::{FileAttr, FileType};
+
+use super::key::ScopedKey;
+use super::tikv_fs::TiFs;
+use crate::MountOption;
+
+/// The pd of the cluster in docker-compose.yaml, tests on it are run by `cargo test -- --ignored`.
+pub const PD_ENDPOINT: &str = "127.0.0.1:2379";
+
+/// The block size of the filesystem on the cluster, other than the default so that nothing
+/// holds by the default only. The debugger tests make it with the same size.
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// A filesystem on the cluster mounted with `options`, its root is made by root.
+pub async fn tifs(mut options: Vec<MountOption>) -> TiFs {
+    options.push(MountOption::BlkSize(BLOCK_SIZE.to_string()));
+    let fs = TiFs::construct(
+        vec![PD_ENDPOINT],
+        Default::default(),
+        Default::default(),
+        options,
+        None,
+    )
+    .await
+    .unwrap();
+    fs.prepare(0, 0).await.unwrap();
+    fs
+}
+
+/// Attributes of a regular file, other fields are taken by `..file_attr(uid, gid, perm)`.
+pub fn file_attr(uid: u32, gid: u32, perm: u16) -> FileAttr {
+    FileAttr {
+        ino: 2,
+        size: 0,
+        blocks: 0,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm,
+        nlink: 1,
+        uid,
+        gid,
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+/// The number of block keys stored for `ino`.
+pub async fn stored_blocks(fs: &TiFs, ino: u64) -> usize {
+    fs.spin_no_delay(move |_, txn| {
+        Box::pin(async move {
+            let range = ScopedKey::block_range(ino, 0..u64::MAX);
+            Ok(txn.scan_keys(range, u32::MAX).await?.count())
+        })
+    })
+    .await
+    .unwrap()
+}
+
+/// A name no earlier run of the tests has taken, so that runs never see each other's files.
+pub fn unique_name(prefix: &str) -> ByteString {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{}-{}", prefix, nanos).into()
+}
@@ -0,0 +1,303 @@
+use bytes::Bytes;
+use bytestring::ByteString;
+use fuser::FileType;
+
+use super::error::{FsError, Result};
+use super::key::ROOT_INODE;
+use super::mode::{check_regular_file, make_mode};
+use super::reply::DirItem;
+use super::tikv_fs::{TiFs, DIR_PARENT, DIR_SELF};
+use super::transaction::Txn;
+use crate::MountOption;
+
+/// A client operating on files of tifs by paths, without mounting it by FUSE.
+///
+/// Every operation is a transaction of its own, so that a file written by `write_file`
+/// is replaced as a whole. Permissions are not checked, files are made as the owner
+/// of the client.
+pub struct TifsClient {
+    fs: TiFs,
+    uid: u32,
+    gid: u32,
+}
+
+impl TifsClient {
+    pub const FILE_PERM: u16 = 0o644;
+    pub const DIR_PERM: u16 = 0o755;
+
+    /// Connect to the filesystem on `endpoints` with the mount options,
+    /// files are made as the user of this process.
+    pub async fn connect(endpoints: Vec<&str>, options: Vec<MountOption>) -> anyhow::Result<Self> {
+        let (fs, _) = crate::connect_tifs(endpoints, options).await?;
+        // SAFETY: getting ids of the process always succeeds
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Ok(Self::new(fs, uid, gid).await?)
+    }
+
+    /// Operate on `fs` as `uid` and `gid`, a new filesystem is formatted first.
+    pub async fn new(fs: TiFs, uid: u32, gid: u32) -> Result<Self> {
+        fs.prepare(gid, uid).await?;
+        Ok(Self { fs, uid, gid })
+    }
+
+    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let path = split_path(path)?;
+        self.fs
+            .spin_no_delay(move |_, txn| {
+                let path = path.clone();
+                Box::pin(async move {
+                    let ino = resolve(txn, &path).await?;
+                    check_regular_file(&txn.read_inode(ino).await?)?;
+                    txn.read_data(ino, 0, None).await
+                })
+            })
+            .await
+    }
+
+    /// Replace the content of the file at `path` by `data`, the file is made if it's missing.
+    pub async fn write_file(&self, path: &str, data: impl Into<Bytes>) -> Result<()> {
+        let (dir, name) = split_parent(path)?.ok_or(FsError::IsDirectory { ino: ROOT_INODE })?;
+        let data = data.into();
+        let (uid, gid) = (self.uid, self.gid);
+        self.fs
+            .spin_no_delay(move |_, txn| {
+                let (dir, name, data) = (dir.clone(), name.clone(), data.clone());
+                Box::pin(async move {
+                    let parent = resolve(txn, &dir).await?;
+                    let ino = match txn.lookup(parent, name.clone()).await {
+                        Ok(ino) => {
+                            let mut inode = txn.read_inode(ino).await?;
+                            check_regular_file(&inode)?;
+                            inode.check_overwrite()?;
+                            txn.truncate(&mut inode, 0).await?;
+                            txn.save_inode(&inode).await?;
+                            ino
+                        }
+                        Err(FsError::FileNotFound { .. }) => {
+                            let mode = make_mode(FileType::RegularFile, Self::FILE_PERM);
+                            // no umask applies to the client, a default ACL of `parent` does
+                            let mode = txn.creation_mode(parent, mode, 0).await?;
+                            txn.make_inode(parent, name, mode, gid, uid, 0).await?.ino
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    if !data.is_empty() {
+                        txn.write_data(ino, 0, data).await?;
+                    }
+                    Ok(())
+                })
+            })
+            .await
+    }
+
+    /// Entries of the directory at `path`, without `.` and `..`.
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<DirItem>> {
+        let path = split_path(path)?;
+        self.fs
+            .spin_no_delay(move |_, txn| {
+                let path = path.clone();
+                Box::pin(async move {
+                    let ino = resolve(txn, &path).await?;
+                    Ok(txn
+                        .read_dir(ino)
+                        .await?
+                        .into_iter()
+                        .filter(|item| DIR_SELF != item.name && DIR_PARENT != item.name)
+                        .collect())
+                })
+            })
+            .await
+    }
+
+    pub async fn create_dir(&self, path: &str) -> Result<()> {
+        let (dir, name) = split_parent(path)?.ok_or_else(|| FsError::FileExist {
+            file: path.to_owned(),
+        })?;
+        let (uid, gid) = (self.uid, self.gid);
+        self.fs
+            .spin_no_delay(move |_, txn| {
+                let (dir, name) = (dir.clone(), name.clone());
+                Box::pin(async move {
+                    let parent = resolve(txn, &dir).await?;
+                    let mode = txn.creation_mode(parent, Self::DIR_PERM as u32, 0).await?;
+                    txn.mkdir(parent, name, mode, gid, uid).await?;
+                    Ok(())
+                })
+            })
+            .await
+    }
+
+    /// Remove the file at `path`, a directory is only removed if it's empty.
+    pub async fn remove(&self, path: &str) -> Result<()> {
+        let (dir, name) = split_parent(path)?.ok_or(FsError::IsDirectory { ino: ROOT_INODE })?;
+        self.fs
+            .spin_no_delay(move |_, txn| {
+                let (dir, name) = (dir.clone(), name.clone());
+                Box::pin(async move {
+                    let parent = resolve(txn, &dir).await?;
+                    let ino = txn.lookup(parent, name.clone()).await?;
+                    match txn.read_inode(ino).await?.kind {
+                        FileType::Directory => txn.rmdir(parent, name).await,
+                        _ => txn.unlink(parent, name).await,
+                    }
+                })
+            })
+            .await
+    }
+}
+
+/// Names along `path` from the root, empty names and `.` are skipped.
+fn split_path(path: &str) -> Result<Vec<ByteString>> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && DIR_SELF != *name)
+        .map(|name| {
+            TiFs::check_file_name(name)?;
+            Ok(name.into())
+        })
+        .collect()
+}
+
+/// Names along the parent of `path` and the last name, `None` for the root.
+fn split_parent(path: &str) -> Result<Option<(Vec<ByteString>, ByteString)>> {
+    let mut names = split_path(path)?;
+    Ok(names.pop().map(|name| (names, name)))
+}
+
+async fn resolve(txn: &mut Txn, path: &[ByteString]) -> Result<u64> {
+    let mut ino = ROOT_INODE;
+    for name in path {
+        ino = txn.lookup(ino, name.clone()).await?;
+    }
+    Ok(ino)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing;
+
+    async fn client() -> TifsClient {
+        TifsClient::new(testing::tifs(Vec::new()).await, 1000, 1000)
+            .await
+            .unwrap()
+    }
+
+    async fn perm(client: &TifsClient, path: &str) -> u16 {
+        let path = split_path(path).unwrap();
+        client
+            .fs
+            .spin_no_delay(move |_, txn| {
+                let path = path.clone();
+                Box::pin(async move {
+                    let ino = resolve(txn, &path).await?;
+                    Ok(txn.read_inode(ino).await?.perm)
+                })
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn write_and_read_files() {
+        let client = client().await;
+        let dir = format!("/{}", testing::unique_name("client"));
+        client.create_dir(&dir).await.unwrap();
+        assert_eq!(perm(&client, &dir).await, TifsClient::DIR_PERM);
+        assert!(client.list_dir(&dir).await.unwrap().is_empty());
+
+        let file = format!("{}/file", dir);
+        client.write_file(&file, "hello tifs").await.unwrap();
+        assert_eq!(perm(&client, &file).await, TifsClient::FILE_PERM);
+        assert_eq!(client.read_file(&file).await.unwrap(), b"hello tifs");
+
+        // an overwrite replaces the whole content, a longer one included
+        client.write_file(&file, "hi").await.unwrap();
+        assert_eq!(client.read_file(&file).await.unwrap(), b"hi");
+        client.write_file(&file, vec![7u8; 1 << 20]).await.unwrap();
+        assert_eq!(client.read_file(&file).await.unwrap(), vec![7u8; 1 << 20]);
+
+        let sub = format!("{}/sub", dir);
+        client.create_dir(&sub).await.unwrap();
+        let mut items: Vec<_> = client
+            .list_dir(&dir)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.name, item.typ))
+            .collect();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            items,
+            [
+                ("file".to_owned(), FileType::RegularFile),
+                ("sub".to_owned(), FileType::Directory)
+            ]
+        );
+        assert!(matches!(
+            client.create_dir(&sub).await,
+            Err(FsError::FileExist { .. })
+        ));
+        assert!(matches!(
+            client.read_file(&sub).await,
+            Err(FsError::IsDirectory { .. })
+        ));
+
+        client.remove(&file).await.unwrap();
+        assert!(matches!(
+            client.read_file(&file).await,
+            Err(FsError::FileNotFound { .. })
+        ));
+        client.remove(&sub).await.unwrap();
+        client.remove(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn keep_non_empty_dirs() {
+        let client = client().await;
+        let dir = format!("/{}", testing::unique_name("client"));
+        let file = format!("{}/file", dir);
+        client.create_dir(&dir).await.unwrap();
+        client.write_file(&file, "kept").await.unwrap();
+
+        let err = client.remove(&dir).await.unwrap_err();
+        assert!(matches!(err, FsError::DirNotEmpty { .. }));
+        assert_eq!(client.read_file(&file).await.unwrap(), b"kept");
+
+        client.remove(&file).await.unwrap();
+        client.remove(&dir).await.unwrap();
+        assert!(matches!(
+            client.list_dir(&dir).await,
+            Err(FsError::FileNotFound { .. })
+        ));
+    }
+
+    fn names(path: &str) -> Vec<String> {
+        split_path(path)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(names("/a/b/c"), ["a", "b", "c"]);
+        assert_eq!(names("a//b/./c/"), ["a", "b", "c"]);
+        assert_eq!(names("/a/../b"), ["a", "..", "b"]);
+        assert!(names("/").is_empty());
+        assert!(names("").is_empty());
+
+        let (dir, name) = split_parent("/a/b/c").unwrap().unwrap();
+        assert_eq!(dir, [ByteString::from("a"), ByteString::from("b")]);
+        assert_eq!(name, "c");
+        assert!(split_parent("/./").unwrap().is_none());
+
+        let long = format!("/a/{}", "x".repeat(TiFs::MAX_NAME_LEN as usize + 1));
+        assert!(matches!(
+            split_path(&long),
+            Err(FsError::NameTooLong { .. })
+        ));
+    }
+}
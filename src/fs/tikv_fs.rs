@@ -1,8 +1,15 @@
+use std::convert::TryInto;
 use std::fmt::{self, Debug};
 use std::future::Future;
 use std::matches;
+use std::mem::size_of;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -10,33 +17,90 @@ use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::consts::FOPEN_DIRECT_IO;
 use fuser::*;
+use humantime::parse_duration;
 use libc::{F_RDLCK, F_UNLCK, F_WRLCK, SEEK_CUR, SEEK_END, SEEK_SET};
 use parse_size::parse_size;
 use tikv_client::{Config, TransactionClient};
-use tokio::time::sleep;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout, timeout_at};
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use super::async_fs::AsyncFileSystem;
-use super::dir::Directory;
+use super::atime::AtimePolicy;
+use super::cache::{AttrCache, BlockCache, DentryCache};
+use super::client::RobustnessConfig;
+use super::commit::CommitPolicy;
+use super::crypto::BlockCipher;
+use super::dir::{listing, Directory};
 use super::error::{FsError, Result};
+use super::file_handler::{checked_range, offset_from, FileHandler, FileHandlers};
+use super::health::{self, HealthChecker, SharedClient};
+use super::inode::RecordLock;
+#[cfg(target_os = "linux")]
+use super::inode::{FS_IOC32_GETFLAGS, FS_IOC32_SETFLAGS, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS};
 use super::key::ROOT_INODE;
-use super::mode::make_mode;
+use super::meta::Meta;
+use super::metrics::{self, Metrics};
+use super::mode::{
+    check_access, check_chmod, check_chown, check_regular_file, chown_perm, make_mode,
+    parse_groups, AttrOverride,
+};
+use super::readahead::Readahead;
 use super::reply::{
-    get_time, Attr, Create, Data, Dir, Entry, Lock, Lseek, Open, StatFs, Write, Xattr,
+    Attr, Bmap, Create, Data, Dir, DirPlus, Entry, Ioctl, Lock, Lseek, Open, StatFs, Write, Xattr,
 };
+use super::scrub::Scrubber;
 use super::transaction::Txn;
+use super::write_buffer::{InodeBuffers, WriteBuffer, WriteBuffers};
 use crate::MountOption;
 
 pub const DIR_SELF: ByteString = ByteString::from_static(".");
 pub const DIR_PARENT: ByteString = ByteString::from_static("..");
 
+// the ioctls of `cp --reflink`, cloning a file or a range of it from another fd
+#[cfg(target_os = "linux")]
+pub const FICLONE: u32 = 0x4004_9409;
+#[cfg(target_os = "linux")]
+pub const FICLONERANGE: u32 = 0x4020_940d;
+
 pub struct TiFs {
     pub pd_endpoints: Vec<String>,
     pub config: Config,
-    pub client: TransactionClient,
+    pub client: SharedClient,
     pub direct_io: bool,
     pub block_size: u64,
+    pub inline_data_threshold: u64,
+    pub inline_divisor: u64,
     pub max_size: Option<u64>,
+    pub disable_atime: bool,
+    pub atime_policy: AtimePolicy,
+    pub dedup: bool,
+    pub cipher: Option<Arc<BlockCipher>>,
+    pub checksum: bool,
+    pub posix_locks: bool,
+    pub commit_policy: CommitPolicy,
+    pub attr_override: AttrOverride,
+    pub region_retry_limit: u32,
+    pub spin_delay: Option<Duration>,
+    pub op_timeout: Option<Duration>,
+    pub attr_cache: Option<AttrCache>,
+    pub dentry_cache: Option<DentryCache>,
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub entry_timeout: Duration,
+    pub attr_timeout: Duration,
+    pub file_handlers: FileHandlers,
+    pub concurrency: Semaphore,
+    pub max_concurrency: u32,
+    pub scan_limit: u32,
+    pub scrub_interval: Option<Duration>,
+    pub scrub_batch: u64,
+    pub max_write: u32,
+    pub max_readahead: u32,
+    pub metrics: Metrics,
+    pub write_buffers: Option<WriteBuffers>,
+    pub readahead: Option<Arc<Readahead>>,
+    pub notifier: RwLock<Option<Notifier>>,
+    pub mountpoint: RwLock<Option<PathBuf>>,
 }
 
 type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
@@ -44,21 +108,306 @@ type BoxedFuture<'a, T> = Pin<Box<dyn 'a + Send + Future<Output = Result<T>>>>;
 impl TiFs {
     pub const SCAN_LIMIT: u32 = 1 << 10;
     pub const DEFAULT_BLOCK_SIZE: u64 = 1 << 16;
+    pub const MIN_BLOCK_SIZE: u64 = Txn::INLINE_DATA_THRESHOLD_BASE;
+    // a block is a single value in TiKV, it must fit in a raft entry
+    pub const MAX_BLOCK_SIZE: u64 = 1 << 22;
     pub const MAX_NAME_LEN: u32 = 1 << 8;
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
+    pub const DEFAULT_MAX_CONCURRENCY: u32 = 1 << 8;
+    pub const DESTROY_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_MOUNT_RETRY_DELAY: Duration = Duration::from_secs(1);
+    // a read request is replied in a single buffer, so the kernel is asked to keep it small
+    pub const MAX_READ: u64 = 1 << 20;
+    // region errors are retried with exponential backoff, from 10ms up to 640ms
+    pub const DEFAULT_REGION_RETRY_LIMIT: u32 = 7;
+    pub const REGION_BACKOFF: Duration = Duration::from_millis(10);
+    // operations retried more times are logged as contended
+    pub const RETRY_LOG_THRESHOLD: u32 = 3;
+    // waiting record locks check for conflicting locks at this interval
+    pub const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-    #[instrument]
+    /// Blocks, inline data and symlink targets are encrypted by `cipher` if it's given.
+    ///
+    /// Mount options take precedence over the robustness config.
+    #[instrument(skip(cipher))]
     pub async fn construct<S>(
         pd_endpoints: Vec<S>,
         cfg: Config,
+        robustness: RobustnessConfig,
         options: Vec<MountOption>,
+        cipher: Option<BlockCipher>,
     ) -> anyhow::Result<Self>
     where
         S: Clone + Debug + Into<String>,
     {
-        let client = TransactionClient::new_with_config(pd_endpoints.clone(), cfg.clone())
-            .await
-            .map_err(|err| anyhow!("{}", err))?;
-        info!("connected to pd endpoints: {:?}", pd_endpoints);
+        let grpc_timeout = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::GrpcTimeout(timeout) => Some(timeout),
+                _ => None,
+            })
+            .or(robustness.grpc_timeout.as_ref())
+            .and_then(|timeout| {
+                parse_duration(timeout)
+                    .map_err(|err| error!("fail to parse grpc_timeout({}): {}", timeout, err))
+                    .ok()
+            });
+        // the config is shared by the scrubber and clients rebuilt by the health checker
+        let cfg = match grpc_timeout {
+            Some(timeout) => {
+                debug!("grpc timeout: {:?}", timeout);
+                cfg.with_timeout(timeout)
+            }
+            None => cfg,
+        };
+        let mount_retries = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::MountRetries(retries) => retries
+                    .parse()
+                    .map_err(|err| error!("fail to parse mount_retries({}): {}", retries, err))
+                    .ok(),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let mount_retry_delay = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::MountRetryDelay(delay) => parse_duration(delay)
+                    .map_err(|err| error!("fail to parse mount_retry_delay({}): {}", delay, err))
+                    .ok(),
+                _ => None,
+            })
+            .unwrap_or(Self::DEFAULT_MOUNT_RETRY_DELAY);
+        // the cluster may start later than a mount at boot
+        let (client, latency) = health::retry(mount_retries, mount_retry_delay, || async {
+            let client = TransactionClient::new_with_config(pd_endpoints.clone(), cfg.clone())
+                .await
+                .map_err(|err| anyhow!("{}", err))?;
+            info!("connected to pd endpoints: {:?}", pd_endpoints);
+            // fail the mount upfront rather than every operation on a misconfigured cluster
+            let latency = health::ping(&client, true).await.map_err(|err| {
+                anyhow!(
+                    "the cluster is unavailable by pd endpoints {:?}, a write and read back fails: {}",
+                    pd_endpoints,
+                    err
+                )
+            })?;
+            Ok::<_, anyhow::Error>((client, latency))
+        })
+        .await?;
+        debug!("cluster ping: {:?}", latency);
+        let explicit_block_size = options.iter().find_map(|option| match option {
+            MountOption::BlkSize(size) => parse_size(size)
+                .map_err(|err| {
+                    error!("fail to parse blksize({}): {}", size, err);
+                    err
+                })
+                .ok(),
+            _ => None,
+        });
+        let commit_policy = CommitPolicy::from_options(&options);
+        let stored = Self::stored_meta(&client, commit_policy).await?;
+        let stored_block_size = stored.as_ref().map(|meta| meta.block_size);
+        let block_size = Self::choose_block_size(explicit_block_size, stored_block_size)?;
+        debug!("block size: {}", block_size);
+        Self::check_block_size(block_size)?;
+        let inline_divisor = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::InlineDivisor(divisor) => {
+                    Self::parse_inline_divisor(divisor, block_size)
+                }
+                _ => None,
+            })
+            .or_else(|| stored.as_ref().and_then(|meta| meta.inline_divisor))
+            .unwrap_or(Txn::INLINE_DATA_THRESHOLD_BASE);
+        let inline_data_threshold = if options
+            .iter()
+            .any(|option| matches!(option, MountOption::NoInline))
+        {
+            0
+        } else {
+            options
+                .iter()
+                .find_map(|option| match option {
+                    MountOption::InlineThreshold(size) => parse_size(size)
+                        .map_err(|err| {
+                            error!("fail to parse inline_threshold({}): {}", size, err);
+                            err
+                        })
+                        .map(|size| {
+                            if size > block_size {
+                                warn!(
+                                    "inline threshold({}) exceeds block size({}), use block size instead",
+                                    size, block_size
+                                );
+                            }
+                            size.min(block_size)
+                        })
+                        .ok(),
+                    _ => None,
+                })
+                // the threshold the filesystem is made with, unless it's given
+                .or_else(|| stored.as_ref().and_then(|meta| meta.inline_threshold))
+                .unwrap_or(block_size / inline_divisor)
+        };
+        debug!("inline data threshold: {}", inline_data_threshold);
+
+        let scan_limit = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::ScanLimit(limit) => limit
+                    .parse()
+                    .map_err(|err| {
+                        error!("fail to parse scan_limit({}): {}", limit, err);
+                        err
+                    })
+                    .ok()
+                    .filter(|limit| *limit > 0),
+                _ => None,
+            })
+            .unwrap_or(Self::SCAN_LIMIT);
+        debug!("scan limit: {}", scan_limit);
+
+        let scrub_interval = options.iter().find_map(|option| match option {
+            MountOption::Scrub(interval) => parse_duration(interval)
+                .map_err(|err| {
+                    error!("fail to parse scrub({}): {}", interval, err);
+                    err
+                })
+                .ok(),
+            _ => None,
+        });
+        let scrub_batch = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::ScrubBatch(batch) => batch
+                    .parse()
+                    .map_err(|err| {
+                        error!("fail to parse scrub_batch({}): {}", batch, err);
+                        err
+                    })
+                    .ok(),
+                _ => None,
+            })
+            .unwrap_or(Scrubber::DEFAULT_BATCH);
+        let client: SharedClient = Arc::new(RwLock::new(Arc::new(client)));
+        let health_interval = options.iter().find_map(|option| match option {
+            MountOption::HealthCheck(interval) => parse_duration(interval)
+                .map_err(|err| {
+                    error!("fail to parse health_check({}): {}", interval, err);
+                    err
+                })
+                .ok(),
+            _ => None,
+        });
+        if let Some(interval) = health_interval {
+            let threshold = options
+                .iter()
+                .find_map(|option| match option {
+                    MountOption::HealthThreshold(threshold) => threshold
+                        .parse()
+                        .map_err(|err| {
+                            error!("fail to parse health_threshold({}): {}", threshold, err);
+                            err
+                        })
+                        .ok(),
+                    _ => None,
+                })
+                .unwrap_or(HealthChecker::DEFAULT_THRESHOLD);
+            info!(
+                "start health checker, interval: {:?}, threshold: {}",
+                interval, threshold
+            );
+            tokio::spawn(
+                HealthChecker::new(
+                    pd_endpoints.clone().into_iter().map(Into::into).collect(),
+                    cfg.clone(),
+                    client.clone(),
+                    interval,
+                    threshold,
+                )
+                .run(),
+            );
+        }
+
+        let max_concurrency = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::MaxConcurrency(limit) => limit
+                    .parse()
+                    .map_err(|err| {
+                        error!("fail to parse max_concurrency({}): {}", limit, err);
+                        err
+                    })
+                    .ok()
+                    .filter(|limit| *limit > 0),
+                _ => None,
+            })
+            .unwrap_or(Self::DEFAULT_MAX_CONCURRENCY);
+        debug!("max concurrency: {}", max_concurrency);
+
+        let max_open = options.iter().find_map(|option| match option {
+            MountOption::MaxOpen(limit) => limit
+                .parse()
+                .map_err(|err| {
+                    error!("fail to parse max_open({}): {}", limit, err);
+                    err
+                })
+                .ok()
+                .filter(|limit| *limit > 0),
+            _ => None,
+        });
+        debug!("max open: {:?}", max_open);
+
+        let metrics = Metrics::new()?;
+        let metrics_addr = options.iter().find_map(|option| match option {
+            MountOption::MetricsAddr(addr) => addr
+                .parse::<SocketAddr>()
+                .map_err(|err| {
+                    error!("fail to parse metrics_addr({}): {}", addr, err);
+                    err
+                })
+                .ok(),
+            _ => None,
+        });
+        if let Some(addr) = metrics_addr {
+            info!("serve metrics on {}", addr);
+            let registry = metrics.registry();
+            tokio::spawn(async move {
+                if let Err(err) = metrics::serve(registry, addr).await {
+                    error!("fail to serve metrics on {}: {}", addr, err);
+                }
+            });
+        }
+
+        // align max_write to the block size, so that a block is not written
+        // piecemeal by several requests, each doing read-modify-write on it.
+        let max_write = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::MaxWrite(size) => parse_size(size)
+                    .map_err(|err| error!("fail to parse max_write({}): {}", size, err))
+                    .ok(),
+                _ => None,
+            })
+            .map_or(block_size as u32, |size| {
+                Self::whole_blocks(size, block_size)
+            });
+        // reading ahead beyond `max_read` makes no larger requests, by default
+        let max_readahead = options
+            .iter()
+            .find_map(|option| match option {
+                MountOption::MaxReadahead(size) => parse_size(size)
+                    .map_err(|err| error!("fail to parse max_readahead({}): {}", size, err))
+                    .ok(),
+                _ => None,
+            })
+            .map_or(Self::whole_blocks(Self::MAX_READ, block_size), |size| {
+                size.min(u32::MAX as u64) as u32
+            });
+
         Ok(TiFs {
             client,
             pd_endpoints: pd_endpoints.clone().into_iter().map(Into::into).collect(),
@@ -66,22 +415,75 @@ impl TiFs {
             direct_io: options
                 .iter()
                 .any(|option| matches!(option, MountOption::DirectIO)),
-            block_size: options
+            block_size,
+            inline_data_threshold,
+            inline_divisor,
+            disable_atime: options
+                .iter()
+                .any(|option| matches!(option, MountOption::DisableAtime)),
+            atime_policy: options
                 .iter()
+                .rev()
                 .find_map(|option| match option {
-                    MountOption::BlkSize(size) => parse_size(size)
-                        .map_err(|err| {
-                            error!("fail to parse blksize({}): {}", size, err);
-                            err
-                        })
-                        .map(|size| {
-                            debug!("block size: {}", size);
-                            size
-                        })
-                        .ok(),
+                    MountOption::StrictAtime => Some(AtimePolicy::Strict),
+                    MountOption::RelAtime => Some(AtimePolicy::Relative),
+                    MountOption::NoAtime => Some(AtimePolicy::Never),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+            dedup: options
+                .iter()
+                .any(|option| matches!(option, MountOption::Dedup)),
+            cipher: cipher.map(Arc::new),
+            checksum: options
+                .iter()
+                .any(|option| matches!(option, MountOption::Checksum)),
+            posix_locks: options
+                .iter()
+                .any(|option| matches!(option, MountOption::PosixLocks)),
+            commit_policy,
+            region_retry_limit: robustness
+                .retry_limit
+                .unwrap_or(Self::DEFAULT_REGION_RETRY_LIMIT),
+            spin_delay: robustness.spin_delay.as_ref().and_then(|delay| {
+                parse_duration(delay)
+                    .map_err(|err| error!("fail to parse spin-delay({}): {}", delay, err))
+                    .ok()
+            }),
+            op_timeout: options
+                .iter()
+                .find_map(|option| match option {
+                    MountOption::OpTimeout(timeout) => Some(timeout),
                     _ => None,
                 })
-                .unwrap_or(Self::DEFAULT_BLOCK_SIZE),
+                .or(robustness.op_timeout.as_ref())
+                .and_then(|timeout| {
+                    parse_duration(timeout)
+                        .map_err(|err| error!("fail to parse op_timeout({}): {}", timeout, err))
+                        .ok()
+                }),
+            attr_override: AttrOverride {
+                uid: options.iter().find_map(|option| match option {
+                    MountOption::Uid(uid) => Self::parse_id("uid", uid, 10),
+                    _ => None,
+                }),
+                gid: options.iter().find_map(|option| match option {
+                    MountOption::Gid(gid) => Self::parse_id("gid", gid, 10),
+                    _ => None,
+                }),
+                fmask: options.iter().find_map(|option| match option {
+                    MountOption::Fmask(mask) => {
+                        Self::parse_id("fmask", mask, 8).map(|m| (m & 0o777) as u16)
+                    }
+                    _ => None,
+                }),
+                dmask: options.iter().find_map(|option| match option {
+                    MountOption::Dmask(mask) => {
+                        Self::parse_id("dmask", mask, 8).map(|m| (m & 0o777) as u16)
+                    }
+                    _ => None,
+                }),
+            },
             max_size: options.iter().find_map(|option| match option {
                 MountOption::MaxSize(size) => parse_size(size)
                     .map_err(|err| {
@@ -95,22 +497,256 @@ impl TiFs {
                     .ok(),
                 _ => None,
             }),
+            attr_cache: options.iter().find_map(|option| match option {
+                MountOption::AttrCache(ttl) => parse_duration(ttl)
+                    .map_err(|err| {
+                        error!("fail to parse attrcache({}): {}", ttl, err);
+                        err
+                    })
+                    .map(|ttl| {
+                        debug!("attr cache ttl: {:?}", ttl);
+                        AttrCache::new(ttl)
+                    })
+                    .ok(),
+                _ => None,
+            }),
+            dentry_cache: options.iter().find_map(|option| match option {
+                MountOption::DentryCache(ttl) => parse_duration(ttl)
+                    .map_err(|err| {
+                        error!("fail to parse dentrycache({}): {}", ttl, err);
+                        err
+                    })
+                    .map(|ttl| {
+                        debug!("dentry cache ttl: {:?}", ttl);
+                        DentryCache::new(ttl)
+                    })
+                    .ok(),
+                _ => None,
+            }),
+            block_cache: options.iter().find_map(|option| match option {
+                MountOption::BlockCache(size) => parse_size(size)
+                    .map_err(|err| {
+                        error!("fail to parse blockcache({}): {}", size, err);
+                        err
+                    })
+                    .ok()
+                    .filter(|size| *size > 0)
+                    .map(|size| {
+                        debug!("block cache size: {}", size);
+                        Arc::new(BlockCache::new(size))
+                    }),
+                _ => None,
+            }),
+            entry_timeout: options
+                .iter()
+                .find_map(|option| match option {
+                    MountOption::EntryTimeout(ttl) => parse_duration(ttl)
+                        .map_err(|err| {
+                            error!("fail to parse entry_timeout({}): {}", ttl, err);
+                            err
+                        })
+                        .ok(),
+                    _ => None,
+                })
+                .unwrap_or(Self::DEFAULT_TTL),
+            attr_timeout: options
+                .iter()
+                .find_map(|option| match option {
+                    MountOption::AttrTimeout(ttl) => parse_duration(ttl)
+                        .map_err(|err| {
+                            error!("fail to parse attr_timeout({}): {}", ttl, err);
+                            err
+                        })
+                        .ok(),
+                    _ => None,
+                })
+                .unwrap_or(Self::DEFAULT_TTL),
+            file_handlers: FileHandlers::new(max_open),
+            write_buffers: options
+                .iter()
+                .any(|option| matches!(option, MountOption::Writeback))
+                .then(WriteBuffers::default),
+            readahead: options
+                .iter()
+                .find_map(|option| match option {
+                    MountOption::Readahead(size) => parse_size(size)
+                        .map_err(|err| {
+                            error!("fail to parse readahead({}): {}", size, err);
+                            err
+                        })
+                        .ok(),
+                    _ => None,
+                })
+                .filter(|size| *size > 0)
+                .map(|size| {
+                    debug!("readahead: {}", size);
+                    Arc::new(Readahead::new(size))
+                }),
+            metrics,
+            concurrency: Semaphore::new(max_concurrency as usize),
+            max_concurrency,
+            scan_limit,
+            scrub_interval,
+            scrub_batch,
+            max_write,
+            max_readahead,
+            notifier: RwLock::new(None),
+            mountpoint: RwLock::new(None),
         })
     }
 
+    /// The stored meta, `None` if the filesystem isn't made yet.
+    async fn stored_meta(
+        client: &TransactionClient,
+        commit_policy: CommitPolicy,
+    ) -> Result<Option<Meta>> {
+        let mut txn = Txn::begin_optimistic(
+            client,
+            Self::DEFAULT_BLOCK_SIZE,
+            None,
+            Self::MAX_NAME_LEN,
+            0,
+            true,
+            AtimePolicy::Never,
+            false,
+            None,
+            commit_policy,
+        )
+        .await?;
+        let meta = txn.read_meta().await;
+        txn.rollback().await?;
+        meta
+    }
+
+    /// Mount with the block size given by `blksize`, which must be the stored one if any.
+    /// Without `blksize`, an existing filesystem is mounted with its stored block size.
+    fn choose_block_size(explicit: Option<u64>, stored: Option<u64>) -> Result<u64> {
+        match (explicit, stored) {
+            (Some(explicit), Some(stored)) if explicit != stored => {
+                Err(FsError::block_size_conflict(stored, explicit))
+            }
+            (Some(explicit), _) => Ok(explicit),
+            (None, Some(stored)) => {
+                if stored != Self::DEFAULT_BLOCK_SIZE {
+                    warn!(
+                        "blksize is not given, use the stored block size({}) instead of {}",
+                        stored,
+                        Self::DEFAULT_BLOCK_SIZE
+                    );
+                }
+                Ok(stored)
+            }
+            (None, None) => Ok(Self::DEFAULT_BLOCK_SIZE),
+        }
+    }
+
+    /// The block size must be a power of two, so that blocks align with pages and `max_write`.
+    pub fn check_block_size(size: u64) -> Result<()> {
+        if size.is_power_of_two() && (Self::MIN_BLOCK_SIZE..=Self::MAX_BLOCK_SIZE).contains(&size) {
+            Ok(())
+        } else {
+            Err(FsError::InvalidBlockSize {
+                size,
+                min: Self::MIN_BLOCK_SIZE,
+                max: Self::MAX_BLOCK_SIZE,
+            })
+        }
+    }
+
+    /// The largest read request, in whole blocks.
+    pub fn max_read(&self) -> u32 {
+        Self::whole_blocks(Self::MAX_READ, self.block_size)
+    }
+
+    /// Round a request size down to whole blocks, at least one block and fitting in 32 bits.
+    pub fn whole_blocks(size: u64, block_size: u64) -> u32 {
+        (size.max(block_size).min(u32::MAX as u64) / block_size * block_size) as u32
+    }
+
+    fn client(&self) -> Arc<TransactionClient> {
+        self.client.read().unwrap().clone()
+    }
+
+    fn cached_attr(&self, ino: u64) -> Option<(FileAttr, u64)> {
+        self.attr_cache.as_ref().and_then(|cache| cache.get(ino))
+    }
+
+    // the epoch of the attribute cache, taken before the transaction reading attributes to cache
+    fn attr_epoch(&self) -> u64 {
+        self.attr_cache.as_ref().map_or(0, AttrCache::epoch)
+    }
+
+    fn cache_attr(&self, attr: FileAttr, generation: u64, epoch: u64) {
+        if let Some(cache) = &self.attr_cache {
+            cache.insert(attr, generation, epoch)
+        }
+    }
+
+    // an entry resolved without any request, if both the name and the attributes are cached
+    fn cached_entry(&self, parent: u64, name: &ByteString) -> Option<(FileAttr, u64)> {
+        let ino = self.dentry_cache.as_ref()?.get(parent, name)?;
+        self.cached_attr(ino)
+    }
+
+    // the epoch of the dentry cache, taken before the transaction reading entries to cache
+    fn dentry_epoch(&self) -> u64 {
+        self.dentry_cache.as_ref().map_or(0, DentryCache::epoch)
+    }
+
+    fn cache_dentry(&self, parent: u64, name: ByteString, ino: u64, epoch: u64) {
+        if let Some(cache) = &self.dentry_cache {
+            cache.insert(parent, name, ino, epoch)
+        }
+    }
+
+    fn invalidate_caches(&self, txn: &Txn) {
+        if let Some(cache) = &self.attr_cache {
+            txn.saved_inodes().for_each(|ino| cache.invalidate(ino))
+        }
+        if let Some(cache) = &self.dentry_cache {
+            txn.changed_dirs()
+                .for_each(|parent| cache.invalidate(parent))
+        }
+        if let Some(readahead) = &self.readahead {
+            txn.saved_inodes().for_each(|ino| readahead.invalidate(ino))
+        }
+        if let Some(cache) = &self.block_cache {
+            txn.written_inodes().for_each(|ino| cache.invalidate(ino))
+        }
+    }
+
+    // run `future` until the deadline of the operation, if it has one
+    async fn before_deadline<T>(
+        &self,
+        deadline: Option<Instant>,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match (deadline, self.op_timeout) {
+            (Some(deadline), Some(op_timeout)) => timeout_at(deadline.into(), future)
+                .await
+                .unwrap_or(Err(FsError::Timeout(op_timeout))),
+            _ => future.await,
+        }
+    }
+
     #[instrument(skip(txn, f))]
-    async fn process_txn<F, T>(&self, txn: &mut Txn, f: F) -> Result<T>
+    async fn process_txn<F, T>(&self, deadline: Option<Instant>, txn: &mut Txn, f: F) -> Result<T>
     where
         T: 'static + Send,
         F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
     {
-        match f(self, txn).await {
+        // the commit is never cut short, an operation timed out has not committed
+        match self.before_deadline(deadline, f(self, txn)).await {
             Ok(v) => {
                 let commit_start = SystemTime::now();
+                let commit_timer = self.metrics.commit_duration.start_timer();
                 txn.commit().await?;
+                commit_timer.observe_duration();
+                self.invalidate_caches(txn);
                 debug!(
-                    "transaction committed in {} ms",
-                    commit_start.elapsed().unwrap().as_millis()
+                    "transaction committed in {} ms after {} reads",
+                    commit_start.elapsed().unwrap().as_millis(),
+                    txn.reads()
                 );
                 Ok(v)
             }
@@ -122,58 +758,495 @@ impl TiFs {
         }
     }
 
-    async fn with_optimistic<F, T>(&self, f: F) -> Result<T>
-    where
-        T: 'static + Send,
-        F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
-    {
-        let mut txn = Txn::begin_optimistic(
-            &self.client,
-            self.block_size,
-            self.max_size,
-            Self::MAX_NAME_LEN,
-        )
-        .await?;
-        self.process_txn(&mut txn, f).await
+    async fn with_optimistic<F, T>(&self, deadline: Option<Instant>, f: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: for<'a> FnOnce(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
+    {
+        let begin = async {
+            // transactions beyond the limit queue here
+            let permit = self
+                .concurrency
+                .acquire()
+                .await
+                .map_err(|err| FsError::UnknownError(err.to_string()))?;
+            let txn = Txn::begin_optimistic(
+                &self.client(),
+                self.block_size,
+                self.max_size,
+                Self::MAX_NAME_LEN,
+                self.inline_data_threshold,
+                self.disable_atime,
+                self.atime_policy,
+                self.dedup,
+                self.cipher.clone(),
+                self.commit_policy,
+            )
+            .await?;
+            Ok((permit, txn))
+        };
+        let (_permit, txn) = self.before_deadline(deadline, begin).await?;
+        let mut txn = txn
+            .with_block_cache(self.block_cache.clone())
+            .with_scan_limit(self.scan_limit)
+            .with_checksum(self.checksum);
+        self.process_txn(deadline, &mut txn, f).await
+    }
+
+    async fn spin<F, T>(&self, delay: Option<Duration>, mut f: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
+    {
+        // the configured delay applies to every spin
+        let delay = delay.or(self.spin_delay);
+        let deadline = self
+            .op_timeout
+            .map(|op_timeout| Instant::now() + op_timeout);
+        let mut region_retries = 0;
+        let mut retries = 0;
+        let result = loop {
+            match self.with_optimistic(deadline, &mut f).await {
+                Ok(v) => break Ok(v),
+                Err(FsError::KeyError(err)) => {
+                    trace!("spin because of a key error({})", err);
+                    self.metrics.txn_retries.inc();
+                    retries += 1;
+                    if let Some(time) = delay {
+                        sleep(time).await;
+                    }
+                }
+                Err(FsError::RegionError(err)) if region_retries < self.region_retry_limit => {
+                    let shift = region_retries.min(Self::DEFAULT_REGION_RETRY_LIMIT - 1);
+                    let backoff = Self::REGION_BACKOFF * (1 << shift);
+                    debug!("retry in {:?} because of a region error({})", backoff, err);
+                    self.metrics.txn_retries.inc();
+                    region_retries += 1;
+                    retries += 1;
+                    sleep(backoff).await;
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        if retries > 0 {
+            self.metrics.op_retries.observe(retries as f64);
+        }
+        // the operation and the inode are in the span of the request
+        if retries > Self::RETRY_LOG_THRESHOLD {
+            debug!("operation retried {} times", retries);
+        }
+        result
+    }
+
+    pub(crate) async fn spin_no_delay<F, T>(&self, f: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
+    {
+        self.spin(None, f).await
+    }
+
+    /// Check the stored meta against the options, and make the root directory
+    /// of a new filesystem, owned by `uid` and `gid`.
+    pub async fn prepare(&self, gid: u32, uid: u32) -> Result<()> {
+        self.spin_no_delay(move |fs, txn| {
+            Box::pin(async move {
+                info!("initializing tifs on {:?} ...", &fs.pd_endpoints);
+                if let Some(meta) = txn.read_meta().await? {
+                    if meta.importing {
+                        let err = FsError::UnfinishedImport;
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    if meta.block_size != txn.block_size() {
+                        let err = FsError::block_size_conflict(meta.block_size, txn.block_size());
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    if meta.disable_atime != txn.disable_atime() {
+                        let err = FsError::DisableAtimeConflict {
+                            origin: meta.disable_atime,
+                            new: txn.disable_atime(),
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    if meta.dedup != txn.dedup() {
+                        let err = FsError::DedupConflict {
+                            origin: meta.dedup,
+                            new: txn.dedup(),
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    if meta.encrypted != txn.encrypted() {
+                        let err = FsError::EncryptionConflict {
+                            origin: meta.encrypted,
+                            new: txn.encrypted(),
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    if meta.checksum != txn.checksum() {
+                        let err = FsError::ChecksumConflict {
+                            origin: meta.checksum,
+                            new: txn.checksum(),
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                    // filesystems made before the divisor was recorded use the default
+                    let origin = meta
+                        .inline_divisor
+                        .unwrap_or(Txn::INLINE_DATA_THRESHOLD_BASE);
+                    if origin != fs.inline_divisor {
+                        let err = FsError::InlineDivisorConflict {
+                            origin,
+                            new: fs.inline_divisor,
+                        };
+                        error!("{}", err);
+                        return Err(err);
+                    }
+                }
+
+                let root_inode = txn.read_inode(ROOT_INODE).await;
+                if let Err(FsError::InodeNotFound { inode: _ }) = root_inode {
+                    let attr = txn
+                        .mkdir(
+                            0,
+                            Default::default(),
+                            make_mode(FileType::Directory, 0o777),
+                            gid,
+                            uid,
+                        )
+                        .await?;
+                    debug!("make root directory {:?}", &attr);
+                    if let Some(mut meta) = txn.read_meta().await? {
+                        meta.inline_divisor = Some(fs.inline_divisor);
+                        meta.inline_threshold = Some(fs.inline_data_threshold);
+                        txn.save_meta(&meta).await?;
+                    }
+                    Ok(())
+                } else {
+                    root_inode.map(|_| ())
+                }
+            })
+        })
+        .await
+    }
+
+    /// Start the background scrubber if it's enabled, it verifies the filesystem by the settings
+    /// in the stored meta, which are checked against the options by `prepare`.
+    async fn start_scrubber(&self) -> Result<()> {
+        let interval = match self.scrub_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        let meta = match self
+            .spin_no_delay(|_, txn| Box::pin(txn.read_meta()))
+            .await?
+        {
+            Some(meta) => meta,
+            None => return Ok(()),
+        };
+        let client =
+            TransactionClient::new_with_config(self.pd_endpoints.clone(), self.config.clone())
+                .await?;
+        info!(
+            "start scrubber, interval: {:?}, batch: {}",
+            interval, self.scrub_batch
+        );
+        tokio::spawn(
+            Scrubber::new(
+                client,
+                meta.block_size,
+                self.commit_policy,
+                self.scan_limit,
+                meta.checksum,
+                interval,
+                self.scrub_batch,
+            )
+            .run(),
+        );
+        Ok(())
+    }
+
+    /// The flags replied to the kernel and the handler for a file opened by `flags`.
+    fn file_handler(&self, flags: i32) -> (u32, FileHandler) {
+        let mut open_flags = 0;
+        #[cfg(target_os = "linux")]
+        if self.direct_io || flags & libc::O_DIRECT != 0 {
+            open_flags |= FOPEN_DIRECT_IO;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.direct_io {
+            open_flags |= FOPEN_DIRECT_IO;
+        }
+        let mut handler = FileHandler::new(0, flags);
+        handler.direct = open_flags & FOPEN_DIRECT_IO != 0;
+        (open_flags, handler)
+    }
+
+    async fn read_dir(&self, ino: u64) -> Result<Directory> {
+        self.spin_no_delay(move |_, txn| Box::pin(txn.read_dir(ino)))
+            .await
+    }
+
+    async fn read_inode(&self, ino: u64) -> Result<FileAttr> {
+        Ok(self.read_entry(ino).await?.0)
+    }
+
+    /// The inode an fd of process `pid` refers to, which must be a file of this filesystem.
+    #[cfg(target_os = "linux")]
+    async fn inode_of_fd(&self, pid: u32, fd: i64) -> Result<u64> {
+        let mountpoint = self.mountpoint.read().unwrap().clone();
+        let mountpoint = mountpoint.ok_or(FsError::CloneAcrossFs { fd })?;
+        // both are stated by the kernel, requests of this filesystem are served meanwhile
+        let root = tokio::fs::metadata(mountpoint).await?;
+        let source = tokio::fs::metadata(format!("/proc/{}/fd/{}", pid, fd)).await?;
+        if source.dev() != root.dev() {
+            return Err(FsError::CloneAcrossFs { fd });
+        }
+        Ok(source.ino())
+    }
+
+    /// Clone `len` bytes from `offset_in` of `ino_in` to `offset_out` of `ino_out`, as
+    /// `FICLONERANGE`, all of the source from `offset_in` if `len` is 0.
+    ///
+    /// Ranges must be of whole blocks but for the tail of the source, they're cloned in
+    /// transactions of at most `Txn::CLONE_BATCH_BLOCKS` blocks.
+    #[cfg(target_os = "linux")]
+    async fn clone_range(
+        &self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<()> {
+        self.flush_buffers(ino_in).await?;
+        self.flush_buffers(ino_out).await?;
+        let size = self.read_inode(ino_in).await?.size;
+        let invalid = |ino, offset: u64| FsError::InvalidOffset {
+            ino,
+            offset: offset as i64,
+        };
+        let end = match len {
+            0 => size.max(offset_in),
+            len => offset_in
+                .checked_add(len)
+                .ok_or_else(|| invalid(ino_in, offset_in))?,
+        };
+        if offset_in % self.block_size != 0 || (end % self.block_size != 0 && end < size) {
+            return Err(invalid(ino_in, offset_in));
+        }
+        if offset_out % self.block_size != 0 || offset_out.checked_add(end - offset_in).is_none() {
+            return Err(invalid(ino_out, offset_out));
+        }
+        // a file is never cloned onto itself
+        let overlapped = offset_in < offset_out + (end - offset_in) && offset_out < end;
+        if ino_in == ino_out && overlapped {
+            return Err(invalid(ino_out, offset_out));
+        }
+
+        let end = end.min(size);
+        let mut cloned = 0;
+        while offset_in + cloned < end {
+            let (offset_in, offset_out) = (offset_in + cloned, offset_out + cloned);
+            let len = end - offset_in;
+            let copied = self
+                .spin_no_delay(move |_, txn| {
+                    Box::pin(txn.copy_range(ino_in, offset_in, ino_out, offset_out, len))
+                })
+                .await?;
+            if copied == 0 {
+                break;
+            }
+            cloned += copied as u64;
+        }
+        self.metrics.written_bytes.inc_by(cloned);
+        self.notify_inval_attr(ino_out);
+        Ok(())
+    }
+
+    /// Read attributes of an inode along with its generation.
+    async fn read_entry(&self, ino: u64) -> Result<(FileAttr, u64)> {
+        if let Some(cached) = self.cached_attr(ino) {
+            return Ok(cached);
+        }
+        let epoch = self.attr_epoch();
+        let ino = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
+            .await?;
+        self.cache_attr(ino.file_attr, ino.generation, epoch);
+        Ok((ino.file_attr, ino.generation))
+    }
+
+    /// Ask the kernel to drop cached attributes of the inode, so that watchers see the change.
+    ///
+    /// Notifications are sent from a blocking task, they must not block the reply of the request.
+    fn notify_inval_attr(&self, ino: u64) {
+        let notifier = match self.notifier.read().unwrap().clone() {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        tokio::task::spawn_blocking(move || {
+            // a negative offset invalidates attributes only, cached pages are kept
+            if let Err(err) = notifier.inval_inode(ino, -1, 0) {
+                // the kernel may have forgotten the inode
+                debug!("fail to invalidate attributes of inode({}): {}", ino, err);
+            }
+        });
+    }
+
+    /// Prefetch the data following a read in background, if the handler reads sequentially.
+    ///
+    /// The prefetch runs in its own transaction, it never updates the access time.
+    fn read_ahead(&self, readahead: &Arc<Readahead>, ino: u64, fh: u64, start: u64, len: u64) {
+        let prefetch = match readahead.advance(ino, fh, start, len) {
+            Some(prefetch) => prefetch,
+            None => return,
+        };
+        let readahead = readahead.clone();
+        let client = self.client();
+        let block_size = self.block_size;
+        let max_size = self.max_size;
+        let inline_data_threshold = self.inline_data_threshold;
+        let dedup = self.dedup;
+        let cipher = self.cipher.clone();
+        let checksum = self.checksum;
+        let commit_policy = self.commit_policy;
+        let scan_limit = self.scan_limit;
+        let block_cache = self.block_cache.clone();
+        tokio::spawn(async move {
+            let data: Result<Vec<u8>> = async {
+                let mut txn = Txn::begin_optimistic(
+                    &client,
+                    block_size,
+                    max_size,
+                    Self::MAX_NAME_LEN,
+                    inline_data_threshold,
+                    true,
+                    AtimePolicy::Never,
+                    dedup,
+                    cipher,
+                    commit_policy,
+                )
+                .await?
+                .with_block_cache(block_cache)
+                .with_scan_limit(scan_limit)
+                .with_checksum(checksum);
+                let data = txn
+                    .read_data_direct(prefetch.ino, prefetch.offset, prefetch.size)
+                    .await;
+                txn.rollback().await?;
+                data
+            }
+            .await;
+            if let Err(err) = &data {
+                debug!("fail to prefetch {:?}: {}", prefetch, err);
+            }
+            readahead.fill(prefetch, data.ok());
+        });
+    }
+
+    /// Commit buffered writes of the inode.
+    async fn flush_buffers(&self, ino: u64) -> Result<()> {
+        if let Some(buffers) = &self.write_buffers {
+            let target = buffers.get(ino);
+            let mut handles = target.lock().await;
+            self.commit_buffers(ino, &mut handles, None).await?;
+        }
+        Ok(())
+    }
+
+    /// The entry read before with buffered writes of the inode committed, as `getattr` replies.
+    async fn flushed_entry(&self, entry: (FileAttr, u64)) -> Result<(FileAttr, u64)> {
+        let ino = entry.0.ino;
+        match &self.write_buffers {
+            Some(buffers) if buffers.buffered(ino) => {
+                self.flush_buffers(ino).await?;
+                self.read_entry(ino).await
+            }
+            _ => Ok(entry),
+        }
     }
 
-    async fn spin<F, T>(&self, delay: Option<Duration>, mut f: F) -> Result<T>
-    where
-        T: 'static + Send,
-        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
-    {
-        loop {
-            match self.with_optimistic(&mut f).await {
-                Ok(v) => break Ok(v),
-                Err(FsError::KeyError(err)) => {
-                    trace!("spin because of a key error({})", err);
-                    if let Some(time) = delay {
-                        sleep(time).await;
-                    }
-                }
-                Err(err) => break Err(err),
-            }
+    /// Commit buffered writes of a handler.
+    async fn flush_buffer(&self, ino: u64, fh: u64) -> Result<()> {
+        if let Some(buffers) = &self.write_buffers {
+            let target = buffers.get(ino);
+            let mut handles = target.lock().await;
+            self.commit_buffer(ino, fh, &mut handles).await?;
         }
+        Ok(())
     }
 
-    async fn spin_no_delay<F, T>(&self, f: F) -> Result<T>
-    where
-        T: 'static + Send,
-        F: for<'a> FnMut(&'a TiFs, &'a mut Txn) -> BoxedFuture<'a, T>,
-    {
-        self.spin(None, f).await
+    /// Commit buffers of every handler except `keep`.
+    async fn commit_buffers(
+        &self,
+        ino: u64,
+        handles: &mut InodeBuffers,
+        keep: Option<u64>,
+    ) -> Result<()> {
+        let fhs: Vec<u64> = handles
+            .keys()
+            .copied()
+            .filter(|fh| Some(*fh) != keep)
+            .collect();
+        for fh in fhs {
+            self.commit_buffer(ino, fh, handles).await?;
+        }
+        Ok(())
     }
 
-    async fn read_dir(&self, ino: u64) -> Result<Directory> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.read_dir(ino)))
+    /// Commit the buffer of a handler, it's kept on failure.
+    async fn commit_buffer(&self, ino: u64, fh: u64, handles: &mut InodeBuffers) -> Result<()> {
+        let (offset, data): (u64, Bytes) = match handles.remove(&fh) {
+            Some(buffer) => (buffer.offset, buffer.data.into()),
+            None => return Ok(()),
+        };
+        let committed = {
+            let data = data.clone();
+            self.spin_no_delay(move |_, txn| {
+                Box::pin(txn.write(ino, FileHandler::default(), offset as i64, data.clone()))
+            })
             .await
+        };
+        if let Err(err) = committed {
+            // the buffer is put back, so that a later flush retries it
+            handles.insert(fh, WriteBuffer::new(offset, data.into()));
+            return Err(err);
+        }
+        Ok(())
     }
 
-    async fn read_inode(&self, ino: u64) -> Result<FileAttr> {
-        let ino = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
-            .await?;
-        Ok(ino.file_attr)
+    /// Buffer a write of a handler, buffers it may overlap are committed first.
+    async fn buffer_write(
+        &self,
+        buffers: &WriteBuffers,
+        ino: u64,
+        fh: u64,
+        start: u64,
+        data: Bytes,
+    ) -> Result<usize> {
+        let len = data.len();
+        let target = buffers.get(ino);
+        let mut handles = target.lock().await;
+        self.commit_buffers(ino, &mut handles, Some(fh)).await?;
+        let appended = match handles.get_mut(&fh) {
+            Some(buffer) => buffer.append(start, &data),
+            None => false,
+        };
+        if !appended {
+            self.commit_buffers(ino, &mut handles, None).await?;
+            check_regular_file(&self.read_inode(ino).await?)?;
+            handles.insert(fh, WriteBuffer::new(start, data.into()));
+        }
+        if matches!(handles.get(&fh), Some(buffer) if buffer.is_full(self.block_size)) {
+            self.commit_buffer(ino, fh, &mut handles).await?;
+        }
+        Ok(len)
     }
 
     async fn setlkw(
@@ -225,7 +1298,42 @@ impl TiFs {
         Ok(())
     }
 
-    fn check_file_name(name: &str) -> Result<()> {
+    /// Place a POSIX record lock, waiting for conflicting locks to be released if `wait`.
+    async fn set_record_lock(&self, ino: u64, lock: RecordLock, wait: bool) -> Result<()> {
+        loop {
+            let placed = self
+                .spin_no_delay(move |_, txn| {
+                    Box::pin(async move {
+                        let mut inode = txn.read_inode(ino).await?;
+                        if inode.file_attr.kind == FileType::Directory {
+                            return Err(FsError::InvalidLock);
+                        }
+                        let state = &mut inode.lock_state;
+                        if lock.typ != F_UNLCK as i32
+                            && state
+                                .conflict(lock.owner, lock.start, lock.end, lock.typ)
+                                .is_some()
+                        {
+                            return if wait {
+                                Ok(false)
+                            } else {
+                                Err(FsError::InvalidLock)
+                            };
+                        }
+                        state.set_record(lock);
+                        txn.save_inode(&inode).await?;
+                        Ok(true)
+                    })
+                })
+                .await?;
+            if placed {
+                return Ok(());
+            }
+            sleep(Self::LOCK_POLL_INTERVAL).await;
+        }
+    }
+
+    pub(crate) fn check_file_name(name: &str) -> Result<()> {
         if name.len() <= Self::MAX_NAME_LEN as usize {
             Ok(())
         } else {
@@ -234,6 +1342,45 @@ impl TiFs {
             })
         }
     }
+
+    /// Symlink targets are inlined into inodes however long they are, beyond the inline
+    /// threshold, so they are only limited by `PATH_MAX`.
+    fn check_link_target(link: &str) -> Result<()> {
+        if link.len() < libc::PATH_MAX as usize {
+            Ok(())
+        } else {
+            Err(FsError::NameTooLong {
+                file: link.to_string(),
+            })
+        }
+    }
+
+    /// Parse the divisor of the block size giving the inline threshold, it must be in
+    /// `[2, block_size]` so that the threshold is neither zero nor a whole block.
+    /// `block_size` is the stored one of an existing filesystem.
+    fn parse_inline_divisor(value: &str, block_size: u64) -> Option<u64> {
+        match value.parse() {
+            Ok(divisor) if (2..=block_size).contains(&divisor) => Some(divisor),
+            Ok(divisor) => {
+                error!(
+                    "inline_divisor({}) should be in [2, block size({})]",
+                    divisor, block_size
+                );
+                None
+            }
+            Err(err) => {
+                error!("fail to parse inline_divisor({}): {}", value, err);
+                None
+            }
+        }
+    }
+
+    /// Parse an id or a mask of the mount options, invalid ones are logged and ignored.
+    fn parse_id(name: &str, value: &str, radix: u32) -> Option<u32> {
+        u32::from_str_radix(value, radix)
+            .map_err(|err| error!("fail to parse {}({}): {}", name, value, err))
+            .ok()
+    }
 }
 
 impl Debug for TiFs {
@@ -246,67 +1393,147 @@ impl Debug for TiFs {
 impl AsyncFileSystem for TiFs {
     #[tracing::instrument]
     async fn init(&self, gid: u32, uid: u32, config: &mut KernelConfig) -> Result<()> {
-        // config
-        //     .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS)
-        //     .expect("kernel config failed to add cap_fuse FUSE_POSIX_LOCKS");
-        #[cfg(not(target_os = "macos"))]
+        let _timer = self.metrics.op("init");
+        // only one kind of locks is shared by mounts, as `setlk` doesn't tell them apart, see
+        // `LockState`
+        if self.posix_locks {
+            config
+                .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS)
+                .expect("kernel config failed to add cap_fuse FUSE_POSIX_LOCKS");
+        } else {
+            #[cfg(not(target_os = "macos"))]
+            config
+                .add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS)
+                .expect("kernel config failed to add cap_fuse FUSE_CAP_FLOCK_LOCKS");
+        }
+        // the kernel checks permissions by ACLs read from `getxattr`, while ACLs are inherited
+        // and kept in sync with the permission bits here
+        #[cfg(target_os = "linux")]
+        if let Err(err) = config.add_capabilities(fuser::consts::FUSE_POSIX_ACL) {
+            warn!("kernel doesn't support posix acl: {:#x}", err);
+        }
+        // let the kernel pass O_TRUNC to `open`, rather than a separate `setattr`
         config
-            .add_capabilities(fuser::consts::FUSE_FLOCK_LOCKS)
-            .expect("kernel config failed to add cap_fuse FUSE_CAP_FLOCK_LOCKS");
+            .add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC)
+            .expect("kernel config failed to add cap_fuse FUSE_ATOMIC_O_TRUNC");
 
-        self.spin_no_delay(move |fs, txn| {
-            Box::pin(async move {
-                info!("initializing tifs on {:?} ...", &fs.pd_endpoints);
-                if let Some(meta) = txn.read_meta().await? {
-                    if meta.block_size != txn.block_size() {
-                        let err = FsError::block_size_conflict(meta.block_size, txn.block_size());
-                        error!("{}", err);
-                        return Err(err);
-                    }
-                }
+        // values beyond the limits of the kernel are clamped to the nearest
+        let max_write = match config.set_max_write(self.max_write) {
+            Ok(_) => self.max_write,
+            Err(nearest) => {
+                warn!(
+                    "kernel refused max_write({}), fall back to {}",
+                    self.max_write, nearest
+                );
+                config
+                    .set_max_write(nearest)
+                    .expect("kernel config failed to set max_write");
+                nearest
+            }
+        };
+        let max_readahead = match config.set_max_readahead(self.max_readahead) {
+            Ok(_) => self.max_readahead,
+            Err(nearest) => {
+                warn!(
+                    "kernel refused max_readahead({}), fall back to {}",
+                    self.max_readahead, nearest
+                );
+                config
+                    .set_max_readahead(nearest)
+                    .expect("kernel config failed to set max_readahead");
+                nearest
+            }
+        };
+        info!(
+            "negotiated max_write: {}, max_readahead: {}",
+            max_write, max_readahead
+        );
+        self.prepare(gid, uid).await?;
+        self.start_scrubber().await
+    }
 
-                let root_inode = txn.read_inode(ROOT_INODE).await;
-                if let Err(FsError::InodeNotFound { inode: _ }) = root_inode {
-                    let attr = txn
-                        .mkdir(
-                            0,
-                            Default::default(),
-                            make_mode(FileType::Directory, 0o777),
-                            gid,
-                            uid,
-                        )
-                        .await?;
-                    debug!("make root directory {:?}", &attr);
-                    Ok(())
-                } else {
-                    root_inode.map(|_| ())
-                }
-            })
-        })
+    /// Wait for in-flight transactions, then reject new ones.
+    /// The client is closed when the filesystem drops.
+    #[tracing::instrument]
+    async fn destroy(&self) {
+        info!("waiting for in-flight transactions");
+        match timeout(
+            Self::DESTROY_TIMEOUT,
+            self.concurrency.acquire_many(self.max_concurrency),
+        )
         .await
+        {
+            Ok(Ok(_permits)) => info!("all transactions finished"),
+            Ok(Err(err)) => error!("fail to wait for transactions: {}", err),
+            Err(_) => warn!(
+                "transactions are still running after {:?}",
+                Self::DESTROY_TIMEOUT
+            ),
+        }
+        self.concurrency.close();
+    }
+
+    fn set_notifier(&self, notifier: Notifier) {
+        *self.notifier.write().unwrap() = Some(notifier);
+    }
+
+    fn set_mountpoint(&self, mountpoint: &Path) {
+        *self.mountpoint.write().unwrap() = Some(mountpoint.to_owned());
     }
 
     #[tracing::instrument]
     async fn lookup(&self, parent: u64, name: ByteString) -> Result<Entry> {
+        let _timer = self.metrics.op("lookup");
         Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
-            let name = name.clone();
-            Box::pin(async move {
-                let ino = txn.lookup(parent, name).await?;
-                Ok(Entry::new(txn.read_inode(ino).await?.into(), 0))
-            })
-        })
-        .await
+        let entry = match self.cached_entry(parent, &name) {
+            Some(cached) => cached,
+            None => {
+                let lookup_name = name.clone();
+                let attr_epoch = self.attr_epoch();
+                let dentry_epoch = self.dentry_epoch();
+                let (attr, generation) = self
+                    .spin_no_delay(move |fs, txn| {
+                        let name = lookup_name.clone();
+                        Box::pin(async move {
+                            let ino = txn.lookup(parent, name).await?;
+                            match fs.cached_attr(ino) {
+                                Some(cached) => Ok(cached),
+                                None => {
+                                    let inode = txn.read_inode(ino).await?;
+                                    Ok((inode.file_attr, inode.generation))
+                                }
+                            }
+                        })
+                    })
+                    .await?;
+                // the cache keeps stored attributes
+                self.cache_attr(attr, generation, attr_epoch);
+                self.cache_dentry(parent, name, attr.ino, dentry_epoch);
+                (attr, generation)
+            }
+        };
+        let (attr, generation) = self.flushed_entry(entry).await?;
+        Ok(Entry::new(
+            self.entry_timeout,
+            self.attr_override.apply(attr),
+            generation,
+        ))
     }
 
     #[tracing::instrument]
     async fn getattr(&self, ino: u64) -> Result<Attr> {
-        Ok(Attr::new(self.read_inode(ino).await?))
+        let _timer = self.metrics.op("getattr");
+        self.flush_buffers(ino).await?;
+        let attr = self.read_inode(ino).await?;
+        Ok(Attr::new(self.attr_timeout, self.attr_override.apply(attr)))
     }
 
     #[tracing::instrument]
     async fn setattr(
         &self,
+        req_uid: u32,
+        req_gid: u32,
+        pid: u32,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -321,43 +1548,77 @@ impl AsyncFileSystem for TiFs {
         _bkuptime: Option<SystemTime>,
         flags: Option<u32>,
     ) -> Result<Attr> {
-        self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                // TODO: how to deal with fh, chgtime, bkuptime?
-                let mut attr = txn.read_inode(ino).await?;
-                attr.perm = match mode {
-                    Some(m) => m as _,
-                    None => attr.perm,
-                };
-                attr.uid = uid.unwrap_or(attr.uid);
-                attr.gid = gid.unwrap_or(attr.gid);
-                attr.set_size(size.unwrap_or(attr.size), txn.block_size());
-                attr.atime = match atime {
-                    None => attr.atime,
-                    Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) => SystemTime::now(),
-                };
-                attr.mtime = match mtime {
-                    Some(TimeOrNow::SpecificTime(t)) => t,
-                    Some(TimeOrNow::Now) | None => SystemTime::now(),
-                };
-                attr.ctime = ctime.unwrap_or_else(SystemTime::now);
-                attr.crtime = crtime.unwrap_or(attr.crtime);
-                attr.flags = flags.unwrap_or(attr.flags);
-                txn.save_inode(&attr).await?;
-                Ok(Attr {
-                    time: get_time(),
-                    attr: attr.into(),
+        let _timer = self.metrics.op("setattr");
+        self.flush_buffers(ino).await?;
+        let chown = uid.is_some() || gid.is_some();
+        let groups = match gid {
+            Some(_) if req_uid != 0 => caller_groups(pid, req_gid).await,
+            _ => vec![req_gid],
+        };
+        let attr = self
+            .spin_no_delay(move |fs, txn| {
+                let groups = groups.clone();
+                Box::pin(async move {
+                    // TODO: how to deal with fh, chgtime, bkuptime?
+                    let mut attr = txn.read_inode(ino).await?;
+                    // only flags of an immutable or append-only inode may change
+                    if mode.is_some()
+                        || uid.is_some()
+                        || gid.is_some()
+                        || size.is_some()
+                        || atime.is_some()
+                        || mtime.is_some()
+                        || crtime.is_some()
+                    {
+                        attr.check_overwrite()?;
+                    }
+                    if chown {
+                        check_chown(&attr, req_uid, &groups, uid, gid)?;
+                    }
+                    if let Some(m) = mode {
+                        check_chmod(&attr, req_uid)?;
+                        attr.chmod(m as _)?;
+                    }
+                    if chown {
+                        attr.uid = uid.unwrap_or(attr.uid);
+                        attr.gid = gid.unwrap_or(attr.gid);
+                        attr.perm = chown_perm(&attr, req_uid);
+                    }
+                    if let Some(size) = size {
+                        txn.truncate(&mut attr, size).await?;
+                    }
+                    attr.atime = match atime {
+                        None => attr.atime,
+                        Some(TimeOrNow::SpecificTime(t)) => t,
+                        Some(TimeOrNow::Now) => SystemTime::now(),
+                    };
+                    attr.mtime = match mtime {
+                        Some(TimeOrNow::SpecificTime(t)) => t,
+                        Some(TimeOrNow::Now) | None => SystemTime::now(),
+                    };
+                    attr.ctime = ctime.unwrap_or_else(SystemTime::now);
+                    attr.crtime = crtime.unwrap_or(attr.crtime);
+                    if let Some(flags) = flags {
+                        attr.check_set_flags(req_uid, flags)?;
+                        attr.flags = flags;
+                    }
+                    txn.save_inode(&attr).await?;
+                    Ok(Attr::new(
+                        fs.attr_timeout,
+                        fs.attr_override.apply(attr.into()),
+                    ))
                 })
             })
-        })
-        .await
+            .await?;
+        self.notify_inval_attr(ino);
+        Ok(attr)
     }
 
     #[tracing::instrument]
     async fn readdir(&self, ino: u64, _fh: u64, offset: i64) -> Result<Dir> {
+        let _timer = self.metrics.op("readdir");
         let mut dir = Dir::offset(offset as usize);
-        let directory = self.read_dir(ino).await?;
+        let directory = listing(ino, self.read_dir(ino).await?);
         for item in directory.into_iter().skip(offset as usize) {
             dir.push(item)
         }
@@ -366,20 +1627,37 @@ impl AsyncFileSystem for TiFs {
     }
 
     #[tracing::instrument]
-    async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
-        // TODO: deal with flags
-        let fh = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.open(ino)))
-            .await?;
+    async fn readdirplus(&self, ino: u64, _fh: u64, offset: i64) -> Result<DirPlus> {
+        let _timer = self.metrics.op("readdirplus");
+        let mut dir = DirPlus::offset(offset as usize);
+        let directory = listing(ino, self.read_dir(ino).await?);
+        for item in directory.into_iter().skip(offset as usize) {
+            let entry = self.read_entry(item.ino).await?;
+            let (attr, generation) = self.flushed_entry(entry).await?;
+            let attr = self.attr_override.apply(attr);
+            dir.push(item, Entry::new(self.entry_timeout, attr, generation))
+        }
+        debug!("read directory plus {:?}", &dir);
+        Ok(dir)
+    }
 
-        let mut open_flags = 0;
-        #[cfg(target_os = "linux")]
-        if self.direct_io || flags & libc::O_DIRECT != 0 {
-            open_flags |= FOPEN_DIRECT_IO;
+    #[tracing::instrument]
+    async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
+        let _timer = self.metrics.op("open");
+        if flags & libc::O_TRUNC != 0 {
+            // buffered writes must not resurface after truncation
+            self.flush_buffers(ino).await?;
         }
-        #[cfg(not(target_os = "linux"))]
-        if self.direct_io {
-            open_flags |= FOPEN_DIRECT_IO;
+
+        let (open_flags, handler) = self.file_handler(flags);
+        // take the handler first, so opens beyond `max_open` never reach the store
+        let fh = self.file_handlers.open(ino, handler)?;
+        if let Err(err) = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.open(ino, flags)))
+            .await
+        {
+            self.file_handlers.close(ino, fh)?;
+            return Err(err);
         }
         Ok(Open::new(fh, open_flags))
     }
@@ -394,9 +1672,24 @@ impl AsyncFileSystem for TiFs {
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Data> {
-        let data = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.read(ino, fh, offset, size)))
-            .await?;
+        let _timer = self.metrics.op("read");
+        self.flush_buffers(ino).await?;
+        let handler = self.file_handlers.get(ino, fh)?;
+        let start = handler.position(ino, offset, size as u64)?;
+        let readahead = self.readahead.as_ref().filter(|_| !handler.direct);
+        let prefetched = readahead.and_then(|ra| ra.read(ino, fh, start, size as u64));
+        let data = match prefetched {
+            Some(data) => data,
+            None => {
+                self.spin_no_delay(move |_, txn| Box::pin(txn.read(ino, handler, offset, size)))
+                    .await?
+            }
+        };
+        // a short read reaches the end of file
+        if let Some(readahead) = readahead.filter(|_| data.len() == size as usize) {
+            self.read_ahead(readahead, ino, fh, start, size as u64);
+        }
+        self.metrics.read_bytes.inc_by(data.len() as u64);
         Ok(Data::new(data))
     }
 
@@ -406,16 +1699,37 @@ impl AsyncFileSystem for TiFs {
         ino: u64,
         fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: Bytes,
         _write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
-        let data: Bytes = data.into();
+        let _timer = self.metrics.op("write");
+        let handler = self.file_handlers.get(ino, fh)?;
+        // the kernel sends and expects at most u32::MAX bytes, bounded by max_write
+        let requested = data.len() as u64;
+        if requested > u32::MAX as u64 {
+            return Err(FsError::InvalidWriteSize {
+                len: requested,
+                max: u32::MAX as u64,
+            });
+        }
+        if let Some(buffers) = &self.write_buffers {
+            if !handler.is_append() && !handler.direct {
+                let start = handler.position(ino, offset, requested)?;
+                let len = self.buffer_write(buffers, ino, fh, start, data).await?;
+                self.metrics.written_bytes.inc_by(len as u64);
+                return Write::written(len as u64, requested);
+            }
+            // appends are positioned by the committed size, and direct writes are never buffered
+            self.flush_buffers(ino).await?;
+        }
         let len = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.write(ino, fh, offset, data.clone())))
+            .spin_no_delay(move |_, txn| Box::pin(txn.write(ino, handler, offset, data.clone())))
             .await?;
-        Ok(Write::new(len as u32))
+        self.metrics.written_bytes.inc_by(len as u64);
+        self.notify_inval_attr(ino);
+        Write::written(len as u64, requested)
     }
 
     /// Create a directory.
@@ -427,20 +1741,38 @@ impl AsyncFileSystem for TiFs {
         mode: u32,
         gid: u32,
         uid: u32,
-        _umask: u32,
+        umask: u32,
     ) -> Result<Entry> {
+        let _timer = self.metrics.op("mkdir");
         Self::check_file_name(&name)?;
-        let attr = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.mkdir(parent, name.clone(), mode, gid, uid)))
+        let inode = self
+            .spin_no_delay(move |_, txn| {
+                let name = name.clone();
+                Box::pin(async move {
+                    let mode = txn.creation_mode(parent, mode, umask).await?;
+                    txn.mkdir(parent, name, mode, gid, uid).await
+                })
+            })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        Ok(Entry::new(
+            self.entry_timeout,
+            self.attr_override.apply(inode.file_attr),
+            inode.generation,
+        ))
     }
 
     #[tracing::instrument]
-    async fn rmdir(&self, parent: u64, raw_name: ByteString) -> Result<()> {
+    async fn rmdir(&self, uid: u32, parent: u64, raw_name: ByteString) -> Result<()> {
+        let _timer = self.metrics.op("rmdir");
         Self::check_file_name(&raw_name)?;
-        self.spin_no_delay(move |_, txn| Box::pin(txn.rmdir(parent, raw_name.clone())))
-            .await
+        self.spin_no_delay(move |_, txn| {
+            let name = raw_name.clone();
+            Box::pin(async move {
+                txn.check_sticky(uid, parent, name.clone()).await?;
+                txn.rmdir(parent, name).await
+            })
+        })
+        .await
     }
 
     #[tracing::instrument]
@@ -451,21 +1783,48 @@ impl AsyncFileSystem for TiFs {
         mode: u32,
         gid: u32,
         uid: u32,
-        _umask: u32,
+        umask: u32,
         rdev: u32,
     ) -> Result<Entry> {
+        let _timer = self.metrics.op("mknod");
         Self::check_file_name(&name)?;
-        let attr = self
+        let inode = self
             .spin_no_delay(move |_, txn| {
-                Box::pin(txn.make_inode(parent, name.clone(), mode, gid, uid, rdev))
+                let name = name.clone();
+                Box::pin(async move {
+                    let mode = txn.creation_mode(parent, mode, umask).await?;
+                    txn.make_inode(parent, name, mode, gid, uid, rdev).await
+                })
             })
             .await?;
-        Ok(Entry::new(attr.into(), 0))
+        Ok(Entry::new(
+            self.entry_timeout,
+            self.attr_override.apply(inode.file_attr),
+            inode.generation,
+        ))
     }
 
     #[tracing::instrument]
-    async fn access(&self, _ino: u64, _mask: i32) -> Result<()> {
-        Ok(())
+    async fn access(&self, uid: u32, gid: u32, pid: u32, ino: u64, mask: i32) -> Result<()> {
+        let _timer = self.metrics.op("access");
+        let groups = match uid {
+            0 => vec![gid],
+            _ => caller_groups(pid, gid).await,
+        };
+        let inode = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
+            .await?;
+        // permissions are checked as presented
+        let attr = self.attr_override.apply(inode.file_attr);
+        let allowed = match inode.access_acl()? {
+            Some(acl) => acl.check(&attr, uid, &groups, mask),
+            None => check_access(&attr, uid, &groups, mask),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied { ino, mask })
+        }
     }
 
     async fn create(
@@ -478,42 +1837,62 @@ impl AsyncFileSystem for TiFs {
         umask: u32,
         flags: i32,
     ) -> Result<Create> {
+        let _timer = self.metrics.op("create");
         Self::check_file_name(&name)?;
-        let entry = self.mknod(parent, name, mode, gid, uid, umask, 0).await?;
-        let open = self.open(entry.stat.ino, flags).await?;
+        // made and opened in one transaction, so that racing creates of the name conflict
+        // and all but one fail by `EEXIST`, and the file is never seen before it's opened
+        let inode = self
+            .spin_no_delay(move |_, txn| {
+                let name = name.clone();
+                Box::pin(async move {
+                    let mode = txn.creation_mode(parent, mode, umask).await?;
+                    let inode = txn.make_inode(parent, name, mode, gid, uid, 0).await?;
+                    txn.open(inode.ino, flags).await?;
+                    Ok(inode)
+                })
+            })
+            .await?;
+        let ino = inode.ino;
+        let (open_flags, handler) = self.file_handler(flags);
+        let fh = match self.file_handlers.open(ino, handler) {
+            Ok(fh) => fh,
+            Err(err) => {
+                // the file is left made but closed
+                self.spin_no_delay(move |_, txn| Box::pin(txn.close(ino, None)))
+                    .await?;
+                return Err(err);
+            }
+        };
         Ok(Create::new(
-            entry.stat,
-            entry.generation,
-            open.fh,
-            open.flags,
+            self.entry_timeout,
+            self.attr_override.apply(inode.file_attr),
+            inode.generation,
+            fh,
+            open_flags,
         ))
     }
 
     async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek> {
-        self.spin_no_delay(move |_, txn| {
-            Box::pin(async move {
-                let mut file_handler = txn.read_fh(ino, fh).await?;
-                let inode = txn.read_inode(ino).await?;
-                let target_cursor = match whence {
-                    SEEK_SET => offset,
-                    SEEK_CUR => file_handler.cursor as i64 + offset,
-                    SEEK_END => inode.size as i64 + offset,
-                    _ => return Err(FsError::UnknownWhence { whence }),
-                };
-
-                if target_cursor < 0 {
-                    return Err(FsError::InvalidOffset {
-                        ino: inode.ino,
-                        offset: target_cursor,
-                    });
-                }
+        let _timer = self.metrics.op("lseek");
+        self.flush_buffers(ino).await?;
+        let mut file_handler = self.file_handlers.get(ino, fh)?;
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => file_handler.cursor,
+            SEEK_END => {
+                let inode = self
+                    .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
+                    .await?;
+                inode.size
+            }
+            _ => return Err(FsError::UnknownWhence { whence }),
+        };
+        let target_cursor =
+            offset_from(base, offset).ok_or(FsError::InvalidOffset { ino, offset })?;
 
-                file_handler.cursor = target_cursor as u64;
-                txn.save_fh(ino, fh, &file_handler).await?;
-                Ok(Lseek::new(target_cursor))
-            })
-        })
-        .await
+        file_handler.cursor = target_cursor;
+        self.file_handlers.save(ino, fh, file_handler)?;
+        Ok(Lseek::new(target_cursor as i64))
     }
 
     async fn release(
@@ -521,46 +1900,123 @@ impl AsyncFileSystem for TiFs {
         ino: u64,
         fh: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.close(ino, fh)))
-            .await
+        let _timer = self.metrics.op("release");
+        let flushed = self.flush_buffers(ino).await;
+        // the store is closed while the handler still exists, a flock lock is released by
+        // closing the open file holding it, even if the process holding it is killed
+        let closed = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.close(ino, lock_owner)))
+            .await;
+        self.file_handlers.close(ino, fh)?;
+        if let Some(buffers) = &self.write_buffers {
+            buffers.release(ino);
+        }
+        if let Some(readahead) = &self.readahead {
+            readahead.release(ino, fh);
+        }
+        closed?;
+        flushed
+    }
+
+    /// Commit buffered writes of the handler, called on every close of a file descriptor,
+    /// so that `close` reports failed writes. Record locks of the closing process are released
+    /// here as well, flock locks are released along with the open file by `release`.
+    async fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<()> {
+        let _timer = self.metrics.op("flush");
+        let flushed = self.flush_buffer(ino, fh).await;
+        if self.posix_locks {
+            self.spin_no_delay(move |_, txn| Box::pin(txn.unlock_records(ino, lock_owner)))
+                .await?;
+        }
+        flushed
+    }
+
+    /// Buffered writes are committed here, other writes are committed in their own
+    /// transactions, so there is nothing else to flush.
+    async fn fsync(&self, ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
+        let _timer = self.metrics.op("fsync");
+        self.flush_buffers(ino).await?;
+        self.read_inode(ino).await?;
+        Ok(())
+    }
+
+    async fn fsyncdir(&self, ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
+        let _timer = self.metrics.op("fsyncdir");
+        self.read_inode(ino).await?;
+        Ok(())
     }
 
     /// Create a hard link.
     async fn link(&self, ino: u64, newparent: u64, newname: ByteString) -> Result<Entry> {
+        let _timer = self.metrics.op("link");
         Self::check_file_name(&newname)?;
         let inode = self
-            .spin_no_delay(move |_, txn| Box::pin(txn.link(ino, newparent, newname.clone())))
+            .spin_no_delay(move |_, txn| {
+                let newname = newname.clone();
+                Box::pin(async move {
+                    // directories are only linked by `rename`
+                    if txn.read_inode(ino).await?.kind == FileType::Directory {
+                        return Err(FsError::LinkDirectory { ino });
+                    }
+                    txn.link(ino, newparent, newname).await
+                })
+            })
             .await?;
-        Ok(Entry::new(inode.into(), 0))
+        Ok(Entry::new(
+            self.entry_timeout,
+            self.attr_override.apply(inode.file_attr),
+            inode.generation,
+        ))
     }
 
-    async fn unlink(&self, parent: u64, raw_name: ByteString) -> Result<()> {
-        self.spin_no_delay(move |_, txn| Box::pin(txn.unlink(parent, raw_name.clone())))
-            .await
+    async fn unlink(&self, uid: u32, parent: u64, raw_name: ByteString) -> Result<()> {
+        let _timer = self.metrics.op("unlink");
+        self.spin_no_delay(move |_, txn| {
+            let name = raw_name.clone();
+            Box::pin(async move {
+                txn.check_sticky(uid, parent, name.clone()).await?;
+                txn.unlink_file(parent, name).await
+            })
+        })
+        .await
     }
 
     async fn rename(
         &self,
+        uid: u32,
         parent: u64,
         raw_name: ByteString,
         newparent: u64,
         new_raw_name: ByteString,
         _flags: u32,
     ) -> Result<()> {
+        let _timer = self.metrics.op("rename");
         Self::check_file_name(&raw_name)?;
         Self::check_file_name(&new_raw_name)?;
         self.spin_no_delay(move |_, txn| {
             let name = raw_name.clone();
             let new_name = new_raw_name.clone();
             Box::pin(async move {
+                txn.check_sticky(uid, parent, name.clone()).await?;
                 let ino = txn.lookup(parent, name.clone()).await?;
+                // the existing target would be replaced
+                if let Some(target) = txn.get_index(newparent, new_name.clone()).await? {
+                    txn.check_sticky(uid, newparent, new_name.clone()).await?;
+                    if target == ino {
+                        // both names link to the same inode, rename does nothing
+                        return Ok(());
+                    }
+                }
+                let kind = txn.read_inode(ino).await?.file_attr.kind;
+                if kind == FileType::Directory {
+                    txn.check_ancestor(ino, newparent).await?;
+                }
                 txn.link(ino, newparent, new_name).await?;
                 txn.unlink(parent, name).await?;
-                let inode = txn.read_inode(ino).await?;
-                if inode.file_attr.kind == FileType::Directory {
+                if kind == FileType::Directory {
                     txn.unlink(ino, DIR_PARENT).await?;
                     txn.link(newparent, ino, DIR_PARENT).await?;
                 }
@@ -579,8 +2035,10 @@ impl AsyncFileSystem for TiFs {
         name: ByteString,
         link: ByteString,
     ) -> Result<Entry> {
+        let _timer = self.metrics.op("symlink");
         Self::check_file_name(&name)?;
-        self.spin_no_delay(move |_, txn| {
+        Self::check_link_target(&link)?;
+        self.spin_no_delay(move |fs, txn| {
             let name = name.clone();
             let link = link.clone();
             Box::pin(async move {
@@ -596,19 +2054,90 @@ impl AsyncFileSystem for TiFs {
                     .await?;
 
                 txn.write_link(&mut attr, link.into_bytes()).await?;
-                Ok(Entry::new(attr.into(), 0))
+                Ok(Entry::new(
+                    fs.entry_timeout,
+                    fs.attr_override.apply(attr.file_attr),
+                    attr.generation,
+                ))
             })
         })
         .await
     }
 
     async fn readlink(&self, ino: u64) -> Result<Data> {
+        let _timer = self.metrics.op("readlink");
         self.spin(None, move |_, txn| {
             Box::pin(async move { Ok(Data::new(txn.read_link(ino).await?)) })
         })
         .await
     }
 
+    /// Files are not backed by a block device, so there is nothing to map;
+    /// callers would fall back on EOPNOTSUPP rather than ENOSYS.
+    #[tracing::instrument]
+    async fn bmap(&self, _ino: u64, _blocksize: u32, _idx: u64) -> Result<Bmap> {
+        let _timer = self.metrics.op("bmap");
+        Err(FsError::Unsupported { op: "bmap" })
+    }
+
+    /// Get and set inode flags by the ioctls of `lsattr` and `chattr`, the kernel passes them
+    /// unrestricted, sized by their commands.
+    #[tracing::instrument]
+    async fn ioctl(
+        &self,
+        req_uid: u32,
+        pid: u32,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: Bytes,
+        out_size: u32,
+    ) -> Result<Ioctl> {
+        let _timer = self.metrics.op("ioctl");
+        match cmd {
+            #[cfg(target_os = "linux")]
+            FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS => {
+                let attr = self.read_inode(ino).await?;
+                let mut data = attr.flags.to_ne_bytes().to_vec();
+                data.resize(out_size.min(size_of::<u64>() as u32) as usize, 0);
+                Ok(Ioctl::new(0, data))
+            }
+            #[cfg(target_os = "linux")]
+            FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => {
+                let flags = match in_data.len() {
+                    8 => u64::from_ne_bytes(in_data[..].try_into().unwrap()) as u32,
+                    4 => u32::from_ne_bytes(in_data[..].try_into().unwrap()),
+                    _ => return Err(FsError::UnknownIoctl { cmd }),
+                };
+                self.spin_no_delay(move |_, txn| Box::pin(txn.set_flags(ino, req_uid, flags)))
+                    .await?;
+                self.notify_inval_attr(ino);
+                Ok(Ioctl::new(0, Vec::new()))
+            }
+            // `in_data` is an int of the source fd, or a `struct file_clone_range`
+            #[cfg(target_os = "linux")]
+            FICLONE | FICLONERANGE => {
+                let field =
+                    |i: usize| u64::from_ne_bytes(in_data[i * 8..][..8].try_into().unwrap());
+                let (fd, offset_in, len, offset_out) = match (cmd, in_data.len()) {
+                    (FICLONE, 4) => {
+                        let fd = i32::from_ne_bytes(in_data[..].try_into().unwrap());
+                        (fd as i64, 0, 0, 0)
+                    }
+                    (FICLONERANGE, 32) => (field(0) as i64, field(1), field(2), field(3)),
+                    _ => return Err(FsError::UnknownIoctl { cmd }),
+                };
+                self.file_handlers.get(ino, fh)?;
+                let ino_in = self.inode_of_fd(pid, fd).await?;
+                self.clone_range(ino_in, offset_in, ino, offset_out, len)
+                    .await?;
+                Ok(Ioctl::new(0, Vec::new()))
+            }
+            _ => Err(FsError::UnknownIoctl { cmd }),
+        }
+    }
+
     #[tracing::instrument]
     async fn fallocate(
         &self,
@@ -618,6 +2147,8 @@ impl AsyncFileSystem for TiFs {
         length: i64,
         _mode: i32,
     ) -> Result<()> {
+        let _timer = self.metrics.op("fallocate");
+        self.flush_buffers(ino).await?;
         self.spin_no_delay(move |_, txn| {
             Box::pin(async move {
                 let mut inode = txn.read_inode(ino).await?;
@@ -628,8 +2159,71 @@ impl AsyncFileSystem for TiFs {
         Ok(())
     }
 
+    /// Copy within the cluster, data never goes through the kernel.
+    /// Whole blocks are cloned, see `Txn::copy_range`.
+    #[tracing::instrument]
+    async fn copy_file_range(
+        &self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<Write> {
+        let _timer = self.metrics.op("copy_file_range");
+        // no flags are defined yet
+        if flags != 0 {
+            return Err(FsError::UnknownCopyFlags { flags });
+        }
+        self.file_handlers.get(ino_in, fh_in)?;
+        self.file_handlers.get(ino_out, fh_out)?;
+        // the number of bytes copied is replied in 32 bits
+        let len = len.min(u32::MAX as u64);
+        for (ino, offset) in [(ino_in, offset_in), (ino_out, offset_out)] {
+            checked_range(0, offset, len).ok_or(FsError::InvalidOffset { ino, offset })?;
+        }
+        self.flush_buffers(ino_in).await?;
+        self.flush_buffers(ino_out).await?;
+        let copied = self
+            .spin_no_delay(move |_, txn| {
+                Box::pin(txn.copy_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len))
+            })
+            .await?;
+        self.metrics.written_bytes.inc_by(copied as u64);
+        self.notify_inval_attr(ino_out);
+        Write::written(copied as u64, len)
+    }
+
+    /// Pages dropped by `POSIX_FADV_DONTNEED` are written back, so buffered writes of the
+    /// inode are committed, and its blocks are dropped from the block cache and read ahead
+    /// no more until the next reads.
+    async fn fadvise(
+        &self,
+        ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _len: i64,
+        advice: i32,
+    ) -> Result<()> {
+        let _timer = self.metrics.op("fadvise");
+        if advice == libc::POSIX_FADV_DONTNEED {
+            self.flush_buffers(ino).await?;
+            if let Some(cache) = &self.block_cache {
+                cache.invalidate(ino);
+            }
+            if let Some(readahead) = &self.readahead {
+                readahead.invalidate(ino);
+            }
+        }
+        Ok(())
+    }
+
     // TODO: Find an api to calculate total and available space on tikv.
     async fn statfs(&self, _ino: u64) -> Result<StatFs> {
+        let _timer = self.metrics.op("statfs");
         self.spin_no_delay(|_, txn| Box::pin(txn.statfs())).await
     }
 
@@ -639,12 +2233,23 @@ impl AsyncFileSystem for TiFs {
         ino: u64,
         _fh: u64,
         lock_owner: u64,
-        _start: u64,
-        _end: u64,
+        start: u64,
+        end: u64,
         typ: i32,
         pid: u32,
         sleep: bool,
     ) -> Result<()> {
+        let _timer = self.metrics.op("setlk");
+        if self.posix_locks {
+            let lock = RecordLock {
+                owner: lock_owner,
+                pid,
+                start,
+                end,
+                typ,
+            };
+            return self.set_record_lock(ino, lock, sleep).await;
+        }
         #[cfg(any(target_os = "freebsd", target_os = "macos"))]
         let typ = typ as i16;
         let not_again = self.spin_no_delay(move |_, txn| {
@@ -699,10 +2304,7 @@ impl AsyncFileSystem for TiFs {
                         _ => Err(FsError::InvalidLock),
                     },
                     F_UNLCK => {
-                        inode.lock_state.owner_set.remove(&lock_owner);
-                        if inode.lock_state.owner_set.is_empty() {
-                            inode.lock_state.lk_type = F_UNLCK;
-                        }
+                        inode.lock_state.unlock(lock_owner);
                         txn.save_inode(&inode).await?;
                         warn!("setlk F_UNLCK return, inode:{:?}, pid:{:?}, typ para: {:?}, state type: {:?}, owner: {:?}, sleep: {:?},", inode, pid, typ, inode.lock_state.lk_type, lock_owner, sleep);
                         Ok(true)
@@ -721,22 +2323,29 @@ impl AsyncFileSystem for TiFs {
     }
 
     #[tracing::instrument]
+    /// Report a record lock conflicting with the requested one, or `F_UNLCK` if it could be placed.
     async fn getlk(
         &self,
         ino: u64,
         _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
         pid: u32,
     ) -> Result<Lock> {
+        let _timer = self.metrics.op("getlk");
         // TODO: read only operation need not txn?
         self.spin_no_delay(move |_, txn| {
             Box::pin(async move {
                 let inode = txn.read_inode(ino).await?;
-                warn!("getlk, inode:{:?}, pid:{:?}", inode, pid);
-                Ok(Lock::_new(0, 0, inode.lock_state.lk_type as i32, 0))
+                debug!("getlk, inode:{:?}, pid:{:?}", inode, pid);
+                Ok(
+                    match inode.lock_state.conflict(lock_owner, start, end, typ) {
+                        Some(held) => Lock::_new(held.start, held.end, held.typ, held.pid),
+                        None => Lock::_new(start, end, F_UNLCK as i32, 0),
+                    },
+                )
             })
         })
         .await
@@ -745,45 +2354,1018 @@ impl AsyncFileSystem for TiFs {
     /// Set an extended attribute.
     async fn setxattr(
         &self,
-        _ino: u64,
-        _name: ByteString,
-        _value: Vec<u8>,
-        _flags: i32,
+        ino: u64,
+        name: ByteString,
+        value: Vec<u8>,
+        flags: i32,
         _position: u32,
     ) -> Result<()> {
-        // TODO: implement me
-        Ok(())
+        let _timer = self.metrics.op("setxattr");
+        self.spin_no_delay(move |_, txn| {
+            Box::pin(txn.set_xattr(ino, name.clone(), value.clone(), flags))
+        })
+        .await
     }
 
     /// Get an extended attribute.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    async fn getxattr(&self, _ino: u64, _name: ByteString, size: u32) -> Result<Xattr> {
-        // TODO: implement me
-        if size == 0 {
-            Ok(Xattr::size(0))
-        } else {
-            Ok(Xattr::data(Vec::new()))
-        }
+    async fn getxattr(&self, ino: u64, name: ByteString, size: u32) -> Result<Xattr> {
+        let _timer = self.metrics.op("getxattr");
+        let value = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.get_xattr(ino, name.clone())))
+            .await?;
+        xattr_reply(value, size)
     }
 
     /// List extended attribute names.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    async fn listxattr(&self, _ino: u64, size: u32) -> Result<Xattr> {
-        // TODO: implement me
-        if size == 0 {
-            Ok(Xattr::size(0))
-        } else {
-            Ok(Xattr::data(Vec::new()))
-        }
+    async fn listxattr(&self, ino: u64, size: u32) -> Result<Xattr> {
+        let _timer = self.metrics.op("listxattr");
+        let names = self
+            .spin_no_delay(move |_, txn| Box::pin(txn.list_xattr(ino)))
+            .await?;
+        xattr_reply(names, size)
     }
 
     /// Remove an extended attribute.
-    async fn removexattr(&self, _ino: u64, _name: ByteString) -> Result<()> {
-        // TODO: implement me
-        Ok(())
+    async fn removexattr(&self, ino: u64, name: ByteString) -> Result<()> {
+        let _timer = self.metrics.op("removexattr");
+        self.spin_no_delay(move |_, txn| Box::pin(txn.remove_xattr(ino, name.clone())))
+            .await
+    }
+}
+
+/// Groups of the caller(`pid`), which belongs to its supplementary groups besides `gid`.
+/// Only `gid` is known where the groups of a process can't be read.
+async fn caller_groups(pid: u32, gid: u32) -> Vec<u32> {
+    let mut groups = match tokio::fs::read_to_string(format!("/proc/{}/status", pid)).await {
+        Ok(status) => parse_groups(&status),
+        Err(err) => {
+            debug!("fail to read groups of process({}): {}", pid, err);
+            Vec::new()
+        }
+    };
+    groups.push(gid);
+    groups
+}
+
+/// The size of `data` if `size` is 0, or `data` if it fits in `size`.
+fn xattr_reply(data: Vec<u8>, size: u32) -> Result<Xattr> {
+    if size == 0 {
+        Ok(Xattr::size(data.len() as u32))
+    } else if data.len() <= size as usize {
+        Ok(Xattr::data(data))
+    } else {
+        Err(FsError::BufferTooSmall {
+            size,
+            len: data.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::block::Placement;
+    use crate::fs::cache::BlockStamp;
+    use crate::fs::key::ScopedKey;
+    use crate::fs::testing;
+
+    #[tokio::test]
+    #[ignore]
+    async fn racing_exclusive_creates() {
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("exclusive");
+        let flags = libc::O_CREAT | libc::O_EXCL | libc::O_RDWR;
+        let create = || {
+            let mode = make_mode(FileType::RegularFile, 0o644);
+            fs.create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+        };
+
+        let (first, second) = tokio::join!(create(), create());
+        let (created, err) = match (first, second) {
+            (Ok(created), Err(err)) | (Err(err), Ok(created)) => (created, err),
+            (first, second) => panic!("both creates end by {:?} and {:?}", first, second),
+        };
+        assert!(matches!(err, FsError::FileExist { .. }));
+        assert_eq!(libc::c_int::from(err), libc::EEXIST);
+
+        let ino = created.attr.ino;
+        fs.release(ino, created.fh, flags, None, false)
+            .await
+            .unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn stalled_operation_times_out_uncommitted() {
+        let fs = testing::tifs(vec![MountOption::OpTimeout("100ms".to_owned())]).await;
+        let name = testing::unique_name("stalled");
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, libc::O_RDWR)
+            .await
+            .unwrap();
+        let ino = created.attr.ino;
+
+        let err = fs
+            .spin_no_delay(move |_, txn| {
+                Box::pin(async move {
+                    txn.put(ScopedKey::block(ino, 0), vec![0u8; 16]).await?;
+                    std::future::pending::<()>().await;
+                    Ok(())
+                })
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FsError::Timeout(_)));
+        assert_eq!(libc::c_int::from(err), libc::ETIMEDOUT);
+        assert_eq!(testing::stored_blocks(&fs, ino).await, 0);
+
+        fs.release(ino, created.fh, libc::O_RDWR, None, false)
+            .await
+            .unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    // the reads of a write of `blocks` blocks, half a block off the boundaries
+    async fn reads_of_write(fs: &TiFs, ino: u64, blocks: u64) -> usize {
+        let block_size = fs.block_size;
+        fs.spin_no_delay(move |_, txn| {
+            Box::pin(async move {
+                let data = vec![7u8; (blocks * block_size) as usize];
+                let before = txn.reads();
+                txn.write_data(ino, block_size / 2, data.into()).await?;
+                Ok(txn.reads() - before)
+            })
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn write_reads_blocks_at_once() {
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("batched");
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, libc::O_RDWR)
+            .await
+            .unwrap();
+        let ino = created.attr.ino;
+        let block_size = fs.block_size;
+        fs.spin_no_delay(move |_, txn| {
+            Box::pin(async move {
+                let data = vec![1u8; (64 * block_size) as usize];
+                txn.write_data(ino, 0, data.into()).await
+            })
+        })
+        .await
+        .unwrap();
+
+        // blocks replaced or partially written are read in batches, not one by one
+        let few = reads_of_write(&fs, ino, 4).await;
+        let many = reads_of_write(&fs, ino, 32).await;
+        assert_eq!(few, many);
+
+        let data = fs
+            .spin_no_delay(move |_, txn| Box::pin(async move { txn.read_data(ino, 0, None).await }))
+            .await
+            .unwrap();
+        let written = (block_size / 2) as usize..(block_size / 2 + 32 * block_size) as usize;
+        for (i, byte) in data.iter().enumerate() {
+            let expected = if written.contains(&i) { 7 } else { 1 };
+            assert_eq!(*byte, expected, "byte {}", i);
+        }
+
+        fs.release(ino, created.fh, libc::O_RDWR, None, false)
+            .await
+            .unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn small_read_fetches_its_block() {
+        let fs = testing::tifs(vec![MountOption::BlockCache("16m".to_owned())]).await;
+        let name = testing::unique_name("small-read");
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, libc::O_RDWR)
+            .await
+            .unwrap();
+        let ino = created.attr.ino;
+        let block_size = fs.block_size;
+        fs.spin_no_delay(move |_, txn| {
+            Box::pin(async move {
+                let data: Vec<u8> = (0..16 * block_size)
+                    .map(|i| (i / block_size) as u8)
+                    .collect();
+                txn.write_data(ino, 0, data.into()).await
+            })
+        })
+        .await
+        .unwrap();
+
+        let offset = 7 * block_size + block_size / 2;
+        let (data, stamp) = fs
+            .spin_no_delay(move |_, txn| {
+                Box::pin(async move {
+                    let data = txn.read_data(ino, offset, Some(1)).await?;
+                    Ok((data, BlockStamp::of(&txn.read_inode(ino).await?)))
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(data, [7]);
+        // every fetched block is cached, the others are not even scanned
+        let cache = fs.block_cache.as_ref().unwrap();
+        let fetched: Vec<u64> = (0..16)
+            .filter(|block| cache.get(ino, stamp, *block).is_some())
+            .collect();
+        assert_eq!(fetched, [7]);
+
+        fs.release(ino, created.fh, libc::O_RDWR, None, false)
+            .await
+            .unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn dontneed_drops_cached_blocks() {
+        let fs = testing::tifs(vec![MountOption::BlockCache("16m".to_owned())]).await;
+        let name = testing::unique_name("dontneed");
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        let data = Bytes::from(vec![1u8; 2 * fs.block_size as usize]);
+        fs.write(ino, fh, 0, data.clone(), 0, flags, None)
+            .await
+            .unwrap();
+        let read = fs.read(ino, fh, 0, data.len() as u32, flags, None);
+        assert_eq!(read.await.unwrap().data, data);
+
+        let stamp = fs
+            .spin_no_delay(move |_, txn| {
+                Box::pin(async move { Ok(BlockStamp::of(&txn.read_inode(ino).await?)) })
+            })
+            .await
+            .unwrap();
+        let cache = fs.block_cache.as_ref().unwrap();
+        let cached = || {
+            (0..2)
+                .filter(|block| cache.get(ino, stamp, *block).is_some())
+                .count()
+        };
+        assert_eq!(cached(), 2);
+        fs.fadvise(ino, fh, 0, 0, libc::POSIX_FADV_DONTNEED)
+            .await
+            .unwrap();
+        assert_eq!(cached(), 0);
+
+        fs.release(ino, fh, flags, None, true).await.unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn links_keep_owner() {
+        let fs = testing::tifs(Vec::new()).await;
+        let dir_mode = make_mode(FileType::Directory, 0o777);
+        let from_name = testing::unique_name("link-from");
+        let to_name = testing::unique_name("link-to");
+        let from = fs.mkdir(ROOT_INODE, from_name.clone(), dir_mode, 0, 0, 0);
+        let from = from.await.unwrap().stat.ino;
+        let to = fs.mkdir(ROOT_INODE, to_name.clone(), dir_mode, 0, 0, 0);
+        let to = to.await.unwrap().stat.ino;
+        let mode = make_mode(FileType::RegularFile, 0o640);
+        let created = fs
+            .create(1000, 100, from, "file".into(), mode, 0, libc::O_RDWR)
+            .await
+            .unwrap();
+        let ino = created.attr.ino;
+        fs.release(ino, created.fh, libc::O_RDWR, None, false)
+            .await
+            .unwrap();
+
+        let linked = fs.link(ino, to, "link".into()).await.unwrap().stat;
+        assert_eq!(linked.ino, ino);
+        assert_eq!((linked.uid, linked.gid, linked.perm), (1000, 100, 0o640));
+        assert_eq!(linked.nlink, 2);
+        let attr = fs.getattr(ino).await.unwrap().attr;
+        assert_eq!((attr.uid, attr.gid, attr.nlink), (1000, 100, 2));
+        assert_eq!(fs.lookup(to, "link".into()).await.unwrap().stat.ino, ino);
+
+        // both names link to the inode, so the rename keeps them both
+        fs.rename(1000, from, "file".into(), to, "link".into(), 0)
+            .await
+            .unwrap();
+        assert_eq!(fs.lookup(from, "file".into()).await.unwrap().stat.ino, ino);
+        assert_eq!(fs.lookup(to, "link".into()).await.unwrap().stat.ino, ino);
+        assert_eq!(fs.getattr(ino).await.unwrap().attr.nlink, 2);
+
+        fs.unlink(1000, from, "file".into()).await.unwrap();
+        let attr = fs.lookup(to, "link".into()).await.unwrap().stat;
+        assert_eq!((attr.uid, attr.nlink), (1000, 1));
+        fs.unlink(1000, to, "link".into()).await.unwrap();
+        fs.rmdir(0, ROOT_INODE, from_name).await.unwrap();
+        fs.rmdir(0, ROOT_INODE, to_name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn list_entries_with_their_types() {
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("listed");
+        let dir_mode = make_mode(FileType::Directory, 0o755);
+        let dir = fs.mkdir(ROOT_INODE, name.clone(), dir_mode, 0, 0, 0o022);
+        let dir = dir.await.unwrap().stat.ino;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let file = fs
+            .create(0, 0, dir, "file".into(), mode, 0o022, libc::O_RDWR)
+            .await
+            .unwrap();
+        fs.release(file.attr.ino, file.fh, libc::O_RDWR, None, false)
+            .await
+            .unwrap();
+        let sub = fs.mkdir(dir, "sub".into(), dir_mode, 0, 0, 0o022);
+        let sub = sub.await.unwrap().stat.ino;
+        let link = fs.symlink(0, 0, dir, "link".into(), "file".into());
+        let link = link.await.unwrap().stat.ino;
+
+        let expected = [
+            (dir, ".", FileType::Directory),
+            (ROOT_INODE, "..", FileType::Directory),
+            (file.attr.ino, "file", FileType::RegularFile),
+            (sub, "sub", FileType::Directory),
+            (link, "link", FileType::Symlink),
+        ];
+        let listed = fs.readdir(dir, 0, 0).await.unwrap();
+        let mut items: Vec<_> = listed
+            .items()
+            .iter()
+            .map(|item| (item.ino, item.name.as_str(), item.typ))
+            .collect();
+        // `.` and `..` come first, the others in any order
+        items[2..].sort_by_key(|(ino, _, _)| *ino);
+        assert_eq!(items, expected);
+
+        // entries of readdirplus agree with the inodes
+        let listed = fs.readdirplus(dir, 0, 2).await.unwrap();
+        assert_eq!(listed.items().len(), 3);
+        for (item, entry) in listed.items() {
+            assert_eq!((entry.stat.ino, entry.stat.kind), (item.ino, item.typ));
+        }
+
+        fs.unlink(0, dir, "file".into()).await.unwrap();
+        fs.unlink(0, dir, "link".into()).await.unwrap();
+        fs.rmdir(0, dir, "sub".into()).await.unwrap();
+        fs.rmdir(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn buffered_writes_are_seen_by_entries() {
+        let fs = testing::tifs(vec![MountOption::Writeback]).await;
+        let name = testing::unique_name("buffered");
+        let dir_mode = make_mode(FileType::Directory, 0o755);
+        let dir = fs.mkdir(ROOT_INODE, name.clone(), dir_mode, 0, 0, 0o022);
+        let dir = dir.await.unwrap().stat.ino;
+        let flags = libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, dir, "file".into(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        // less than a block, so that it stays in the buffer
+        let data = Bytes::from(vec![1u8; 16]);
+
+        fs.write(ino, fh, 0, data.clone(), 0, flags, None)
+            .await
+            .unwrap();
+        let entry = fs.lookup(dir, "file".into()).await.unwrap();
+        assert_eq!(entry.stat.size, 16);
+
+        fs.write(ino, fh, 16, data.clone(), 0, flags, None)
+            .await
+            .unwrap();
+        let listed = fs.readdirplus(dir, 0, 2).await.unwrap();
+        let (_, entry) = &listed.items()[0];
+        assert_eq!((entry.stat.ino, entry.stat.size), (ino, 32));
+
+        fs.release(ino, fh, flags, None, true).await.unwrap();
+        fs.unlink(0, dir, "file".into()).await.unwrap();
+        fs.rmdir(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn copied_blocks_are_apart_from_the_source() {
+        let fs = testing::tifs(Vec::new()).await;
+        let block_size = fs.block_size as usize;
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let mut files = Vec::new();
+        for prefix in ["clone-src", "clone-dst"] {
+            let name = testing::unique_name(prefix);
+            let created = fs
+                .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+                .await
+                .unwrap();
+            files.push((name, created.attr.ino, created.fh));
+        }
+        let (src, src_fh) = (files[0].1, files[0].2);
+        let (dst, dst_fh) = (files[1].1, files[1].2);
+
+        // whole blocks with a tail copied byte by byte
+        let mut data = testing::unique_name("clone").as_bytes().to_vec();
+        data.resize(2 * block_size + 100, 1);
+        fs.write(src, src_fh, 0, data.clone().into(), 0, flags, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            fs.copy_file_range(src, src_fh, 0, dst, dst_fh, 0, data.len() as u64, 1)
+                .await,
+            Err(FsError::UnknownCopyFlags { flags: 1 })
+        ));
+        let mut copied = 0;
+        while copied < data.len() {
+            let offset = copied as i64;
+            let len = (data.len() - copied) as u64;
+            let written = fs
+                .copy_file_range(src, src_fh, offset, dst, dst_fh, offset, len, 0)
+                .await
+                .unwrap();
+            copied += written.size as usize;
+        }
+        // whole blocks are copied as they are stored, without dedup they take keys of their own
+        assert_eq!(testing::stored_blocks(&fs, dst).await, 3);
+        let read = |ino, fh| fs.read(ino, fh, 0, data.len() as u32 + 1, flags, None);
+        assert_eq!(read(dst, dst_fh).await.unwrap().data, data);
+
+        // writing one of them leaves the other one as it was
+        fs.write(dst, dst_fh, 0, Bytes::from_static(b"new"), 0, flags, None)
+            .await
+            .unwrap();
+        assert_eq!(read(src, src_fh).await.unwrap().data, data);
+        let mut written = data.clone();
+        written[..3].copy_from_slice(b"new");
+        assert_eq!(read(dst, dst_fh).await.unwrap().data, written);
+
+        for (name, ino, fh) in files {
+            fs.release(ino, fh, flags, None, true).await.unwrap();
+            fs.unlink(0, ROOT_INODE, name).await.unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore]
+    async fn clone_whole_blocks() {
+        let fs = testing::tifs(Vec::new()).await;
+        let block_size = fs.block_size;
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let mut files = Vec::new();
+        for prefix in ["ficlone-src", "ficlone-dst"] {
+            let name = testing::unique_name(prefix);
+            let created = fs
+                .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+                .await
+                .unwrap();
+            files.push((name, created.attr.ino, created.fh));
+        }
+        let (src, src_fh) = (files[0].1, files[0].2);
+        let (dst, dst_fh) = (files[1].1, files[1].2);
+        let data: Vec<u8> = (0..3 * block_size + 7).map(|i| i as u8).collect();
+        fs.write(src, src_fh, 0, data.clone().into(), 0, flags, None)
+            .await
+            .unwrap();
+
+        // ranges must be of whole blocks, but for the tail of the source
+        assert!(matches!(
+            fs.clone_range(src, 1, dst, 0, block_size).await,
+            Err(FsError::InvalidOffset { .. })
+        ));
+        assert!(matches!(
+            fs.clone_range(src, 0, dst, 0, block_size + 1).await,
+            Err(FsError::InvalidOffset { .. })
+        ));
+        assert!(matches!(
+            fs.clone_range(src, 0, src, block_size, 2 * block_size)
+                .await,
+            Err(FsError::InvalidOffset { .. })
+        ));
+        fs.clone_range(src, block_size, dst, 0, 0).await.unwrap();
+        let cloned = fs
+            .read(dst, dst_fh, 0, data.len() as u32, flags, None)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(cloned, &data[block_size as usize..]);
+        assert_eq!(testing::stored_blocks(&fs, dst).await, 3);
+
+        for (name, ino, fh) in files {
+            fs.release(ino, fh, flags, None, true).await.unwrap();
+            fs.unlink(0, ROOT_INODE, name).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn removing_last_link_deletes_blocks() {
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("blocks");
+        let other = testing::unique_name("blocks-link");
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let ino = created.attr.ino;
+        let data = Bytes::from(vec![1u8; 3 * fs.block_size as usize]);
+        fs.write(ino, created.fh, 0, data.clone(), 0, flags, None)
+            .await
+            .unwrap();
+        // a sparse block far beyond the others
+        fs.write(ino, created.fh, 1 << 40, data, 0, flags, None)
+            .await
+            .unwrap();
+        fs.release(ino, created.fh, flags, None, true)
+            .await
+            .unwrap();
+        assert_eq!(testing::stored_blocks(&fs, ino).await, 6);
+
+        fs.link(ino, ROOT_INODE, other.clone()).await.unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+        assert_eq!(testing::stored_blocks(&fs, ino).await, 6);
+        fs.unlink(0, ROOT_INODE, other).await.unwrap();
+        assert_eq!(testing::stored_blocks(&fs, ino).await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unlinked_open_file_is_cleared_on_close() {
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("unlinked");
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        let data = Bytes::from(vec![1u8; 2 * fs.block_size as usize]);
+        fs.write(ino, fh, 0, data.clone(), 0, flags, None)
+            .await
+            .unwrap();
+
+        // the open file is still written and read after its last link is gone
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+        let offset = data.len() as i64;
+        fs.write(ino, fh, offset, data.clone(), 0, flags, None)
+            .await
+            .unwrap();
+        let read = fs.read(ino, fh, offset, data.len() as u32, flags, None);
+        assert_eq!(read.await.unwrap().data, data);
+        assert_eq!(testing::stored_blocks(&fs, ino).await, 4);
+
+        fs.release(ino, fh, flags, None, true).await.unwrap();
+        assert_eq!(testing::stored_blocks(&fs, ino).await, 0);
+        let stored = fs
+            .spin_no_delay(move |_, txn| Box::pin(txn.get(ScopedKey::inode(ino))))
+            .await
+            .unwrap();
+        assert!(stored.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unlinked_numbers_are_reused_with_newer_generations() {
+        let fs = testing::tifs(Vec::new()).await;
+        let freed_name = testing::unique_name("freed");
+        let reusing_name = testing::unique_name("reusing");
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        // in one transaction, so that no other test takes the free numbers meanwhile
+        let reusing = reusing_name.clone();
+        let (freed, smallest, reused) = fs
+            .spin_no_delay(move |_, txn| {
+                let (freed_name, reusing) = (freed_name.clone(), reusing.clone());
+                Box::pin(async move {
+                    let freed = txn
+                        .make_inode(ROOT_INODE, freed_name.clone(), mode, 0, 0, 0)
+                        .await?;
+                    txn.unlink(ROOT_INODE, freed_name).await?;
+                    let free = txn.get(ScopedKey::free_inode(freed.ino)).await?;
+                    assert_eq!(free, Some(freed.generation.to_be_bytes().to_vec()));
+
+                    // the smallest free number is reused, others may be freed before
+                    let pair = txn.scan(ScopedKey::free_inode_scope(), 1).await?.next();
+                    let pair = pair.unwrap();
+                    let smallest = match ScopedKey::parse(pair.key().into())? {
+                        ScopedKey::FreeInode(ino) => ino,
+                        key => panic!("unexpected key {:?}", key),
+                    };
+                    let generation = <[u8; 8]>::try_from(pair.value().as_slice()).unwrap();
+                    let smallest = (smallest, u64::from_be_bytes(generation));
+                    let reused = txn.make_inode(ROOT_INODE, reusing, mode, 0, 0, 0).await?;
+                    Ok((freed, smallest, reused))
+                })
+            })
+            .await
+            .unwrap();
+        assert!(smallest.0 <= freed.ino);
+        assert_eq!(reused.ino, smallest.0);
+        // stale handles of the removed inode never resolve to the new one
+        assert!(reused.generation > smallest.1);
+        let entry = fs.lookup(ROOT_INODE, reusing_name.clone()).await.unwrap();
+        assert_eq!(
+            (entry.stat.ino, entry.generation),
+            (reused.ino, reused.generation)
+        );
+
+        fs.unlink(0, ROOT_INODE, reusing_name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn usage_matches_a_full_scan() {
+        let fs = testing::tifs(Vec::new()).await;
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let data = Bytes::from(vec![1u8; 3 * fs.block_size as usize]);
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            let name = testing::unique_name("usage");
+            let created = fs
+                .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+                .await
+                .unwrap();
+            let (ino, fh) = (created.attr.ino, created.fh);
+            fs.write(ino, fh, 0, data.clone(), 0, flags, None)
+                .await
+                .unwrap();
+            let offset = data.len() as i64;
+            fs.write(ino, fh, offset, data.clone(), 0, flags, None)
+                .await
+                .unwrap();
+            fs.release(ino, fh, flags, None, true).await.unwrap();
+            names.push(name);
+        }
+        fs.unlink(0, ROOT_INODE, names.pop().unwrap())
+            .await
+            .unwrap();
+
+        // the changes of every transaction are added to meta on commit, so any snapshot of it
+        // agrees with the inodes
+        let (stored, scanned) = fs
+            .spin_no_delay(|_, txn| {
+                Box::pin(async move {
+                    let stored = txn.read_meta().await?.and_then(|meta| meta.usage);
+                    Ok((stored, txn.recompute_usage().await?))
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(stored, Some(scanned));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn getlk_reports_the_holder() {
+        let fs = testing::tifs(vec![MountOption::PosixLocks]).await;
+        let name = testing::unique_name("getlk");
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = fs
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        let (holder, other, pid) = (1, 2, 100);
+        let (read, write, unlocked) = (F_RDLCK as i32, F_WRLCK as i32, F_UNLCK as i32);
+        fs.setlk(ino, fh, holder, 10, 19, write, pid, false)
+            .await
+            .unwrap();
+
+        let lock = fs.getlk(ino, fh, other, 0, 14, read, 200).await.unwrap();
+        assert_eq!(
+            (lock.start, lock.end, lock.typ, lock.pid),
+            (10, 19, write, pid)
+        );
+        let lock = fs.getlk(ino, fh, other, 20, 29, write, 200).await.unwrap();
+        assert_eq!((lock.start, lock.end, lock.typ), (20, 29, unlocked));
+        // the holder itself conflicts with nobody
+        let lock = fs.getlk(ino, fh, holder, 0, 14, write, pid).await.unwrap();
+        assert_eq!((lock.start, lock.end, lock.typ), (0, 14, unlocked));
+
+        fs.setlk(ino, fh, holder, 0, i64::MAX as u64, unlocked, pid, false)
+            .await
+            .unwrap();
+        let lock = fs.getlk(ino, fh, other, 0, 14, write, 200).await.unwrap();
+        assert_eq!(lock.typ, unlocked);
+        fs.release(ino, fh, flags, None, true).await.unwrap();
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn flock_and_record_locks_are_apart() {
+        let flock = testing::tifs(Vec::new()).await;
+        let posix = testing::tifs(vec![MountOption::PosixLocks]).await;
+        let name = testing::unique_name("locks");
+        let flags = libc::O_CREAT | libc::O_RDWR;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let created = flock
+            .create(0, 0, ROOT_INODE, name.clone(), mode, 0o022, flags)
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+        let (read, write) = (F_RDLCK as i32, F_WRLCK as i32);
+        let whole = i64::MAX as u64;
+
+        // an open file holds a flock lock, while processes hold record locks of the same file
+        flock
+            .setlk(ino, fh, 1, 0, whole, write, 100, false)
+            .await
+            .unwrap();
+        posix
+            .setlk(ino, fh, 2, 0, 9, write, 200, false)
+            .await
+            .unwrap();
+        posix
+            .setlk(ino, fh, 3, 10, 19, write, 300, false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            posix.setlk(ino, fh, 3, 5, 14, read, 300, false).await,
+            Err(FsError::InvalidLock)
+        ));
+        assert!(matches!(
+            flock.setlk(ino, fh, 4, 0, whole, read, 400, false).await,
+            Err(FsError::InvalidLock)
+        ));
+        let lock = posix.getlk(ino, fh, 4, 0, whole, read, 400).await.unwrap();
+        assert_eq!((lock.start, lock.end, lock.pid), (0, 9, 200));
+
+        // a process closing the file releases only its record locks
+        posix.flush(ino, fh, 2).await.unwrap();
+        let lock = posix.getlk(ino, fh, 4, 0, whole, read, 400).await.unwrap();
+        assert_eq!((lock.start, lock.end, lock.pid), (10, 19, 300));
+        assert!(matches!(
+            flock.setlk(ino, fh, 4, 0, whole, read, 400, false).await,
+            Err(FsError::InvalidLock)
+        ));
+
+        // releasing the open file releases only its flock lock
+        flock.release(ino, fh, flags, Some(1), true).await.unwrap();
+        let state = flock
+            .spin_no_delay(move |_, txn| Box::pin(txn.read_inode(ino)))
+            .await
+            .unwrap()
+            .lock_state;
+        assert!(state.owner_set.is_empty());
+        assert_eq!(state.records.len(), 1);
+        let lock = posix.getlk(ino, fh, 4, 0, whole, write, 400).await.unwrap();
+        assert_eq!((lock.start, lock.end, lock.typ), (10, 19, write));
+        flock.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn chmod_and_chown_take_the_owner() {
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("chmod");
+        let (owner, other) = (1000, 1001);
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let entry = fs
+            .mknod(ROOT_INODE, name.clone(), mode, owner, owner, 0o022, 0)
+            .await
+            .unwrap();
+        let ino = entry.stat.ino;
+        let setattr = |req_uid, mode, uid| {
+            fs.setattr(
+                req_uid, req_uid, 0, ino, mode, uid, None, None, None, None, None, None, None,
+                None, None, None,
+            )
+        };
+
+        // the permission bits are checked here, as `no_default_permissions` leaves it to tifs
+        assert!(matches!(
+            setattr(other, Some(0o666), None).await,
+            Err(FsError::ChangeMode { .. })
+        ));
+        assert!(matches!(
+            setattr(other, None, Some(other)).await,
+            Err(FsError::ChangeOwner { .. })
+        ));
+        let attr = fs.getattr(ino).await.unwrap().attr;
+        assert_eq!((attr.perm, attr.uid), (0o644, owner));
+
+        assert_eq!(
+            setattr(owner, Some(0o600), None).await.unwrap().attr.perm,
+            0o600
+        );
+        let attr = setattr(0, Some(0o640), Some(other)).await.unwrap().attr;
+        assert_eq!((attr.perm, attr.uid), (0o640, other));
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore]
+    async fn immutable_flags_take_root() {
+        use crate::fs::inode::FS_IMMUTABLE_FL;
+
+        let fs = testing::tifs(Vec::new()).await;
+        let name = testing::unique_name("immutable");
+        let owner = 1000;
+        let mode = make_mode(FileType::RegularFile, 0o644);
+        let entry = fs
+            .mknod(ROOT_INODE, name.clone(), mode, owner, owner, 0o022, 0)
+            .await
+            .unwrap();
+        let ino = entry.stat.ino;
+        let setattr = |req_uid, mode, flags| {
+            fs.setattr(
+                req_uid, req_uid, 0, ino, mode, None, None, None, None, None, None, None, None,
+                None, None, flags,
+            )
+        };
+
+        // the owner may set other flags, but neither set nor clear the immutable flag
+        let nodump = 0x40;
+        assert_eq!(
+            setattr(owner, None, Some(nodump)).await.unwrap().attr.flags,
+            nodump
+        );
+        assert!(matches!(
+            setattr(owner, None, Some(FS_IMMUTABLE_FL)).await,
+            Err(FsError::ChangeFlags { .. })
+        ));
+        assert!(matches!(
+            setattr(owner + 1, None, Some(0)).await,
+            Err(FsError::ChangeFlags { .. })
+        ));
+        let attr = setattr(0, None, Some(FS_IMMUTABLE_FL)).await.unwrap().attr;
+        assert_eq!(attr.flags, FS_IMMUTABLE_FL);
+        assert!(matches!(
+            setattr(0, Some(0o600), None).await,
+            Err(FsError::Immutable { .. })
+        ));
+        assert!(matches!(
+            setattr(owner, None, Some(0)).await,
+            Err(FsError::ChangeFlags { .. })
+        ));
+
+        // `lsattr` and `chattr` go through ioctls
+        let got = fs
+            .ioctl(owner, 0, ino, 0, 0, FS_IOC_GETFLAGS, Bytes::new(), 8)
+            .await
+            .unwrap();
+        assert_eq!(got.data, (FS_IMMUTABLE_FL as u64).to_ne_bytes());
+        let cleared = Bytes::copy_from_slice(&0u32.to_ne_bytes());
+        assert!(matches!(
+            fs.ioctl(owner, 0, ino, 0, 0, FS_IOC32_SETFLAGS, cleared.clone(), 0)
+                .await,
+            Err(FsError::ChangeFlags { .. })
+        ));
+        fs.ioctl(0, 0, ino, 0, 0, FS_IOC32_SETFLAGS, cleared, 0)
+            .await
+            .unwrap();
+        assert_eq!(fs.getattr(ino).await.unwrap().attr.flags, 0);
+        fs.unlink(0, ROOT_INODE, name).await.unwrap();
+    }
+
+    #[test]
+    fn whole_blocks() {
+        let block_size = TiFs::DEFAULT_BLOCK_SIZE;
+        assert_eq!(TiFs::whole_blocks(1 << 20, block_size), 1 << 20);
+        assert_eq!(TiFs::whole_blocks((1 << 20) + 1, block_size), 1 << 20);
+        assert_eq!(TiFs::whole_blocks(1, block_size), block_size as u32);
+        assert_eq!(
+            TiFs::whole_blocks(u64::MAX, block_size),
+            u32::MAX / block_size as u32 * block_size as u32
+        );
+        assert_eq!(
+            TiFs::whole_blocks(u64::MAX, TiFs::MIN_BLOCK_SIZE) as u64 % TiFs::MIN_BLOCK_SIZE,
+            0
+        );
+    }
+
+    #[test]
+    fn adopt_stored_block_size() {
+        let default = TiFs::DEFAULT_BLOCK_SIZE;
+        // a new filesystem
+        assert_eq!(TiFs::choose_block_size(None, None).unwrap(), default);
+        assert_eq!(TiFs::choose_block_size(Some(512), None).unwrap(), 512);
+        // an existing filesystem mounted without blksize
+        assert_eq!(TiFs::choose_block_size(None, Some(512)).unwrap(), 512);
+        assert_eq!(
+            TiFs::choose_block_size(None, Some(default)).unwrap(),
+            default
+        );
+        // an existing filesystem mounted with blksize
+        assert_eq!(TiFs::choose_block_size(Some(512), Some(512)).unwrap(), 512);
+        assert!(matches!(
+            TiFs::choose_block_size(Some(4096), Some(512)),
+            Err(FsError::BlockSizeConflict {
+                origin: 512,
+                new: 4096
+            })
+        ));
+    }
+
+    #[test]
+    fn check_block_size() {
+        for size in [0, 1, 8, 15, 100, 3 << 10, (1 << 16) + 1, 1 << 23, u64::MAX] {
+            assert!(
+                matches!(
+                    TiFs::check_block_size(size),
+                    Err(FsError::InvalidBlockSize { .. })
+                ),
+                "size({})",
+                size
+            );
+        }
+        for size in [
+            TiFs::MIN_BLOCK_SIZE,
+            512,
+            4 << 10,
+            TiFs::DEFAULT_BLOCK_SIZE,
+            TiFs::MAX_BLOCK_SIZE,
+        ] {
+            assert!(TiFs::check_block_size(size).is_ok(), "size({})", size);
+        }
+    }
+
+    #[test]
+    fn check_link_target() {
+        // far beyond the inline threshold of the smallest blocks
+        let long = "a/".repeat(1 << 10);
+        assert!(long.len() as u64 > TiFs::MIN_BLOCK_SIZE / Txn::INLINE_DATA_THRESHOLD_BASE);
+        assert!(TiFs::check_link_target(&long).is_ok());
+        assert!(matches!(
+            TiFs::check_link_target(&"a".repeat(libc::PATH_MAX as usize)),
+            Err(FsError::NameTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn inline_divisor_thresholds() {
+        let block_size = TiFs::DEFAULT_BLOCK_SIZE;
+        assert_eq!(TiFs::parse_inline_divisor("8", block_size), Some(8));
+        assert_eq!(TiFs::parse_inline_divisor("2", block_size), Some(2));
+        assert_eq!(TiFs::parse_inline_divisor("1", block_size), None);
+        // checked against the block size of the filesystem
+        assert_eq!(TiFs::parse_inline_divisor("32", 16), None);
+        assert_eq!(
+            TiFs::parse_inline_divisor(&block_size.to_string(), block_size),
+            Some(block_size)
+        );
+        assert_eq!(TiFs::parse_inline_divisor("0", block_size), None);
+        assert_eq!(
+            TiFs::parse_inline_divisor(&(block_size + 1).to_string(), block_size),
+            None
+        );
+        assert_eq!(TiFs::parse_inline_divisor("-1", block_size), None);
+
+        for divisor in [2, 8, Txn::INLINE_DATA_THRESHOLD_BASE, block_size] {
+            let threshold = block_size / divisor;
+            assert!(threshold > 0 && threshold <= block_size);
+            assert_eq!(
+                Placement::of_write(false, 0, threshold, threshold, false),
+                Placement::Inline,
+                "divisor({})",
+                divisor
+            );
+            assert_eq!(
+                Placement::of_write(true, threshold, threshold + 1, threshold, false),
+                Placement::Blocks { transfer: true },
+                "divisor({})",
+                divisor
+            );
+        }
+    }
+
+    #[test]
+    fn parse_id() {
+        assert_eq!(TiFs::parse_id("uid", "1000", 10), Some(1000));
+        assert_eq!(TiFs::parse_id("fmask", "022", 8), Some(0o22));
+        assert_eq!(TiFs::parse_id("fmask", "9", 8), None);
+        assert_eq!(TiFs::parse_id("gid", "-1", 10), None);
     }
 }
@@ -1,43 +1,201 @@
-use std::ops::{Deref, DerefMut};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use bytes::Bytes;
 use bytestring::ByteString;
 use fuser::{FileAttr, FileType};
-use tikv_client::{Transaction, TransactionClient, TransactionOptions};
-use tracing::{debug, instrument, trace};
-
-use super::block::empty_block;
-use super::dir::Directory;
+use tikv_client::{
+    BoundRange, Key, KvPair, Snapshot, Timestamp, TimestampExt, Transaction, TransactionClient,
+    TransactionOptions, Value,
+};
+use tracing::{debug, error, instrument, trace};
+
+use super::acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
+use super::atime::AtimePolicy;
+use super::block::{
+    append_checksum, block_value, blocks_of_range, copy_block, decode_content, empty_block,
+    encode_content, hash_block, inline_block, read_inlined, split_blocks, strip_checksum, Hash,
+    Placement,
+};
+use super::cache::{BlockCache, BlockStamp};
+use super::commit::CommitPolicy;
+use super::crypto::BlockCipher;
+use super::dir::{self, Directory};
 use super::error::{FsError, Result};
-use super::file_handler::FileHandler;
+use super::file_handler::{checked_range, FileHandler};
 use super::index::Index;
 use super::inode::Inode;
-use super::key::{ScopedKey, ROOT_INODE};
-use super::meta::Meta;
-use super::mode::{as_file_kind, as_file_perm, make_mode};
+use super::key::{Pages, ScopedKey, ROOT_INODE};
+use super::meta::{Meta, Usage};
+use super::mode::{
+    apply_umask, as_file_kind, as_file_perm, check_directory, check_regular_file, inherit_setgid,
+    make_mode, sticky_forbids,
+};
+use super::prefetch::Prefetched;
 use super::reply::{DirItem, StatFs};
-use super::tikv_fs::{DIR_PARENT, DIR_SELF};
+use super::tikv_fs::{TiFs, DIR_PARENT, DIR_SELF};
+
+/// Where a transaction reads and writes, a snapshot only reads at its timestamp.
+enum Store {
+    Optimistic(Transaction),
+    Snapshot(Snapshot),
+}
 
 pub struct Txn {
-    txn: Transaction,
+    store: Store,
     block_size: u64,
     max_blocks: Option<u64>,
     max_name_len: u32,
+    inline_data_threshold: u64,
+    disable_atime: bool,
+    atime_policy: AtimePolicy,
+    dedup: bool,
+    cipher: Option<Arc<BlockCipher>>,
+    checksum: bool,
+    saved_inodes: HashSet<u64>,
+    // blocks of saved inodes before and after the transaction, added to the usage in meta
+    // once on commit, so that meta is not rewritten by every save
+    usage_changes: HashMap<u64, (Option<u64>, Option<u64>)>,
+    // directories whose entries are set or removed
+    changed_dirs: HashSet<u64>,
+    block_cache: Option<Arc<BlockCache>>,
+    // inodes whose blocks are written, they bypass the block cache
+    written_inodes: HashSet<u64>,
+    scan_limit: u32,
+    prefetched: Prefetched,
+    // requests sent to read the store
+    reads: usize,
 }
 
 impl Txn {
-    const INLINE_DATA_THRESHOLD_BASE: u64 = 1 << 4;
+    pub const INLINE_DATA_THRESHOLD_BASE: u64 = 1 << 4;
+    pub const READ_BATCH_SIZE: u64 = 1 << 20;
+    pub const CLONE_BATCH_BLOCKS: u64 = 1 << 10;
 
     fn inline_data_threshold(&self) -> u64 {
-        self.block_size / Self::INLINE_DATA_THRESHOLD_BASE
+        self.inline_data_threshold
     }
 
     pub fn block_size(&self) -> u64 {
         self.block_size
     }
 
+    pub fn dedup(&self) -> bool {
+        self.dedup
+    }
+
+    pub fn scan_limit(&self) -> u32 {
+        self.scan_limit
+    }
+
+    /// The number of requests this transaction sent to read the store.
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    /// Encrypt data of files if encryption is enabled.
+    fn encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&data),
+            None => Ok(data),
+        }
+    }
+
+    fn decrypt(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&value),
+            None => Ok(value),
+        }
+    }
+
+    /// Hash of the data of a block in dedup mode, keyed by the key if encryption is enabled.
+    fn content_hash(&self, data: &[u8]) -> Hash {
+        match &self.cipher {
+            Some(cipher) => cipher.content_hash(data),
+            None => hash_block(data),
+        }
+    }
+
+    /// Seal the data of a file block, its checksum trails the data before encryption.
+    fn seal_block(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.checksum {
+            self.encrypt(append_checksum(data))
+        } else {
+            self.encrypt(data)
+        }
+    }
+
+    /// Open a sealed file block, a block not matching its checksum fails the read.
+    fn unseal_block(&self, ino: u64, block: u64, value: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.decrypt(value)?;
+        if !self.checksum {
+            return Ok(data);
+        }
+        strip_checksum(data).ok_or_else(|| {
+            let err = FsError::ChecksumMismatch { ino, block };
+            error!("{}", err);
+            err
+        })
+    }
+
+    /// Inodes saved or removed by this transaction.
+    pub fn saved_inodes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.saved_inodes.iter().copied()
+    }
+
+    /// Directories whose entries are set or removed by this transaction.
+    pub fn changed_dirs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.changed_dirs.iter().copied()
+    }
+
+    /// Inodes whose blocks are written or deleted by this transaction.
+    pub fn written_inodes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.written_inodes.iter().copied()
+    }
+
+    pub fn disable_atime(&self) -> bool {
+        self.disable_atime
+    }
+
+    /// Update atime of an inode being read, returns false if atime is left unchanged.
+    fn update_atime(&self, inode: &mut Inode) -> bool {
+        let now = SystemTime::now();
+        if self.disable_atime || !self.atime_policy.should_update(inode, now) {
+            false
+        } else {
+            inode.atime = now;
+            true
+        }
+    }
+
+    /// The usage in meta with the changes of this transaction.
+    fn usage(&self, meta: &Meta) -> Option<Usage> {
+        meta.usage.map(|mut usage| {
+            for (old_blocks, new_blocks) in self.usage_changes.values() {
+                usage.update(*old_blocks, *new_blocks);
+            }
+            usage
+        })
+    }
+
     fn check_space_left(&self, meta: &Meta) -> Result<()> {
+        if let (Some(usage), Some(max_blocks)) = (self.usage(meta), self.max_blocks) {
+            return if usage.blocks >= max_blocks {
+                Err(FsError::NoSpaceLeft(self.block_size * max_blocks))
+            } else {
+                Ok(())
+            };
+        }
         match meta.last_stat {
             Some(ref stat) if stat.bavail == 0 => {
                 Err(FsError::NoSpaceLeft(stat.bsize as u64 * stat.blocks))
@@ -46,72 +204,191 @@ impl Txn {
         }
     }
 
+    /// Fail if growing an inode from `old_blocks` to `new_blocks` would exceed the max size.
+    fn check_growth(&self, meta: &Meta, old_blocks: u64, new_blocks: u64) -> Result<()> {
+        match (self.usage(meta), self.max_blocks) {
+            (Some(usage), Some(max_blocks)) => {
+                if usage.fits(old_blocks, new_blocks, max_blocks) {
+                    Ok(())
+                } else {
+                    Err(FsError::NoSpaceLeft(self.block_size * max_blocks))
+                }
+            }
+            _ => self.check_space_left(meta),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn begin_optimistic(
         client: &TransactionClient,
         block_size: u64,
         max_size: Option<u64>,
         max_name_len: u32,
+        inline_data_threshold: u64,
+        disable_atime: bool,
+        atime_policy: AtimePolicy,
+        dedup: bool,
+        cipher: Option<Arc<BlockCipher>>,
+        commit_policy: CommitPolicy,
     ) -> Result<Self> {
         Ok(Txn {
-            txn: client
-                .begin_with_options(TransactionOptions::new_optimistic().use_async_commit())
-                .await?,
+            store: Store::Optimistic(client.begin_with_options(commit_policy.options()).await?),
             block_size,
             max_blocks: max_size.map(|size| size / block_size),
             max_name_len,
+            inline_data_threshold,
+            disable_atime,
+            atime_policy,
+            dedup,
+            cipher,
+            checksum: false,
+            saved_inodes: HashSet::new(),
+            usage_changes: HashMap::new(),
+            changed_dirs: HashSet::new(),
+            block_cache: None,
+            written_inodes: HashSet::new(),
+            scan_limit: TiFs::SCAN_LIMIT,
+            prefetched: Prefetched::default(),
+            reads: 0,
         })
     }
 
-    pub async fn open(&mut self, ino: u64) -> Result<u64> {
-        let mut inode = self.read_inode(ino).await?;
-        let fh = inode.next_fh;
-        self.save_fh(ino, fh, &FileHandler::default()).await?;
-        inode.next_fh += 1;
-        inode.opened_fh += 1;
-        self.save_inode(&inode).await?;
-        Ok(fh)
+    /// A read-only transaction on the snapshot at `timestamp`, so that reads across transactions
+    /// on the same timestamp are consistent to each other. Writing fails by `EROFS`.
+    pub fn snapshot(
+        client: &TransactionClient,
+        timestamp: u64,
+        block_size: u64,
+        max_name_len: u32,
+        inline_data_threshold: u64,
+        dedup: bool,
+        cipher: Option<Arc<BlockCipher>>,
+    ) -> Self {
+        Txn {
+            store: Store::Snapshot(client.snapshot(
+                Timestamp::from_version(timestamp),
+                TransactionOptions::new_optimistic(),
+            )),
+            block_size,
+            max_blocks: None,
+            max_name_len,
+            inline_data_threshold,
+            disable_atime: true,
+            atime_policy: AtimePolicy::Never,
+            dedup,
+            cipher,
+            checksum: false,
+            saved_inodes: HashSet::new(),
+            usage_changes: HashMap::new(),
+            changed_dirs: HashSet::new(),
+            block_cache: None,
+            written_inodes: HashSet::new(),
+            scan_limit: TiFs::SCAN_LIMIT,
+            prefetched: Prefetched::default(),
+            reads: 0,
+        }
     }
 
-    pub async fn close(&mut self, ino: u64, fh: u64) -> Result<()> {
-        self.read_fh(ino, fh).await?;
-        self.delete(ScopedKey::handler(ino, fh)).await?;
+    /// Scan at most `limit` keys a page.
+    pub fn with_scan_limit(mut self, limit: u32) -> Self {
+        self.scan_limit = limit;
+        self
+    }
 
+    /// Store a checksum with every block of files, and verify it on reading the block.
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Serve reads of file blocks from `cache`, blocks read are cached there as well.
+    pub fn with_block_cache(mut self, cache: Option<Arc<BlockCache>>) -> Self {
+        self.block_cache = cache;
+        self
+    }
+
+    /// Open an inode, regular files opened with `O_TRUNC` are truncated in the same transaction.
+    pub async fn open(&mut self, ino: u64, flags: i32) -> Result<()> {
         let mut inode = self.read_inode(ino).await?;
-        inode.opened_fh -= 1;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            // an append-only file is only opened to append
+            match flags & (libc::O_APPEND | libc::O_TRUNC) {
+                libc::O_APPEND => inode.check_mutable()?,
+                _ => inode.check_overwrite()?,
+            }
+        }
+        inode.opened_fh += 1;
+        if flags & libc::O_TRUNC != 0 && inode.kind == FileType::RegularFile {
+            self.truncate(&mut inode, 0).await?;
+            let now = SystemTime::now();
+            inode.mtime = now;
+            inode.ctime = now;
+        }
         self.save_inode(&inode).await
     }
 
-    pub async fn read_fh(&mut self, ino: u64, fh: u64) -> Result<FileHandler> {
-        let data = self
-            .get(ScopedKey::handler(ino, fh))
-            .await?
-            .ok_or(FsError::FhNotFound { ino, fh })?;
-        FileHandler::deserialize(&data)
+    /// Close an inode, the flock lock of `lock_owner` is released along with the open file.
+    pub async fn close(&mut self, ino: u64, lock_owner: Option<u64>) -> Result<()> {
+        let mut inode = self.read_inode(ino).await?;
+        inode.opened_fh -= 1;
+        if let Some(owner) = lock_owner {
+            inode.lock_state.unlock(owner);
+        }
+        if inode.nlink == 0 && inode.opened_fh == 0 {
+            // the last handle of an inode unlinked while it's opened
+            self.clear_blocks(&inode).await?;
+            return self.remove_inode(ino).await;
+        }
+        self.save_inode(&inode).await
     }
 
-    pub async fn save_fh(&mut self, ino: u64, fh: u64, handler: &FileHandler) -> Result<()> {
-        Ok(self
-            .put(ScopedKey::handler(ino, fh), handler.serialize()?)
-            .await?)
+    /// Release the record locks of `owner`, the inode is saved only if it held any.
+    pub async fn unlock_records(&mut self, ino: u64, owner: u64) -> Result<()> {
+        let mut inode = self.read_inode(ino).await?;
+        if inode.lock_state.unlock_records(owner) {
+            self.save_inode(&inode).await?;
+        }
+        Ok(())
     }
 
-    pub async fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
-        let handler = self.read_fh(ino, fh).await?;
-        let start = handler.cursor as i64 + offset;
-        if start < 0 {
-            return Err(FsError::InvalidOffset { ino, offset: start });
+    pub async fn read(
+        &mut self,
+        ino: u64,
+        handler: FileHandler,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<u8>> {
+        check_regular_file(&self.read_inode(ino).await?)?;
+        let start = handler.position(ino, offset, size as u64)?;
+        if handler.direct {
+            self.read_data_direct(ino, start, size as u64).await
+        } else {
+            self.read_data(ino, start, Some(size as u64)).await
         }
-        self.read_data(ino, start as u64, Some(size as u64)).await
     }
 
-    pub async fn write(&mut self, ino: u64, fh: u64, offset: i64, data: Bytes) -> Result<usize> {
-        let handler = self.read_fh(ino, fh).await?;
-        let start = handler.cursor as i64 + offset;
-        if start < 0 {
-            return Err(FsError::InvalidOffset { ino, offset: start });
-        }
+    pub async fn write(
+        &mut self,
+        ino: u64,
+        handler: FileHandler,
+        offset: i64,
+        data: Bytes,
+    ) -> Result<usize> {
+        check_regular_file(&self.read_inode(ino).await?)?;
+        let start = if handler.is_append() {
+            // read the size within this transaction, so that concurrent appends conflict
+            let size = self.read_inode(ino).await?.size;
+            checked_range(size, 0, data.len() as u64)
+                .ok_or(FsError::InvalidOffset { ino, offset })?
+        } else {
+            handler.position(ino, offset, data.len() as u64)?
+        };
 
-        self.write_data(ino, start as u64, data).await
+        if handler.direct {
+            self.write_data_direct(ino, start, data).await
+        } else {
+            self.write_data(ino, start, data).await
+        }
     }
 
     pub async fn make_inode(
@@ -123,19 +400,36 @@ impl Txn {
         uid: u32,
         rdev: u32,
     ) -> Result<Inode> {
-        let mut meta = self
-            .read_meta()
-            .await?
-            .unwrap_or_else(|| Meta::new(self.block_size));
+        let mut meta = match self.read_meta().await? {
+            Some(meta) => meta,
+            None => {
+                let mut meta = Meta::new(
+                    self.block_size,
+                    self.disable_atime,
+                    self.dedup,
+                    self.encrypted(),
+                );
+                meta.checksum = self.checksum;
+                // inode numbers start over on a new filesystem
+                meta.renew_generation();
+                meta
+            }
+        };
         self.check_space_left(&meta)?;
-        let ino = meta.inode_next;
-        meta.inode_next += 1;
+        let free = self.pop_free_inode().await?;
+        let (ino, generation) = meta.allocate(free);
 
         debug!("get ino({})", ino);
         self.save_meta(&meta).await?;
 
         let file_type = as_file_kind(mode);
+        let (mut gid, mut perm) = (gid, as_file_perm(mode));
         if parent >= ROOT_INODE {
+            let parent_dir = self.read_inode(parent).await?;
+            parent_dir.check_mutable()?;
+            let (inherited_gid, inherited_perm) = inherit_setgid(&parent_dir, file_type, gid, perm);
+            gid = inherited_gid;
+            perm = inherited_perm;
             if self.get_index(parent, name.clone()).await?.is_some() {
                 return Err(FsError::FileExist {
                     file: name.to_string(),
@@ -156,7 +450,7 @@ impl Txn {
             // TODO: update attributes of directory
         }
 
-        let inode = FileAttr {
+        let mut inode: Inode = FileAttr {
             ino,
             size: 0,
             blocks: 0,
@@ -165,7 +459,7 @@ impl Txn {
             ctime: SystemTime::now(),
             crtime: SystemTime::now(),
             kind: file_type,
-            perm: as_file_perm(mode),
+            perm,
             nlink: 1,
             uid,
             gid,
@@ -174,6 +468,13 @@ impl Txn {
             flags: 0,
         }
         .into();
+        inode.generation = generation;
+        // symlinks have no ACL
+        if file_type != FileType::Symlink {
+            if let Some(default) = self.default_acl(parent).await? {
+                inode.inherit_acl(&default);
+            }
+        }
 
         debug!("made inode ({:?})", &inode);
 
@@ -181,6 +482,14 @@ impl Txn {
         Ok(inode)
     }
 
+    /// Fails if directory `newparent` is `ino` or one of its descendants.
+    pub async fn check_ancestor(&mut self, ino: u64, newparent: u64) -> Result<()> {
+        dir::check_ancestor(self, ino, newparent, |txn, dir| {
+            Box::pin(txn.get_index(dir, DIR_PARENT))
+        })
+        .await
+    }
+
     pub async fn get_index(&mut self, parent: u64, name: ByteString) -> Result<Option<u64>> {
         let key = ScopedKey::index(parent, &name);
         self.get(key)
@@ -196,11 +505,13 @@ impl Txn {
     pub async fn set_index(&mut self, parent: u64, name: ByteString, ino: u64) -> Result<()> {
         let key = ScopedKey::index(parent, &name);
         let value = Index::new(ino).serialize()?;
+        self.changed_dirs.insert(parent);
         Ok(self.put(key, value).await?)
     }
 
     pub async fn remove_index(&mut self, parent: u64, name: ByteString) -> Result<()> {
         let key = ScopedKey::index(parent, &name);
+        self.changed_dirs.insert(parent);
         Ok(self.delete(key).await?)
     }
 
@@ -209,26 +520,180 @@ impl Txn {
             .get(ScopedKey::inode(ino))
             .await?
             .ok_or(FsError::InodeNotFound { inode: ino })?;
-        Ok(Inode::deserialize(&value)?)
+        let mut inode = Inode::deserialize(&value)?;
+        if let Some(inlined) = inode.inline_data.take() {
+            inode.inline_data = Some(self.decrypt(inlined)?);
+        }
+        // report the chunk size the data is actually stored in as `st_blksize`
+        inode.blksize = self.block_size as u32;
+        if self.disable_atime {
+            inode.atime = inode.mtime;
+        }
+        Ok(inode)
     }
 
     pub async fn save_inode(&mut self, inode: &Inode) -> Result<()> {
         let key = ScopedKey::inode(inode.ino);
+        self.saved_inodes.insert(inode.ino);
 
         if inode.nlink == 0 && inode.opened_fh == 0 {
+            self.update_usage(inode.ino, None).await?;
             self.delete(key).await?;
+            self.push_free_inode(inode.ino, inode.generation).await?;
         } else {
-            self.put(key, inode.serialize()?).await?;
+            self.update_usage(inode.ino, Some(inode.blocks)).await?;
+            let value = match (&self.cipher, &inode.inline_data) {
+                (Some(cipher), Some(inlined)) => {
+                    let mut sealed = inode.clone();
+                    sealed.inline_data = Some(cipher.encrypt(inlined)?);
+                    sealed.serialize()?
+                }
+                _ => inode.serialize()?,
+            };
+            self.put(key, value).await?;
             debug!("save inode: {:?}", inode);
         }
         Ok(())
     }
 
     pub async fn remove_inode(&mut self, ino: u64) -> Result<()> {
+        self.saved_inodes.insert(ino);
+        let generation = match self.get(ScopedKey::inode(ino)).await? {
+            Some(data) => Inode::deserialize(&data)?.generation,
+            None => return Ok(()),
+        };
+        self.update_usage(ino, None).await?;
         self.delete(ScopedKey::inode(ino)).await?;
+        self.push_free_inode(ino, generation).await
+    }
+
+    /// Free the number of a removed inode to be reused.
+    async fn push_free_inode(&mut self, ino: u64, generation: u64) -> Result<()> {
+        if ino == ROOT_INODE {
+            return Ok(());
+        }
+        let value = generation.to_be_bytes().to_vec();
+        self.put(ScopedKey::free_inode(ino), value).await?;
+        Ok(())
+    }
+
+    /// Take the smallest free inode number and the generation of its last inode.
+    async fn pop_free_inode(&mut self) -> Result<Option<(u64, u64)>> {
+        let pair = match self.scan(ScopedKey::free_inode_scope(), 1).await?.next() {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        let ino = match ScopedKey::parse(pair.key().into())? {
+            ScopedKey::FreeInode(ino) => ino,
+            key => return Err(FsError::InvalidScopedKey(Key::from(key).into())),
+        };
+        let generation = <[u8; 8]>::try_from(pair.value().as_slice())
+            .map(u64::from_be_bytes)
+            .map_err(|_| FsError::UnknownError(format!("invalid free inode({})", ino)))?;
+        self.delete(ScopedKey::free_inode(ino)).await?;
+        Ok(Some((ino, generation)))
+    }
+
+    /// Delete all blocks of an inode which is going to be removed.
+    async fn clear_blocks(&mut self, inode: &Inode) -> Result<()> {
+        if inode.inline_data.is_some() {
+            return Ok(());
+        }
+        // blocks of directories are never deduplicated
+        let deduped = self.dedup && inode.kind == FileType::RegularFile;
+        self.delete_blocks(inode.ino, 0, deduped).await
+    }
+
+    /// Delete the stored blocks of an inode from block `start` on, the contents `deduped`
+    /// blocks refer to are released.
+    /// Blocks are found by scanning rather than by the size, so that holes of a sparse file
+    /// cost nothing, and blocks of an inode which can't be decoded are found as well.
+    pub async fn delete_blocks(&mut self, ino: u64, start: u64, deduped: bool) -> Result<()> {
+        self.written_inodes.insert(ino);
+        let mut pages = Pages::new(
+            ScopedKey::block_range(ino, start..u64::MAX),
+            self.scan_limit,
+        );
+        while let Some(range) = pages.range() {
+            let pairs: Vec<_> = if deduped {
+                self.scan(range, pages.limit()).await?.collect()
+            } else {
+                self.scan_keys(range, pages.limit())
+                    .await?
+                    .map(|key| KvPair::new(key, Vec::new()))
+                    .collect()
+            };
+            for pair in &pairs {
+                if deduped {
+                    self.release_content(pair.value()).await?;
+                }
+                self.delete(pair.key().clone()).await?;
+            }
+            pages.advance(pairs.len(), pairs.last().map(|pair| pair.key()));
+        }
+        Ok(())
+    }
+
+    /// Account the stored inode changing to `new_blocks` in the usage of meta,
+    /// `None` if the inode is going to be removed.
+    async fn update_usage(&mut self, ino: u64, new_blocks: Option<u64>) -> Result<()> {
+        if let Some((_, blocks)) = self.usage_changes.get_mut(&ino) {
+            *blocks = new_blocks;
+            return Ok(());
+        }
+        let old_blocks = match self.get(ScopedKey::inode(ino)).await? {
+            Some(data) => Some(Inode::deserialize(&data)?.blocks),
+            None => None,
+        };
+        self.usage_changes.insert(ino, (old_blocks, new_blocks));
         Ok(())
     }
 
+    /// Add the usage changes of this transaction to meta.
+    async fn save_usage(&mut self) -> Result<()> {
+        if self
+            .usage_changes
+            .values()
+            .all(|(old_blocks, new_blocks)| old_blocks == new_blocks)
+        {
+            return Ok(());
+        }
+        if let Some(mut meta) = self.read_meta().await? {
+            if let Some(usage) = self.usage(&meta) {
+                meta.usage = Some(usage);
+                self.save_meta(&meta).await?;
+            }
+        }
+        self.usage_changes.clear();
+        Ok(())
+    }
+
+    /// Recompute the usage by scanning all inodes, and save it to meta.
+    pub async fn recompute_usage(&mut self) -> Result<Usage> {
+        let mut meta = self
+            .read_meta()
+            .await?
+            .expect("meta should not be none after fs initialized");
+        let mut usage = Usage::default();
+        let mut pages = Pages::new(
+            ScopedKey::inode_range(ROOT_INODE..meta.inode_next),
+            self.scan_limit,
+        );
+        while let Some(range) = pages.range() {
+            let pairs: Vec<_> = self.scan(range, pages.limit()).await?.collect();
+            for pair in &pairs {
+                let inode = Inode::deserialize(pair.value())?;
+                usage.update(None, Some(inode.blocks));
+            }
+            pages.advance(pairs.len(), pairs.last().map(|pair| pair.key()));
+        }
+        meta.usage = Some(usage);
+        self.save_meta(&meta).await?;
+        // the scan sees the inodes saved by this transaction
+        self.usage_changes.clear();
+        Ok(usage)
+    }
+
     pub async fn read_meta(&mut self) -> Result<Option<Meta>> {
         let opt_data = self.get(ScopedKey::meta()).await?;
         opt_data.map(|data| Meta::deserialize(&data)).transpose()
@@ -240,12 +705,10 @@ impl Txn {
     }
 
     async fn transfer_inline_data_to_block(&mut self, inode: &mut Inode) -> Result<()> {
-        debug_assert!(inode.size <= self.inline_data_threshold());
-        let key = ScopedKey::block(inode.ino, 0);
-        let mut data = inode.inline_data.clone().unwrap();
-        data.resize(self.block_size as usize, 0);
-        self.put(key, data).await?;
-        inode.inline_data = None;
+        let inlined = inode.inline_data.take().unwrap();
+        if let Some(block) = inline_block(&inlined, self.block_size) {
+            self.put_block(inode.ino, 0, block).await?;
+        }
         Ok(())
     }
 
@@ -255,10 +718,10 @@ impl Txn {
         start: u64,
         data: &[u8],
     ) -> Result<usize> {
-        debug_assert!(inode.size <= self.inline_data_threshold());
         let size = data.len() as u64;
+        // symlink targets are always inlined, regardless of the threshold
         debug_assert!(
-            start + size <= self.inline_data_threshold(),
+            inode.kind == FileType::Symlink || start + size <= self.inline_data_threshold(),
             "{} + {} > {}",
             start,
             size,
@@ -290,21 +753,13 @@ impl Txn {
         start: u64,
         size: u64,
     ) -> Result<Vec<u8>> {
-        debug_assert!(inode.size <= self.inline_data_threshold());
-
-        let start = start as usize;
-        let size = size as usize;
-
         let inlined = inode.inline_data.as_ref().unwrap();
         debug_assert!(inode.size as usize == inlined.len());
-        let mut data = vec![0; size];
-        if inlined.len() > start {
-            let to_copy = size.min(inlined.len() - start);
-            data[..to_copy].copy_from_slice(&inlined[start..start + to_copy]);
-        }
+        let data = read_inlined(inlined, start, size);
 
-        inode.atime = SystemTime::now();
-        self.save_inode(inode).await?;
+        if self.update_atime(inode) {
+            self.save_inode(inode).await?;
+        }
 
         Ok(data)
     }
@@ -327,127 +782,366 @@ impl Txn {
             return self.read_inline_data(&mut attr, start, size).await;
         }
 
-        let target = start + size;
-        let start_block = start / self.block_size;
-        let end_block = (target + self.block_size - 1) / self.block_size;
+        let data = self.read_blocks(&attr, start, size).await?;
+        if self.update_atime(&mut attr) {
+            self.save_inode(&attr).await?;
+        }
+        Ok(data)
+    }
 
-        let pairs = self
-            .scan(
-                ScopedKey::block_range(ino, start_block..end_block),
-                (end_block - start_block) as u32,
-            )
-            .await?;
+    /// Read for a direct handler, exactly `size` bytes are returned unless the file ends
+    /// before, and the access time is left alone.
+    pub async fn read_data_direct(&mut self, ino: u64, start: u64, size: u64) -> Result<Vec<u8>> {
+        let attr = self.read_inode(ino).await?;
+        if start >= attr.size {
+            return Ok(Vec::new());
+        }
+        let size = size.min(attr.size - start);
+        match &attr.inline_data {
+            // data inlined by buffered writes is not moved by reads
+            Some(inlined) => Ok(read_inlined(inlined, start, size)),
+            None => self.read_blocks(&attr, start, size).await,
+        }
+    }
 
-        let mut data = pairs
-            .enumerate()
-            .flat_map(|(i, pair)| {
-                let key = if let Ok(ScopedKey::Block { ino: _, block }) =
+    /// Read `size` bytes from the blocks, absent blocks are read as zeros.
+    ///
+    /// Blocks are scanned in batches of `READ_BATCH_SIZE` and copied into the result right away,
+    /// so a read holds at most a batch of blocks besides the result. Blocks in the block cache
+    /// are not scanned, unless they lie between blocks to scan.
+    async fn read_blocks(&mut self, inode: &Inode, start: u64, size: u64) -> Result<Vec<u8>> {
+        let ino = inode.ino;
+        // only blocks holding the requested bytes are fetched, there are no ranged value reads
+        let blocks = blocks_of_range(start, size, self.block_size);
+        let batch = (Self::READ_BATCH_SIZE / self.block_size).max(1);
+        // blocks written by this transaction are not committed yet
+        let cache = self
+            .block_cache
+            .clone()
+            .filter(|_| !self.written_inodes.contains(&ino));
+        let stamp = BlockStamp::of(inode);
+
+        // sized by the request, a small read never holds a whole block
+        let mut data = vec![0; size as usize];
+        let mut next_block = blocks.start;
+        while next_block < blocks.end {
+            let batch_end = blocks.end.min(next_block + batch);
+            let fetch = match &cache {
+                Some(cache) => {
+                    let (cached, fetch) = cache.get_range(ino, stamp, next_block..batch_end);
+                    for (block, value) in cached {
+                        copy_block(&mut data, start, block * self.block_size, &value);
+                    }
+                    fetch
+                }
+                None => Some(next_block..batch_end),
+            };
+            next_block = batch_end;
+            let fetch = match fetch {
+                Some(fetch) => fetch,
+                None => continue,
+            };
+
+            let pairs: Vec<_> = self
+                .scan(
+                    ScopedKey::block_range(ino, fetch.clone()),
+                    (fetch.end - fetch.start) as u32,
+                )
+                .await?
+                .collect();
+            // absent blocks are cached as holes
+            let mut holes = HashSet::new();
+            if cache.is_some() {
+                holes.extend(fetch);
+            }
+            for pair in pairs {
+                let block = if let Ok(ScopedKey::Block { ino: _, block }) =
                     ScopedKey::parse(pair.key().into())
                 {
                     block
                 } else {
                     unreachable!("the keys from scanning should be always valid block keys")
                 };
-                let value = pair.into_value();
-                (start_block as usize + i..key as usize)
-                    .map(|_| empty_block(self.block_size))
-                    .chain(vec![value])
-            })
-            .enumerate()
-            .fold(
-                Vec::with_capacity(
-                    ((end_block - start_block) * self.block_size - start % self.block_size)
-                        as usize,
-                ),
-                |mut data, (i, value)| {
-                    let mut slice = value.as_slice();
-                    if i == 0 {
-                        slice = &slice[(start % self.block_size) as usize..]
-                    }
-
-                    data.extend_from_slice(slice);
-                    data
-                },
-            );
+                let mut value = pair.into_value();
+                if self.dedup {
+                    value = self.get_content(ino, block, &value).await?;
+                } else {
+                    value = self.unseal_block(ino, block, value)?;
+                }
 
-        data.resize(size as usize, 0);
-        attr.atime = SystemTime::now();
-        self.save_inode(&attr).await?;
+                copy_block(&mut data, start, block * self.block_size, &value);
+                holes.remove(&block);
+                if let Some(cache) = &cache {
+                    cache.insert(ino, stamp, block, value);
+                }
+            }
+            if let Some(cache) = &cache {
+                for block in holes {
+                    cache.insert(ino, stamp, block, Vec::new());
+                }
+            }
+        }
         Ok(data)
     }
 
     pub async fn clear_data(&mut self, ino: u64) -> Result<u64> {
         let mut attr = self.read_inode(ino).await?;
-        let end_block = (attr.size + self.block_size - 1) / self.block_size;
-
-        for block in 0..end_block {
-            self.delete(ScopedKey::block(ino, block)).await?;
-        }
-
         let clear_size = attr.size;
-        attr.size = 0;
+        self.truncate(&mut attr, 0).await?;
         attr.atime = SystemTime::now();
         self.save_inode(&attr).await?;
         Ok(clear_size)
     }
 
-    #[instrument(skip(self, data))]
-    pub async fn write_data(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
-        let write_start = SystemTime::now();
-        debug!("write data at ({})[{}]", ino, start);
-        let meta = self.read_meta().await?.unwrap();
-        self.check_space_left(&meta)?;
+    /// Change the size of a file without saving the inode.
+    /// Blocks beyond the new size are deleted, so that they won't resurface on re-growth.
+    pub async fn truncate(&mut self, inode: &mut Inode, size: u64) -> Result<()> {
+        if let Some(inlined) = inode.inline_data.as_mut() {
+            if size <= self.inline_data_threshold() || size <= inlined.len() as u64 {
+                inlined.resize(size as usize, 0);
+                inode.set_size(size, self.block_size);
+                return Ok(());
+            }
+            self.transfer_inline_data_to_block(inode).await?;
+        }
 
-        let mut inode = self.read_inode(ino).await?;
-        let size = data.len();
-        let target = start + size as u64;
+        if size < inode.size {
+            let old_end_block = (inode.size + self.block_size - 1) / self.block_size;
+            let new_end_block = (size + self.block_size - 1) / self.block_size;
+            self.delete_blocks(inode.ino, new_end_block, self.dedup)
+                .await?;
+
+            let tail = (size % self.block_size) as usize;
+            if tail != 0 {
+                let block = size / self.block_size;
+                if let Some(mut value) = self.get_block(inode.ino, block).await? {
+                    if value.len() > tail {
+                        value.truncate(tail);
+                        value.resize(self.block_size as usize, 0);
+                        self.put_block(inode.ino, block, value).await?;
+                    }
+                }
+            }
+        }
 
-        if inode.inline_data.is_some() && target > self.inline_data_threshold() {
-            self.transfer_inline_data_to_block(&mut inode).await?;
+        inode.set_size(size, self.block_size);
+        Ok(())
+    }
+
+    /// Read a block that is going to be partially overwritten.
+    /// A block lying entirely beyond the end of file holds no data, so it is not fetched.
+    async fn read_block_for_update(&mut self, ino: u64, block: u64, size: u64) -> Result<Vec<u8>> {
+        if block * self.block_size >= size {
+            return Ok(empty_block(self.block_size));
         }
+        Ok(self
+            .get_block(ino, block)
+            .await?
+            .unwrap_or_else(|| empty_block(self.block_size)))
+    }
 
-        if (inode.inline_data.is_some() || inode.size == 0)
-            && target <= self.inline_data_threshold()
-        {
-            return self.write_inline_data(&mut inode, start, &data).await;
+    /// Read the data of a file block, resolving its content in dedup mode.
+    async fn get_block(&mut self, ino: u64, block: u64) -> Result<Option<Vec<u8>>> {
+        match self.get(ScopedKey::block(ino, block)).await? {
+            Some(value) if self.dedup => self.get_content(ino, block, &value).await.map(Some),
+            Some(value) => self.unseal_block(ino, block, value).map(Some),
+            None => Ok(None),
         }
+    }
 
-        let mut block_index = start / self.block_size;
-        let start_key = ScopedKey::block(ino, block_index);
-        let start_index = (start % self.block_size) as usize;
+    /// Store the data of a file block, once per content in dedup mode.
+    /// Directories are never deduplicated, their blocks are stored as is.
+    async fn put_block(&mut self, ino: u64, block: u64, data: Vec<u8>) -> Result<()> {
+        self.written_inodes.insert(ino);
+        let key = ScopedKey::block(ino, block);
+        if !self.dedup {
+            let value = self.seal_block(data)?;
+            self.put(key, value).await?;
+            return Ok(());
+        }
+        self.put_hashed_block(ino, block, self.content_hash(&data), data)
+            .await
+    }
 
-        let first_block_size = self.block_size as usize - start_index;
+    /// Store a block by the hash of its data in dedup mode.
+    async fn put_hashed_block(
+        &mut self,
+        ino: u64,
+        block: u64,
+        hash: Hash,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.written_inodes.insert(ino);
+        let key = ScopedKey::block(ino, block);
+        let old = self.get(key).await?;
+        if old.as_deref() == Some(&hash[..]) {
+            return Ok(());
+        }
+        self.retain_content(hash, data).await?;
+        if let Some(old) = old {
+            self.release_content(&old).await?;
+        }
+        self.put(key, hash.to_vec()).await?;
+        Ok(())
+    }
 
-        let (first_block, mut rest) = data.split_at(first_block_size.min(data.len()));
+    /// Delete a file block, dropping its reference to the content in dedup mode.
+    async fn delete_block(&mut self, ino: u64, block: u64) -> Result<()> {
+        self.written_inodes.insert(ino);
+        let key = ScopedKey::block(ino, block);
+        if self.dedup {
+            if let Some(old) = self.get(key).await? {
+                self.release_content(&old).await?;
+            }
+        }
+        self.delete(key).await?;
+        Ok(())
+    }
 
-        let mut start_value = self
-            .get(start_key)
+    /// Read the content a deduplicated block entry refers to.
+    async fn get_content(&mut self, ino: u64, block: u64, hash: &[u8]) -> Result<Vec<u8>> {
+        let not_found = || FsError::BlockNotFound { inode: ino, block };
+        let hash = Hash::try_from(hash).map_err(|_| not_found())?;
+        let content = self
+            .get(ScopedKey::content(hash))
             .await?
-            .unwrap_or_else(|| empty_block(self.block_size));
+            .ok_or_else(not_found)?;
+        let data = decode_content(&content)
+            .map(|(_, data)| data.to_vec())
+            .ok_or_else(not_found)?;
+        self.unseal_block(ino, block, data)
+    }
+
+    /// Add a reference to the content, which is stored with the first reference.
+    async fn retain_content(&mut self, hash: Hash, data: Vec<u8>) -> Result<()> {
+        let key = ScopedKey::content(hash);
+        let content = match self.get(key).await?.as_deref().and_then(decode_content) {
+            Some((refs, stored)) => encode_content(refs + 1, stored),
+            None => encode_content(1, &self.seal_block(data)?),
+        };
+        self.put(key, content).await?;
+        Ok(())
+    }
+
+    /// Drop a reference to the content, which is deleted with the last reference.
+    async fn release_content(&mut self, hash: &[u8]) -> Result<()> {
+        // an entry of another length refers to no content
+        let hash = match Hash::try_from(hash) {
+            Ok(hash) => hash,
+            Err(_) => return Ok(()),
+        };
+        let key = ScopedKey::content(hash);
+        if let Some(content) = self.get(key).await? {
+            match decode_content(&content) {
+                Some((refs, data)) if refs > 1 => {
+                    self.put(key, encode_content(refs - 1, data)).await?
+                }
+                _ => self.delete(key).await?,
+            }
+        }
+        Ok(())
+    }
 
-        start_value[start_index..start_index + first_block.len()].copy_from_slice(first_block);
+    pub async fn write_data(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
+        self.write_range(ino, start, data, false).await
+    }
 
-        self.put(start_key, start_value).await?;
+    /// Write for a direct handler, the data always goes to blocks and the access time is left
+    /// alone.
+    pub async fn write_data_direct(&mut self, ino: u64, start: u64, data: Bytes) -> Result<usize> {
+        self.write_range(ino, start, data, true).await
+    }
 
-        while !rest.is_empty() {
-            block_index += 1;
-            let key = ScopedKey::block(ino, block_index);
-            let (curent_block, current_rest) =
-                rest.split_at((self.block_size as usize).min(rest.len()));
-            let mut value = curent_block.to_vec();
-            if value.len() < self.block_size as usize {
-                let mut last_value = self
-                    .get(key)
-                    .await?
-                    .unwrap_or_else(|| empty_block(self.block_size));
-                last_value[..value.len()].copy_from_slice(&value);
-                value = last_value;
+    #[instrument(skip(self, data))]
+    async fn write_range(
+        &mut self,
+        ino: u64,
+        start: u64,
+        data: Bytes,
+        direct: bool,
+    ) -> Result<usize> {
+        let write_start = SystemTime::now();
+        debug!("write data at ({})[{}]", ino, start);
+        let meta = self.read_meta().await?.unwrap();
+
+        let mut inode = self.read_inode(ino).await?;
+        inode.check_write(start)?;
+        let size = data.len();
+        let target = start + size as u64;
+        let new_blocks = (inode.size.max(target) + self.block_size - 1) / self.block_size;
+        self.check_growth(&meta, inode.blocks, new_blocks)?;
+
+        let placement = Placement::of_write(
+            inode.inline_data.is_some(),
+            inode.size,
+            target,
+            self.inline_data_threshold(),
+            direct,
+        );
+        match placement {
+            Placement::Inline => return self.write_inline_data(&mut inode, start, &data).await,
+            Placement::Blocks { transfer: true } => {
+                self.transfer_inline_data_to_block(&mut inode).await?
             }
-            self.put(key, value).await?;
-            rest = current_rest;
+            Placement::Blocks { transfer: false } => (),
         }
 
-        inode.atime = SystemTime::now();
+        let pieces: Vec<_> = split_blocks(start, &data, self.block_size).collect();
+        // blocks partially written, and entries replaced in dedup mode, are read in one request
+        let keys = pieces
+            .iter()
+            .filter(|(block, _, piece)| {
+                self.dedup
+                    || (piece.len() != self.block_size as usize
+                        && block * self.block_size < inode.size)
+            })
+            .map(|(block, _, _)| ScopedKey::block(ino, *block).into())
+            .collect();
+        self.prefetch(keys).await?;
+
+        let mut blocks = Vec::with_capacity(pieces.len());
+        for (block, offset, piece) in pieces {
+            // whole blocks are copied once, into values ready to seal
+            let value = if piece.len() == self.block_size as usize {
+                block_value(piece)
+            } else {
+                let mut value = self.read_block_for_update(ino, block, inode.size).await?;
+                value[offset..offset + piece.len()].copy_from_slice(piece);
+                value
+            };
+            blocks.push((block, value));
+        }
+        if self.dedup {
+            let hashes: Vec<Hash> = blocks
+                .iter()
+                .map(|(_, value)| self.content_hash(value))
+                .collect();
+            // contents referred to before and after the write, in one more request
+            let mut keys: Vec<Key> = hashes
+                .iter()
+                .map(|hash| ScopedKey::content(*hash).into())
+                .collect();
+            for (block, _) in &blocks {
+                let key = ScopedKey::block(ino, *block).into();
+                if let Some(Some(old)) = self.prefetched.get(&key) {
+                    if let Ok(hash) = Hash::try_from(&old[..]) {
+                        keys.push(ScopedKey::content(hash).into());
+                    }
+                }
+            }
+            self.prefetch(keys).await?;
+            for ((block, value), hash) in blocks.into_iter().zip(hashes) {
+                self.put_hashed_block(ino, block, hash, value).await?;
+            }
+        } else {
+            for (block, value) in blocks {
+                self.put_block(ino, block, value).await?;
+            }
+        }
+
+        if !direct {
+            inode.atime = SystemTime::now();
+        }
         inode.mtime = SystemTime::now();
         inode.ctime = SystemTime::now();
         inode.set_size(inode.size.max(target), self.block_size);
@@ -468,14 +1162,29 @@ impl Txn {
         self.write_inline_data(inode, 0, &data).await
     }
 
+    /// Path resolution reads symlinks all the time, so it never writes, even for atime.
     pub async fn read_link(&mut self, ino: u64) -> Result<Vec<u8>> {
-        let mut inode = self.read_inode(ino).await?;
+        let inode = self.read_inode(ino).await?;
         debug_assert!(inode.file_attr.kind == FileType::Symlink);
-        let size = inode.size;
-        self.read_inline_data(&mut inode, 0, size).await
+        Ok(inode.link_target())
+    }
+
+    /// Set the flags of an inode, even an immutable or append-only one.
+    pub async fn set_flags(&mut self, ino: u64, req_uid: u32, flags: u32) -> Result<Inode> {
+        let mut inode = self.read_inode(ino).await?;
+        inode.check_set_flags(req_uid, flags)?;
+        inode.flags = flags;
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await?;
+        Ok(inode)
     }
 
     pub async fn link(&mut self, ino: u64, newparent: u64, newname: ByteString) -> Result<Inode> {
+        self.read_inode(newparent).await?.check_mutable()?;
+        // `..` links the parent of a directory moving into it
+        if newname != DIR_PARENT {
+            self.read_inode(ino).await?.check_overwrite()?;
+        }
         if let Some(old_ino) = self.get_index(newparent, newname.clone()).await? {
             let inode = self.read_inode(old_ino).await?;
             match inode.kind {
@@ -495,18 +1204,49 @@ impl Txn {
         });
 
         self.save_dir(newparent, &dir).await?;
-        inode.nlink += 1;
-        inode.ctime = SystemTime::now();
+        inode.link();
         self.save_inode(&inode).await?;
         Ok(inode)
     }
 
+    /// Check whether the caller(`uid`) may remove or rename the entry `name` of `parent`.
+    pub async fn check_sticky(&mut self, uid: u32, parent: u64, name: ByteString) -> Result<()> {
+        let dir = self.read_inode(parent).await?;
+        if dir.perm & libc::S_ISVTX as u16 == 0 {
+            return Ok(());
+        }
+        let ino = self.lookup(parent, name.clone()).await?;
+        let file = self.read_inode(ino).await?;
+        if sticky_forbids(&dir, &file, uid) {
+            return Err(FsError::StickyEntry {
+                dir: parent,
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Unlink an entry which is not a directory, directories are removed by `rmdir`.
+    pub async fn unlink_file(&mut self, parent: u64, name: ByteString) -> Result<()> {
+        let ino = self.lookup(parent, name.clone()).await?;
+        if self.read_inode(ino).await?.kind == FileType::Directory {
+            return Err(FsError::IsDirectory { ino });
+        }
+        self.unlink(parent, name).await
+    }
+
     pub async fn unlink(&mut self, parent: u64, name: ByteString) -> Result<()> {
+        let dir = self.read_inode(parent).await?;
+        check_directory(&dir)?;
+        dir.check_overwrite()?;
         match self.get_index(parent, name.clone()).await? {
             None => Err(FsError::FileNotFound {
                 file: name.to_string(),
             }),
             Some(ino) => {
+                if name != DIR_SELF && name != DIR_PARENT {
+                    self.read_inode(ino).await?.check_overwrite()?;
+                }
                 self.remove_index(parent, name.clone()).await?;
                 let parent_dir = self.read_dir(parent).await?;
                 let new_parent_dir: Directory = parent_dir
@@ -516,8 +1256,11 @@ impl Txn {
                 self.save_dir(parent, &new_parent_dir).await?;
 
                 let mut inode = self.read_inode(ino).await?;
-                inode.nlink -= 1;
-                inode.ctime = SystemTime::now();
+                inode.unlink();
+                // the inode is removed with its last link, unless it's still opened
+                if inode.nlink == 0 && inode.opened_fh == 0 {
+                    self.clear_blocks(&inode).await?;
+                }
                 self.save_inode(&inode).await?;
                 Ok(())
             }
@@ -548,7 +1291,103 @@ impl Txn {
         }
     }
 
+    /// The default ACL of directory `parent`, inherited by files made in it.
+    async fn default_acl(&mut self, parent: u64) -> Result<Option<Acl>> {
+        if parent < ROOT_INODE {
+            return Ok(None);
+        }
+        match self.read_inode(parent).await?.xattrs.get(ACL_DEFAULT) {
+            Some(value) => Acl::parse(value),
+            None => Ok(None),
+        }
+    }
+
+    /// The mode of a file made in `parent`, the umask is ignored if `parent` has a default ACL,
+    /// which restricts the permissions instead.
+    pub async fn creation_mode(&mut self, parent: u64, mode: u32, umask: u32) -> Result<u32> {
+        Ok(match self.default_acl(parent).await? {
+            Some(_) => mode,
+            None => apply_umask(mode, umask),
+        })
+    }
+
+    pub async fn get_xattr(&mut self, ino: u64, name: ByteString) -> Result<Vec<u8>> {
+        self.read_inode(ino)
+            .await?
+            .xattrs
+            .remove(&*name)
+            .ok_or_else(|| FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            })
+    }
+
+    /// Names of the extended attributes, each followed by a nul.
+    pub async fn list_xattr(&mut self, ino: u64) -> Result<Vec<u8>> {
+        let inode = self.read_inode(ino).await?;
+        let mut names = Vec::new();
+        for name in inode.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    /// Set an extended attribute by the `XATTR_CREATE` or `XATTR_REPLACE` flags, ACLs are
+    /// validated, and the permission bits follow the access ACL, which is not stored if the
+    /// permission bits are enough.
+    pub async fn set_xattr(
+        &mut self,
+        ino: u64,
+        name: ByteString,
+        value: Vec<u8>,
+        flags: i32,
+    ) -> Result<()> {
+        let mut inode = self.read_inode(ino).await?;
+        inode.check_overwrite()?;
+        inode.check_set_xattr(&name, &value, flags)?;
+        match &*name {
+            ACL_ACCESS => {
+                inode.xattrs.remove(ACL_ACCESS);
+                if let Some(acl) = Acl::parse(&value)? {
+                    inode.perm = (inode.perm & !0o777) | acl.perm();
+                    if !acl.is_minimal() {
+                        inode.xattrs.insert(ACL_ACCESS.to_owned(), acl.encode());
+                    }
+                }
+            }
+            ACL_DEFAULT => {
+                if inode.kind != FileType::Directory {
+                    return Err(FsError::DefaultAclOfFile { ino });
+                }
+                inode.xattrs.remove(ACL_DEFAULT);
+                if let Some(acl) = Acl::parse(&value)? {
+                    inode.xattrs.insert(ACL_DEFAULT.to_owned(), acl.encode());
+                }
+            }
+            _ => {
+                inode.xattrs.insert(name.to_string(), value);
+            }
+        }
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
+    pub async fn remove_xattr(&mut self, ino: u64, name: ByteString) -> Result<()> {
+        let mut inode = self.read_inode(ino).await?;
+        inode.check_overwrite()?;
+        if inode.xattrs.remove(&*name).is_none() {
+            return Err(FsError::XattrNotFound {
+                ino,
+                name: name.to_string(),
+            });
+        }
+        inode.ctime = SystemTime::now();
+        self.save_inode(&inode).await
+    }
+
     pub async fn lookup(&mut self, parent: u64, name: ByteString) -> Result<u64> {
+        check_directory(&self.read_inode(parent).await?)?;
         self.get_index(parent, name.clone())
             .await?
             .ok_or_else(|| FsError::FileNotFound {
@@ -557,10 +1396,23 @@ impl Txn {
     }
 
     pub async fn fallocate(&mut self, inode: &mut Inode, offset: i64, length: i64) -> Result<()> {
-        let target_size = (offset + length) as u64;
+        check_regular_file(inode)?;
+        let target_size = u64::try_from(length)
+            .ok()
+            .filter(|length| *length > 0)
+            .and_then(|length| Some(checked_range(0, offset, length)? + length))
+            .ok_or(FsError::InvalidOffset {
+                ino: inode.ino,
+                offset,
+            })?;
+        inode.check_write(offset as u64)?;
         if target_size <= inode.size {
             return Ok(());
         }
+        if let Some(meta) = self.read_meta().await? {
+            let new_blocks = (target_size + self.block_size - 1) / self.block_size;
+            self.check_growth(&meta, inode.blocks, new_blocks)?;
+        }
 
         if inode.inline_data.is_some() {
             if target_size <= self.inline_data_threshold() {
@@ -579,6 +1431,136 @@ impl Txn {
         Ok(())
     }
 
+    /// Copy `len` bytes from `offset_in` of `ino_in` to `offset_out` of `ino_out`, as
+    /// `copy_file_range`. Returns the number of bytes copied, which may be less than `len`.
+    ///
+    /// Whole blocks at aligned offsets are cloned, see `clone_blocks`.
+    /// Other ranges are copied by at most `READ_BATCH_SIZE` bytes at a time.
+    pub async fn copy_range(
+        &mut self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<usize> {
+        let src = self.read_inode(ino_in).await?;
+        check_regular_file(&src)?;
+        let dst = self.read_inode(ino_out).await?;
+        check_regular_file(&dst)?;
+        dst.check_write(offset_out)?;
+        if offset_in >= src.size {
+            return Ok(0);
+        }
+        let len = len.min(src.size - offset_in);
+        let aligned = offset_in % self.block_size == 0 && offset_out % self.block_size == 0;
+        if aligned && src.inline_data.is_none() && len >= self.block_size {
+            let blocks = (len / self.block_size).min(Self::CLONE_BATCH_BLOCKS);
+            return self
+                .clone_blocks(
+                    ino_in,
+                    offset_in / self.block_size,
+                    ino_out,
+                    offset_out / self.block_size,
+                    blocks,
+                )
+                .await;
+        }
+        let data = self
+            .read_data_direct(ino_in, offset_in, len.min(Self::READ_BATCH_SIZE))
+            .await?;
+        self.write_data(ino_out, offset_out, data.into()).await
+    }
+
+    /// Let `blocks` blocks of `ino_out` from `block_out` be the blocks of `ino_in` from `block_in`,
+    /// holes of the source punch holes in the destination.
+    ///
+    /// In dedup mode the destination refers to the contents of the source blocks, which are
+    /// copied on write of either file. Otherwise the stored values are copied as they are,
+    /// data never leaves the cluster to be decoded and encoded again.
+    async fn clone_blocks(
+        &mut self,
+        ino_in: u64,
+        block_in: u64,
+        ino_out: u64,
+        block_out: u64,
+        blocks: u64,
+    ) -> Result<usize> {
+        let meta = self.read_meta().await?.unwrap();
+        let mut inode = self.read_inode(ino_out).await?;
+        let target = (block_out + blocks) * self.block_size;
+        let new_blocks = (inode.size.max(target) + self.block_size - 1) / self.block_size;
+        self.check_growth(&meta, inode.blocks, new_blocks)?;
+        if inode.inline_data.is_some() {
+            self.transfer_inline_data_to_block(&mut inode).await?;
+        }
+
+        self.written_inodes.insert(ino_out);
+        let mut sources: HashMap<u64, Vec<u8>> = HashMap::new();
+        for pair in self
+            .scan(
+                ScopedKey::block_range(ino_in, block_in..block_in + blocks),
+                blocks as u32,
+            )
+            .await?
+        {
+            if let Ok(ScopedKey::Block { ino: _, block }) = ScopedKey::parse(pair.key().into()) {
+                sources.insert(block - block_in, pair.into_value());
+            }
+        }
+        // the replaced blocks, and the contents referred to, are fetched at once
+        let keys: Vec<Key> = (0..blocks)
+            .map(|i| ScopedKey::block(ino_out, block_out + i).into())
+            .collect();
+        self.prefetch(keys).await?;
+        if self.dedup {
+            let contents: Vec<Key> = sources
+                .values()
+                .filter_map(|hash| Hash::try_from(&hash[..]).ok())
+                .map(|hash| ScopedKey::content(hash).into())
+                .collect();
+            self.prefetch(contents).await?;
+        }
+        for i in 0..blocks {
+            let key = ScopedKey::block(ino_out, block_out + i);
+            let old = self.get(key).await?;
+            let value = sources.remove(&i);
+            if old == value {
+                continue;
+            }
+            if self.dedup {
+                if let Some(hash) = &value {
+                    self.share_content(ino_in, block_in + i, hash).await?;
+                }
+                if let Some(old) = old {
+                    self.release_content(&old).await?;
+                }
+            }
+            match value {
+                Some(value) => self.put(key, value).await?,
+                None => self.delete(key).await?,
+            }
+        }
+
+        let now = SystemTime::now();
+        inode.mtime = now;
+        inode.ctime = now;
+        inode.set_size(inode.size.max(target), self.block_size);
+        self.save_inode(&inode).await?;
+        Ok((blocks * self.block_size) as usize)
+    }
+
+    /// Add a reference to the stored content of a deduplicated block entry.
+    async fn share_content(&mut self, ino: u64, block: u64, hash: &[u8]) -> Result<()> {
+        let not_found = || FsError::BlockNotFound { inode: ino, block };
+        let hash = Hash::try_from(hash).map_err(|_| not_found())?;
+        let key = ScopedKey::content(hash);
+        let content = self.get(key).await?.ok_or_else(not_found)?;
+        let (refs, data) = decode_content(&content).ok_or_else(not_found)?;
+        self.put(key, encode_content(refs + 1, data)).await?;
+        Ok(())
+    }
+
     pub async fn mkdir(
         &mut self,
         parent: u64,
@@ -589,7 +1571,9 @@ impl Txn {
     ) -> Result<Inode> {
         let dir_mode = make_mode(FileType::Directory, mode as _);
         let mut inode = self.make_inode(parent, name, dir_mode, gid, uid, 0).await?;
-        inode.perm = mode as _;
+        // keep the special bits, the permission bits may be restricted by a default ACL,
+        // and the setgid bit may be inherited from the parent
+        inode.perm |= mode as u16 & !0o777;
         self.save_inode(&inode).await?;
         self.save_dir(inode.ino, &Directory::new()).await?;
         self.link(inode.ino, inode.ino, DIR_SELF).await?;
@@ -599,47 +1583,62 @@ impl Txn {
         self.read_inode(inode.ino).await
     }
 
+    /// Read a directory, the encoded directory may span several blocks.
     pub async fn read_dir(&mut self, ino: u64) -> Result<Directory> {
-        let data = self
-            .get(ScopedKey::block(ino, 0))
+        let inode = self.read_inode(ino).await?;
+        check_directory(&inode)?;
+        let end_block = inode.data_blocks(self.block_size).max(1);
+        let data: Vec<u8> = self
+            .scan(ScopedKey::block_range(ino, 0..end_block), end_block as u32)
             .await?
-            .ok_or(FsError::BlockNotFound {
+            .flat_map(|pair| pair.into_value())
+            .collect();
+        if data.is_empty() {
+            return Err(FsError::BlockNotFound {
                 inode: ino,
                 block: 0,
-            })?;
+            });
+        }
         trace!("read data: {}", String::from_utf8_lossy(&data));
         super::dir::decode(&data)
     }
 
+    /// Save a directory, the encoded directory is split into blocks and the last one is not padded.
     pub async fn save_dir(&mut self, ino: u64, dir: &[DirItem]) -> Result<Inode> {
         let data = super::dir::encode(dir)?;
         let mut inode = self.read_inode(ino).await?;
-        inode.set_size(data.len() as u64, self.block_size);
+        let old_blocks = inode.data_blocks(self.block_size);
+        let mut new_blocks = 0;
+        for chunk in data.chunks(self.block_size as usize) {
+            self.put(ScopedKey::block(ino, new_blocks), chunk.to_vec())
+                .await?;
+            new_blocks += 1;
+        }
+        for block in new_blocks..old_blocks {
+            self.delete(ScopedKey::block(ino, block)).await?;
+        }
+
+        inode.set_dir_size(dir.len(), data.len() as u64, self.block_size);
         inode.atime = SystemTime::now();
         inode.mtime = SystemTime::now();
         inode.ctime = SystemTime::now();
         self.save_inode(&inode).await?;
-        self.put(ScopedKey::block(ino, 0), data).await?;
         Ok(inode)
     }
 
     pub async fn statfs(&mut self) -> Result<StatFs> {
         let bsize = self.block_size as u32;
-        let mut meta = self
+        let meta = self
             .read_meta()
             .await?
             .expect("meta should not be none after fs initialized");
         let next_inode = meta.inode_next;
-        let (used_blocks, files) = self
-            .scan(
-                ScopedKey::inode_range(ROOT_INODE..next_inode),
-                (next_inode - ROOT_INODE) as u32,
-            )
-            .await?
-            .map(|pair| Inode::deserialize(pair.value()))
-            .try_fold((0, 0), |(blocks, files), inode| {
-                Ok::<_, FsError>((blocks + inode?.blocks, files + 1))
-            })?;
+        let usage = match meta.usage {
+            Some(usage) => usage,
+            // only once for filesystems created before usage was maintained
+            None => self.recompute_usage().await?,
+        };
+        let (used_blocks, files) = (usage.blocks, usage.files);
         let ffree = std::u64::MAX - next_inode;
         let bfree = match self.max_blocks {
             Some(max_blocks) if max_blocks > used_blocks => max_blocks - used_blocks,
@@ -662,22 +1661,95 @@ impl Txn {
             0,
         );
         trace!("statfs: {:?}", stat);
-        meta.last_stat = Some(stat.clone());
-        self.save_meta(&meta).await?;
         Ok(stat)
     }
 }
 
-impl Deref for Txn {
-    type Target = Transaction;
+/// Raw access to keys.
+impl Txn {
+    pub async fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let key = key.into();
+        if let Some(value) = self.prefetched.get(&key) {
+            return Ok(value.clone());
+        }
+        self.reads += 1;
+        Ok(match &mut self.store {
+            Store::Optimistic(txn) => txn.get(key).await?,
+            Store::Snapshot(snapshot) => snapshot.get(key).await?,
+        })
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.txn
+    /// Fetch values of keys in one request, reading them later takes no request.
+    pub async fn prefetch(&mut self, keys: Vec<Key>) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.reads += 1;
+        let pairs: Vec<_> = match &mut self.store {
+            Store::Optimistic(txn) => txn.batch_get(keys.clone()).await?.collect(),
+            Store::Snapshot(snapshot) => snapshot.batch_get(keys.clone()).await?.collect(),
+        };
+        self.prefetched.fill(keys, pairs);
+        Ok(())
+    }
+
+    pub async fn scan(
+        &mut self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        self.reads += 1;
+        let pairs: Vec<_> = match &mut self.store {
+            Store::Optimistic(txn) => txn.scan(range, limit).await?.collect(),
+            Store::Snapshot(snapshot) => snapshot.scan(range, limit).await?.collect(),
+        };
+        Ok(pairs.into_iter())
+    }
+
+    pub async fn scan_keys(
+        &mut self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+    ) -> Result<impl Iterator<Item = Key>> {
+        self.reads += 1;
+        let keys: Vec<_> = match &mut self.store {
+            Store::Optimistic(txn) => txn.scan_keys(range, limit).await?.collect(),
+            Store::Snapshot(snapshot) => snapshot.scan_keys(range, limit).await?.collect(),
+        };
+        Ok(keys.into_iter())
+    }
+
+    pub async fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let key = key.into();
+        self.prefetched.forget(&key);
+        match &mut self.store {
+            Store::Optimistic(txn) => Ok(txn.put(key, value).await?),
+            Store::Snapshot(_) => Err(FsError::ReadOnly),
+        }
+    }
+
+    pub async fn delete(&mut self, key: impl Into<Key>) -> Result<()> {
+        let key = key.into();
+        self.prefetched.forget(&key);
+        match &mut self.store {
+            Store::Optimistic(txn) => Ok(txn.delete(key).await?),
+            Store::Snapshot(_) => Err(FsError::ReadOnly),
+        }
     }
-}
 
-impl DerefMut for Txn {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.txn
+    /// Commit the transaction, there is nothing to commit on a snapshot.
+    pub async fn commit(&mut self) -> Result<()> {
+        self.save_usage().await?;
+        if let Store::Optimistic(txn) = &mut self.store {
+            txn.commit().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn rollback(&mut self) -> Result<()> {
+        if let Store::Optimistic(txn) = &mut self.store {
+            txn.rollback().await?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Contiguous data written by a file handler but not committed yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteBuffer {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl WriteBuffer {
+    pub fn new(offset: u64, data: Vec<u8>) -> Self {
+        Self { offset, data }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+
+    /// Append data written at `offset`, refuses unless it follows the buffer.
+    pub fn append(&mut self, offset: u64, data: &[u8]) -> bool {
+        if offset != self.end() {
+            return false;
+        }
+        self.data.extend_from_slice(data);
+        true
+    }
+
+    /// Whether the buffer holds a whole block or ends on a block boundary.
+    pub fn is_full(&self, block_size: u64) -> bool {
+        self.data.len() as u64 >= block_size || self.end() % block_size == 0
+    }
+}
+
+/// Buffers of an inode, keyed by file handlers.
+pub type InodeBuffers = HashMap<u64, WriteBuffer>;
+
+/// The write buffers on this mount.
+///
+/// Buffers of an inode are locked together, so that they can be committed
+/// before any operation which may overlap them.
+#[derive(Debug, Default)]
+pub struct WriteBuffers {
+    inodes: Mutex<HashMap<u64, Arc<AsyncMutex<InodeBuffers>>>>,
+}
+
+impl WriteBuffers {
+    pub fn get(&self, ino: u64) -> Arc<AsyncMutex<InodeBuffers>> {
+        self.inodes.lock().unwrap().entry(ino).or_default().clone()
+    }
+
+    /// Whether the inode has buffered writes, or somebody is holding its buffers.
+    pub fn buffered(&self, ino: u64) -> bool {
+        match self.inodes.lock().unwrap().get(&ino) {
+            Some(buffers) => !matches!(buffers.try_lock(), Ok(buffers) if buffers.is_empty()),
+            None => false,
+        }
+    }
+
+    /// Forget the inode if it has no buffer and nobody is holding it.
+    pub fn release(&self, ino: u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+        let unused = match inodes.get(&ino) {
+            Some(buffers) => {
+                Arc::strong_count(buffers) == 1
+                    && matches!(buffers.try_lock(), Ok(buffers) if buffers.is_empty())
+            }
+            None => false,
+        };
+        if unused {
+            inodes.remove(&ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_contiguous() {
+        let mut buffer = WriteBuffer::new(4, vec![0; 4]);
+        assert!(!buffer.append(0, &[1; 4]));
+        assert!(!buffer.append(12, &[1; 4]));
+        assert!(buffer.append(8, &[1; 4]));
+        assert_eq!(buffer.end(), 12);
+        assert_eq!(buffer.data, [0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn full_buffer() {
+        assert!(!WriteBuffer::new(0, vec![0; 4]).is_full(16));
+        assert!(WriteBuffer::new(12, vec![0; 4]).is_full(16));
+        assert!(WriteBuffer::new(4, vec![0; 16]).is_full(16));
+    }
+}
@@ -5,12 +5,15 @@ pub mod fs;
 use std::path::PathBuf;
 
 use fs::async_fs::AsyncFs;
-use fs::client::TlsConfig;
+pub use fs::async_fs::MountHandle;
+use fs::client::ClientConfig;
+use fs::crypto::BlockCipher;
+pub use fs::tifs_client::TifsClient;
 use fs::tikv_fs::TiFs;
 use fuser::MountOption as FuseMountOption;
 use paste::paste;
 use tokio::fs::{metadata, read_to_string};
-use tracing::debug;
+use tracing::{debug, warn};
 
 const DEFAULT_TLS_CONFIG_PATH: &str = "~/.tifs/tls.toml";
 
@@ -132,10 +135,53 @@ define_options! { MountOption (FuseMountOption) {
     builtin Exec,
     builtin NoExec,
     builtin DirSync,
+    builtin NoAtime,
+    builtin "allow_other" AllowOther,
+    builtin "allow_root" AllowRoot,
     define "direct_io" DirectIO,
     define BlkSize(String),
     define MaxSize(String), // size of filesystem
     define Tls(String),
+    define "disable_atime" DisableAtime,
+    define RelAtime,
+    define StrictAtime,
+    define NoInline,
+    define "inline_threshold" InlineThreshold(String),
+    define "inline_divisor" InlineDivisor(String), // inline threshold as a fraction of blocks
+    define AttrCache(String), // ttl of the inode attribute cache
+    define DentryCache(String), // ttl of the directory entry cache
+    define BlockCache(String), // size of the cache of file blocks
+    define Scrub(String), // interval between slices of background verification
+    define "scrub_batch" ScrubBatch(String), // number of inodes in a slice
+    define "health_check" HealthCheck(String), // interval of pd endpoints health checking
+    define "health_threshold" HealthThreshold(String), // failures before reconnecting
+    define "entry_timeout" EntryTimeout(String), // ttl of entries cached by the kernel
+    define "attr_timeout" AttrTimeout(String), // ttl of attributes cached by the kernel
+    define "max_concurrency" MaxConcurrency(String), // number of concurrent transactions
+    define "metrics_addr" MetricsAddr(String), // address serving prometheus metrics
+    define Writeback, // buffer sequential writes of a file handler
+    define Dedup, // store identical blocks once
+    define Checksum, // verify blocks by their CRC32C
+    define Encrypt, // encrypt data by the key in the tls config
+    define "grpc_timeout" GrpcTimeout(String), // timeout of requests to pd and tikv
+    define "op_timeout" OpTimeout(String), // timeout of an operation including its retries
+    define Uid(String), // owner presented for every file
+    define Gid(String), // group presented for every file
+    define Fmask(String), // permission bits cleared for files, in octal
+    define Dmask(String), // permission bits cleared for directories, in octal
+    define Readahead(String), // data prefetched for sequential readers
+    define "max_open" MaxOpen(String), // number of file handlers opened at once
+    define "scan_limit" ScanLimit(String), // number of keys in a page of scanning
+    define "mount_retries" MountRetries(String), // times to retry connecting on mounting
+    define "mount_retry_delay" MountRetryDelay(String), // delay between retries of connecting
+    define "max_write" MaxWrite(String), // size of the largest write request
+    define "max_readahead" MaxReadahead(String), // size of reading ahead by the kernel
+    define "noallow_other" NoAllowOther, // only the user mounting may access the mount
+    define "no_default_permissions" NoDefaultPermissions, // leave permission checks to tifs
+    define "async_commit" AsyncCommit, // finish transactions once prewritten, the default
+    define "noasync_commit" NoAsyncCommit, // commit transactions in two phases
+    define "one_pc" OnePc, // commit transactions within one region in a single phase
+    define "posix_locks" PosixLocks, // share POSIX record locks by mounts instead of flock locks
 //    define "opt" OptionName(Display_Debug_Clone_PartialEq_FromStr_able)
 }}
 
@@ -238,6 +284,20 @@ mod tests {
             ),
             "[DirectIO, NoDev, BlkSize(32)]"
         );
+        assert_eq!(
+            format!(
+                "{:?}",
+                MountOption::to_vec(vec!["disable_atime"].iter().copied())
+            ),
+            "[DisableAtime]"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                MountOption::to_vec(vec!["disableatime"].iter().copied())
+            ),
+            "[Unknown(\"disableatime\")]"
+        );
     }
 
     #[test]
@@ -251,10 +311,98 @@ mod tests {
             Some(FuseMountOption::DirSync)
         );
         assert_eq!(MountOption::DirectIO.to_builtin(), None);
+        assert_eq!(
+            MountOption::NoAtime.to_builtin(),
+            Some(FuseMountOption::NoAtime)
+        );
+        assert_eq!(MountOption::RelAtime.to_builtin(), None);
         assert_eq!(MountOption::BlkSize("1".to_owned()).to_builtin(), None);
         assert_eq!(MountOption::MaxSize("1".to_owned()).to_builtin(), None);
     }
 
+    #[test]
+    fn allow_other_by_default() {
+        let allowed = |options: &[MountOption]| {
+            let fuse_options = fuse_options(&["127.0.0.1:2379"], options).unwrap();
+            let allow = |option| fuse_options.iter().filter(|o| **o == option).count();
+            (
+                allow(FuseMountOption::AllowOther),
+                allow(FuseMountOption::AllowRoot),
+            )
+        };
+        assert_eq!(allowed(&[]), (1, 0));
+        assert_eq!(allowed(&[MountOption::AllowOther]), (1, 0));
+        assert_eq!(allowed(&[MountOption::AllowRoot]), (0, 1));
+        assert_eq!(allowed(&[MountOption::NoAllowOther]), (0, 0));
+        assert_eq!(
+            allowed(&[MountOption::AllowOther, MountOption::AllowRoot]),
+            (0, 1)
+        );
+        assert_eq!(
+            allowed(&[MountOption::AllowRoot, MountOption::AllowOther]),
+            (1, 0)
+        );
+        assert_eq!(
+            allowed(&[MountOption::AllowOther, MountOption::NoAllowOther]),
+            (0, 0)
+        );
+        assert_eq!(String::from(MountOption::AllowRoot), "allow_root");
+        assert_eq!(String::from(MountOption::NoAllowOther), "noallow_other");
+    }
+
+    #[test]
+    fn fuse_options_of_mounts() {
+        let fsname = |endpoints: &[&str]| {
+            fuse_options(endpoints, &[])
+                .unwrap()
+                .into_iter()
+                .find(|option| matches!(option, FuseMountOption::FSName(_)))
+        };
+        // mounts of different clusters in one process are told apart by their names
+        assert_eq!(
+            fsname(&["127.0.0.1:2379"]),
+            Some(FuseMountOption::FSName("tifs:127.0.0.1:2379".to_owned()))
+        );
+        assert_eq!(
+            fsname(&["10.0.0.1:2379", "10.0.0.2:2379"]),
+            Some(FuseMountOption::FSName(
+                "tifs:10.0.0.1:2379,10.0.0.2:2379".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn default_permissions_toggle() {
+        let checked_by_kernel = |options: &[MountOption]| {
+            fuse_options(&["127.0.0.1:2379"], options)
+                .unwrap()
+                .contains(&FuseMountOption::DefaultPermissions)
+        };
+        assert!(checked_by_kernel(&[]));
+        assert!(checked_by_kernel(&[MountOption::AllowRoot]));
+        assert!(!checked_by_kernel(&[
+            MountOption::NoDefaultPermissions,
+            MountOption::NoAllowOther
+        ]));
+
+        // other users may never reach files unchecked
+        let refused = |options: &[MountOption]| fuse_options(&["127.0.0.1:2379"], options).is_err();
+        assert!(refused(&[MountOption::NoDefaultPermissions]));
+        assert!(refused(&[
+            MountOption::NoDefaultPermissions,
+            MountOption::AllowRoot
+        ]));
+        assert!(refused(&[
+            MountOption::NoAllowOther,
+            MountOption::NoDefaultPermissions,
+            MountOption::AllowOther
+        ]));
+        assert_eq!(
+            String::from(MountOption::NoDefaultPermissions),
+            "no_default_permissions"
+        );
+    }
+
     #[test]
     fn format_mount_options() {
         assert_eq!(String::from(MountOption::NoDev), "nodev");
@@ -267,28 +415,207 @@ mod tests {
             String::from(MountOption::BlkSize("1MiB".to_owned())),
             "blksize=1MiB"
         );
+        assert_eq!(String::from(MountOption::DisableAtime), "disable_atime");
+        assert_eq!(String::from(MountOption::NoAtime), "noatime");
+        assert_eq!(String::from(MountOption::RelAtime), "relatime");
+        assert_eq!(String::from(MountOption::StrictAtime), "strictatime");
+        assert_eq!(String::from(MountOption::NoInline), "noinline");
+        assert_eq!(String::from(MountOption::Writeback), "writeback");
+        assert_eq!(String::from(MountOption::Dedup), "dedup");
+        assert_eq!(String::from(MountOption::Checksum), "checksum");
+        assert_eq!(String::from(MountOption::AsyncCommit), "async_commit");
+        assert_eq!(String::from(MountOption::NoAsyncCommit), "noasync_commit");
+        assert_eq!(String::from(MountOption::OnePc), "one_pc");
+        assert_eq!(String::from(MountOption::PosixLocks), "posix_locks");
+        assert_eq!(String::from(MountOption::Encrypt), "encrypt");
+        assert_eq!(
+            String::from(MountOption::InlineThreshold("4KiB".to_owned())),
+            "inline_threshold=4KiB"
+        );
+        assert_eq!(
+            String::from(MountOption::InlineDivisor("8".to_owned())),
+            "inline_divisor=8"
+        );
+        assert_eq!(
+            String::from(MountOption::DentryCache("1s".to_owned())),
+            "dentrycache=1s"
+        );
+        assert_eq!(
+            String::from(MountOption::ScrubBatch("128".to_owned())),
+            "scrub_batch=128"
+        );
+        assert_eq!(
+            String::from(MountOption::HealthCheck("5s".to_owned())),
+            "health_check=5s"
+        );
+        assert_eq!(
+            String::from(MountOption::GrpcTimeout("2s".to_owned())),
+            "grpc_timeout=2s"
+        );
+        assert_eq!(
+            String::from(MountOption::OpTimeout("30s".to_owned())),
+            "op_timeout=30s"
+        );
+        assert_eq!(
+            String::from(MountOption::Uid("1000".to_owned())),
+            "uid=1000"
+        );
+        assert_eq!(String::from(MountOption::Gid("100".to_owned())), "gid=100");
+        assert_eq!(
+            String::from(MountOption::Readahead("256KiB".to_owned())),
+            "readahead=256KiB"
+        );
+        assert_eq!(
+            String::from(MountOption::BlockCache("256m".to_owned())),
+            "blockcache=256m"
+        );
+        assert_eq!(
+            String::from(MountOption::MaxOpen("1024".to_owned())),
+            "max_open=1024"
+        );
+        assert_eq!(
+            String::from(MountOption::ScanLimit("256".to_owned())),
+            "scan_limit=256"
+        );
+        assert_eq!(
+            String::from(MountOption::MountRetries("30".to_owned())),
+            "mount_retries=30"
+        );
+        assert_eq!(
+            String::from(MountOption::MountRetryDelay("2s".to_owned())),
+            "mount_retry_delay=2s"
+        );
+        assert_eq!(
+            String::from(MountOption::MaxWrite("1m".to_owned())),
+            "max_write=1m"
+        );
+        assert_eq!(
+            String::from(MountOption::Fmask("022".to_owned())),
+            "fmask=022"
+        );
+        assert_eq!(
+            String::from(MountOption::Dmask("002".to_owned())),
+            "dmask=002"
+        );
+        assert_eq!(
+            String::from(MountOption::EntryTimeout("1s".to_owned())),
+            "entry_timeout=1s"
+        );
+        assert_eq!(
+            String::from(MountOption::AttrTimeout("500ms".to_owned())),
+            "attr_timeout=500ms"
+        );
+        assert_eq!(
+            String::from(MountOption::MaxConcurrency("64".to_owned())),
+            "max_concurrency=64"
+        );
+        assert_eq!(
+            String::from(MountOption::MetricsAddr("0.0.0.0:9110".to_owned())),
+            "metrics_addr=0.0.0.0:9110"
+        );
+    }
+
+    async fn mount_background(prefix: &str) -> (std::path::PathBuf, MountHandle) {
+        let mountpoint = std::env::temp_dir().join(fs::testing::unique_name(prefix).to_string());
+        tokio::fs::create_dir(&mountpoint).await.unwrap();
+        let handle = mount_tifs_background(
+            mountpoint.to_string_lossy().into_owned(),
+            vec![fs::testing::PD_ENDPOINT],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        (mountpoint, handle)
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unmount_background_mounts_independently() {
+        let (first, first_handle) = mount_background("first-mount").await;
+        let (second, second_handle) = mount_background("second-mount").await;
+        let name = fs::testing::unique_name("shared").to_string();
+
+        // blocking calls on the mount points go to other threads, the runtime serves them
+        tokio::fs::write(first.join(&name), "by the first")
+            .await
+            .unwrap();
+        assert_eq!(
+            tokio::fs::read(second.join(&name)).await.unwrap(),
+            b"by the first"
+        );
+
+        first_handle.unmount().await.unwrap();
+        assert!(tokio::fs::metadata(first.join(&name)).await.is_err());
+        // the other mount is still served
+        tokio::fs::write(second.join(&name), "by the second")
+            .await
+            .unwrap();
+        assert_eq!(
+            tokio::fs::read(second.join(&name)).await.unwrap(),
+            b"by the second"
+        );
+
+        tokio::fs::remove_file(second.join(&name)).await.unwrap();
+        second_handle.unmount().await.unwrap();
+        tokio::fs::remove_dir(&first).await.unwrap();
+        tokio::fs::remove_dir(&second).await.unwrap();
     }
 }
 
-pub async fn mount_tifs_daemonize<F>(
-    mountpoint: String,
-    endpoints: Vec<&str>,
-    options: Vec<MountOption>,
-    make_daemon: F,
-) -> anyhow::Result<()>
-where
-    F: FnOnce() -> anyhow::Result<()>,
-{
-    let mut fuse_options = vec![
-        FuseMountOption::FSName(format!("tifs:{}", endpoints.join(","))),
-        FuseMountOption::AllowOther,
-        FuseMountOption::DefaultPermissions,
-    ];
+/// Options of the fuse mount, other users may access it unless `allow_root` or `noallow_other`
+/// is given, the last of them wins.
+///
+/// Permissions are checked by the kernel unless `no_default_permissions` is given, then only
+/// `access` is checked by tifs, against the mode and the access ACL. Other operations are not
+/// checked, so `no_default_permissions` is refused unless `noallow_other` is given.
+fn fuse_options(
+    endpoints: &[&str],
+    options: &[MountOption],
+) -> anyhow::Result<Vec<FuseMountOption>> {
+    let mut fuse_options = vec![FuseMountOption::FSName(format!(
+        "tifs:{}",
+        endpoints.join(",")
+    ))];
+    // fusermount refuses both `allow_other` and `allow_root`, so the last one given wins
+    let access = options
+        .iter()
+        .rev()
+        .find_map(|option| match option {
+            MountOption::AllowOther => Some(Some(FuseMountOption::AllowOther)),
+            MountOption::AllowRoot => Some(Some(FuseMountOption::AllowRoot)),
+            MountOption::NoAllowOther => Some(None),
+            _ => None,
+        })
+        .unwrap_or(Some(FuseMountOption::AllowOther));
+    if options.contains(&MountOption::NoDefaultPermissions) {
+        if let Some(access) = &access {
+            return Err(anyhow::anyhow!(
+                "no_default_permissions leaves files unchecked for other users, \
+                 it requires noallow_other rather than {:?}",
+                access
+            ));
+        }
+        warn!("permissions are only checked on access(2) without default_permissions");
+    } else {
+        fuse_options.push(FuseMountOption::DefaultPermissions);
+    }
+    fuse_options.extend(access);
 
     #[cfg(target_os = "linux")]
     fuse_options.push(FuseMountOption::AutoUnmount);
 
-    fuse_options.extend(MountOption::collect_builtin(options.iter()));
+    fuse_options.extend(MountOption::collect_builtin(options.iter().filter(
+        |option| !matches!(option, MountOption::AllowOther | MountOption::AllowRoot),
+    )));
+    Ok(fuse_options)
+}
+
+/// Connect to the cluster, returns the filesystem and the options to mount it with.
+async fn connect_tifs(
+    endpoints: Vec<&str>,
+    options: Vec<MountOption>,
+) -> anyhow::Result<(TiFs, Vec<FuseMountOption>)> {
+    let mut fuse_options = fuse_options(&endpoints, &options)?;
 
     let tls_cfg_path = options
         .iter()
@@ -301,23 +628,82 @@ where
         })
         .unwrap_or_else(default_tls_config_path)?;
 
-    let client_cfg = if metadata(&tls_cfg_path).await.is_ok() {
-        let client_cfg_contents = read_to_string(tls_cfg_path).await?;
-        toml::from_str::<TlsConfig>(&client_cfg_contents)?.into()
+    let tls_cfg = if metadata(&tls_cfg_path).await.is_ok() {
+        let client_cfg_contents = read_to_string(&tls_cfg_path).await?;
+        Some(toml::from_str::<ClientConfig>(&client_cfg_contents)?)
+    } else {
+        None
+    };
+
+    let cipher = if options
+        .iter()
+        .any(|option| matches!(option, MountOption::Encrypt))
+    {
+        let key_path = tls_cfg
+            .as_ref()
+            .and_then(|cfg| cfg.encryption_key_path.clone())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "encrypt requires encryption-key-path in {}",
+                    tls_cfg_path.display()
+                )
+            })?;
+        Some(BlockCipher::from_hex(&read_to_string(key_path).await?)?)
     } else {
-        Default::default()
+        None
     };
 
+    let robustness = tls_cfg
+        .as_ref()
+        .map(|cfg| cfg.robustness.clone())
+        .unwrap_or_default();
+    let client_cfg: tikv_client::Config = tls_cfg.map(Into::into).unwrap_or_default();
     debug!("use tikv client config: {:?}", client_cfg);
-    let fs_impl = TiFs::construct(endpoints, client_cfg, options).await?;
+    let fs_impl = TiFs::construct(endpoints, client_cfg, robustness, options, cipher).await?;
+
+    // the kernel never sends a read larger than `max_read`
+    #[cfg(target_os = "linux")]
+    fuse_options.push(FuseMountOption::CUSTOM(format!(
+        "max_read={}",
+        fs_impl.max_read()
+    )));
+    Ok((fs_impl, fuse_options))
+}
+
+pub async fn mount_tifs_daemonize<F>(
+    mountpoint: String,
+    endpoints: Vec<&str>,
+    options: Vec<MountOption>,
+    make_daemon: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce() -> anyhow::Result<()>,
+{
+    let (fs_impl, fuse_options) = connect_tifs(endpoints, options).await?;
 
     make_daemon()?;
 
-    fuser::mount2(AsyncFs::from(fs_impl), mountpoint, &fuse_options)?;
+    AsyncFs::from(fs_impl)
+        .spawn_mount(mountpoint, &fuse_options)?
+        .join()
+        .await?;
 
     Ok(())
 }
 
+/// Mount tifs without blocking, it's served until the returned handle is unmounted.
+///
+/// Filesystems of different clusters can be mounted on their own mount points this way,
+/// sharing the runtime of the process.
+pub async fn mount_tifs_background(
+    mountpoint: String,
+    endpoints: Vec<&str>,
+    options: Vec<MountOption>,
+) -> anyhow::Result<MountHandle> {
+    let (fs_impl, fuse_options) = connect_tifs(endpoints, options).await?;
+    Ok(AsyncFs::from(fs_impl).spawn_mount(mountpoint, &fuse_options)?)
+}
+
 pub async fn mount_tifs(
     mountpoint: String,
     endpoints: Vec<&str>,